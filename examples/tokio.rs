@@ -0,0 +1,51 @@
+//! Runs two plates programs at the same time on a single thread, switching between them every 20 instructions.
+//!
+//! Run with `cargo run --example tokio --features tokio`.
+
+use anyhow::Error;
+use plates::{
+    lexer::Lexer,
+    parser::Parser,
+    runtime::{Runtime, Status},
+};
+
+const BUDGET: usize = 20;
+
+/// Prints "Hello from <name>!" three times.
+fn program(name: &str) -> Vec<String> {
+    let mut lines = vec!["DEFN greet (1) {".to_owned(), "    PUSH 0".to_owned()];
+    lines.extend(
+        format!("Hello from {name}!\n")
+            .chars()
+            .rev()
+            .map(|c| format!("    PUSH {}", c as u32)),
+    );
+    lines.extend([
+        "    PUSH 1 PUSH __print__ CALLIF".to_owned(),
+        "}".to_owned(),
+        "PUSH 3 PUSH greet PUSH 1 PUSH __call_n_times__ CALLIF".to_owned(),
+    ]);
+    lines
+}
+
+async fn run(lines: Vec<String>) -> Result<(), Error> {
+    let mut runtime = Runtime::new();
+    let mut parser = Parser::new(Lexer::new(lines.into_iter()));
+    while let Some(instruction) = parser.next_instruction()? {
+        runtime.push_instruction(instruction);
+        loop {
+            match runtime.run_budgeted(BUDGET)? {
+                Status::Pending => tokio::task::yield_now().await,
+                Status::Done => break,
+                Status::Exit => return Ok(()),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Error> {
+    let (a, b) = tokio::join!(run(program("A")), run(program("B")));
+    a.and(b)
+}
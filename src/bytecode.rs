@@ -0,0 +1,288 @@
+//! Binary encoding of instruction streams (`.platesc` files).
+//!
+//! A file starts with the magic bytes `PLTC` followed by a version byte. The rest of the file is a sequence of
+//! instructions, each of which starts with a one-byte opcode. All integers are little-endian.
+//!
+//! | Opcode | Instruction    | Operands                                                              |
+//! |--------|----------------|-----------------------------------------------------------------------|
+//! | `0x01` | `PushData`     | value (`u32`)                                                         |
+//! | `0x02` | `PushFunction` | name length (`u32`), name (UTF-8)                                     |
+//! | `0x03` | `PushRandom`   |                                                                       |
+//! | `0x04` | `PushArg`      | index (`u64`)                                                         |
+//! | `0x05` | `Define`       | name length (`u32`), name (UTF-8), arg count (`u32`), flags (`u8`), origin, body length in instructions (`u32`), body |
+//! | `0x06` | `CallIf`       |                                                                       |
+//! | `0x07` | `Exit`         |                                                                       |
+//! | `0x08` | `PushData`     | value (`u64`), for values that do not fit in a `u32`                  |
+//! | `0x09` | `PushBig`      | length of the decimal digits (`u32`), digits (ASCII)                  |
+//!
+//! The flags of a definition are `0x01` if the function is private (`DEFN_PRIV`) and `0x02` if the file that it was
+//! defined in is known, in which case the origin is that file's name (length (`u32`), name (UTF-8)). Otherwise, the
+//! origin is left out. The origin is what keeps private functions from being called from other files. Docstrings and
+//! the other attributes of definitions are not kept.
+
+use anyhow::{anyhow, Error};
+
+use crate::parser::{Attributes, Instruction};
+
+const MAGIC: &[u8] = b"PLTC";
+const VERSION: u8 = 2;
+
+const OP_PUSH_DATA: u8 = 0x01;
+const OP_PUSH_FUNCTION: u8 = 0x02;
+const OP_PUSH_RANDOM: u8 = 0x03;
+const OP_PUSH_ARG: u8 = 0x04;
+const OP_DEFINE: u8 = 0x05;
+const OP_CALLIF: u8 = 0x06;
+const OP_EXIT: u8 = 0x07;
+const OP_PUSH_DATA64: u8 = 0x08;
+const OP_PUSH_BIG: u8 = 0x09;
+
+const FLAG_PRIVATE: u8 = 0x01;
+const FLAG_ORIGIN: u8 = 0x02;
+
+const ERR_HEADER: &str = "Bytecode error: Invalid header.";
+const ERR_EOF: &str = "Bytecode error: Unexpected end of file.";
+const ERR_NESTED: &str = "Bytecode error: Nested definitions are not allowed.";
+const ERR_UTF8: &str = "Bytecode error: Invalid UTF-8 in function name.";
+
+pub fn encode(instructions: &[Instruction]) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    for instruction in instructions {
+        encode_instruction(instruction, &mut bytes);
+    }
+    bytes
+}
+
+fn encode_instruction(instruction: &Instruction, bytes: &mut Vec<u8>) {
+    match instruction {
+        Instruction::PushData(n) => match u32::try_from(*n) {
+            Ok(n) => {
+                bytes.push(OP_PUSH_DATA);
+                bytes.extend(n.to_le_bytes());
+            }
+            Err(_) => {
+                bytes.push(OP_PUSH_DATA64);
+                bytes.extend(n.to_le_bytes());
+            }
+        },
+        Instruction::PushBig(n) => {
+            bytes.push(OP_PUSH_BIG);
+            encode_string(n, bytes);
+        }
+        Instruction::PushFunction(f) => {
+            bytes.push(OP_PUSH_FUNCTION);
+            encode_string(f, bytes);
+        }
+        Instruction::PushRandom => bytes.push(OP_PUSH_RANDOM),
+        Instruction::PushArg(n) => {
+            bytes.push(OP_PUSH_ARG);
+            bytes.extend((*n as u64).to_le_bytes());
+        }
+        Instruction::Define(f, arg_count, body, attributes) => {
+            bytes.push(OP_DEFINE);
+            encode_string(f, bytes);
+            bytes.extend(arg_count.to_le_bytes());
+            let mut flags = 0;
+            if attributes.private {
+                flags |= FLAG_PRIVATE;
+            }
+            if attributes.origin.is_some() {
+                flags |= FLAG_ORIGIN;
+            }
+            bytes.push(flags);
+            if let Some(origin) = &attributes.origin {
+                encode_string(origin, bytes);
+            }
+            bytes.extend((body.len() as u32).to_le_bytes());
+            for instruction in body {
+                encode_instruction(instruction, bytes);
+            }
+        }
+        Instruction::CallIf => bytes.push(OP_CALLIF),
+        Instruction::Exit => bytes.push(OP_EXIT),
+    }
+}
+
+fn encode_string(s: &str, bytes: &mut Vec<u8>) {
+    bytes.extend((s.len() as u32).to_le_bytes());
+    bytes.extend(s.as_bytes());
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Vec<Instruction>, Error> {
+    let mut decoder = match bytes.strip_prefix(MAGIC) {
+        Some([VERSION, rest @ ..]) => Decoder { bytes: rest },
+        _ => return Err(anyhow!(ERR_HEADER)),
+    };
+
+    let mut instructions = Vec::new();
+    while !decoder.bytes.is_empty() {
+        instructions.push(decoder.decode_instruction(false)?);
+    }
+    Ok(instructions)
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn decode_instruction(&mut self, inside_defn: bool) -> Result<Instruction, Error> {
+        let instruction = match self.take(1)?[0] {
+            OP_PUSH_DATA => Instruction::PushData(self.take_u32()?.into()),
+            OP_PUSH_DATA64 => Instruction::PushData(self.take_u64()?),
+            OP_PUSH_BIG => Instruction::PushBig(self.take_string()?),
+            OP_PUSH_FUNCTION => Instruction::PushFunction(self.take_string()?),
+            OP_PUSH_RANDOM => Instruction::PushRandom,
+            OP_PUSH_ARG => {
+                let n = self.take_u64()?;
+                Instruction::PushArg(usize::try_from(n)?)
+            }
+            OP_DEFINE if inside_defn => return Err(anyhow!(ERR_NESTED)),
+            OP_DEFINE => {
+                let f = self.take_string()?;
+                let arg_count = self.take_u32()?;
+                let flags = self.take(1)?[0];
+                let attributes = Attributes {
+                    private: flags & FLAG_PRIVATE != 0,
+                    origin: match flags & FLAG_ORIGIN {
+                        0 => None,
+                        _ => Some(self.take_string()?),
+                    },
+                    ..Attributes::default()
+                };
+                let body_len = self.take_u32()?;
+                let body = (0..body_len)
+                    .map(|_| self.decode_instruction(true))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Instruction::Define(f, arg_count, body, attributes)
+            }
+            OP_CALLIF => Instruction::CallIf,
+            OP_EXIT => Instruction::Exit,
+            op => return Err(anyhow!("Bytecode error: Unknown opcode {op:#04x}.")),
+        };
+        Ok(instruction)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&[u8], Error> {
+        if self.bytes.len() < n {
+            return Err(anyhow!(ERR_EOF));
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn take_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn take_u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn take_string(&mut self) -> Result<String, Error> {
+        let len = self.take_u32()? as usize;
+        let bytes = self.take(len)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| anyhow!(ERR_UTF8))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! assert_err_with_msg {
+        ( $value:expr, $msg:expr ) => {
+            match $value {
+                Ok(x) => panic!("Expected an error but received 'Ok({x:?})'."),
+                Err(e) => assert_eq!($msg, format!("{e}")),
+            };
+        };
+    }
+
+    #[test]
+    fn round_trip() {
+        let instructions = vec![
+            Instruction::Define(
+                "swap".to_owned(),
+                2,
+                vec![
+                    Instruction::PushArg(0),
+                    Instruction::PushArg(1),
+                    Instruction::Exit,
+                ],
+                Attributes::default(),
+            ),
+            Instruction::PushData(u32::MAX.into()),
+            Instruction::PushData(u64::MAX),
+            Instruction::PushBig("123456789012345678901234567890".to_owned()),
+            Instruction::PushRandom,
+            Instruction::PushFunction("swap".to_owned()),
+            Instruction::Define("empty".to_owned(), 0, vec![], Attributes::default()),
+            Instruction::Define(
+                "helper".to_owned(),
+                0,
+                vec![Instruction::PushData(1)],
+                Attributes {
+                    private: true,
+                    origin: Some("lib.plates".to_owned()),
+                    ..Attributes::default()
+                },
+            ),
+            Instruction::CallIf,
+        ];
+
+        let decoded = decode(&encode(&instructions));
+
+        assert!(decoded.is_ok());
+        assert_eq!(instructions, decoded.unwrap());
+    }
+
+    #[test]
+    fn empty_program() {
+        assert_eq!(b"PLTC\x02".to_vec(), encode(&[]));
+        assert!(decode(b"PLTC\x02").unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_header() {
+        assert_err_with_msg!(decode(b"PLTX\x01"), ERR_HEADER);
+        assert_err_with_msg!(decode(b"PLTC\x03\x06"), ERR_HEADER);
+        // Version 1 did not keep which functions are private
+        assert_err_with_msg!(decode(b"PLTC\x01\x06"), ERR_HEADER);
+        assert_err_with_msg!(decode(b""), ERR_HEADER);
+    }
+
+    #[test]
+    fn truncated() {
+        assert_err_with_msg!(decode(b"PLTC\x02\x01\x00\x00"), ERR_EOF);
+        assert_err_with_msg!(decode(b"PLTC\x02\x02\x05\x00\x00\x00abc"), ERR_EOF);
+        // Body declared to have one instruction but has none
+        assert_err_with_msg!(
+            decode(b"PLTC\x02\x05\x01\x00\x00\x00f\x00\x00\x00\x00\x00\x01\x00\x00\x00"),
+            ERR_EOF
+        );
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        assert_err_with_msg!(
+            decode(b"PLTC\x02\x06\xff"),
+            "Bytecode error: Unknown opcode 0xff."
+        );
+    }
+
+    #[test]
+    fn nested_define() {
+        assert_err_with_msg!(
+            decode(b"PLTC\x02\x05\x01\x00\x00\x00f\x00\x00\x00\x00\x00\x01\x00\x00\x00\x05"),
+            ERR_NESTED
+        );
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        assert_err_with_msg!(decode(b"PLTC\x02\x02\x01\x00\x00\x00\xff"), ERR_UTF8);
+    }
+}
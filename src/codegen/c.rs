@@ -0,0 +1,204 @@
+use std::{path::Path, process};
+
+use anyhow::{anyhow, Error};
+
+use super::{Op, Program};
+
+const PRELUDE: &str = include_str!("c_prelude.c");
+
+/// Built-in functions implemented by the prelude.
+pub const SUPPORTED_BUILTINS: &[&str] = &["__print__", "__input__", "__birl__"];
+
+/// Renders the program as a standalone C99 source file.
+pub fn generate(program: &Program) -> Result<String, Error> {
+    program.check_builtins(SUPPORTED_BUILTINS)?;
+
+    // Segments are identified by a single number in C, so number them consecutively across all blocks
+    let mut offsets = Vec::new();
+    let mut segment_count = 0;
+    for block in &program.blocks {
+        offsets.push(segment_count);
+        segment_count += block.segments.len();
+    }
+
+    let mut output = PRELUDE.to_owned();
+
+    // End with NULL so that the array is never empty
+    output += "\nstatic const char *const NAMES[] = {";
+    for name in &program.names {
+        output += &format!("{name:?}, ");
+    }
+    output += "NULL};\n";
+    output += &format!(
+        "\nstatic size_t name_count(void) {{\n    return {};\n}}\n",
+        program.names.len()
+    );
+    output += "\nstatic const char *function_name(size_t f) {\n    return NAMES[f];\n}\n";
+
+    output += "\nstatic void run(void) {\n";
+    output += "    size_t segment;\n";
+    output += "    while (frame_pop(&segment)) {\n";
+    output += "        switch (segment) {\n";
+    for (b, block) in program.blocks.iter().enumerate() {
+        for (s, segment) in block.segments.iter().enumerate() {
+            output += &format!("        case {}:\n", offsets[b] + s);
+            for op in segment {
+                output += &format!("            {}\n", generate_op(&offsets, b, op));
+            }
+            output += "            break;\n";
+        }
+    }
+    output += "        }\n";
+    output += "    }\n";
+    output += "}\n";
+
+    Ok(output)
+}
+
+fn generate_op(offsets: &[usize], block: usize, op: &Op) -> String {
+    match op {
+        Op::PushData(n) => format!("push_data({n}u);"),
+        Op::PushFunction(f) => format!("push_function({f});"),
+        Op::PushRandom => "push_random();".to_owned(),
+        Op::PushArg(n) => format!("push_arg({n});"),
+        Op::Define {
+            name,
+            arg_count,
+            body,
+        } => format!("define({name}, {arg_count}u, {});", offsets[*body]),
+        Op::CallIf { resume: None } => "call_if(NO_RESUME);".to_owned(),
+        Op::CallIf { resume: Some(s) } => format!("call_if({});", offsets[block] + s),
+        Op::Exit => "return;".to_owned(),
+    }
+}
+
+/// Compiles the generated source file into an executable using `cc` (or the compiler in the `CC` environment
+/// variable).
+pub fn compile(source_file: &Path, output: &Path) -> Result<(), Error> {
+    let cc = std::env::var("CC").unwrap_or_else(|_| "cc".to_owned());
+    let status = process::Command::new(&cc)
+        .args(["-std=c99", "-O2", "-o"])
+        .arg(output)
+        .arg(source_file)
+        .status()
+        .map_err(|e| anyhow!(e).context(format!("Failed to run '{cc}'.")))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("Build error: '{cc}' exited with {status}."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::{Command, Stdio};
+
+    use super::*;
+    use crate::{
+        codegen::lower,
+        parser::{Attributes, Instruction},
+    };
+
+    #[test]
+    fn generate_segments() {
+        let program = lower(&[
+            Instruction::Define(
+                "f".to_owned(),
+                1,
+                vec![Instruction::PushArg(0), Instruction::Exit],
+                Attributes::default(),
+            ),
+            Instruction::PushData(7),
+            Instruction::PushData(1),
+            Instruction::PushFunction("f".to_owned()),
+            Instruction::CallIf,
+            Instruction::PushRandom,
+        ])
+        .unwrap();
+
+        let source = generate(&program).unwrap();
+        let generated = &source[source.find("\nstatic const char *const NAMES").unwrap()..];
+
+        let expected = concat!(
+            "\nstatic const char *const NAMES[] = {\"f\", NULL};\n",
+            "\nstatic size_t name_count(void) {\n",
+            "    return 1;\n",
+            "}\n",
+            "\nstatic const char *function_name(size_t f) {\n",
+            "    return NAMES[f];\n",
+            "}\n",
+            "\nstatic void run(void) {\n",
+            "    size_t segment;\n",
+            "    while (frame_pop(&segment)) {\n",
+            "        switch (segment) {\n",
+            "        case 0:\n",
+            "            define(0, 1u, 2);\n",
+            "            push_data(7u);\n",
+            "            push_data(1u);\n",
+            "            push_function(0);\n",
+            "            call_if(1);\n",
+            "            break;\n",
+            "        case 1:\n",
+            "            push_random();\n",
+            "            break;\n",
+            "        case 2:\n",
+            "            push_arg(0);\n",
+            "            return;\n",
+            "            break;\n",
+            "        }\n",
+            "    }\n",
+            "}\n",
+        );
+        assert_eq!(expected, generated);
+    }
+
+    #[test]
+    fn compile_and_run() {
+        let shout = vec![
+            Instruction::PushData(0),
+            Instruction::PushArg(0),
+            Instruction::PushData(1),
+            Instruction::PushFunction("__print__".to_owned()),
+            Instruction::CallIf,
+        ];
+        let program = lower(&[
+            Instruction::Define("shout".to_owned(), 1, shout, Attributes::default()),
+            Instruction::PushData(0x1F600),
+            Instruction::PushData(1),
+            Instruction::PushFunction("shout".to_owned()),
+            Instruction::CallIf,
+            Instruction::PushData(0),
+            Instruction::PushFunction("undefined".to_owned()),
+            Instruction::CallIf,
+            Instruction::PushData(33),
+            Instruction::PushData(1),
+            Instruction::PushFunction("shout".to_owned()),
+            Instruction::CallIf,
+            Instruction::PushData(1),
+            Instruction::PushFunction("undefined".to_owned()),
+            Instruction::CallIf,
+        ])
+        .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("plates-codegen-c-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source_file = dir.join("main.c");
+        let executable = dir.join("main");
+        std::fs::write(&source_file, generate(&program).unwrap()).unwrap();
+        compile(&source_file, &executable).unwrap();
+
+        let output = Command::new(&executable)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!("😀!", String::from_utf8_lossy(&output.stdout));
+        assert_eq!(
+            "Runtime error: Undefined argument or function.\n",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(Some(1), output.status.code());
+    }
+}
@@ -0,0 +1,303 @@
+/* Generated by `plates build`. */
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <time.h>
+
+#define ERR_UNDERFLOW "Runtime error: Stack underflow."
+#define ERR_UNDEFINED "Runtime error: Undefined argument or function."
+#define ERR_TYPE "Runtime error: Wrong type."
+#define ERR_UTF32 "Runtime error: Invalid UTF-32 code point."
+#define ERR_STDOUT "Environment error: Failed to flush stdout."
+#define ERR_STDIN "Environment error: Failed to read from stdin."
+#define ERR_MEMORY "Environment error: Out of memory."
+
+/* Marks a CALLIF that is the last instruction in its block. */
+#define NO_RESUME SIZE_MAX
+
+/* Defined after the prelude, since they depend on the program. */
+static size_t name_count(void);
+static const char *function_name(size_t f);
+static void run(void);
+
+typedef struct {
+    int is_function;
+    uint32_t data;
+    size_t function;
+} Word;
+
+typedef struct {
+    Word *items;
+    size_t len;
+    size_t cap;
+} WordVec;
+
+/* Pending segments, identified by their index in the program. */
+typedef struct {
+    size_t *items;
+    size_t len;
+    size_t cap;
+} FrameVec;
+
+static WordVec stack;
+static WordVec args;
+static FrameVec frames;
+/* Indexed by function name. */
+static int *defined;
+static uint32_t *arg_counts;
+static size_t *bodies;
+static uint64_t rng;
+
+static void fail(const char *msg) {
+    fflush(stdout);
+    fprintf(stderr, "%s\n", msg);
+    exit(1);
+}
+
+static void *grow(void *items, size_t *cap, size_t item_size) {
+    *cap = *cap == 0 ? 16 : 2 * *cap;
+    items = realloc(items, *cap * item_size);
+    if (items == NULL) {
+        fail(ERR_MEMORY);
+    }
+    return items;
+}
+
+static void word_push(WordVec *v, Word w) {
+    if (v->len == v->cap) {
+        v->items = grow(v->items, &v->cap, sizeof(Word));
+    }
+    v->items[v->len++] = w;
+}
+
+static int word_pop(WordVec *v, Word *w) {
+    if (v->len == 0) {
+        return 0;
+    }
+    *w = v->items[--v->len];
+    return 1;
+}
+
+static void frame_push(size_t segment) {
+    if (frames.len == frames.cap) {
+        frames.items = grow(frames.items, &frames.cap, sizeof(size_t));
+    }
+    frames.items[frames.len++] = segment;
+}
+
+static int frame_pop(size_t *segment) {
+    if (frames.len == 0) {
+        return 0;
+    }
+    *segment = frames.items[--frames.len];
+    return 1;
+}
+
+static void push_data(uint32_t n) {
+    Word w = {0, n, 0};
+    word_push(&stack, w);
+}
+
+static void push_function(size_t f) {
+    Word w = {1, 0, f};
+    word_push(&stack, w);
+}
+
+static void push_random(void) {
+    /* xorshift64* */
+    rng ^= rng >> 12;
+    rng ^= rng << 25;
+    rng ^= rng >> 27;
+    push_data((uint32_t)((rng * UINT64_C(0x2545f4914f6cdd1d)) >> 32));
+}
+
+static void push_arg(size_t n) {
+    if (n >= args.len) {
+        fail(ERR_UNDEFINED);
+    }
+    word_push(&stack, args.items[n]);
+}
+
+static void define(size_t f, uint32_t arg_count, size_t body) {
+    defined[f] = 1;
+    arg_counts[f] = arg_count;
+    bodies[f] = body;
+}
+
+static uint32_t pop_data(void) {
+    Word w;
+    if (!word_pop(&stack, &w)) {
+        fail(ERR_UNDERFLOW);
+    }
+    if (w.is_function) {
+        fail(ERR_TYPE);
+    }
+    return w.data;
+}
+
+static void call_print(void) {
+    for (;;) {
+        uint32_t n = pop_data();
+        if (n == 0) {
+            if (fflush(stdout) != 0) {
+                fail(ERR_STDOUT);
+            }
+            return;
+        }
+        if (n > 0x10ffff || (n >= 0xd800 && n <= 0xdfff)) {
+            fail(ERR_UTF32);
+        }
+
+        /* Encode as UTF-8 */
+        if (n < 0x80) {
+            putchar((int)n);
+        } else if (n < 0x800) {
+            putchar((int)(0xc0 | (n >> 6)));
+            putchar((int)(0x80 | (n & 0x3f)));
+        } else if (n < 0x10000) {
+            putchar((int)(0xe0 | (n >> 12)));
+            putchar((int)(0x80 | ((n >> 6) & 0x3f)));
+            putchar((int)(0x80 | (n & 0x3f)));
+        } else {
+            putchar((int)(0xf0 | (n >> 18)));
+            putchar((int)(0x80 | ((n >> 12) & 0x3f)));
+            putchar((int)(0x80 | ((n >> 6) & 0x3f)));
+            putchar((int)(0x80 | (n & 0x3f)));
+        }
+    }
+}
+
+static void call_input(void) {
+    WordVec line = {NULL, 0, 0};
+    int c = getchar();
+    while (c != EOF) {
+        uint32_t n = (uint32_t)c;
+        int extra = 0;
+
+        /* Decode UTF-8 */
+        if (c < 0x80) {
+            extra = 0;
+        } else if (c < 0xc0) {
+            fail(ERR_STDIN);
+        } else if (c < 0xe0) {
+            n = (uint32_t)c & 0x1f;
+            extra = 1;
+        } else if (c < 0xf0) {
+            n = (uint32_t)c & 0x0f;
+            extra = 2;
+        } else if (c < 0xf8) {
+            n = (uint32_t)c & 0x07;
+            extra = 3;
+        } else {
+            fail(ERR_STDIN);
+        }
+        for (; extra > 0; extra--) {
+            c = getchar();
+            if (c == EOF || (c & 0xc0) != 0x80) {
+                fail(ERR_STDIN);
+            }
+            n = (n << 6) | ((uint32_t)c & 0x3f);
+        }
+        if (n > 0x10ffff || (n >= 0xd800 && n <= 0xdfff)) {
+            fail(ERR_STDIN);
+        }
+
+        Word w = {0, n, 0};
+        word_push(&line, w);
+        if (n == '\n') {
+            break;
+        }
+        c = getchar();
+    }
+    if (ferror(stdin)) {
+        fail(ERR_STDIN);
+    }
+
+    /* The first character goes on top */
+    while (line.len > 0) {
+        word_push(&stack, line.items[--line.len]);
+    }
+    free(line.items);
+}
+
+static void call_birl(void) {
+    Word a, b;
+    if (!word_pop(&stack, &a) || !word_pop(&stack, &b)) {
+        fail(ERR_UNDERFLOW);
+    }
+    if (a.is_function || b.is_function) {
+        fail(ERR_TYPE);
+    }
+    uint32_t result = ~a.data | b.data;
+    push_data((result << 1) | (result >> 31));
+}
+
+static void call_builtin(const char *f) {
+    if (strcmp(f, "__print__") == 0) {
+        call_print();
+    } else if (strcmp(f, "__input__") == 0) {
+        call_input();
+    } else if (strcmp(f, "__birl__") == 0) {
+        call_birl();
+    } else {
+        fail(ERR_UNDEFINED);
+    }
+}
+
+static void call_if(size_t resume) {
+    Word f, n;
+    if (!word_pop(&stack, &f) || !word_pop(&stack, &n)) {
+        fail(ERR_UNDERFLOW);
+    }
+    if (!f.is_function || n.is_function) {
+        fail(ERR_TYPE);
+    }
+
+    if (resume != NO_RESUME) {
+        frame_push(resume);
+    }
+    if (n.data == 0) {
+        return;
+    }
+
+    args.len = 0;
+    const char *name = function_name(f.function);
+    if (strncmp(name, "__", 2) == 0) {
+        call_builtin(name);
+        return;
+    }
+
+    if (!defined[f.function]) {
+        fail(ERR_UNDEFINED);
+    }
+    for (uint32_t i = 0; i < arg_counts[f.function]; i++) {
+        Word w;
+        if (!word_pop(&stack, &w)) {
+            fail(ERR_UNDERFLOW);
+        }
+        word_push(&args, w);
+    }
+    frame_push(bodies[f.function]);
+}
+
+int main(void) {
+    /* Add one so that calloc never has to allocate zero bytes */
+    size_t n = name_count() + 1;
+    defined = calloc(n, sizeof(int));
+    arg_counts = calloc(n, sizeof(uint32_t));
+    bodies = calloc(n, sizeof(size_t));
+    if (defined == NULL || arg_counts == NULL || bodies == NULL) {
+        fail(ERR_MEMORY);
+    }
+    /* xorshift requires a nonzero state */
+    rng = (((uint64_t)time(NULL) << 32) ^ (uint64_t)clock()) | 1;
+
+    frame_push(0);
+    run();
+
+    if (fflush(stdout) != 0) {
+        fail(ERR_STDOUT);
+    }
+    return 0;
+}
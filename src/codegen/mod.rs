@@ -0,0 +1,299 @@
+//! Translation of plates programs into other languages.
+//!
+//! Programs are first lowered into a [`Program`], which the backends (Rust and C) then render. Each block of code (the top
+//! level, and the body of each `DEFN`) is split into segments that end at a `CALLIF`. The generated code runs a
+//! dispatch loop over a stack of (block, segment) frames, which plays the role of the interpreter's instruction
+//! stack: a `CALLIF` pushes the segment that follows it and then, if the function is called, the first segment of
+//! the function body. As in the interpreter, a `CALLIF` at the very end of a block does not push anything for the
+//! caller, so tail recursion runs in constant space.
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{anyhow, Error};
+
+use crate::parser::Instruction;
+
+const ERR_WORD_SIZE: &str =
+    "Build error: Words larger than 32 bits are not supported by the compilers.";
+
+pub mod c;
+pub mod rust;
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Backend {
+    /// Generate Rust and compile it with rustc
+    Rust,
+    /// Generate C99 and compile it with cc
+    C,
+}
+
+impl Backend {
+    pub fn generate(self, program: &Program) -> Result<String, Error> {
+        match self {
+            Backend::Rust => rust::generate(program),
+            Backend::C => c::generate(program),
+        }
+    }
+
+    pub fn compile(self, source_file: &Path, output: &Path) -> Result<(), Error> {
+        match self {
+            Backend::Rust => rust::compile(source_file, output),
+            Backend::C => c::compile(source_file, output),
+        }
+    }
+
+    pub fn source_extension(self) -> &'static str {
+        match self {
+            Backend::Rust => "rs",
+            Backend::C => "c",
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Program {
+    /// All the function names used in the program (including built-in functions), in order of first appearance.
+    /// Function words are represented by their index in this list.
+    pub names: Vec<String>,
+    /// Block 0 is the top level. Every other block is the body of a `DEFN`.
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Block {
+    /// Every segment but the last ends with [`Op::CallIf`].
+    pub segments: Vec<Vec<Op>>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Op {
+    PushData(u32),
+    PushFunction(usize),
+    PushRandom,
+    PushArg(usize),
+    Define {
+        name: usize,
+        arg_count: u32,
+        body: usize,
+    },
+    /// `resume` is the index of the segment (in the same block) to run once the call is done, or `None` if the
+    /// `CALLIF` was the last instruction in the block.
+    CallIf {
+        resume: Option<usize>,
+    },
+    Exit,
+}
+
+impl Program {
+    /// Returns an error if the program uses a built-in function that is not in `supported`.
+    pub fn check_builtins(&self, supported: &[&str]) -> Result<(), Error> {
+        match self.names.iter().find(|f| {
+            crate::runtime::BUILTIN_FUNCTIONS.contains(&f.as_str())
+                && !supported.contains(&f.as_str())
+        }) {
+            None => Ok(()),
+            Some(f) => Err(anyhow!(
+                "Build error: The built-in function '{f}' is not supported by this backend."
+            )),
+        }
+    }
+}
+
+/// Returns an error if a literal does not fit in a 32-bit word, which is all that the backends support, or if a function
+/// is private, since the compiled programs do not know which file each function came from.
+pub fn lower(instructions: &[Instruction]) -> Result<Program, Error> {
+    let mut lowering = Lowering::default();
+    lowering.lower_block(instructions)?;
+    Ok(Program {
+        names: lowering.names,
+        blocks: lowering.blocks,
+    })
+}
+
+#[derive(Default)]
+struct Lowering {
+    names: Vec<String>,
+    name_indices: HashMap<String, usize>,
+    blocks: Vec<Block>,
+}
+
+impl Lowering {
+    fn lower_block(&mut self, instructions: &[Instruction]) -> Result<usize, Error> {
+        // Reserve the index before lowering any nested blocks
+        let id = self.blocks.len();
+        self.blocks.push(Block::default());
+
+        let mut segments = vec![vec![]];
+        for (i, instruction) in instructions.iter().enumerate() {
+            let op = match instruction {
+                Instruction::PushData(n) => match u32::try_from(*n) {
+                    Err(_) => return Err(anyhow!(ERR_WORD_SIZE)),
+                    Ok(n) => Op::PushData(n),
+                },
+                Instruction::PushBig(_) => return Err(anyhow!(ERR_WORD_SIZE)),
+                Instruction::PushFunction(f) => Op::PushFunction(self.name(f)),
+                Instruction::PushRandom => Op::PushRandom,
+                Instruction::PushArg(n) => Op::PushArg(*n),
+                Instruction::Define(f, _, _, attributes) if attributes.private => {
+                    return Err(anyhow!(
+                        "Build error: Function '{f}' is private (DEFN_PRIV), which is not supported by the compilers."
+                    ))
+                }
+                Instruction::Define(f, arg_count, body, _) => Op::Define {
+                    name: self.name(f),
+                    arg_count: *arg_count,
+                    body: self.lower_block(body)?,
+                },
+                Instruction::CallIf if i + 1 == instructions.len() => Op::CallIf { resume: None },
+                Instruction::CallIf => Op::CallIf {
+                    resume: Some(segments.len()),
+                },
+                Instruction::Exit => Op::Exit,
+            };
+
+            let ends_segment = matches!(op, Op::CallIf { resume: Some(_) });
+            segments.last_mut().unwrap().push(op);
+            if ends_segment {
+                segments.push(vec![]);
+            }
+        }
+
+        self.blocks[id] = Block { segments };
+        Ok(id)
+    }
+
+    fn name(&mut self, f: &str) -> usize {
+        if let Some(i) = self.name_indices.get(f) {
+            return *i;
+        }
+        let i = self.names.len();
+        self.names.push(f.to_owned());
+        self.name_indices.insert(f.to_owned(), i);
+        i
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Attributes;
+
+    #[test]
+    fn lower_program() {
+        let instructions = vec![
+            Instruction::Define(
+                "loop".to_owned(),
+                1,
+                vec![
+                    Instruction::PushArg(0),
+                    Instruction::PushData(1),
+                    Instruction::PushFunction("__print__".to_owned()),
+                    Instruction::CallIf,
+                    Instruction::PushData(1),
+                    Instruction::PushFunction("loop".to_owned()),
+                    Instruction::CallIf,
+                ],
+                Attributes::default(),
+            ),
+            Instruction::PushRandom,
+            Instruction::PushData(1),
+            Instruction::PushFunction("loop".to_owned()),
+            Instruction::CallIf,
+            Instruction::Exit,
+        ];
+        let expected = Program {
+            names: vec!["loop".to_owned(), "__print__".to_owned()],
+            blocks: vec![
+                Block {
+                    segments: vec![
+                        vec![
+                            Op::Define {
+                                name: 0,
+                                arg_count: 1,
+                                body: 1,
+                            },
+                            Op::PushRandom,
+                            Op::PushData(1),
+                            Op::PushFunction(0),
+                            Op::CallIf { resume: Some(1) },
+                        ],
+                        vec![Op::Exit],
+                    ],
+                },
+                Block {
+                    segments: vec![
+                        vec![
+                            Op::PushArg(0),
+                            Op::PushData(1),
+                            Op::PushFunction(1),
+                            Op::CallIf { resume: Some(1) },
+                        ],
+                        vec![
+                            Op::PushData(1),
+                            Op::PushFunction(0),
+                            Op::CallIf { resume: None },
+                        ],
+                    ],
+                },
+            ],
+        };
+
+        assert_eq!(expected, lower(&instructions).unwrap());
+    }
+
+    #[test]
+    fn lower_empty_body() {
+        let instructions = vec![Instruction::Define(
+            "f".to_owned(),
+            0,
+            vec![],
+            Attributes::default(),
+        )];
+        let expected = Program {
+            names: vec!["f".to_owned()],
+            blocks: vec![
+                Block {
+                    segments: vec![vec![Op::Define {
+                        name: 0,
+                        arg_count: 0,
+                        body: 1,
+                    }]],
+                },
+                Block {
+                    segments: vec![vec![]],
+                },
+            ],
+        };
+
+        assert_eq!(expected, lower(&instructions).unwrap());
+    }
+
+    #[test]
+    fn private_function() {
+        let instructions = [Instruction::Define(
+            "helper".to_owned(),
+            0,
+            vec![],
+            Attributes {
+                private: true,
+                ..Attributes::default()
+            },
+        )];
+
+        assert_eq!(
+            "Build error: Function 'helper' is private (DEFN_PRIV), which is not supported by the compilers.",
+            format!("{}", lower(&instructions).unwrap_err())
+        );
+    }
+
+    #[test]
+    fn unsupported_builtin() {
+        let program = lower(&[Instruction::PushFunction("__input__".to_owned())]).unwrap();
+
+        assert!(program.check_builtins(&["__input__"]).is_ok());
+        assert_eq!(
+            "Build error: The built-in function '__input__' is not supported by this backend.",
+            format!("{}", program.check_builtins(&["__print__"]).unwrap_err())
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::{path::Path, process};
+
+use anyhow::{anyhow, Error};
+
+use super::{Op, Program};
+
+const PRELUDE: &str = include_str!("rust_prelude.rs");
+
+/// Built-in functions implemented by the prelude.
+pub const SUPPORTED_BUILTINS: &[&str] = &["__print__", "__input__", "__birl__"];
+
+/// Renders the program as a standalone Rust source file.
+pub fn generate(program: &Program) -> Result<String, Error> {
+    program.check_builtins(SUPPORTED_BUILTINS)?;
+
+    let mut output = PRELUDE.to_owned();
+
+    output += "\nconst NAMES: &[&str] = &[\n";
+    for name in &program.names {
+        output += &format!("    {name:?},\n");
+    }
+    output += "];\n";
+
+    output += "\nfn run(rt: &mut Runtime) -> Result<(), &'static str> {\n";
+    output += "    while let Some(frame) = rt.frames.pop() {\n";
+    output += "        match frame {\n";
+    for (b, block) in program.blocks.iter().enumerate() {
+        for (s, segment) in block.segments.iter().enumerate() {
+            output += &format!("            ({b}, {s}) => {{\n");
+            for op in segment {
+                output += &format!("                {}\n", generate_op(b, op));
+            }
+            output += "            }\n";
+        }
+    }
+    output += "            _ => unreachable!(),\n";
+    output += "        }\n";
+    output += "    }\n";
+    output += "    Ok(())\n";
+    output += "}\n";
+
+    Ok(output)
+}
+
+fn generate_op(block: usize, op: &Op) -> String {
+    match op {
+        Op::PushData(n) => format!("rt.push_data({n});"),
+        Op::PushFunction(f) => format!("rt.push_function({f});"),
+        Op::PushRandom => "rt.push_random();".to_owned(),
+        Op::PushArg(n) => format!("rt.push_arg({n})?;"),
+        Op::Define {
+            name,
+            arg_count,
+            body,
+        } => format!("rt.define({name}, {arg_count}, {body});"),
+        Op::CallIf { resume: None } => "rt.call_if(None)?;".to_owned(),
+        Op::CallIf { resume: Some(s) } => format!("rt.call_if(Some(({block}, {s})))?;"),
+        Op::Exit => "return Ok(());".to_owned(),
+    }
+}
+
+/// Compiles the generated source file into an executable using `rustc` (or the compiler in the `RUSTC` environment
+/// variable).
+pub fn compile(source_file: &Path, output: &Path) -> Result<(), Error> {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let status = process::Command::new(&rustc)
+        .args(["--edition", "2021", "-O", "-o"])
+        .arg(output)
+        .arg(source_file)
+        .status()
+        .map_err(|e| anyhow!(e).context(format!("Failed to run '{rustc}'.")))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("Build error: '{rustc}' exited with {status}."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::{Command, Stdio};
+
+    use super::*;
+    use crate::{
+        codegen::lower,
+        parser::{Attributes, Instruction},
+    };
+
+    #[test]
+    fn generate_segments() {
+        let program = lower(&[
+            Instruction::Define(
+                "f".to_owned(),
+                1,
+                vec![Instruction::PushArg(0), Instruction::Exit],
+                Attributes::default(),
+            ),
+            Instruction::PushData(7),
+            Instruction::PushData(1),
+            Instruction::PushFunction("f".to_owned()),
+            Instruction::CallIf,
+            Instruction::PushRandom,
+        ])
+        .unwrap();
+
+        let source = generate(&program).unwrap();
+        let run_fn = &source[source.find("\nconst NAMES").unwrap()..];
+
+        let expected = concat!(
+            "\nconst NAMES: &[&str] = &[\n",
+            "    \"f\",\n",
+            "];\n",
+            "\nfn run(rt: &mut Runtime) -> Result<(), &'static str> {\n",
+            "    while let Some(frame) = rt.frames.pop() {\n",
+            "        match frame {\n",
+            "            (0, 0) => {\n",
+            "                rt.define(0, 1, 1);\n",
+            "                rt.push_data(7);\n",
+            "                rt.push_data(1);\n",
+            "                rt.push_function(0);\n",
+            "                rt.call_if(Some((0, 1)))?;\n",
+            "            }\n",
+            "            (0, 1) => {\n",
+            "                rt.push_random();\n",
+            "            }\n",
+            "            (1, 0) => {\n",
+            "                rt.push_arg(0)?;\n",
+            "                return Ok(());\n",
+            "            }\n",
+            "            _ => unreachable!(),\n",
+            "        }\n",
+            "    }\n",
+            "    Ok(())\n",
+            "}\n",
+        );
+        assert_eq!(expected, run_fn);
+    }
+
+    #[test]
+    fn compile_and_run() {
+        let shout = vec![
+            Instruction::PushData(0),
+            Instruction::PushArg(0),
+            Instruction::PushData(1),
+            Instruction::PushFunction("__print__".to_owned()),
+            Instruction::CallIf,
+        ];
+        let program = lower(&[
+            Instruction::Define("shout".to_owned(), 1, shout, Attributes::default()),
+            Instruction::PushData(51),
+            Instruction::PushData(1),
+            Instruction::PushFunction("shout".to_owned()),
+            Instruction::CallIf,
+            Instruction::PushData(0),
+            Instruction::PushFunction("undefined".to_owned()),
+            Instruction::CallIf,
+            Instruction::PushData(33),
+            Instruction::PushData(1),
+            Instruction::PushFunction("shout".to_owned()),
+            Instruction::CallIf,
+            Instruction::PushData(1),
+            Instruction::PushFunction("undefined".to_owned()),
+            Instruction::CallIf,
+        ])
+        .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("plates-codegen-rust-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source_file = dir.join("main.rs");
+        let executable = dir.join("main");
+        std::fs::write(&source_file, generate(&program).unwrap()).unwrap();
+        compile(&source_file, &executable).unwrap();
+
+        let output = Command::new(&executable)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!("3!", String::from_utf8_lossy(&output.stdout));
+        assert_eq!(
+            "Runtime error: Undefined argument or function.\n",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(Some(1), output.status.code());
+    }
+}
@@ -0,0 +1,157 @@
+// Generated by `plates build`.
+#![allow(dead_code, unreachable_code, unused_mut)]
+
+use std::io::Write;
+
+const ERR_UNDERFLOW: &str = "Runtime error: Stack underflow.";
+const ERR_UNDEFINED: &str = "Runtime error: Undefined argument or function.";
+const ERR_TYPE: &str = "Runtime error: Wrong type.";
+const ERR_UTF32: &str = "Runtime error: Invalid UTF-32 code point.";
+const ERR_STDOUT: &str = "Environment error: Failed to flush stdout.";
+const ERR_STDIN: &str = "Environment error: Failed to read from stdin.";
+
+#[derive(Clone)]
+enum Word {
+    Data(u32),
+    Function(usize),
+}
+
+struct Runtime {
+    stack: Vec<Word>,
+    args: Vec<Word>,
+    /// Indexed by function name. Holds the argument count and body block of each defined function.
+    functions: Vec<Option<(u32, usize)>>,
+    /// Pending (block, segment) pairs.
+    frames: Vec<(usize, usize)>,
+    rng: u64,
+}
+
+impl Runtime {
+    fn new() -> Self {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Runtime {
+            stack: Vec::new(),
+            args: Vec::new(),
+            functions: vec![None; NAMES.len()],
+            frames: vec![(0, 0)],
+            // xorshift requires a nonzero state
+            rng: seed | 1,
+        }
+    }
+
+    fn push_data(&mut self, n: u32) {
+        self.stack.push(Word::Data(n));
+    }
+
+    fn push_function(&mut self, f: usize) {
+        self.stack.push(Word::Function(f));
+    }
+
+    fn push_random(&mut self) {
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let n = (self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as u32;
+        self.push_data(n);
+    }
+
+    fn push_arg(&mut self, n: usize) -> Result<(), &'static str> {
+        let value = self.args.get(n).cloned().ok_or(ERR_UNDEFINED)?;
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn define(&mut self, f: usize, arg_count: u32, body: usize) {
+        self.functions[f] = Some((arg_count, body));
+    }
+
+    fn call_if(&mut self, resume: Option<(usize, usize)>) -> Result<(), &'static str> {
+        let (f, n) = match (self.stack.pop(), self.stack.pop()) {
+            (None, _) | (_, None) => return Err(ERR_UNDERFLOW),
+            (Some(Word::Function(f)), Some(Word::Data(n))) => (f, n),
+            _ => return Err(ERR_TYPE),
+        };
+
+        if let Some(frame) = resume {
+            self.frames.push(frame);
+        }
+        if n == 0 {
+            return Ok(());
+        }
+
+        self.args.clear();
+        if NAMES[f].starts_with("__") {
+            return self.call_builtin(NAMES[f]);
+        }
+
+        let (arg_count, body) = self.functions[f].ok_or(ERR_UNDEFINED)?;
+        for _ in 0..arg_count {
+            let n = self.stack.pop().ok_or(ERR_UNDERFLOW)?;
+            self.args.push(n);
+        }
+        self.frames.push((body, 0));
+        Ok(())
+    }
+
+    fn call_builtin(&mut self, f: &str) -> Result<(), &'static str> {
+        match f {
+            "__print__" => self.call_print(),
+            "__input__" => self.call_input(),
+            "__birl__" => self.call_birl(),
+            _ => Err(ERR_UNDEFINED),
+        }
+    }
+
+    fn call_print(&mut self) -> Result<(), &'static str> {
+        loop {
+            let n = self.pop_data()?;
+            if n == 0 {
+                return std::io::stdout().flush().map_err(|_| ERR_STDOUT);
+            }
+            let c = char::from_u32(n).ok_or(ERR_UTF32)?;
+            print!("{c}");
+        }
+    }
+
+    fn call_input(&mut self) -> Result<(), &'static str> {
+        let mut line = String::new();
+        std::io::stdin()
+            .read_line(&mut line)
+            .map_err(|_| ERR_STDIN)?;
+        for c in line.chars().rev() {
+            self.push_data(c as u32);
+        }
+        Ok(())
+    }
+
+    fn call_birl(&mut self) -> Result<(), &'static str> {
+        let (a, b) = match (self.stack.pop(), self.stack.pop()) {
+            (_, None) | (None, _) => return Err(ERR_UNDERFLOW),
+            (Some(Word::Data(a)), Some(Word::Data(b))) => (a, b),
+            _ => return Err(ERR_TYPE),
+        };
+        self.push_data((!a | b).rotate_left(1));
+        Ok(())
+    }
+
+    fn pop_data(&mut self) -> Result<u32, &'static str> {
+        match self.stack.pop() {
+            None => Err(ERR_UNDERFLOW),
+            Some(Word::Function(_)) => Err(ERR_TYPE),
+            Some(Word::Data(n)) => Ok(n),
+        }
+    }
+}
+
+fn main() {
+    let mut rt = Runtime::new();
+    let result = run(&mut rt);
+    let _ = std::io::stdout().flush();
+    if let Err(e) = result {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
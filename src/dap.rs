@@ -0,0 +1,1330 @@
+//! A Debug Adapter Protocol server (`plates dap`), so that editors can debug plates programs.
+//!
+//! The server debugs one file at a time. Line breakpoints stop before the top-level instructions on their line, and a
+//! breakpoint inside a DEFN stops whenever that function is called, since the instructions in function bodies do not
+//! remember their lines. Variables show the stack, the arguments of the function that was called last, and the
+//! instructions that will run next.
+//!
+//! Breakpoints can have conditions (see [`crate::predicate`]), and `break when <condition>` in the debug console stops
+//! the program whenever the condition becomes true, wherever it is. `watch <function>` stops it whenever the function
+//! is defined, redefined, or called.
+//!
+//! The runtime keeps a journal of the most recent steps, so that the program can go back with step back, reverse
+//! continue (which goes back as far as the journal goes), or `back <N>` in the debug console.
+//!
+//! After editing the file, `reload` in the debug console swaps the functions that changed into the running program
+//! (see [`crate::reload`]).
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, BufRead, Write},
+    ops::RangeInclusive,
+    path::PathBuf,
+    rc::Rc,
+    sync::mpsc::{self, Receiver},
+};
+
+use anyhow::{anyhow, Context, Error};
+use serde_json::{json, Value};
+
+use crate::{
+    lexer::Lexer,
+    parser::{Instruction, Parser},
+    predicate::Predicate,
+    print_error,
+    reader::FileReader,
+    reload,
+    runtime::{Runtime, Step, Undone, Word},
+    terminal::NoTerminal,
+    SharedBuffer,
+};
+
+/// While the program runs, how many steps to take between checks for a pause request.
+const POLL_INTERVAL: usize = 1000;
+/// DAP clients need a thread, even though programs only have one.
+const THREAD_ID: u64 = 1;
+
+/// How many steps can be undone.
+const JOURNAL_CAPACITY: usize = 10_000;
+
+/// The `variablesReference`s of the scopes.
+const STACK: u64 = 1;
+const ARGUMENTS: u64 = 2;
+const PENDING: u64 = 3;
+
+/// Runs a debug adapter over stdin and stdout until the client disconnects.
+pub fn run_server() -> Result<(), Error> {
+    let (sender, receiver) = mpsc::channel();
+    // Requests are read in the background so that a running program can be paused
+    std::thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        loop {
+            match read_message(&mut stdin) {
+                Err(e) => return print_error(&e),
+                Ok(None) => return,
+                Ok(Some(message)) => {
+                    if sender.send(message).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+    Adapter::new(receiver, Box::new(io::stdout())).run()
+}
+
+/// Reads a message with a `Content-Length` header, or `None` at the end of the input.
+fn read_message(input: &mut impl BufRead) -> Result<Option<Value>, Error> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = Some(value.trim().parse::<usize>()?);
+            }
+        }
+    }
+    let length = length.ok_or_else(|| anyhow!("Missing Content-Length header."))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// How far to run before stopping again.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Mode {
+    Continue,
+    /// Stop before the next entry of the instruction stack.
+    StepIn,
+    /// Stop once the call stack is no deeper than it was.
+    Next(usize),
+    /// Stop once the call stack is shallower than it was.
+    StepOut(usize),
+}
+
+/// What [`Program::next_top_level`] found.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Next {
+    /// The instruction stack was not empty yet.
+    Pending,
+    /// The next top-level instruction was parsed and pushed.
+    Parsed,
+    End,
+}
+
+/// A top-level instruction, with its lines (see [`Program::lines`] and [`Program::new_lines`]).
+#[derive(Clone, Debug)]
+struct TopLevel {
+    instruction: Instruction,
+    lines: RangeInclusive<usize>,
+    new_lines: RangeInclusive<usize>,
+}
+
+/// The program being debugged.
+struct Program {
+    path: PathBuf,
+    runtime: Runtime,
+    stdout: SharedBuffer,
+    parser: Parser<Lexer<FileReader>>,
+    /// The lines of the top-level instruction that is running.
+    lines: RangeInclusive<usize>,
+    /// The lines of the top-level instruction that is running, except for the line where the previous one ended.
+    new_lines: RangeInclusive<usize>,
+    /// Whether the top-level instruction that is running is a DEFN.
+    is_definition: bool,
+    /// The lines of each DEFN.
+    definitions: HashMap<String, RangeInclusive<usize>>,
+    stop_on_entry: bool,
+    /// The most recent top-level instructions that were pushed, oldest first, so that going back can return to them.
+    history: VecDeque<TopLevel>,
+    /// The top-level instructions that the program went back before, to run again before parsing more (the next one
+    /// last).
+    undone: Vec<TopLevel>,
+}
+
+/// A condition from `break when`.
+struct Condition {
+    predicate: Predicate,
+    /// Whether the condition held the last time it was checked, since the program only stops when it becomes true.
+    held: bool,
+}
+
+struct Adapter {
+    requests: Receiver<Value>,
+    /// Requests that arrived while the program was running, to be handled once it stops.
+    queued: VecDeque<Value>,
+    output: Box<dyn Write>,
+    seq: u64,
+    program: Option<Program>,
+    /// Whether the client has sent all its breakpoints, so that the program can start.
+    configured: bool,
+    /// The lines with breakpoints in each file, with their conditions.
+    breakpoints: HashMap<PathBuf, HashMap<usize, Option<Predicate>>>,
+    function_breakpoints: HashMap<String, Option<Predicate>>,
+    conditions: Vec<Condition>,
+    /// The functions from `watch`, in the order they were added.
+    watched_functions: Vec<String>,
+}
+
+impl Adapter {
+    fn new(requests: Receiver<Value>, output: Box<dyn Write>) -> Self {
+        Adapter {
+            requests,
+            queued: VecDeque::new(),
+            output,
+            seq: 0,
+            program: None,
+            configured: false,
+            breakpoints: HashMap::new(),
+            function_breakpoints: HashMap::new(),
+            conditions: Vec::new(),
+            watched_functions: Vec::new(),
+        }
+    }
+
+    fn run(mut self) -> Result<(), Error> {
+        loop {
+            let request = match self.queued.pop_front() {
+                Some(request) => request,
+                None => match self.requests.recv() {
+                    Err(_) => return Ok(()),
+                    Ok(request) => request,
+                },
+            };
+            if !self.handle(&request)? {
+                return Ok(());
+            }
+        }
+    }
+
+    fn send(&mut self, mut message: Value) -> Result<(), Error> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+        self.output.flush()?;
+        Ok(())
+    }
+
+    fn respond(&mut self, request: &Value, body: Result<Value, Error>) -> Result<(), Error> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": body.is_ok(),
+        });
+        match body {
+            Ok(body) => response["body"] = body,
+            Err(e) => response["message"] = json!(e.to_string()),
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Result<(), Error> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    /// Handles a request. Returns false if the client disconnected.
+    fn handle(&mut self, request: &Value) -> Result<bool, Error> {
+        let arguments = &request["arguments"];
+        match request["command"].as_str().unwrap_or_default() {
+            "initialize" => {
+                let capabilities = json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsFunctionBreakpoints": true,
+                    "supportsConditionalBreakpoints": true,
+                    "supportsStepBack": true,
+                });
+                self.respond(request, Ok(capabilities))?;
+                self.event("initialized", json!({}))?;
+            }
+            "launch" => match Program::launch(arguments) {
+                Err(e) => self.respond(request, Err(e))?,
+                Ok((program, conditions)) => {
+                    self.program = Some(program);
+                    for condition in conditions {
+                        self.add_condition(condition);
+                    }
+                    let watched = arguments["watch"].as_array().into_iter().flatten();
+                    self.watched_functions
+                        .extend(watched.filter_map(Value::as_str).map(str::to_owned));
+                    self.respond(request, Ok(json!({})))?;
+                    if self.configured {
+                        self.start()?;
+                    }
+                }
+            },
+            "setBreakpoints" => {
+                let path = arguments["source"]["path"].as_str().unwrap_or_default();
+                let mut lines = HashMap::new();
+                let mut verified = Vec::new();
+                for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+                    let Some(line) = breakpoint["line"].as_u64() else {
+                        continue;
+                    };
+                    match breakpoint_condition(breakpoint) {
+                        Err(e) => verified.push(
+                            json!({ "verified": false, "line": line, "message": e.to_string() }),
+                        ),
+                        Ok(condition) => {
+                            lines.insert(line as usize, condition);
+                            verified.push(json!({ "verified": true, "line": line }));
+                        }
+                    }
+                }
+                self.breakpoints.insert(canonicalize(path.into()), lines);
+                self.respond(request, Ok(json!({ "breakpoints": verified })))?;
+            }
+            "setFunctionBreakpoints" => {
+                self.function_breakpoints.clear();
+                let mut verified = Vec::new();
+                for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+                    let Some(name) = breakpoint["name"].as_str() else {
+                        continue;
+                    };
+                    match breakpoint_condition(breakpoint) {
+                        Err(e) => {
+                            verified.push(json!({ "verified": false, "message": e.to_string() }))
+                        }
+                        Ok(condition) => {
+                            self.function_breakpoints.insert(name.to_owned(), condition);
+                            verified.push(json!({ "verified": true }));
+                        }
+                    }
+                }
+                self.respond(request, Ok(json!({ "breakpoints": verified })))?;
+            }
+            "configurationDone" => {
+                self.respond(request, Ok(json!({})))?;
+                self.configured = true;
+                if self.program.is_some() {
+                    self.start()?;
+                }
+            }
+            "threads" => {
+                let threads = json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] });
+                self.respond(request, Ok(threads))?;
+            }
+            "stackTrace" => {
+                let frames = self.stack_frames();
+                self.respond(request, frames)?;
+            }
+            "scopes" => {
+                let scope = |name: &str, reference: u64| json!({ "name": name, "variablesReference": reference, "expensive": false });
+                let scopes = json!({ "scopes": [
+                    scope("Stack", STACK),
+                    scope("Arguments", ARGUMENTS),
+                    scope("Pending instructions", PENDING),
+                ] });
+                self.respond(request, Ok(scopes))?;
+            }
+            "variables" => {
+                let variables = self.variables(arguments["variablesReference"].as_u64());
+                self.respond(request, variables)?;
+            }
+            "evaluate" => {
+                let expression = arguments["expression"].as_str().unwrap_or_default();
+                match back_count(expression) {
+                    Some(n) => {
+                        let result = n.and_then(|n| self.back(n));
+                        let went_back = result.is_ok();
+                        let body = result.map(|n| json!({ "result": format!("Went back {n} step(s)."), "variablesReference": 0 }));
+                        self.respond(request, body)?;
+                        if went_back {
+                            self.stop("step")?;
+                        }
+                    }
+                    None => {
+                        let result = self.evaluate(expression);
+                        let body = result
+                            .map(|result| json!({ "result": result, "variablesReference": 0 }));
+                        self.respond(request, body)?;
+                    }
+                }
+            }
+            command @ ("stepBack" | "reverseContinue") => {
+                let n = if command == "stepBack" { 1 } else { usize::MAX };
+                match self.back(n) {
+                    Err(e) => self.respond(request, Err(e))?,
+                    Ok(_) => {
+                        self.respond(request, Ok(json!({})))?;
+                        self.stop("step")?;
+                    }
+                }
+            }
+            command @ ("continue" | "next" | "stepIn" | "stepOut") => {
+                let Some(program) = &self.program else {
+                    self.respond(request, Err(anyhow!("The program is not running.")))?;
+                    return Ok(true);
+                };
+                let depth = program.runtime.call_stack().count();
+                let mode = match command {
+                    "continue" => Mode::Continue,
+                    "next" => Mode::Next(depth),
+                    "stepIn" => Mode::StepIn,
+                    _ => Mode::StepOut(depth),
+                };
+                self.respond(request, Ok(json!({ "allThreadsContinued": true })))?;
+                self.resume(mode)?;
+            }
+            // The program is already stopped whenever a request is handled
+            "pause" => self.respond(request, Ok(json!({})))?,
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(json!({})))?;
+                return Ok(false);
+            }
+            command => {
+                self.respond(request, Err(anyhow!("Unsupported request '{command}'.")))?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn start(&mut self) -> Result<(), Error> {
+        let Some(program) = &mut self.program else {
+            return Ok(());
+        };
+        if !program.stop_on_entry {
+            return self.resume(Mode::Continue);
+        }
+        match program.next_top_level() {
+            Err(e) => self.finish(Some(e)),
+            Ok(Next::End) => self.finish(None),
+            Ok(_) => self.stop("entry"),
+        }
+    }
+
+    /// Runs the program until it stops for `mode`, hits a breakpoint, is paused, or ends.
+    fn resume(&mut self, mode: Mode) -> Result<(), Error> {
+        // The program starts where it stopped, which must not count as stopping again
+        let mut first = true;
+        // The function that the last step called, if any
+        let mut called = None;
+        // What the last step did to a watched function, if anything
+        let mut changed = None;
+        let mut steps = 0;
+        loop {
+            let Some(program) = &mut self.program else {
+                return Ok(());
+            };
+            let parsed = match program.next_top_level() {
+                Err(e) => return self.finish(Some(e)),
+                Ok(Next::End) => return self.finish(None),
+                Ok(next) => next == Next::Parsed,
+            };
+            let depth = program.runtime.call_stack().count();
+            self.flush_output()?;
+            if !first || parsed {
+                let became_true = self.check_conditions();
+                let reason = if parsed && self.hits_line_breakpoint() {
+                    Some("breakpoint")
+                } else if called.is_some_and(|f: String| self.hits_function_breakpoint(&f)) {
+                    Some("function breakpoint")
+                } else if let Some(change) = changed.take() {
+                    let output = format!("Stopped because {change}.\n");
+                    self.event("output", json!({ "category": "console", "output": output }))?;
+                    Some("data breakpoint")
+                } else if let Some(condition) = became_true {
+                    let output = format!("Stopped because {condition} became true.\n");
+                    self.event("output", json!({ "category": "console", "output": output }))?;
+                    Some("data breakpoint")
+                } else {
+                    match mode {
+                        Mode::Continue => None,
+                        Mode::StepIn => Some("step"),
+                        Mode::Next(d) if depth <= d => Some("step"),
+                        Mode::StepOut(d) if depth < d => Some("step"),
+                        Mode::Next(_) | Mode::StepOut(_) => None,
+                    }
+                };
+                if let Some(reason) = reason {
+                    return self.stop(reason);
+                }
+            }
+            first = false;
+
+            steps += 1;
+            if steps % POLL_INTERVAL == 0 && self.poll_pause()? {
+                return self.stop("pause");
+            }
+            changed = self.watched_change();
+            let program = self.program.as_mut().expect("the program is running");
+            match program.runtime.step() {
+                Err(e) => return self.finish(Some(e)),
+                Ok(Step::Exit) => return self.finish(None),
+                Ok(Step::Done) => called = None,
+                Ok(Step::Ran { called: true }) => {
+                    called = program.runtime.call_stack().last().map(str::to_owned)
+                }
+                Ok(Step::Ran { called: false }) => called = None,
+            }
+        }
+    }
+
+    /// Checks for requests that arrived while the program was running. Returns true if one of them asked for a pause.
+    fn poll_pause(&mut self) -> Result<bool, Error> {
+        while let Ok(request) = self.requests.try_recv() {
+            match request["command"].as_str() {
+                Some("pause") => {
+                    self.respond(&request, Ok(json!({})))?;
+                    return Ok(true);
+                }
+                _ => self.queued.push_back(request),
+            }
+        }
+        // Stop running if the client wants to disconnect
+        Ok(self.queued.iter().any(|request| {
+            matches!(
+                request["command"].as_str(),
+                Some("disconnect" | "terminate")
+            )
+        }))
+    }
+
+    fn stop(&mut self, reason: &str) -> Result<(), Error> {
+        self.flush_output()?;
+        self.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        )
+    }
+
+    /// Reports the end of the program, with the error that stopped it if there was one.
+    fn finish(&mut self, error: Option<Error>) -> Result<(), Error> {
+        self.flush_output()?;
+        if let Some(e) = &error {
+            let mut message = e.to_string();
+            for cause in e.chain().skip(1) {
+                message += &format!("\n\nCaused by:\n    {cause}");
+            }
+            self.event(
+                "output",
+                json!({ "category": "stderr", "output": message + "\n" }),
+            )?;
+        }
+        self.program = None;
+        self.event("exited", json!({ "exitCode": i32::from(error.is_some()) }))?;
+        self.event("terminated", json!({}))
+    }
+
+    /// Sends what the program printed, its failed assertions, and the warnings about it to the client.
+    fn flush_output(&mut self) -> Result<(), Error> {
+        let Some(program) = &mut self.program else {
+            return Ok(());
+        };
+        let stdout = std::mem::take(&mut *program.stdout.0.borrow_mut());
+        let mut stderr = String::new();
+        for failure in program.runtime.take_assertion_failures() {
+            stderr += &format!("{failure}\n");
+        }
+        let mut console = String::new();
+        for (lint, warning) in program.parser.take_warnings() {
+            console += &format!("Warning {}: {warning}\n", lint.code());
+        }
+        for (category, output) in [
+            ("stdout", String::from_utf8_lossy(&stdout).into_owned()),
+            ("stderr", stderr),
+            ("console", console),
+        ] {
+            if !output.is_empty() {
+                self.event("output", json!({ "category": category, "output": output }))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn hits_line_breakpoint(&self) -> bool {
+        let Some(program) = &self.program else {
+            return false;
+        };
+        let Some(lines) = self.breakpoints.get(&program.path) else {
+            return false;
+        };
+        // A DEFN only stops when its function is called
+        !program.is_definition
+            && lines.iter().any(|(line, condition)| {
+                program.new_lines.contains(line) && program.holds(condition.as_ref())
+            })
+    }
+
+    fn hits_function_breakpoint(&self, f: &str) -> bool {
+        let Some(program) = &self.program else {
+            return false;
+        };
+        if let Some(condition) = self.function_breakpoints.get(f) {
+            if program.holds(condition.as_ref()) {
+                return true;
+            }
+        }
+        match (
+            self.breakpoints.get(&program.path),
+            program.definitions.get(f),
+        ) {
+            (Some(lines), Some(definition)) => lines.iter().any(|(line, condition)| {
+                definition.contains(line) && program.holds(condition.as_ref())
+            }),
+            _ => false,
+        }
+    }
+
+    /// Starts checking a `break when` condition. If it already holds, the program only stops once it stops holding and
+    /// then holds again.
+    fn add_condition(&mut self, predicate: Predicate) {
+        let held = self
+            .program
+            .as_ref()
+            .is_some_and(|program| predicate.eval(&program.runtime));
+        self.conditions.push(Condition { predicate, held });
+    }
+
+    /// Checks every `break when` condition, and returns the first one that became true since the last check.
+    fn check_conditions(&mut self) -> Option<String> {
+        let program = self.program.as_ref()?;
+        let mut became_true = None;
+        for condition in &mut self.conditions {
+            let holds = condition.predicate.eval(&program.runtime);
+            if holds && !condition.held && became_true.is_none() {
+                became_true = Some(condition.predicate.to_string());
+            }
+            condition.held = holds;
+        }
+        became_true
+    }
+
+    /// Undoes up to `n` steps, and returns how many were undone.
+    fn back(&mut self, n: usize) -> Result<usize, Error> {
+        let program = self
+            .program
+            .as_mut()
+            .ok_or_else(|| anyhow!("The program is not running."))?;
+        let steps = program.back(n);
+        // Conditions become true relative to where the program went back to
+        for condition in &mut self.conditions {
+            condition.held = condition.predicate.eval(&program.runtime);
+        }
+        Ok(steps)
+    }
+
+    /// Describes what the next step does to a watched function, if anything, and where.
+    fn watched_change(&self) -> Option<String> {
+        let program = self.program.as_ref()?;
+        let runtime = &program.runtime;
+        let (f, change) = match (runtime.next_definition(), runtime.next_call()) {
+            (Some(f), _) if runtime.is_defined(f) => (f, "redefined"),
+            (Some(f), _) => (f, "defined"),
+            (None, Some(f)) => (f, "called"),
+            (None, None) => return None,
+        };
+        if !self.watched_functions.iter().any(|g| g == f) {
+            return None;
+        }
+        let context = match runtime.call_stack().last() {
+            Some(caller) => format!("by {caller}"),
+            None => "at the top level".to_owned(),
+        };
+        Some(format!(
+            "{f} was {change} {context} (line {})",
+            program.lines.start()
+        ))
+    }
+
+    /// Runs a command from the debug console:
+    /// - `break when <condition>` to stop whenever the condition becomes true, `break` to list those conditions, and
+    ///   `break clear` to remove them.
+    /// - `watch <function>` to stop whenever the function is defined, redefined, or called, `watch` to list the watched
+    ///   functions, and `watch clear` to stop watching them.
+    /// - `reload` to swap the functions that changed in the file into the program.
+    ///
+    /// Anything else is evaluated as a condition.
+    fn evaluate(&mut self, expression: &str) -> Result<String, Error> {
+        let expression = expression.trim();
+        if let Some(condition) = expression.strip_prefix("break when ") {
+            let condition = Predicate::parse(condition)?;
+            let result = format!("Stopping when {condition} becomes true.");
+            self.add_condition(condition);
+            return Ok(result);
+        }
+        if let Some(f) = expression.strip_prefix("watch ") {
+            let f = f.trim();
+            if f == "clear" {
+                self.watched_functions.clear();
+                return Ok("Stopped watching every function.".to_owned());
+            }
+            if !self.watched_functions.iter().any(|g| g == f) {
+                self.watched_functions.push(f.to_owned());
+            }
+            return Ok(format!("Watching {f}."));
+        }
+        match expression {
+            "break" if self.conditions.is_empty() => Ok("No conditions.".to_owned()),
+            "break" => Ok(self
+                .conditions
+                .iter()
+                .map(|condition| condition.predicate.to_string())
+                .collect::<Vec<_>>()
+                .join("\n")),
+            "break clear" => {
+                self.conditions.clear();
+                Ok("Removed every condition.".to_owned())
+            }
+            "watch" if self.watched_functions.is_empty() => Ok("No watched functions.".to_owned()),
+            "watch" => Ok(self.watched_functions.join("\n")),
+            "reload" => {
+                let program = self
+                    .program
+                    .as_mut()
+                    .ok_or_else(|| anyhow!("The program is not running."))?;
+                let reader = FileReader::new(vec![program.path.clone()])
+                    .context("Failed to read the program.")?;
+                let mut parser = Parser::new(Lexer::new(reader));
+                let mut instructions = Vec::new();
+                while let Some(instruction) = parser.next_instruction()? {
+                    instructions.push(instruction);
+                }
+                Ok(reload::reload(&mut program.runtime, instructions).to_string())
+            }
+            expression => {
+                let condition = Predicate::parse(expression)?;
+                let program = self
+                    .program
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("The program is not running."))?;
+                Ok(condition.eval(&program.runtime).to_string())
+            }
+        }
+    }
+
+    /// The frames of the functions that are running, innermost first, followed by the top level.
+    fn stack_frames(&self) -> Result<Value, Error> {
+        let program = self
+            .program
+            .as_ref()
+            .ok_or_else(|| anyhow!("The program is not running."))?;
+        let source = json!({
+            "name": program.path.file_name().map(|name| name.to_string_lossy()),
+            "path": program.path,
+        });
+        let calls = program.runtime.call_stack().collect::<Vec<_>>();
+        let mut frames = calls
+            .iter()
+            .rev()
+            .map(|f| {
+                let line = program
+                    .definitions
+                    .get(*f)
+                    .map_or(*program.lines.end(), |lines| *lines.start());
+                (f.to_string(), line)
+            })
+            .collect::<Vec<_>>();
+        frames.push(("(top level)".to_owned(), *program.lines.end()));
+        let frames = frames
+            .into_iter()
+            .enumerate()
+            .map(|(id, (name, line))| {
+                json!({ "id": id, "name": name, "line": line, "column": 1, "source": source })
+            })
+            .collect::<Vec<_>>();
+        Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+    }
+
+    fn variables(&self, reference: Option<u64>) -> Result<Value, Error> {
+        let program = self
+            .program
+            .as_ref()
+            .ok_or_else(|| anyhow!("The program is not running."))?;
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let variables = match reference {
+            Some(STACK) => program
+                .runtime
+                .stack()
+                .iter()
+                .rev()
+                .enumerate()
+                .map(|(i, word)| variable(i.to_string(), describe(word)))
+                .collect::<Vec<_>>(),
+            Some(ARGUMENTS) => program
+                .runtime
+                .args()
+                .iter()
+                .enumerate()
+                .map(|(i, word)| variable(format!("${i}"), describe(word)))
+                .collect(),
+            Some(PENDING) => program
+                .runtime
+                .pending_instructions(usize::MAX)
+                .into_iter()
+                .enumerate()
+                .map(|(i, instruction)| variable(i.to_string(), instruction))
+                .collect(),
+            _ => return Err(anyhow!("Unknown variables reference.")),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+}
+
+impl Program {
+    /// Starts the program, and returns it with the conditions of `breakWhen`.
+    fn launch(arguments: &Value) -> Result<(Self, Vec<Predicate>), Error> {
+        let path = arguments["program"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing 'program'."))?;
+        let path = canonicalize(path.into());
+        let conditions = arguments["breakWhen"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|condition| Predicate::parse(condition.as_str().unwrap_or_default()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let reader = FileReader::new(vec![path.clone()]).context("Failed to read the program.")?;
+        let stdout = SharedBuffer::default();
+        let program = Program {
+            path,
+            runtime: Runtime::new()
+                .with_journal(JOURNAL_CAPACITY)
+                .with_io(stdout.clone(), io::empty())
+                // Stdout carries the messages to the editor, so the program cannot draw on it
+                .with_terminal(Rc::new(NoTerminal)),
+            stdout,
+            parser: Parser::new(Lexer::new(reader)),
+            lines: 0..=0,
+            new_lines: 0..=0,
+            is_definition: false,
+            definitions: HashMap::new(),
+            stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+            history: VecDeque::new(),
+            undone: Vec::new(),
+        };
+        Ok((program, conditions))
+    }
+
+    /// Whether a breakpoint with this condition stops the program.
+    fn holds(&self, condition: Option<&Predicate>) -> bool {
+        condition.is_none_or(|condition| condition.eval(&self.runtime))
+    }
+
+    /// Makes sure that there is something to run, by parsing the next top-level instruction if the instruction stack is
+    /// empty.
+    fn next_top_level(&mut self) -> Result<Next, Error> {
+        if !self.runtime.pending_instructions(1).is_empty() {
+            return Ok(Next::Pending);
+        }
+        let top_level = match self.undone.pop() {
+            Some(top_level) => top_level,
+            None => {
+                let previous = *self.lines.end();
+                let Some(instruction) = self.parser.next_instruction()? else {
+                    return Ok(Next::End);
+                };
+                let line = self.parser.location().map_or(previous, |(_, line)| line);
+                TopLevel {
+                    instruction,
+                    // The instruction starts after the line where the previous one ended, unless they share a line
+                    lines: (previous + 1).min(line)..=line,
+                    new_lines: previous + 1..=line,
+                }
+            }
+        };
+        self.enter(&top_level);
+        if let Instruction::Define(f, _, _, _) = &top_level.instruction {
+            self.definitions.insert(f.clone(), self.lines.clone());
+        }
+        self.runtime.push_instruction(top_level.instruction.clone());
+        if self.history.len() == JOURNAL_CAPACITY {
+            self.history.pop_front();
+        }
+        self.history.push_back(top_level);
+        Ok(Next::Parsed)
+    }
+
+    /// Makes a top-level instruction the one that is running.
+    fn enter(&mut self, top_level: &TopLevel) {
+        self.lines = top_level.lines.clone();
+        self.new_lines = top_level.new_lines.clone();
+        self.is_definition = matches!(top_level.instruction, Instruction::Define(..));
+    }
+
+    /// Undoes up to `n` steps, and returns how many were undone.
+    fn back(&mut self, n: usize) -> usize {
+        let mut steps = 0;
+        while steps < n {
+            match self.runtime.step_back() {
+                None => break,
+                Some(Undone::Step) => steps += 1,
+                Some(Undone::Push(instruction)) => {
+                    let top_level = self.history.pop_back().unwrap_or(TopLevel {
+                        instruction,
+                        lines: 0..=0,
+                        new_lines: 0..=0,
+                    });
+                    self.undone.push(top_level);
+                    match self.history.back().cloned() {
+                        Some(previous) => self.enter(&previous),
+                        None => {
+                            self.lines = 0..=0;
+                            self.new_lines = 0..=0;
+                        }
+                    }
+                }
+            }
+        }
+        steps
+    }
+}
+
+/// The number of steps for `back` or `back <N>` in the debug console, or `None` if the expression is something else.
+fn back_count(expression: &str) -> Option<Result<usize, Error>> {
+    let expression = expression.trim();
+    if expression == "back" {
+        return Some(Ok(1));
+    }
+    let n = expression.strip_prefix("back ")?.trim();
+    Some(
+        n.parse()
+            .map_err(|_| anyhow!("Invalid number of steps '{n}'.")),
+    )
+}
+
+/// The condition of a breakpoint, if it has one.
+fn breakpoint_condition(breakpoint: &Value) -> Result<Option<Predicate>, Error> {
+    match breakpoint["condition"].as_str() {
+        Some(condition) if !condition.trim().is_empty() => Predicate::parse(condition).map(Some),
+        _ => Ok(None),
+    }
+}
+
+fn canonicalize(path: PathBuf) -> PathBuf {
+    path.canonicalize().unwrap_or(path)
+}
+
+fn describe(word: &Word) -> String {
+    match word {
+        Word::Function(f) => format!("function {f}"),
+        word => word.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Runs a debugging session for `source` with the given requests (with the program filled in), and describes what
+    /// the adapter sent back.
+    fn session(source: &str, requests: &[Value]) -> Vec<String> {
+        // Every session gets its own file, since the tests run in parallel
+        static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "plates-test-{}-dap-{}.plates",
+            std::process::id(),
+            SESSIONS.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, source).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        for (seq, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            if request["arguments"]["program"] == json!(true) {
+                request["arguments"]["program"] = json!(path);
+            }
+            if request["arguments"]["source"]["path"] == json!(true) {
+                request["arguments"]["source"]["path"] = json!(path);
+            }
+            sender.send(request).unwrap();
+        }
+        drop(sender);
+
+        let output = SharedBuffer::default();
+        Adapter::new(receiver, Box::new(output.clone()))
+            .run()
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+        let output = output.0.borrow();
+        let mut output = &output[..];
+        let mut messages = vec![];
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(describe_message(&message));
+        }
+        messages
+    }
+
+    fn describe_message(message: &Value) -> String {
+        let body = &message["body"];
+        let list = |items: &Value, describe: &dyn Fn(&Value) -> String| {
+            items
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(describe)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match (message["type"].as_str(), message["command"].as_str()) {
+            (Some("response"), _) if message["success"] == json!(false) => {
+                format!("{} failed: {}", message["command"], message["message"])
+            }
+            (Some("response"), Some("stackTrace")) => list(&body["stackFrames"], &|frame| {
+                format!("{}:{}", frame["name"].as_str().unwrap(), frame["line"])
+            }),
+            (Some("response"), Some("variables")) => list(&body["variables"], &|variable| {
+                format!(
+                    "{} = {}",
+                    variable["name"].as_str().unwrap(),
+                    variable["value"].as_str().unwrap()
+                )
+            }),
+            (Some("response"), Some("evaluate")) => body["result"].as_str().unwrap().to_owned(),
+            (Some("response"), Some(command)) => command.to_owned(),
+            _ => match message["event"].as_str().unwrap() {
+                "stopped" => format!("stopped: {}", body["reason"].as_str().unwrap()),
+                "output" => format!(
+                    "{}: {:?}",
+                    body["category"].as_str().unwrap(),
+                    body["output"].as_str().unwrap()
+                ),
+                "exited" => format!("exited: {}", body["exitCode"]),
+                event => event.to_owned(),
+            },
+        }
+    }
+
+    fn request(command: &str, arguments: Value) -> Value {
+        json!({ "command": command, "arguments": arguments })
+    }
+
+    fn variables(reference: u64) -> Value {
+        request("variables", json!({ "variablesReference": reference }))
+    }
+
+    #[test]
+    fn breakpoints() {
+        let source = "DEFN f (1) {\n    PUSH $0\n}\nPUSH 5\nPUSH 1 PUSH f CALLIF\nPUSH 6 PUSH 7\n";
+        let messages = session(
+            source,
+            &[
+                request("initialize", json!({})),
+                request("launch", json!({ "program": true })),
+                request(
+                    "setBreakpoints",
+                    json!({ "source": { "path": true }, "breakpoints": [{ "line": 2 }, { "line": 6 }] }),
+                ),
+                request("configurationDone", json!({})),
+                request("stackTrace", json!({ "threadId": THREAD_ID })),
+                variables(STACK),
+                variables(ARGUMENTS),
+                variables(PENDING),
+                request("continue", json!({ "threadId": THREAD_ID })),
+                request("stackTrace", json!({ "threadId": THREAD_ID })),
+                variables(STACK),
+                // The breakpoint is on the line of PUSH 7 as well, but only stops once per line
+                request("continue", json!({ "threadId": THREAD_ID })),
+                request("disconnect", json!({})),
+            ],
+        );
+        assert_eq!(
+            vec![
+                "initialize",
+                "initialized",
+                "launch",
+                "setBreakpoints",
+                "configurationDone",
+                "stopped: function breakpoint",
+                "f:1, (top level):5",
+                "",
+                "$0 = 5",
+                "0 = PUSH $0",
+                "continue",
+                "stopped: breakpoint",
+                "(top level):6",
+                "0 = 5",
+                "continue",
+                "exited: 0",
+                "terminated",
+                "disconnect",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn function_breakpoints_and_stepping() {
+        let source =
+            "DEFN f (0) {\n    PUSH 1\n    PUSH 2\n}\nPUSH 1 PUSH f CALLIF\nPUSH 3\nPUSH 4\n";
+        let messages = session(
+            source,
+            &[
+                request("initialize", json!({})),
+                request(
+                    "setFunctionBreakpoints",
+                    json!({ "breakpoints": [{ "name": "f" }] }),
+                ),
+                request("configurationDone", json!({})),
+                request("launch", json!({ "program": true })),
+                request("stepIn", json!({ "threadId": THREAD_ID })),
+                variables(STACK),
+                // Steps over the rest of f
+                request("stepOut", json!({ "threadId": THREAD_ID })),
+                variables(STACK),
+                request("next", json!({ "threadId": THREAD_ID })),
+                variables(STACK),
+                request("next", json!({ "threadId": THREAD_ID })),
+            ],
+        );
+        assert_eq!(
+            vec![
+                "initialize",
+                "initialized",
+                "setFunctionBreakpoints",
+                "configurationDone",
+                "launch",
+                "stopped: function breakpoint",
+                "stepIn",
+                "stopped: step",
+                "0 = 1",
+                "stepOut",
+                "stopped: step",
+                "0 = 2, 1 = 1",
+                "next",
+                "stopped: step",
+                "0 = 3, 1 = 2, 2 = 1",
+                "next",
+                "exited: 0",
+                "terminated",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn conditions() {
+        let source = "PUSH 1\nPUSH 2\nPUSH 3\nPUSH 4\n";
+        let messages = session(
+            source,
+            &[
+                request("initialize", json!({})),
+                request(
+                    "launch",
+                    json!({ "program": true, "breakWhen": ["depth >= 2"] }),
+                ),
+                request(
+                    "setBreakpoints",
+                    json!({ "source": { "path": true }, "breakpoints": [
+                        { "line": 1, "condition": "depth == 5" },
+                        { "line": 4, "condition": "top == 3" },
+                    ] }),
+                ),
+                request("configurationDone", json!({})),
+                variables(STACK),
+                request("evaluate", json!({ "expression": "break when top == 3" })),
+                request("evaluate", json!({ "expression": "depth > 100 or" })),
+                // The first condition still holds, so only the breakpoint stops the program
+                request("continue", json!({ "threadId": THREAD_ID })),
+                request("evaluate", json!({ "expression": "break" })),
+                request("evaluate", json!({ "expression": "top == 3" })),
+                request("continue", json!({ "threadId": THREAD_ID })),
+            ],
+        );
+        assert_eq!(
+            vec![
+                "initialize",
+                "initialized",
+                "launch",
+                "setBreakpoints",
+                "configurationDone",
+                "console: \"Stopped because depth >= 2 became true.\\n\"",
+                "stopped: data breakpoint",
+                "0 = 2, 1 = 1",
+                "Stopping when top == 3 becomes true.",
+                "\"evaluate\" failed: \"Syntax error: Invalid condition 'depth > 100 or'. Expected a number, depth, calls, top, stack[N], or $N.\"",
+                "continue",
+                "stopped: breakpoint",
+                "depth >= 2\ntop == 3",
+                "true",
+                "continue",
+                "exited: 0",
+                "terminated",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn watched_functions() {
+        let source = "DEFN f (0) {\n    PUSH 1\n}\nDEFN g (0) {\n    PUSH 1 PUSH f CALLIF PUSH 3\n}\nDEFN f (0) { PUSH 2 }\nPUSH 1 PUSH g CALLIF\n";
+        let messages = session(
+            source,
+            &[
+                request("initialize", json!({})),
+                request("launch", json!({ "program": true, "watch": ["f"] })),
+                request("configurationDone", json!({})),
+                request("continue", json!({ "threadId": THREAD_ID })),
+                request("continue", json!({ "threadId": THREAD_ID })),
+                request("stackTrace", json!({ "threadId": THREAD_ID })),
+                request("evaluate", json!({ "expression": "watch" })),
+                request("evaluate", json!({ "expression": "reload" })),
+                request("evaluate", json!({ "expression": "watch clear" })),
+                request("continue", json!({ "threadId": THREAD_ID })),
+            ],
+        );
+        assert_eq!(
+            vec![
+                "initialize",
+                "initialized",
+                "launch",
+                "configurationDone",
+                "console: \"Stopped because f was defined at the top level (line 1).\\n\"",
+                "stopped: data breakpoint",
+                "continue",
+                "console: \"Stopped because f was redefined at the top level (line 7).\\n\"",
+                "stopped: data breakpoint",
+                "continue",
+                "console: \"Stopped because f was called by g (line 8).\\n\"",
+                "stopped: data breakpoint",
+                "f:7, g:4, (top level):8",
+                "f",
+                "No functions changed.",
+                "Stopped watching every function.",
+                "continue",
+                "exited: 0",
+                "terminated",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn going_back() {
+        let source = "PUSH 1\nPUSH 2\nDEFN f (0) { PUSH 9 }\nPUSH 1 PUSH f CALLIF\n";
+        let messages = session(
+            source,
+            &[
+                request("initialize", json!({})),
+                request("launch", json!({ "program": true })),
+                request(
+                    "setBreakpoints",
+                    json!({ "source": { "path": true }, "breakpoints": [{ "line": 4 }] }),
+                ),
+                request("configurationDone", json!({})),
+                // Goes back before the DEFN, which is no longer in effect
+                request("stepBack", json!({ "threadId": THREAD_ID })),
+                request("stackTrace", json!({ "threadId": THREAD_ID })),
+                variables(STACK),
+                request("evaluate", json!({ "expression": "back 5" })),
+                variables(STACK),
+                request("continue", json!({ "threadId": THREAD_ID })),
+                variables(STACK),
+                request("evaluate", json!({ "expression": "back x" })),
+                request("reverseContinue", json!({ "threadId": THREAD_ID })),
+                variables(STACK),
+                request("continue", json!({ "threadId": THREAD_ID })),
+                request("continue", json!({ "threadId": THREAD_ID })),
+            ],
+        );
+        assert_eq!(
+            vec![
+                "initialize",
+                "initialized",
+                "launch",
+                "setBreakpoints",
+                "configurationDone",
+                "stopped: breakpoint",
+                "stepBack",
+                "stopped: step",
+                "(top level):3",
+                "0 = 2, 1 = 1",
+                "Went back 2 step(s).",
+                "stopped: step",
+                "",
+                "continue",
+                "stopped: breakpoint",
+                "0 = 2, 1 = 1",
+                "\"evaluate\" failed: \"Invalid number of steps 'x'.\"",
+                "reverseContinue",
+                "stopped: step",
+                "",
+                "continue",
+                "stopped: breakpoint",
+                "continue",
+                "exited: 0",
+                "terminated",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn output_and_errors() {
+        let source = "PUSH 0 PUSH 104 PUSH 1 PUSH __print__ CALLIF\nPUSH 1 PUSH nope CALLIF\n";
+        let messages = session(
+            source,
+            &[
+                request("initialize", json!({})),
+                request("launch", json!({ "program": true, "stopOnEntry": true })),
+                request("configurationDone", json!({})),
+                request("continue", json!({ "threadId": THREAD_ID })),
+            ],
+        );
+        assert_eq!(
+            vec![
+                "initialize",
+                "initialized",
+                "launch",
+                "configurationDone",
+                "stopped: entry",
+                "continue",
+                "stdout: \"h\"",
+                "stderr: \"Runtime error: Undefined argument or function.\\n\"",
+                "exited: 1",
+                "terminated",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn no_terminal() {
+        let messages = session(
+            "PUSH 1 PUSH __clear__ CALLIF\n",
+            &[
+                request("initialize", json!({})),
+                request("launch", json!({ "program": true })),
+                request("configurationDone", json!({})),
+            ],
+        );
+        assert_eq!(
+            vec![
+                "initialize",
+                "initialized",
+                "launch",
+                "configurationDone",
+                "stderr: \"Environment error: Failed to control the terminal.\\n\"",
+                "exited: 1",
+                "terminated",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn missing_program() {
+        let messages = session(
+            "",
+            &[request(
+                "launch",
+                json!({ "program": "does/not/exist.plates" }),
+            )],
+        );
+        assert_eq!(
+            vec!["\"launch\" failed: \"Failed to read the program.\""],
+            messages
+        );
+    }
+}
@@ -0,0 +1,52 @@
+/// Example programs that are bundled with the interpreter: (name, description, source code).
+pub const EXAMPLES: &[(&str, &str, &str)] = &[
+    (
+        "hello",
+        "Prints \"Hello, world!\"",
+        include_str!("examples/hello.plates"),
+    ),
+    (
+        "fizzbuzz",
+        "Counts to 100, replacing multiples of 3 and 5 with Fizz and Buzz",
+        include_str!("examples/fizzbuzz.plates"),
+    ),
+    (
+        "guess",
+        "Asks for the secret word until you guess it",
+        include_str!("examples/guess.plates"),
+    ),
+];
+
+/// Returns the source code of the example with the given name.
+pub fn find(name: &str) -> Option<&'static str> {
+    EXAMPLES
+        .iter()
+        .find(|(example, _, _)| *example == name)
+        .map(|(_, _, source)| *source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::Lexer, parser::Parser, reader::FileReader};
+
+    #[test]
+    fn examples_parse() {
+        for (name, _, source) in EXAMPLES {
+            let mut parser = Parser::new(Lexer::new(FileReader::from_source(source)));
+            loop {
+                match parser.next_instruction() {
+                    Err(e) => panic!("Failed to parse example '{name}': {e}"),
+                    Ok(None) => break,
+                    Ok(Some(_)) => {}
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn find_example() {
+        assert_eq!(Some(include_str!("examples/hello.plates")), find("hello"));
+        assert_eq!(None, find("goodbye"));
+    }
+}
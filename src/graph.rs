@@ -0,0 +1,254 @@
+//! Call graphs (`plates graph`), in the DOT format of Graphviz.
+//!
+//! A function is connected to every function that it pushes by name. Since functions can only be called after being
+//! pushed, this covers every call, including the calls through arguments like `$0`.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::{parser::Instruction, runtime::BUILTIN_FUNCTIONS, test_runner::is_test};
+
+/// The name of the node for the instructions outside of functions.
+pub const TOP_LEVEL: &str = "(top level)";
+
+/// How one function refers to another.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Edge {
+    /// Pushed and then called right away.
+    Call,
+    /// Pushed for later, like a function that is passed to another one.
+    Push,
+}
+
+/// Which functions push which other functions.
+#[derive(Debug, Default)]
+pub struct CallGraph {
+    /// The defined functions with their argument counts, in the order they were defined.
+    functions: Vec<(String, u32)>,
+    /// The functions that each function (or the top level) pushes, in order of appearance.
+    edges: Vec<(String, String, Edge)>,
+}
+
+impl CallGraph {
+    pub fn new(program: &[Instruction]) -> Self {
+        let mut graph = CallGraph::default();
+        graph.add_block(TOP_LEVEL, program);
+        graph
+    }
+
+    fn add_block(&mut self, from: &str, block: &[Instruction]) {
+        for (i, instruction) in block.iter().enumerate() {
+            match instruction {
+                Instruction::PushFunction(to) => {
+                    let edge = match block.get(i + 1) {
+                        Some(Instruction::CallIf) => Edge::Call,
+                        _ => Edge::Push,
+                    };
+                    match self.edges.iter_mut().find(|(f, g, _)| f == from && g == to) {
+                        // A call is more interesting than a push
+                        Some((_, _, existing)) if edge == Edge::Call => *existing = edge,
+                        Some(_) => {}
+                        None => self.edges.push((from.to_owned(), to.clone(), edge)),
+                    }
+                }
+                Instruction::Define(f, arg_count, body, _) => {
+                    if !self.is_defined(f) {
+                        self.functions.push((f.clone(), *arg_count));
+                    }
+                    self.add_block(f, body);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn is_defined(&self, f: &str) -> bool {
+        self.functions.iter().any(|(g, _)| g == f)
+    }
+
+    /// The defined functions (and the top level) that `from` pushes.
+    fn successors<'a>(&'a self, from: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.edges
+            .iter()
+            .filter(move |(f, g, _)| f == from && self.is_defined(g))
+            .map(|(_, g, _)| g.as_str())
+    }
+
+    /// Every function that can be reached from `start` by following edges (not including `start` itself, unless it is
+    /// part of a cycle).
+    fn reachable_from<'a>(&'a self, start: &'a str) -> HashSet<&'a str> {
+        let mut reached = HashSet::new();
+        let mut queue = VecDeque::from([start]);
+        while let Some(f) = queue.pop_front() {
+            for g in self.successors(f) {
+                if reached.insert(g) {
+                    queue.push_back(g);
+                }
+            }
+        }
+        reached
+    }
+
+    /// The functions that can end up calling themselves, in the order they were defined.
+    pub fn recursive_functions(&self) -> Vec<&str> {
+        self.functions
+            .iter()
+            .map(|(f, _)| f.as_str())
+            .filter(|f| self.reachable_from(f).contains(f))
+            .collect()
+    }
+
+    /// The functions that are never pushed by the top level (or by tests), directly or not, in the order they were
+    /// defined.
+    pub fn unreachable_functions(&self) -> Vec<&str> {
+        let mut reached = self.reachable_from(TOP_LEVEL);
+        for (f, _) in &self.functions {
+            if is_test(f) {
+                reached.insert(f);
+                reached.extend(self.reachable_from(f));
+            }
+        }
+        self.functions
+            .iter()
+            .map(|(f, _)| f.as_str())
+            .filter(|f| !reached.contains(f))
+            .collect()
+    }
+
+    /// Renders the graph. Calls are solid arrows and functions that are only pushed (to be called later) are dashed.
+    /// Recursive functions and the calls between them are red, and unreachable functions are gray. Built-in functions
+    /// are only included if `builtins` is true.
+    pub fn to_dot(&self, builtins: bool) -> String {
+        let recursive = self.recursive_functions();
+        let unreachable = self.unreachable_functions();
+        let reach = self
+            .functions
+            .iter()
+            .map(|(f, _)| (f.as_str(), self.reachable_from(f)))
+            .collect::<HashMap<_, _>>();
+
+        let mut dot = "digraph plates {\n".to_owned();
+        if !recursive.is_empty() {
+            dot += &format!("    // Recursive: {}\n", recursive.join(", "));
+        }
+        if !unreachable.is_empty() {
+            dot += &format!("    // Unreachable: {}\n", unreachable.join(", "));
+        }
+        dot += &format!("    {} [shape=box];\n", quote(TOP_LEVEL));
+        for (f, arg_count) in &self.functions {
+            let mut attributes = vec![format!("label={}", quote(&format!("{f} ({arg_count})")))];
+            if recursive.contains(&f.as_str()) {
+                attributes.push("color=red".to_owned());
+            }
+            if unreachable.contains(&f.as_str()) {
+                attributes.push("color=gray, fontcolor=gray, style=dashed".to_owned());
+            }
+            dot += &format!("    {} [{}];\n", quote(f), attributes.join(", "));
+        }
+        let mut shown_builtins = Vec::new();
+        for (_, g, _) in &self.edges {
+            if builtins
+                && !self.is_defined(g)
+                && BUILTIN_FUNCTIONS.contains(&g.as_str())
+                && !shown_builtins.contains(&g)
+            {
+                dot += &format!(
+                    "    {} [shape=box, style=filled, fillcolor=lightgray];\n",
+                    quote(g)
+                );
+                shown_builtins.push(g);
+            }
+        }
+        for (f, g, edge) in &self.edges {
+            if !self.is_defined(g) && !shown_builtins.contains(&g) {
+                continue;
+            }
+            let mut attributes = vec![];
+            if *edge == Edge::Push {
+                attributes.push("style=dashed");
+            }
+            // The edge is part of a cycle if it leads back to where it started
+            if reach
+                .get(g.as_str())
+                .is_some_and(|reached| reached.contains(f.as_str()))
+            {
+                attributes.push("color=red");
+            }
+            dot += &format!("    {} -> {}", quote(f), quote(g));
+            if !attributes.is_empty() {
+                dot += &format!(" [{}]", attributes.join(", "));
+            }
+            dot += ";\n";
+        }
+        dot += "}\n";
+        dot
+    }
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::Lexer, parser::Parser, reader::FileReader};
+
+    fn graph(source: &str) -> CallGraph {
+        let mut parser = Parser::new(Lexer::new(FileReader::from_source(source)));
+        let mut instructions = vec![];
+        while let Some(instruction) = parser.next_instruction().unwrap() {
+            instructions.push(instruction);
+        }
+        CallGraph::new(&instructions)
+    }
+
+    const PROGRAM: &str = "
+        DEFN even (1) { PUSH $0 PUSH odd CALLIF }
+        DEFN odd (1) { PUSH $0 PUSH even CALLIF PUSH 1 PUSH __print__ CALLIF }
+        DEFN twice (1) { PUSH 1 PUSH $0 CALLIF PUSH 1 PUSH $0 CALLIF }
+        DEFN unused (0) { PUSH 1 PUSH even CALLIF }
+        DEFN test_odd (0) { PUSH 1 PUSH odd CALLIF }
+        PUSH even PUSH 1 PUSH twice CALLIF
+    ";
+
+    #[test]
+    fn cycles_and_unreachable_functions() {
+        let graph = graph(PROGRAM);
+        assert_eq!(vec!["even", "odd"], graph.recursive_functions());
+        assert_eq!(vec!["unused"], graph.unreachable_functions());
+    }
+
+    #[test]
+    fn dot() {
+        assert_eq!(
+            "digraph plates {
+    // Recursive: even, odd
+    // Unreachable: unused
+    \"(top level)\" [shape=box];
+    \"even\" [label=\"even (1)\", color=red];
+    \"odd\" [label=\"odd (1)\", color=red];
+    \"twice\" [label=\"twice (1)\"];
+    \"unused\" [label=\"unused (0)\", color=gray, fontcolor=gray, style=dashed];
+    \"test_odd\" [label=\"test_odd (0)\"];
+    \"even\" -> \"odd\" [color=red];
+    \"odd\" -> \"even\" [color=red];
+    \"unused\" -> \"even\";
+    \"test_odd\" -> \"odd\";
+    \"(top level)\" -> \"even\" [style=dashed];
+    \"(top level)\" -> \"twice\";
+}
+",
+            graph(PROGRAM).to_dot(false)
+        );
+    }
+
+    #[test]
+    fn builtins() {
+        let dot = graph("DEFN f (0) { PUSH 1 PUSH __print__ CALLIF }").to_dot(true);
+        assert!(
+            dot.contains("    \"__print__\" [shape=box, style=filled, fillcolor=lightgray];\n"),
+            "{dot}"
+        );
+        assert!(dot.contains("    \"f\" -> \"__print__\";\n"), "{dot}");
+    }
+}
@@ -0,0 +1,192 @@
+use colored::Colorize;
+
+use crate::lexer::{lex_line_with_spans, Token};
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Format {
+    /// HTML, with a CSS class on each highlighted span
+    Html,
+    /// ANSI escape codes, for display in a terminal
+    Ansi,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Class {
+    Keyword,
+    Number,
+    Function,
+    Argument,
+    Punctuation,
+    Comment,
+}
+
+impl Class {
+    fn css_class(self) -> &'static str {
+        match self {
+            Class::Keyword => "plates-keyword",
+            Class::Number => "plates-number",
+            Class::Function => "plates-function",
+            Class::Argument => "plates-argument",
+            Class::Punctuation => "plates-punctuation",
+            Class::Comment => "plates-comment",
+        }
+    }
+}
+
+/// Renders the given source code with syntax highlighting.
+pub fn highlight(source: &str, format: Format) -> String {
+    let mut output = String::new();
+
+    if let Format::Html = format {
+        output += "<pre class=\"plates\"><code>";
+    }
+    for line in source.lines() {
+        for (class, text) in segments(line) {
+            match format {
+                Format::Html => output += &to_html(class, text),
+                Format::Ansi => output += &to_ansi(class, text),
+            }
+        }
+        output.push('\n');
+    }
+    if let Format::Html = format {
+        output += "</code></pre>\n";
+    }
+
+    output
+}
+
+/// Splits a line into pieces, each of which is either highlighted or plain text (e.g., whitespace). Lines with
+/// syntax errors are not highlighted at all.
+fn segments(line: &str) -> Vec<(Option<Class>, &str)> {
+    let tokens = match lex_line_with_spans(line) {
+        Err(_) => return vec![(None, line)],
+        Ok(tokens) => tokens,
+    };
+
+    let mut segments = Vec::new();
+    let mut position = 0;
+    for (token, span) in tokens {
+        if span.start > position {
+            segments.push((None, &line[position..span.start]));
+        }
+        segments.push((Some(classify(&token)), &line[span.clone()]));
+        position = span.end;
+    }
+
+    // The lexer discards comments, so anything after the last token is whitespace and possibly a comment
+    let rest = &line[position..];
+    match rest.find("//") {
+        None if rest.is_empty() => {}
+        None => segments.push((None, rest)),
+        Some(i) => {
+            if i > 0 {
+                segments.push((None, &rest[..i]));
+            }
+            segments.push((Some(Class::Comment), &rest[i..]));
+        }
+    }
+
+    segments
+}
+
+fn classify(token: &Token) -> Class {
+    match token {
+        Token::Push
+        | Token::Defn
+        | Token::DefnPriv
+        | Token::CallIf
+        | Token::Exit
+        | Token::Module
+        | Token::Import
+        | Token::Const
+        | Token::Macro
+        | Token::StackOrder
+        | Token::As => Class::Keyword,
+        Token::Word(_) | Token::BigWord(_) | Token::Asterisk => Class::Number,
+        Token::FunctionName(_) | Token::MacroCall(_) => Class::Function,
+        Token::Argument(_) | Token::NamedArgument(_) | Token::It => Class::Argument,
+        // Strings are only used for docstrings
+        Token::String(_) => Class::Comment,
+        Token::LeftCurlyBracket
+        | Token::RightCurlyBracket
+        | Token::LeftParen
+        | Token::RightParen
+        | Token::Arrow => Class::Punctuation,
+    }
+}
+
+fn to_html(class: Option<Class>, text: &str) -> String {
+    let escaped = text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    match class {
+        None => escaped,
+        Some(c) => format!("<span class=\"{}\">{escaped}</span>", c.css_class()),
+    }
+}
+
+fn to_ansi(class: Option<Class>, text: &str) -> String {
+    let colored = match class {
+        None => return text.to_owned(),
+        Some(Class::Keyword) => text.bold().magenta(),
+        Some(Class::Number) => text.cyan(),
+        Some(Class::Function) => text.yellow(),
+        Some(Class::Argument) => text.green(),
+        Some(Class::Punctuation) => text.normal(),
+        Some(Class::Comment) => text.italic().truecolor(128, 128, 128),
+    };
+    colored.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{highlight, segments, Class, Format};
+
+    #[test]
+    fn segments_with_comment() {
+        let expected = vec![
+            (None, "  "),
+            (Some(Class::Keyword), "PUSH"),
+            (None, " "),
+            (Some(Class::Argument), "$1"),
+            (None, " "),
+            (Some(Class::Comment), "// PUSH 1"),
+        ];
+        assert_eq!(expected, segments("  PUSH $1 // PUSH 1"));
+    }
+
+    #[test]
+    fn segments_with_brackets() {
+        let expected = vec![
+            (Some(Class::Keyword), "DEFN"),
+            (None, " "),
+            (Some(Class::Function), "f"),
+            (None, " "),
+            (Some(Class::Punctuation), "("),
+            (Some(Class::Number), "0"),
+            (Some(Class::Punctuation), ")"),
+            (None, " "),
+            (Some(Class::Punctuation), "{"),
+            (None, "\t"),
+        ];
+        assert_eq!(expected, segments("DEFN f (0) {\t"));
+    }
+
+    #[test]
+    fn segments_with_syntax_error() {
+        assert_eq!(vec![(None, "PUSH <1>")], segments("PUSH <1>"));
+    }
+
+    #[test]
+    fn html() {
+        let expected = concat!(
+            "<pre class=\"plates\"><code>",
+            "<span class=\"plates-keyword\">PUSH</span> <span class=\"plates-number\">*</span>\n",
+            "<span class=\"plates-comment\">// a &lt;b&gt; &amp; c</span>\n",
+            "</code></pre>\n",
+        );
+        assert_eq!(expected, highlight("PUSH *\r\n// a <b> & c", Format::Html));
+    }
+}
@@ -0,0 +1,289 @@
+//! JSON encoding of instruction streams (`--emit json` and `--run-json`), so that other tools can produce and consume
+//! plates programs without parsing the source code.
+//!
+//! A program is an object with a `version` (currently 2) and a list of `instructions`. Each instruction is an object
+//! whose `op` says what it is:
+//!
+//! | `op`            | Fields                                                         |
+//! |-----------------|----------------------------------------------------------------|
+//! | `push_data`     | `value` (a non-negative integer)                               |
+//! | `push_big`      | `digits` (a string of decimal digits)                          |
+//! | `push_function` | `name`                                                         |
+//! | `push_random`   |                                                                |
+//! | `push_arg`      | `index` (0 for `$0`)                                           |
+//! | `define`        | `name`, `args` (the number of arguments), `body` (a list of instructions, which cannot contain `define`), and optionally `private` (`true` for `DEFN_PRIV`) and `origin` (the file that it was defined in) |
+//! | `call_if`       |                                                                |
+//! | `exit`          |                                                                |
+//!
+//! For example, `DEFN f (1) { PUSH $0 } PUSH 3 PUSH 1 PUSH f CALLIF` is
+//!
+//! ```json
+//! {
+//!   "version": 2,
+//!   "instructions": [
+//!     { "op": "define", "name": "f", "args": 1, "body": [{ "op": "push_arg", "index": 0 }] },
+//!     { "op": "push_data", "value": 3 },
+//!     { "op": "push_data", "value": 1 },
+//!     { "op": "push_function", "name": "f" },
+//!     { "op": "call_if" }
+//!   ]
+//! }
+//! ```
+//!
+//! Unknown fields are rejected. A private function can only be called from functions with the same `origin`. As with
+//! the bytecode, docstrings and the other attributes of definitions are not kept.
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::parser::{Attributes, Instruction};
+
+const VERSION: u32 = 2;
+
+const ERR_NESTED: &str = "JSON error: Nested definitions are not allowed.";
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Program {
+    version: u32,
+    instructions: Vec<JsonInstruction>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+enum JsonInstruction {
+    PushData {
+        value: u64,
+    },
+    PushBig {
+        digits: String,
+    },
+    PushFunction {
+        name: String,
+    },
+    // The instructions without fields are empty structs so that `deny_unknown_fields` applies to them too
+    PushRandom {},
+    PushArg {
+        index: usize,
+    },
+    Define {
+        name: String,
+        args: u32,
+        body: Vec<JsonInstruction>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        private: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        origin: Option<String>,
+    },
+    CallIf {},
+    Exit {},
+}
+
+impl From<&Instruction> for JsonInstruction {
+    fn from(instruction: &Instruction) -> Self {
+        match instruction {
+            Instruction::PushData(n) => JsonInstruction::PushData { value: *n },
+            Instruction::PushBig(n) => JsonInstruction::PushBig { digits: n.clone() },
+            Instruction::PushFunction(f) => JsonInstruction::PushFunction { name: f.clone() },
+            Instruction::PushRandom => JsonInstruction::PushRandom {},
+            Instruction::PushArg(n) => JsonInstruction::PushArg { index: *n },
+            Instruction::Define(f, arg_count, body, attributes) => JsonInstruction::Define {
+                name: f.clone(),
+                args: *arg_count,
+                body: body.iter().map(JsonInstruction::from).collect(),
+                private: attributes.private,
+                origin: attributes.origin.clone(),
+            },
+            Instruction::CallIf => JsonInstruction::CallIf {},
+            Instruction::Exit => JsonInstruction::Exit {},
+        }
+    }
+}
+
+impl JsonInstruction {
+    fn into_instruction(self, inside_defn: bool) -> Result<Instruction, Error> {
+        let instruction = match self {
+            JsonInstruction::PushData { value } => Instruction::PushData(value),
+            JsonInstruction::PushBig { digits } => {
+                if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(anyhow!("JSON error: Invalid number '{digits}'."));
+                }
+                Instruction::PushBig(digits)
+            }
+            JsonInstruction::PushFunction { name } => Instruction::PushFunction(name),
+            JsonInstruction::PushRandom {} => Instruction::PushRandom,
+            JsonInstruction::PushArg { index } => Instruction::PushArg(index),
+            JsonInstruction::Define { .. } if inside_defn => return Err(anyhow!(ERR_NESTED)),
+            JsonInstruction::Define {
+                name,
+                args,
+                body,
+                private,
+                origin,
+            } => {
+                let body = body
+                    .into_iter()
+                    .map(|instruction| instruction.into_instruction(true))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let attributes = Attributes {
+                    private,
+                    origin,
+                    ..Attributes::default()
+                };
+                Instruction::Define(name, args, body, attributes)
+            }
+            JsonInstruction::CallIf {} => Instruction::CallIf,
+            JsonInstruction::Exit {} => Instruction::Exit,
+        };
+        Ok(instruction)
+    }
+}
+
+/// Encodes a program as pretty-printed JSON.
+pub fn encode(instructions: &[Instruction]) -> String {
+    let program = Program {
+        version: VERSION,
+        instructions: instructions.iter().map(JsonInstruction::from).collect(),
+    };
+    // Serializing plain structs and strings cannot fail
+    serde_json::to_string_pretty(&program).unwrap()
+}
+
+pub fn decode(json: &str) -> Result<Vec<Instruction>, Error> {
+    let program = serde_json::from_str::<Program>(json).map_err(|e| anyhow!("JSON error: {e}."))?;
+    if program.version != VERSION {
+        return Err(anyhow!(
+            "JSON error: Unsupported version {}. Expected {VERSION}.",
+            program.version
+        ));
+    }
+    program
+        .instructions
+        .into_iter()
+        .map(|instruction| instruction.into_instruction(false))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! assert_err_with_msg {
+        ( $value:expr, $msg:expr ) => {
+            match $value {
+                Ok(x) => panic!("Expected an error but received 'Ok({x:?})'."),
+                Err(e) => assert_eq!($msg, format!("{e}")),
+            };
+        };
+    }
+
+    #[test]
+    fn round_trip() {
+        let instructions = vec![
+            Instruction::Define(
+                "swap".to_owned(),
+                2,
+                vec![
+                    Instruction::PushArg(0),
+                    Instruction::PushArg(1),
+                    Instruction::Exit,
+                ],
+                Attributes::default(),
+            ),
+            Instruction::PushData(u64::MAX),
+            Instruction::PushBig("123456789012345678901234567890".to_owned()),
+            Instruction::PushRandom,
+            Instruction::PushFunction("swap".to_owned()),
+            Instruction::Define("empty".to_owned(), 0, vec![], Attributes::default()),
+            Instruction::Define(
+                "helper".to_owned(),
+                0,
+                vec![Instruction::PushData(1)],
+                Attributes {
+                    private: true,
+                    origin: Some("lib.plates".to_owned()),
+                    ..Attributes::default()
+                },
+            ),
+            Instruction::CallIf,
+        ];
+
+        assert_eq!(instructions, decode(&encode(&instructions)).unwrap());
+    }
+
+    #[test]
+    fn schema() {
+        let json = r#"{
+            "version": 2,
+            "instructions": [
+                { "op": "define", "name": "f", "args": 1, "body": [{ "op": "push_arg", "index": 0 }] },
+                { "op": "define", "name": "g", "args": 0, "body": [], "private": true, "origin": "lib.plates" },
+                { "op": "push_data", "value": 3 },
+                { "op": "push_data", "value": 1 },
+                { "op": "push_function", "name": "f" },
+                { "op": "call_if" }
+            ]
+        }"#;
+        assert_eq!(
+            vec![
+                Instruction::Define(
+                    "f".to_owned(),
+                    1,
+                    vec![Instruction::PushArg(0)],
+                    Attributes::default()
+                ),
+                Instruction::Define(
+                    "g".to_owned(),
+                    0,
+                    vec![],
+                    Attributes {
+                        private: true,
+                        origin: Some("lib.plates".to_owned()),
+                        ..Attributes::default()
+                    }
+                ),
+                Instruction::PushData(3),
+                Instruction::PushData(1),
+                Instruction::PushFunction("f".to_owned()),
+                Instruction::CallIf,
+            ],
+            decode(json).unwrap()
+        );
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(json).unwrap(),
+            serde_json::from_str::<serde_json::Value>(&encode(&decode(json).unwrap())).unwrap()
+        );
+    }
+
+    #[test]
+    fn invalid_programs() {
+        assert_err_with_msg!(
+            decode(r#"{ "version": 1, "instructions": [] }"#),
+            "JSON error: Unsupported version 1. Expected 2."
+        );
+        assert_err_with_msg!(
+            decode(
+                r#"{ "version": 2, "instructions": [
+                    { "op": "define", "name": "f", "args": 0, "body": [
+                        { "op": "define", "name": "g", "args": 0, "body": [] }
+                    ] }
+                ] }"#
+            ),
+            ERR_NESTED
+        );
+        assert_err_with_msg!(
+            decode(r#"{ "version": 2, "instructions": [{ "op": "push_big", "digits": "12a" }] }"#),
+            "JSON error: Invalid number '12a'."
+        );
+        for json in [
+            r#"{ "version": 2, "instructions": [{ "op": "pop" }] }"#,
+            r#"{ "version": 2, "instructions": [{ "op": "push_data", "value": -1 }] }"#,
+            r#"{ "version": 2, "instructions": [{ "op": "call_if", "when": 1 }] }"#,
+            r#"{ "version": 2 }"#,
+            "[",
+        ] {
+            let message = decode(json).unwrap_err().to_string();
+            assert!(message.starts_with("JSON error: "), "{message}");
+        }
+    }
+}
@@ -0,0 +1,612 @@
+//! A minimal Jupyter kernel (`plates kernel`), so that plates can be used in notebooks.
+//!
+//! The kernel speaks version 5.3 of the Jupyter messaging protocol over ZeroMQ. Every cell runs in the same runtime, so
+//! functions and words carry over from one cell to the next, and the stack is shown after each cell.
+
+use std::{io, path::Path};
+
+use anyhow::{anyhow, Context, Error};
+use rand::Rng;
+use serde_json::{json, Value};
+use zeromq::{PubSocket, RepSocket, RouterSocket, Socket, SocketRecv, SocketSend, ZmqMessage};
+
+use crate::{
+    lexer::Lexer,
+    parser::Parser,
+    print_error, print_verbose,
+    runtime::{Runtime, Word},
+    SharedBuffer,
+};
+
+const PROTOCOL_VERSION: &str = "5.3";
+/// Separates the ZeroMQ identities of a message from the message itself.
+const DELIMITER: &[u8] = b"<IDS|MSG>";
+/// The number of words shown after each cell.
+const STACK_LIMIT: usize = 20;
+
+/// Where to listen and how to sign messages, as given by Jupyter in the connection file.
+#[derive(Debug)]
+struct ConnectionInfo {
+    transport: String,
+    ip: String,
+    shell_port: u64,
+    iopub_port: u64,
+    stdin_port: u64,
+    control_port: u64,
+    hb_port: u64,
+    key: String,
+}
+
+impl ConnectionInfo {
+    fn parse(text: &str) -> Result<Self, Error> {
+        let json: Value = serde_json::from_str(text)?;
+        let string = |name: &str| {
+            json[name]
+                .as_str()
+                .map(str::to_owned)
+                .ok_or_else(|| anyhow!("Missing '{name}'."))
+        };
+        let port = |name: &str| {
+            json[name]
+                .as_u64()
+                .ok_or_else(|| anyhow!("Missing '{name}'."))
+        };
+        match json["signature_scheme"].as_str() {
+            None | Some("hmac-sha256") => {}
+            Some(scheme) => return Err(anyhow!("Unsupported signature scheme '{scheme}'.")),
+        }
+        Ok(ConnectionInfo {
+            transport: string("transport")?,
+            ip: string("ip")?,
+            shell_port: port("shell_port")?,
+            iopub_port: port("iopub_port")?,
+            stdin_port: port("stdin_port")?,
+            control_port: port("control_port")?,
+            hb_port: port("hb_port")?,
+            key: string("key")?,
+        })
+    }
+
+    fn endpoint(&self, port: u64) -> String {
+        format!("{}://{}:{port}", self.transport, self.ip)
+    }
+}
+
+/// A message in the Jupyter protocol, along with the ZeroMQ identities of the client that sent it (or should receive
+/// it).
+#[derive(Clone, Debug, PartialEq)]
+struct Message {
+    identities: Vec<Vec<u8>>,
+    header: Value,
+    parent_header: Value,
+    metadata: Value,
+    content: Value,
+}
+
+impl Message {
+    fn msg_type(&self) -> &str {
+        self.header["msg_type"].as_str().unwrap_or_default()
+    }
+
+    /// Decodes the frames of a message, checking its signature if there is a key.
+    fn decode(frames: Vec<Vec<u8>>, key: &str) -> Result<Self, Error> {
+        let delimiter = frames
+            .iter()
+            .position(|frame| frame == DELIMITER)
+            .ok_or_else(|| anyhow!("Missing delimiter."))?;
+        let [signature, header, parent_header, metadata, content] = frames
+            .get(delimiter + 1..delimiter + 6)
+            .ok_or_else(|| anyhow!("Missing parts."))?
+        else {
+            unreachable!("the slice has five frames")
+        };
+        if !key.is_empty()
+            && sign(key, [header, parent_header, metadata, content]).as_bytes() != signature
+        {
+            return Err(anyhow!("Invalid signature."));
+        }
+        Ok(Message {
+            identities: frames[..delimiter].to_vec(),
+            header: serde_json::from_slice(header)?,
+            parent_header: serde_json::from_slice(parent_header)?,
+            metadata: serde_json::from_slice(metadata)?,
+            content: serde_json::from_slice(content)?,
+        })
+    }
+
+    /// Encodes the message as frames, signed with `key` (unless it is empty).
+    fn encode(&self, key: &str) -> Vec<Vec<u8>> {
+        let parts = [
+            &self.header,
+            &self.parent_header,
+            &self.metadata,
+            &self.content,
+        ]
+        .map(|part| part.to_string().into_bytes());
+        let signature = match key {
+            "" => String::new(),
+            _ => sign(key, &parts),
+        };
+        let mut frames = self.identities.clone();
+        frames.push(DELIMITER.to_vec());
+        frames.push(signature.into_bytes());
+        frames.extend(parts);
+        frames
+    }
+}
+
+/// The hexadecimal HMAC-SHA256 of the given parts.
+fn sign(key: &str, parts: impl IntoIterator<Item = impl AsRef<[u8]>>) -> String {
+    let mut hmac = hmac_sha256::HMAC::new(key);
+    for part in parts {
+        hmac.update(part);
+    }
+    hmac.finalize().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// A random ID for messages and sessions.
+fn new_id() -> String {
+    format!("{:032x}", rand::thread_rng().gen::<u128>())
+}
+
+/// The result of running a cell.
+#[derive(Debug)]
+struct CellOutput {
+    /// What the program printed.
+    stdout: String,
+    /// Failed assertions, and a note if the program exited.
+    stderr: String,
+    /// The syntax or runtime error that stopped the cell, if any.
+    error: Option<Error>,
+}
+
+/// The state that carries over from one cell to the next.
+struct Session {
+    runtime: Runtime,
+    stdout: SharedBuffer,
+    execution_count: u64,
+}
+
+impl Session {
+    fn new() -> Self {
+        let stdout = SharedBuffer::default();
+        Session {
+            // Like the REPL, a failed instruction leaves the stack as it was so that the cell can be fixed and rerun
+            runtime: Runtime::new()
+                .with_io(stdout.clone(), io::empty())
+                .with_rollback(true),
+            stdout,
+            execution_count: 0,
+        }
+    }
+
+    /// Runs a cell until it ends or fails. An `EXIT` stops the rest of the cell but keeps the runtime as it is.
+    fn execute(&mut self, code: &str) -> CellOutput {
+        let lines = code.lines().map(str::to_owned).collect::<Vec<_>>();
+        let mut parser = Parser::new(Lexer::new(lines.into_iter()));
+        let mut stderr = String::new();
+        let error = loop {
+            let result = parser
+                .next_instruction()
+                .and_then(|instruction| match instruction {
+                    None => Ok(None),
+                    Some(instruction) => self.runtime.run(instruction).map(Some),
+                });
+            for failure in self.runtime.take_assertion_failures() {
+                stderr += &format!("{failure}\n");
+            }
+            match result {
+                Err(e) => break Some(e),
+                Ok(None) => break None,
+                Ok(Some(true)) => {
+                    stderr += "The program exited.\n";
+                    break None;
+                }
+                Ok(Some(false)) => {}
+            }
+        };
+        let stdout =
+            String::from_utf8_lossy(&std::mem::take(&mut *self.stdout.0.borrow_mut())).into_owned();
+        CellOutput {
+            stdout,
+            stderr,
+            error,
+        }
+    }
+
+    /// The stack as plain text and as an HTML table, with the top of the stack first.
+    fn stack_data(&self) -> Value {
+        let mut html = String::from("<table><tr><th>#</th><th>Word</th></tr>");
+        let stack = self.runtime.stack();
+        for (i, word) in stack.iter().rev().take(STACK_LIMIT).enumerate() {
+            let text = match word {
+                Word::Function(f) => format!("function {f}"),
+                word => word.to_string(),
+            };
+            html += &format!("<tr><td>{i}</td><td>{}</td></tr>", escape_html(&text));
+        }
+        html += "</table>";
+        if stack.len() > STACK_LIMIT {
+            html += &format!("<p>... and {} more</p>", stack.len() - STACK_LIMIT);
+        }
+        if stack.is_empty() {
+            html = "<p>(empty stack)</p>".to_owned();
+        }
+        json!({
+            "text/plain": self.runtime.stack_to_debug_string(STACK_LIMIT, false),
+            "text/html": html,
+        })
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Runs the kernel described by a connection file until it is asked to shut down.
+pub fn run(connection_file: &Path) -> Result<(), Error> {
+    let text = std::fs::read_to_string(connection_file)
+        .context("Environment error: Failed to read the connection file.")?;
+    let info = ConnectionInfo::parse(&text).context("Invalid connection file.")?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    // The heartbeat gets its own thread so that it keeps answering while a cell runs
+    let heartbeat = info.endpoint(info.hb_port);
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(run_heartbeat(&heartbeat))
+    });
+    runtime.block_on(Kernel::new(info).serve())
+}
+
+/// Echoes every heartbeat message back, so that Jupyter knows the kernel is alive.
+async fn run_heartbeat(endpoint: &str) -> Result<(), Error> {
+    let mut socket = RepSocket::new();
+    socket.bind(endpoint).await?;
+    loop {
+        let message = socket.recv().await?;
+        socket.send(message).await?;
+    }
+}
+
+struct Kernel {
+    info: ConnectionInfo,
+    /// The ID of this kernel's session, which is part of the header of every message it sends.
+    session_id: String,
+    session: Session,
+}
+
+impl Kernel {
+    fn new(info: ConnectionInfo) -> Self {
+        Kernel {
+            info,
+            session_id: new_id(),
+            session: Session::new(),
+        }
+    }
+
+    async fn serve(mut self) -> Result<(), Error> {
+        let mut shell = RouterSocket::new();
+        shell
+            .bind(&self.info.endpoint(self.info.shell_port))
+            .await?;
+        let mut control = RouterSocket::new();
+        control
+            .bind(&self.info.endpoint(self.info.control_port))
+            .await?;
+        let mut iopub = PubSocket::new();
+        iopub
+            .bind(&self.info.endpoint(self.info.iopub_port))
+            .await?;
+        // Programs cannot ask the notebook for input, but clients expect to be able to connect
+        let mut stdin = RouterSocket::new();
+        stdin
+            .bind(&self.info.endpoint(self.info.stdin_port))
+            .await?;
+        print_verbose(1, "The kernel is ready.");
+
+        loop {
+            let (message, from_shell) = tokio::select! {
+                message = shell.recv() => (message?, true),
+                message = control.recv() => (message?, false),
+            };
+            let frames = message
+                .into_vec()
+                .into_iter()
+                .map(|frame| frame.to_vec())
+                .collect();
+            let request = match Message::decode(frames, &self.info.key) {
+                Err(e) => {
+                    print_error(&e.context("Ignored a message."));
+                    continue;
+                }
+                Ok(request) => request,
+            };
+            let socket = if from_shell { &mut shell } else { &mut control };
+            if !self.handle(&request, socket, &mut iopub).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Answers a request, between a busy and an idle status. Returns false if the kernel should shut down.
+    async fn handle(
+        &mut self,
+        request: &Message,
+        socket: &mut RouterSocket,
+        iopub: &mut PubSocket,
+    ) -> Result<bool, Error> {
+        self.publish(
+            iopub,
+            request,
+            "status",
+            json!({ "execution_state": "busy" }),
+        )
+        .await?;
+        let mut keep_running = true;
+        match request.msg_type() {
+            "kernel_info_request" => {
+                let content = json!({
+                    "status": "ok",
+                    "protocol_version": PROTOCOL_VERSION,
+                    "implementation": "plates",
+                    "implementation_version": env!("CARGO_PKG_VERSION"),
+                    "language_info": {
+                        "name": "plates",
+                        "version": env!("CARGO_PKG_VERSION"),
+                        "mimetype": "text/x-plates",
+                        "file_extension": ".plates",
+                    },
+                    "banner": "plates",
+                    "help_links": [],
+                });
+                self.reply(socket, request, "kernel_info_reply", content)
+                    .await?;
+            }
+            "execute_request" => self.execute(request, socket, iopub).await?,
+            "comm_info_request" => {
+                let content = json!({ "status": "ok", "comms": {} });
+                self.reply(socket, request, "comm_info_reply", content)
+                    .await?;
+            }
+            "shutdown_request" => {
+                let content = json!({ "status": "ok", "restart": request.content["restart"] });
+                self.reply(socket, request, "shutdown_reply", content)
+                    .await?;
+                keep_running = false;
+            }
+            msg_type => print_verbose(1, &format!("Ignored a message of type '{msg_type}'.")),
+        }
+        self.publish(
+            iopub,
+            request,
+            "status",
+            json!({ "execution_state": "idle" }),
+        )
+        .await?;
+        Ok(keep_running)
+    }
+
+    async fn execute(
+        &mut self,
+        request: &Message,
+        socket: &mut RouterSocket,
+        iopub: &mut PubSocket,
+    ) -> Result<(), Error> {
+        let code = request.content["code"].as_str().unwrap_or_default();
+        let silent = request.content["silent"].as_bool().unwrap_or(false);
+        if !silent {
+            self.session.execution_count += 1;
+        }
+        let execution_count = self.session.execution_count;
+        self.publish(
+            iopub,
+            request,
+            "execute_input",
+            json!({ "code": code, "execution_count": execution_count }),
+        )
+        .await?;
+
+        let output = self.session.execute(code);
+        for (name, text) in [("stdout", &output.stdout), ("stderr", &output.stderr)] {
+            if !text.is_empty() && !silent {
+                let content = json!({ "name": name, "text": text });
+                self.publish(iopub, request, "stream", content).await?;
+            }
+        }
+        let content = match output.error {
+            Some(e) => {
+                let error = json!({
+                    "ename": "Error",
+                    "evalue": e.to_string(),
+                    "traceback": e.chain().map(|cause| cause.to_string()).collect::<Vec<_>>(),
+                });
+                self.publish(iopub, request, "error", error.clone()).await?;
+                let mut content = json!({ "status": "error", "execution_count": execution_count });
+                content
+                    .as_object_mut()
+                    .unwrap()
+                    .extend(error.as_object().unwrap().clone());
+                content
+            }
+            None => {
+                if !silent {
+                    let result = json!({
+                        "execution_count": execution_count,
+                        "data": self.session.stack_data(),
+                        "metadata": {},
+                    });
+                    self.publish(iopub, request, "execute_result", result)
+                        .await?;
+                }
+                json!({
+                    "status": "ok",
+                    "execution_count": execution_count,
+                    "user_expressions": {},
+                    "payload": [],
+                })
+            }
+        };
+        self.reply(socket, request, "execute_reply", content).await
+    }
+
+    /// Creates a message in response to `parent`, addressed to `identities`.
+    fn message(
+        &self,
+        parent: &Message,
+        identities: Vec<Vec<u8>>,
+        msg_type: &str,
+        content: Value,
+    ) -> Message {
+        Message {
+            identities,
+            header: json!({
+                "msg_id": new_id(),
+                "session": self.session_id,
+                "username": "plates",
+                "msg_type": msg_type,
+                "version": PROTOCOL_VERSION,
+            }),
+            parent_header: parent.header.clone(),
+            metadata: json!({}),
+            content,
+        }
+    }
+
+    async fn reply(
+        &self,
+        socket: &mut RouterSocket,
+        request: &Message,
+        msg_type: &str,
+        content: Value,
+    ) -> Result<(), Error> {
+        let reply = self.message(request, request.identities.clone(), msg_type, content);
+        socket.send(to_zmq(reply.encode(&self.info.key))).await?;
+        Ok(())
+    }
+
+    /// Broadcasts a message on the IOPub socket, with its type as the topic.
+    async fn publish(
+        &self,
+        iopub: &mut PubSocket,
+        request: &Message,
+        msg_type: &str,
+        content: Value,
+    ) -> Result<(), Error> {
+        let message = self.message(
+            request,
+            vec![msg_type.as_bytes().to_vec()],
+            msg_type,
+            content,
+        );
+        iopub.send(to_zmq(message.encode(&self.info.key))).await?;
+        Ok(())
+    }
+}
+
+fn to_zmq(frames: Vec<Vec<u8>>) -> ZmqMessage {
+    let mut frames = frames.into_iter();
+    let mut message = ZmqMessage::from(frames.next().unwrap_or_default());
+    for frame in frames {
+        message.push_back(frame.into());
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> Message {
+        Message {
+            identities: vec![b"client".to_vec()],
+            header: json!({ "msg_id": "1", "msg_type": "execute_request" }),
+            parent_header: json!({}),
+            metadata: json!({}),
+            content: json!({ "code": "PUSH 1" }),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let frames = message().encode("secret");
+        assert_eq!(b"client", &frames[0][..]);
+        assert_eq!(DELIMITER, &frames[1][..]);
+        assert_eq!(64, frames[2].len());
+        assert_eq!(message(), Message::decode(frames, "secret").unwrap());
+    }
+
+    #[test]
+    fn invalid_signature() {
+        let frames = message().encode("secret");
+        assert_eq!(
+            "Invalid signature.",
+            Message::decode(frames, "other").unwrap_err().to_string()
+        );
+        // Without a key, messages are not signed at all
+        let frames = message().encode("");
+        assert!(frames[2].is_empty());
+        assert_eq!(message(), Message::decode(frames, "").unwrap());
+    }
+
+    #[test]
+    fn connection_file() {
+        let info = ConnectionInfo::parse(
+            r#"{"transport": "tcp", "ip": "127.0.0.1", "shell_port": 1, "iopub_port": 2, "stdin_port": 3,
+                "control_port": 4, "hb_port": 5, "key": "k", "signature_scheme": "hmac-sha256"}"#,
+        )
+        .unwrap();
+        assert_eq!("tcp://127.0.0.1:4", info.endpoint(info.control_port));
+        let e = ConnectionInfo::parse(r#"{"transport": "tcp"}"#).unwrap_err();
+        assert_eq!("Missing 'ip'.", e.to_string());
+    }
+
+    #[test]
+    fn cells_share_state() {
+        let mut session = Session::new();
+        let output =
+            session.execute("DEFN f (0) {\nPUSH 0 PUSH 104 PUSH 1 PUSH __print__ CALLIF PUSH 7\n}");
+        assert!(output.error.is_none());
+        let output = session.execute("PUSH 1 PUSH f CALLIF");
+        assert_eq!("h", output.stdout);
+        assert!(output.error.is_none());
+        assert_eq!(
+            "<table><tr><th>#</th><th>Word</th></tr><tr><td>0</td><td>7</td></tr></table>",
+            session.stack_data()["text/html"]
+        );
+
+        let output = session.execute("PUSH 1 PUSH nope CALLIF\nPUSH 8");
+        assert_eq!(
+            "Runtime error: Undefined argument or function.",
+            output.error.unwrap().to_string()
+        );
+        // The failed CALLIF puts its arguments back, and the rest of the cell is skipped
+        assert_eq!(
+            &[
+                Word::Data(7),
+                Word::Data(1),
+                Word::Function("nope".to_owned())
+            ],
+            session.runtime.stack()
+        );
+    }
+
+    #[test]
+    fn exit() {
+        let mut session = Session::new();
+        let output = session.execute("PUSH 1\nEXIT\nPUSH 2");
+        assert_eq!("The program exited.\n", output.stderr);
+        assert_eq!(&[Word::Data(1)], session.runtime.stack());
+
+        // The rest of the function that exited does not run in the next cell
+        let output = session.execute("DEFN f (0) { EXIT PUSH 5 }\nPUSH 1 PUSH f CALLIF");
+        assert_eq!("The program exited.\n", output.stderr);
+        session.execute("PUSH 9");
+        assert_eq!(&[Word::Data(1), Word::Data(9)], session.runtime.stack());
+    }
+}
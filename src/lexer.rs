@@ -1,8 +1,9 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, ops::Range};
 
 use anyhow::{anyhow, Context, Error};
+use tracing::trace;
 
-use crate::reader::LineStream;
+use crate::{reader::LineStream, runtime::WordSize};
 
 pub trait TokenStream {
     /// Yields the next token. `None` signals the end of the stream.
@@ -13,30 +14,64 @@ pub trait TokenStream {
 
     /// Returns true if there are no tokens left on the current line.
     fn full_line_consumed(&mut self) -> bool;
+
+    /// Returns the name of the file and the line number that the last token came from, if known.
+    fn location(&self) -> Option<(String, usize)> {
+        None
+    }
+
+    /// Returns the column (in characters, counting from one) that the last token started at, if known.
+    fn column(&self) -> Option<usize> {
+        None
+    }
 }
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum Token {
     Push,
     Defn,
+    DefnPriv,
     CallIf,
     Exit,
+    Module,
+    Import,
+    As,
+    Const,
+    Macro,
+    /// `STACK_ORDER` in a signature, which makes `$0` the deepest argument.
+    StackOrder,
+    /// The invocation of a macro, like `name!`.
+    MacroCall(String),
     Asterisk,
     LeftCurlyBracket,
     RightCurlyBracket,
     FunctionName(String),
-    Word(u32),
+    Word(u64),
+    /// A literal that does not fit in a word, in decimal. Only produced with the `bigint` feature.
+    BigWord(String),
     LeftParen,
     RightParen,
+    /// `->`, which separates the argument count from the result count in a signature.
+    Arrow,
     Argument(usize),
+    /// An argument referred to by the name it was given in the signature, as in `$a`.
+    NamedArgument(String),
+    /// `$it`, the word on top of the stack after the previous line in the REPL.
+    It,
+    /// A string literal, with the escape sequences already replaced. Only used for docstrings.
+    String(String),
 }
 
 pub struct Lexer<T>
 where
     T: LineStream,
 {
-    tokens: VecDeque<Token>,
+    /// The tokens left on the current line, with the columns they start at.
+    tokens: VecDeque<(Token, usize)>,
     reader: T,
+    word_size: WordSize,
+    /// The column of the last token returned.
+    column: Option<usize>,
 }
 
 impl<T> Lexer<T>
@@ -50,20 +85,39 @@ where
         Lexer {
             tokens: VecDeque::new(),
             reader,
+            word_size: WordSize::default(),
+            column: None,
         }
     }
 
+    /// Sets the size of the words that can be pushed. Larger literals are syntax errors.
+    pub fn with_word_size(self, word_size: WordSize) -> Self {
+        Lexer { word_size, ..self }
+    }
+
     /// Gets a new line, lexes it, and adds the tokens to self.tokens. If the
     /// reader has no more lines, returns false. Otherwise, returns true.
     fn refill_tokens(&mut self, depth: usize) -> Result<bool, Error> {
-        let line = match self.reader.next_line(depth) {
+        let line = match self.reader.next_line(depth)? {
             None => return Ok(false),
             Some(x) => x,
         };
 
-        let new_tokens = lex_line(&line)?;
-        for nt in new_tokens {
-            self.tokens.push_back(nt);
+        let mut new_tokens = lex_line_with_spans(&line)?;
+        for (token, _) in new_tokens.iter_mut() {
+            match token {
+                Token::Word(n) if *n > self.word_size.max() && cfg!(feature = "bigint") => {
+                    *token = Token::BigWord(n.to_string());
+                }
+                Token::Word(n) if *n > self.word_size.max() => {
+                    return Err(anyhow!("Syntax error: Invalid word '{n}'."));
+                }
+                _ => {}
+            }
+        }
+        for (nt, span) in new_tokens {
+            let column = line[..span.start].chars().count() + 1;
+            self.tokens.push_back((nt, column));
         }
 
         Ok(true)
@@ -82,9 +136,19 @@ where
         self.tokens.is_empty()
     }
 
+    fn location(&self) -> Option<(String, usize)> {
+        self.reader.location()
+    }
+
+    fn column(&self) -> Option<usize> {
+        self.column
+    }
+
     fn next_token(&mut self, depth: usize) -> Result<Option<Token>, Error> {
         loop {
-            if let Some(t) = self.tokens.pop_front() {
+            if let Some((t, column)) = self.tokens.pop_front() {
+                trace!(token = ?t, column, "token");
+                self.column = Some(column);
                 return Ok(Some(t));
             }
 
@@ -95,17 +159,19 @@ where
     }
 }
 
-fn lex_line(source: &str) -> Result<Vec<Token>, Error> {
+/// Lexes a single line, also returning the byte range that each token occupies in the line.
+pub fn lex_line_with_spans(source: &str) -> Result<Vec<(Token, Range<usize>)>, Error> {
     let mut tokens = Vec::new();
     let mut my_source = source;
 
     loop {
+        let start = source.len() - my_source.trim_start().len();
         match consume_token(my_source)? {
             (None, _) => {
                 return Ok(tokens);
             }
             (Some(token), updated_source) => {
-                tokens.push(token);
+                tokens.push((token, start..source.len() - updated_source.len()));
                 my_source = updated_source;
             }
         }
@@ -123,6 +189,7 @@ fn consume_token(source: &str) -> Result<(Option<Token>, &str), Error> {
             Some('(') => return Ok((Some(Token::LeftParen), &source[1..])),
             Some(')') => return Ok((Some(Token::RightParen), &source[1..])),
             Some('$') => return consume_argument(source),
+            Some('"') => return consume_string(source),
             Some(c) if c.is_whitespace() => {
                 source = consume_whitespace(source)?;
             }
@@ -131,6 +198,7 @@ fn consume_token(source: &str) -> Result<(Option<Token>, &str), Error> {
             // Immediately return None because the comment extends all the way until
             // the end of the line
             _ if source.starts_with("//") => return Ok((None, source)),
+            _ if source.starts_with("->") => return Ok((Some(Token::Arrow), &source[2..])),
             Some(c) if c.is_alphabetic() || c == '_' => return consume_symbol(source),
             Some(c) => return Err(anyhow!("Syntax error: Unexpected character '{c}'.")),
         }
@@ -151,12 +219,20 @@ fn consume_whitespace(source: &str) -> Result<&str, Error> {
 }
 
 fn consume_word(source: &str) -> Result<(Option<Token>, &str), Error> {
-    let (n, updated_source) = consume_base10_int(source)?;
-
-    Ok((Some(Token::Word(n)), updated_source))
+    match consume_base10_int(source) {
+        Ok((n, updated_source)) => Ok((Some(Token::Word(n)), updated_source)),
+        Err(_) if cfg!(feature = "bigint") => {
+            let end = source
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(source.len());
+            let digits = source[..end].trim_start_matches('0');
+            Ok((Some(Token::BigWord(digits.to_owned())), &source[end..]))
+        }
+        Err(e) => Err(e),
+    }
 }
 
-fn consume_base10_int(source: &str) -> Result<(u32, &str), Error> {
+fn consume_base10_int(source: &str) -> Result<(u64, &str), Error> {
     let mut i = 1;
     loop {
         match source.chars().nth(i) {
@@ -167,7 +243,7 @@ fn consume_base10_int(source: &str) -> Result<(u32, &str), Error> {
     }
 
     let n = source[..i]
-        .parse::<u32>()
+        .parse::<u64>()
         .with_context(|| format!("Syntax error: Invalid word '{}'.", &source[..i]))?;
 
     Ok((n, &source[i..]))
@@ -179,22 +255,48 @@ fn consume_symbol(source: &str) -> Result<(Option<Token>, &str), Error> {
     match symbol {
         "PUSH" => Ok((Some(Token::Push), updated_source)),
         "DEFN" => Ok((Some(Token::Defn), updated_source)),
+        "DEFN_PRIV" => Ok((Some(Token::DefnPriv), updated_source)),
         "CALLIF" => Ok((Some(Token::CallIf), updated_source)),
         "EXIT" => Ok((Some(Token::Exit), updated_source)),
-        _ => Ok((Some(Token::FunctionName(symbol.to_owned())), updated_source)),
+        "MODULE" => Ok((Some(Token::Module), updated_source)),
+        "IMPORT" => Ok((Some(Token::Import), updated_source)),
+        "AS" => Ok((Some(Token::As), updated_source)),
+        "CONST" => Ok((Some(Token::Const), updated_source)),
+        "MACRO" => Ok((Some(Token::Macro), updated_source)),
+        "STACK_ORDER" => Ok((Some(Token::StackOrder), updated_source)),
+        _ => match updated_source.strip_prefix('!') {
+            Some(rest) => Ok((Some(Token::MacroCall(symbol.to_owned())), rest)),
+            None => Ok((Some(Token::FunctionName(symbol.to_owned())), updated_source)),
+        },
     }
 }
 
+/// Splits off a symbol, which may be qualified with a module (as in `math::square`).
 fn get_symbol(source: &str) -> (&str, &str) {
-    for (i, c) in source.chars().enumerate() {
-        if !c.is_alphanumeric() && c != '_' {
+    let mut chars = source.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c.is_alphanumeric() || c == '_' {
+            continue;
+        }
+        let qualified = c == ':'
+            && source[i + 1..].starts_with(':')
+            && source[i + 2..].starts_with(|c: char| c.is_alphabetic() || c == '_');
+        if !qualified {
             return (&source[..i], &source[i..]);
         }
+        chars.next();
     }
     (source, "")
 }
 
 fn consume_argument(source: &str) -> Result<(Option<Token>, &str), Error> {
+    if let ("it", updated_source) = get_symbol(&source[1..]) {
+        return Ok((Some(Token::It), updated_source));
+    }
+    if source[1..].starts_with(|c: char| c.is_alphabetic() || c == '_') {
+        let (name, updated_source) = get_symbol(&source[1..]);
+        return Ok((Some(Token::NamedArgument(name.to_owned())), updated_source));
+    }
     let (n, updated_source) = consume_base10_int(&source[1..])?;
 
     let n = usize::try_from(n)?;
@@ -202,6 +304,29 @@ fn consume_argument(source: &str) -> Result<(Option<Token>, &str), Error> {
     Ok((Some(Token::Argument(n)), updated_source))
 }
 
+/// Consumes a string literal, which must end on the same line. The escape sequences are `\"`, `\\`, and `\n`.
+fn consume_string(source: &str) -> Result<(Option<Token>, &str), Error> {
+    let mut string = String::new();
+    let mut chars = source.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((Some(Token::String(string)), &source[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, '"')) => string.push('"'),
+                Some((_, '\\')) => string.push('\\'),
+                Some((_, 'n')) => string.push('\n'),
+                Some((_, c)) => {
+                    return Err(anyhow!("Syntax error: Invalid escape sequence '\\{c}'."))
+                }
+                None => break,
+            },
+            '\n' | '\r' => break,
+            c => string.push(c),
+        }
+    }
+    Err(anyhow!("Syntax error: Unterminated string."))
+}
+
 // This implementation is for testing purposes, so that the parser can be tested on a known stream of tokens.
 impl<T> TokenStream for T
 where
@@ -220,7 +345,7 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::{Lexer, Token, TokenStream};
+    use super::{lex_line_with_spans, Lexer, Token, TokenStream, WordSize};
     use paste::paste;
 
     macro_rules! assert_ok_and_eq {
@@ -247,6 +372,8 @@ mod tests {
         ( $($name:ident: ($inputs:expr, $outputs:expr)),* $(,)? ) => {
             $(
                 #[test]
+                // The cases are written with `vec!` for both the inputs and the outputs
+                #[allow(clippy::useless_vec)]
                 fn $name() {
                     let mut lexer = Lexer::new($inputs.into_iter().map(|x| x.to_owned()));
                     for expected in $outputs {
@@ -368,15 +495,26 @@ mod tests {
     test_lex_success![
         push: ("PUSH", Token::Push),
         defn: ("DEFN", Token::Defn),
+        defn_priv: ("DEFN_PRIV", Token::DefnPriv),
         callif: ("CALLIF", Token::CallIf),
         exit: ("EXIT", Token::Exit),
+        module: ("MODULE", Token::Module),
+        import: ("IMPORT", Token::Import),
+        const_keyword: ("CONST", Token::Const),
+        macro_keyword: ("MACRO", Token::Macro),
+        stack_order: ("STACK_ORDER", Token::StackOrder),
+        macro_call: ("print_all!", Token::MacroCall("print_all".to_owned())),
+        as_keyword: ("AS", Token::As),
         asterisk: ("*", Token::Asterisk),
         left_curly_bracket: ("{", Token::LeftCurlyBracket),
         right_curly_bracket: ("}", Token::RightCurlyBracket),
         left_paren: ("(", Token::LeftParen),
         right_paren: (")", Token::RightParen),
+        arrow: ("->", Token::Arrow),
         argument0: ("$0", Token::Argument(0)),
         argument10: ("$10", Token::Argument(10)),
+        named_argument: ("$left_1", Token::NamedArgument("left_1".to_owned())),
+        it: ("$it", Token::It),
         word_min: ("0", Token::Word(0)),
         // 2^32 - 1
         word_max: ("4294967295", Token::Word(4294967295)),
@@ -385,6 +523,10 @@ mod tests {
                 "my_funcName",
                 Token::FunctionName("my_funcName".to_owned())
             ),
+        string: (r#""a \"b\" \\ {c} // d""#, Token::String(r#"a "b" \ {c} // d"#.to_owned())),
+        empty_string: (r#""""#, Token::String(String::new())),
+        qualified_function_name: ("math::square", Token::FunctionName("math::square".to_owned())),
+        nested_function_name: ("a::b_2::c", Token::FunctionName("a::b_2::c".to_owned())),
     ];
 
     generate_success_test_case![
@@ -410,12 +552,19 @@ mod tests {
             ),
     ];
 
+    // With the bigint feature, these are big words instead
+    #[cfg(not(feature = "bigint"))]
     test_lex_failure![
         fail_on_massive_word: (vec!["9".repeat(1000)], format!("Syntax error: Invalid word '{}'.", "9".repeat(1000))),
         // 2^32
         fail_on_too_large_word: (vec!["4294967296"], "Syntax error: Invalid word '4294967296'."),
+    ];
+
+    test_lex_failure![
         fail_on_negative_word: (vec!["-1"], "Syntax error: Unexpected character '-'."),
         fail_on_hashtag: (vec!["#"], "Syntax error: Unexpected character '#'."),
+        fail_on_unterminated_string: (vec![r#""abc"#], "Syntax error: Unterminated string."),
+        fail_on_invalid_escape: (vec![r#""a\tb""#], "Syntax error: Invalid escape sequence '\\t'."),
     ];
 
     #[test]
@@ -433,6 +582,59 @@ mod tests {
         assert_ok_and_eq!(lexer.next_token(0), None);
     }
 
+    #[test]
+    fn columns() {
+        let lines = vec!["PUSH é".to_owned(), "  CALLIF".to_owned()];
+        let mut lexer = Lexer::new(lines.into_iter());
+        assert_eq!(None, lexer.column());
+        for (token, column) in [
+            (Token::Push, 1),
+            (Token::FunctionName("é".to_owned()), 6),
+            (Token::CallIf, 3),
+        ] {
+            assert_ok_and_eq!(lexer.next_token(0), Some(token));
+            assert_eq!(Some(column), lexer.column());
+        }
+    }
+
+    #[test]
+    fn word_size() {
+        let lines = vec!["PUSH 18446744073709551615".to_owned()];
+        let mut lexer = Lexer::new(lines.into_iter()).with_word_size(WordSize::Bits64);
+        assert_ok_and_eq!(lexer.next_token(0), Some(Token::Push));
+        assert_ok_and_eq!(lexer.next_token(0), Some(Token::Word(u64::MAX)));
+    }
+
+    #[cfg(feature = "bigint")]
+    #[test]
+    fn big_words() {
+        let lines = vec!["PUSH 4294967296 PUSH 000123456789012345678901234567890".to_owned()];
+        let mut lexer = Lexer::new(lines.into_iter());
+        assert_ok_and_eq!(lexer.next_token(0), Some(Token::Push));
+        assert_ok_and_eq!(
+            lexer.next_token(0),
+            Some(Token::BigWord("4294967296".to_owned()))
+        );
+        assert_ok_and_eq!(lexer.next_token(0), Some(Token::Push));
+        assert_ok_and_eq!(
+            lexer.next_token(0),
+            Some(Token::BigWord("123456789012345678901234567890".to_owned()))
+        );
+    }
+
+    #[test]
+    fn spans() {
+        assert_ok_and_eq!(
+            lex_line_with_spans(" \tPUSH $12 DEFN  __print__ // comment"),
+            vec![
+                (Token::Push, 2..6),
+                (Token::Argument(12), 7..10),
+                (Token::Defn, 11..15),
+                (Token::FunctionName("__print__".to_owned()), 17..26),
+            ]
+        );
+    }
+
     #[test]
     fn clear() {
         let lines = vec!["PUSH 123 PUSH 456".to_owned(), "PUSH 789".to_owned()];
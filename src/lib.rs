@@ -0,0 +1,176 @@
+//! plates is a stack-based esoteric programming language. This crate contains the interpreter and the tools that are
+//! built on top of it; the `plates` binary is a thin command-line interface over them.
+
+use std::{
+    cell::RefCell,
+    io::Write,
+    rc::Rc,
+    sync::atomic::{AtomicI8, Ordering},
+};
+
+use anyhow::Error;
+use colored::Colorize;
+
+use crate::{
+    lexer::Lexer,
+    parser::Parser,
+    runtime::{Runtime, Word},
+};
+
+pub mod bytecode;
+pub mod codegen;
+pub mod dap;
+pub mod examples;
+pub mod graph;
+pub mod highlight;
+pub mod json;
+#[cfg(feature = "jupyter")]
+pub mod kernel;
+pub mod lexer;
+pub mod lint;
+pub mod lsp;
+pub mod optimizer;
+pub mod parser;
+pub mod plugin;
+pub mod predicate;
+pub mod printer;
+pub mod process;
+pub mod prompt;
+pub mod reader;
+pub mod reload;
+pub mod rename;
+pub mod replay;
+pub mod runtime;
+pub mod server;
+mod stack_analysis;
+pub mod stats;
+pub mod terminal;
+pub mod test_runner;
+pub mod tutorial;
+pub mod viz;
+pub mod xref;
+
+/// The result of a program run with [`eval`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Output {
+    /// Everything the program printed.
+    pub stdout: String,
+    /// The words left on the stack, from the bottom to the top.
+    pub stack: Vec<Word>,
+    /// True iff the program stopped because of an `EXIT`.
+    pub exited: bool,
+}
+
+/// Runs a program with `input` as stdin and captures what it prints. Syntax and runtime errors are returned as errors.
+///
+/// ```
+/// let output = plates::eval("PUSH 0 PUSH 104 PUSH 105 PUSH 1 PUSH __print__ CALLIF PUSH 7", "").unwrap();
+/// assert_eq!("ih", output.stdout);
+/// assert_eq!(vec![plates::runtime::Word::Data(7)], output.stack);
+/// ```
+pub fn eval(source: &str, input: &str) -> Result<Output, Error> {
+    let stdout = SharedBuffer::default();
+    let mut runtime =
+        Runtime::new().with_io(stdout.clone(), std::io::Cursor::new(input.to_owned()));
+    let lines = source.lines().map(str::to_owned).collect::<Vec<_>>();
+    let mut parser = Parser::new(Lexer::new(lines.into_iter()));
+
+    let mut exited = false;
+    while let Some(instruction) = parser.next_instruction()? {
+        if runtime.run(instruction)? {
+            exited = true;
+            break;
+        }
+    }
+
+    let stdout = String::from_utf8_lossy(&stdout.0.borrow()).into_owned();
+    Ok(Output {
+        stdout,
+        stack: runtime.stack().to_vec(),
+        exited,
+    })
+}
+
+/// A buffer that can be written to through any of its clones.
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuffer(pub(crate) Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How much the `print_*` functions print. See [`set_verbosity`].
+static VERBOSITY: AtomicI8 = AtomicI8::new(0);
+
+/// Sets how much the `print_*` functions print. At -1, only errors, warnings, and debug info are printed. At 0 (the
+/// default), informational messages are printed as well, and higher levels enable the messages of `print_verbose`.
+pub fn set_verbosity(level: i8) {
+    VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+/// Prints an error (and its causes) to stderr.
+pub fn print_error(e: &Error) {
+    let mut msg = format!("{e}");
+    for cause in e.chain().skip(1) {
+        msg += &format!("\n\nCaused by:\n    {cause}");
+    }
+
+    eprintln!("{}", msg.bold().red());
+}
+
+pub fn print_warning(msg: &str) {
+    eprintln!("{}", msg.bold().yellow());
+}
+
+pub fn print_info(msg: &str) {
+    if VERBOSITY.load(Ordering::Relaxed) >= 0 {
+        println!("{}", msg.bold());
+    }
+}
+
+/// Prints a message to stderr if the verbosity is at least `level`.
+pub fn print_verbose(level: i8, msg: &str) {
+    if VERBOSITY.load(Ordering::Relaxed) >= level {
+        eprintln!("{}", msg.truecolor(128, 128, 128));
+    }
+}
+
+pub fn print_debug(msg: &str) {
+    println!("{}", msg.italic().truecolor(128, 128, 128));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eval_input_and_exit() {
+        let output = eval(
+            "PUSH 0 PUSH 1 PUSH __input__ CALLIF PUSH 1 PUSH __strlen__ CALLIF EXIT PUSH 5",
+            "abc\n",
+        )
+        .unwrap();
+        assert_eq!(
+            Output {
+                stdout: String::new(),
+                stack: [0, 10, 99, 98, 97, 4].map(Word::Data).to_vec(),
+                exited: true,
+            },
+            output
+        );
+    }
+
+    #[test]
+    fn eval_error() {
+        assert_eq!(
+            "Runtime error: Stack underflow. CALLIF needs 2 word(s), but the stack only has 1.",
+            format!("{}", eval("PUSH 1 CALLIF", "").unwrap_err())
+        );
+    }
+}
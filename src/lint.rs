@@ -0,0 +1,587 @@
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use anyhow::{anyhow, Error};
+
+use crate::parser::{ArgsOrder, Attributes, Instruction};
+
+/// Built-in functions that should no longer be used, along with what to use instead. None are deprecated yet.
+const DEPRECATED_BUILTINS: &[(&str, &str)] = &[];
+
+/// A kind of warning. Each one has a code (e.g., `W001`) and a name (e.g., `unused-function`), either of which can be
+/// used to change its level.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Lint {
+    /// A function that is defined but never pushed.
+    UnusedFunction,
+    /// A function that is defined more than once.
+    ShadowedDefinition,
+    /// A built-in function that should no longer be used.
+    DeprecatedBuiltin,
+    /// An argument of a function that is never pushed in its body.
+    UnusedArgument,
+    /// Instructions that come after an EXIT in the body of a function, or files that come after a top-level EXIT.
+    UnreachableCode,
+    /// A call that needs more words than the stack would have.
+    StackUnderflow,
+    /// A function that is still on the stack when the program ends, probably because a CALLIF is missing.
+    UncalledFunction,
+    /// A function whose body does not match its stack-effect annotation, as in `DEFN f (2 -> 1)`.
+    StackEffect,
+    /// A CALLIF whose condition is the literal 0, so the function is never called.
+    DeadCall,
+    /// A CALLIF whose condition is a nonzero literal, so the function is always called. This is allowed by default,
+    /// since `PUSH 1 PUSH f CALLIF` is the usual way to call a function.
+    ConstantCondition,
+}
+
+pub const LINTS: &[Lint] = &[
+    Lint::UnusedFunction,
+    Lint::ShadowedDefinition,
+    Lint::DeprecatedBuiltin,
+    Lint::UnusedArgument,
+    Lint::UnreachableCode,
+    Lint::StackUnderflow,
+    Lint::UncalledFunction,
+    Lint::StackEffect,
+    Lint::DeadCall,
+    Lint::ConstantCondition,
+];
+
+impl Lint {
+    pub fn code(self) -> &'static str {
+        match self {
+            Lint::UnusedFunction => "W001",
+            Lint::ShadowedDefinition => "W002",
+            Lint::DeprecatedBuiltin => "W003",
+            Lint::UnusedArgument => "W004",
+            Lint::UnreachableCode => "W005",
+            Lint::StackUnderflow => "W006",
+            Lint::UncalledFunction => "W007",
+            Lint::StackEffect => "W008",
+            Lint::DeadCall => "W009",
+            Lint::ConstantCondition => "W010",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::UnusedFunction => "unused-function",
+            Lint::ShadowedDefinition => "shadowed-definition",
+            Lint::DeprecatedBuiltin => "deprecated-builtin",
+            Lint::UnusedArgument => "unused-argument",
+            Lint::UnreachableCode => "unreachable-code",
+            Lint::StackUnderflow => "stack-underflow",
+            Lint::UncalledFunction => "uncalled-function",
+            Lint::StackEffect => "stack-effect",
+            Lint::DeadCall => "dead-call",
+            Lint::ConstantCondition => "constant-condition",
+        }
+    }
+
+    pub fn default_level(self) -> Level {
+        match self {
+            Lint::ConstantCondition => Level::Allow,
+            _ => Level::Warn,
+        }
+    }
+}
+
+impl FromStr for Lint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LINTS
+            .iter()
+            .find(|lint| lint.code().eq_ignore_ascii_case(s) || lint.name() == s)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown lint '{s}'."))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Level {
+    /// The warning is not reported.
+    Allow,
+    /// The warning is reported.
+    Warn,
+    /// The warning is reported as an error.
+    Deny,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Warning {
+    pub lint: Lint,
+    pub level: Level,
+    pub message: String,
+}
+
+impl Display for Warning {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.level {
+            Level::Deny => "error",
+            _ => "warning",
+        };
+        write!(
+            formatter,
+            "{kind}[{}]: {} ({})",
+            self.lint.code(),
+            self.message,
+            self.lint.name()
+        )
+    }
+}
+
+/// Collects warnings. Every lint is at its [default level](Lint::default_level) unless its level is changed.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    levels: HashMap<Lint, Level>,
+    warnings: Vec<Warning>,
+}
+
+impl Diagnostics {
+    pub fn set_level(&mut self, lint: Lint, level: Level) {
+        self.levels.insert(lint, level);
+    }
+
+    pub fn level(&self, lint: Lint) -> Level {
+        self.levels
+            .get(&lint)
+            .copied()
+            .unwrap_or_else(|| lint.default_level())
+    }
+
+    /// Records a warning, unless the lint is allowed.
+    pub fn warn(&mut self, lint: Lint, message: String) {
+        let level = self.level(lint);
+        if level != Level::Allow {
+            self.warnings.push(Warning {
+                lint,
+                level,
+                message,
+            });
+        }
+    }
+
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Returns true iff any of the warnings is denied.
+    pub fn has_errors(&self) -> bool {
+        self.warnings.iter().any(|w| w.level == Level::Deny)
+    }
+}
+
+/// Returns the warning for a function that is still on the stack when the program ends. The runtime reports this too
+/// (with `--warn-uncalled`), since [`check`] can only tell what the stack will be for simple programs.
+pub fn uncalled_function(f: &str) -> Warning {
+    Warning {
+        lint: Lint::UncalledFunction,
+        level: Level::Warn,
+        message: format!(
+            "Function '{f}' is still on the stack when the program ends. Did you forget to call it?"
+        ),
+    }
+}
+
+/// Looks for likely mistakes in a program that parsed successfully. Some warnings need to know where things are in
+/// the source code, so the parser finds them instead (see [`crate::parser::Parser::take_warnings`]).
+pub fn check(program: &[Instruction], diagnostics: &mut Diagnostics) {
+    let mut definitions = Vec::<&str>::new();
+    for instruction in program {
+        if let Instruction::Define(f, arg_count, body, attributes) = instruction {
+            if definitions.contains(&f.as_str()) {
+                diagnostics.warn(
+                    Lint::ShadowedDefinition,
+                    format!("Function '{f}' is defined more than once."),
+                );
+            } else {
+                definitions.push(f);
+            }
+            check_arguments(f, *arg_count, body, attributes, diagnostics);
+        }
+    }
+
+    for f in definitions {
+        if !crate::test_runner::is_test(f) && !is_pushed_outside(f, program) {
+            diagnostics.warn(
+                Lint::UnusedFunction,
+                format!("Function '{f}' is never used."),
+            );
+        }
+    }
+
+    for f in pushed_functions(program) {
+        if let Some((_, replacement)) = DEPRECATED_BUILTINS.iter().find(|(b, _)| *b == f) {
+            diagnostics.warn(
+                Lint::DeprecatedBuiltin,
+                format!("The built-in function '{f}' is deprecated. Use '{replacement}' instead."),
+            );
+        }
+    }
+
+    check_conditions(program, "the top level", None, diagnostics);
+
+    crate::stack_analysis::check(program, diagnostics);
+}
+
+/// Warns about calls with literal conditions, such as `PUSH 0 PUSH f CALLIF`, in `block` and in the bodies of the
+/// functions it defines. `args` has the argument count and attributes of the function that `block` is the body of.
+fn check_conditions(
+    block: &[Instruction],
+    location: &str,
+    args: Option<(u32, &Attributes)>,
+    diagnostics: &mut Diagnostics,
+) {
+    for window in block.windows(3) {
+        let [Instruction::PushData(n), callee, Instruction::CallIf] = window else {
+            continue;
+        };
+        // The callee as it is written in the source, and as it is described in the message
+        let (source, callee) = match (callee, args) {
+            (Instruction::PushFunction(f), _) => (f.clone(), format!("'{f}'")),
+            (Instruction::PushArg(popped), Some((arg_count, attributes))) => {
+                let arg = format!("${}", arg_name(*popped, arg_count, attributes));
+                (arg.clone(), arg)
+            }
+            _ => continue,
+        };
+        if *n == 0 {
+            diagnostics.warn(
+                Lint::DeadCall,
+                format!("The call to {callee} in {location} can never happen, because its condition is always 0. Remove `PUSH 0 PUSH {source} CALLIF`."),
+            );
+        } else {
+            // There is no unconditional call, so the usual way to write one is the best that can be suggested
+            let suggestion = match n {
+                1 => String::new(),
+                _ => format!(" Write `PUSH 1 PUSH {source} CALLIF`, which is the usual way to call a function unconditionally."),
+            };
+            diagnostics.warn(
+                Lint::ConstantCondition,
+                format!("The call to {callee} in {location} always happens, because its condition is always {n}.{suggestion}"),
+            );
+        }
+    }
+    for instruction in block {
+        if let Instruction::Define(f, arg_count, body, attributes) = instruction {
+            check_conditions(
+                body,
+                &format!("function '{f}'"),
+                Some((*arg_count, attributes)),
+                diagnostics,
+            );
+        }
+    }
+}
+
+/// Returns how the argument that `PushArg(popped)` pushes is written in the source (without the `$`). The body always
+/// numbers the arguments in pop order, but the source may use stack order or names.
+fn arg_name(popped: usize, arg_count: u32, attributes: &Attributes) -> String {
+    let names = &attributes.arg_names;
+    // The last name is the one on top of the stack
+    if let Some(name) = names.len().checked_sub(popped + 1).map(|i| &names[i]) {
+        return name.clone();
+    }
+    match attributes.args_order {
+        ArgsOrder::Pop => popped.to_string(),
+        ArgsOrder::Stack => (arg_count as usize - 1 - popped).to_string(),
+    }
+}
+
+fn check_arguments(
+    f: &str,
+    arg_count: u32,
+    body: &[Instruction],
+    attributes: &Attributes,
+    diagnostics: &mut Diagnostics,
+) {
+    // Report the arguments in the order they are written in the source
+    for i in 0..arg_count as usize {
+        let popped = match attributes.args_order {
+            ArgsOrder::Pop => i,
+            ArgsOrder::Stack => arg_count as usize - 1 - i,
+        };
+        if body.contains(&Instruction::PushArg(popped)) {
+            continue;
+        }
+        let name = arg_name(popped, arg_count, attributes);
+        diagnostics.warn(
+            Lint::UnusedArgument,
+            format!("Argument ${name} of function '{f}' is never used."),
+        );
+    }
+}
+
+/// Returns true iff `f` is pushed somewhere other than in its own body.
+fn is_pushed_outside(f: &str, program: &[Instruction]) -> bool {
+    program.iter().any(|instruction| match instruction {
+        Instruction::PushFunction(g) => g == f,
+        Instruction::Define(g, ..) if g == f => false,
+        Instruction::Define(_, _, body, _) => is_pushed_outside(f, body),
+        _ => false,
+    })
+}
+
+/// Returns every function that is pushed, in order of appearance and without duplicates.
+fn pushed_functions(program: &[Instruction]) -> Vec<&str> {
+    let mut functions = Vec::new();
+    for instruction in program {
+        let pushed = match instruction {
+            Instruction::PushFunction(f) => vec![f.as_str()],
+            Instruction::Define(_, _, body, _) => pushed_functions(body),
+            _ => vec![],
+        };
+        for f in pushed {
+            if !functions.contains(&f) {
+                functions.push(f);
+            }
+        }
+    }
+    functions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(f: &str) -> Vec<Instruction> {
+        vec![
+            Instruction::PushData(1),
+            Instruction::PushFunction(f.to_owned()),
+            Instruction::CallIf,
+        ]
+    }
+
+    fn messages(diagnostics: &Diagnostics) -> Vec<String> {
+        diagnostics
+            .warnings()
+            .iter()
+            .map(|w| w.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn all_lints() {
+        let program = [
+            vec![
+                Instruction::Define("used".to_owned(), 0, vec![], Attributes::default()),
+                Instruction::Define(
+                    "recursive".to_owned(),
+                    0,
+                    call("recursive"),
+                    Attributes::default(),
+                ),
+                Instruction::Define(
+                    "test_something".to_owned(),
+                    0,
+                    call("used"),
+                    Attributes::default(),
+                ),
+                Instruction::Define(
+                    "args".to_owned(),
+                    3,
+                    [vec![Instruction::PushArg(1)], call("args")].concat(),
+                    Attributes::default(),
+                ),
+                Instruction::Define("used".to_owned(), 0, vec![], Attributes::default()),
+            ],
+            call("used"),
+        ]
+        .concat();
+
+        let mut diagnostics = Diagnostics::default();
+        check(&program, &mut diagnostics);
+        assert_eq!(
+            vec![
+                "warning[W004]: Argument $0 of function 'args' is never used. (unused-argument)",
+                "warning[W004]: Argument $2 of function 'args' is never used. (unused-argument)",
+                "warning[W002]: Function 'used' is defined more than once. (shadowed-definition)",
+                "warning[W001]: Function 'recursive' is never used. (unused-function)",
+                "warning[W001]: Function 'args' is never used. (unused-function)",
+            ],
+            messages(&diagnostics)
+        );
+        assert!(!diagnostics.has_errors());
+    }
+
+    #[test]
+    fn stack_order_arguments() {
+        // DEFN f (2 STACK_ORDER) { PUSH $0 }, where $0 is the deepest argument
+        let program = vec![Instruction::Define(
+            "f".to_owned(),
+            2,
+            vec![Instruction::PushArg(1)],
+            Attributes {
+                args_order: ArgsOrder::Stack,
+                ..Attributes::default()
+            },
+        )];
+
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.set_level(Lint::UnusedFunction, Level::Allow);
+        check(&program, &mut diagnostics);
+        assert_eq!(
+            vec!["warning[W004]: Argument $1 of function 'f' is never used. (unused-argument)"],
+            messages(&diagnostics)
+        );
+    }
+
+    #[test]
+    fn named_arguments() {
+        // DEFN f (a b) { PUSH $b }
+        let program = vec![Instruction::Define(
+            "f".to_owned(),
+            2,
+            vec![Instruction::PushArg(0)],
+            Attributes {
+                arg_names: vec!["a".to_owned(), "b".to_owned()],
+                ..Attributes::default()
+            },
+        )];
+
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.set_level(Lint::UnusedFunction, Level::Allow);
+        check(&program, &mut diagnostics);
+        assert_eq!(
+            vec!["warning[W004]: Argument $a of function 'f' is never used. (unused-argument)"],
+            messages(&diagnostics)
+        );
+    }
+
+    #[test]
+    fn dead_calls() {
+        let program = vec![
+            Instruction::PushData(0),
+            Instruction::PushFunction("f".to_owned()),
+            Instruction::CallIf,
+            Instruction::Define(
+                "f".to_owned(),
+                1,
+                vec![
+                    Instruction::PushData(0),
+                    Instruction::PushArg(0),
+                    Instruction::CallIf,
+                    // Only literal conditions are reported
+                    Instruction::PushRandom,
+                    Instruction::PushArg(0),
+                    Instruction::CallIf,
+                ],
+                Attributes::default(),
+            ),
+        ];
+
+        let mut diagnostics = Diagnostics::default();
+        check(&program, &mut diagnostics);
+        assert_eq!(
+            vec![
+                "warning[W009]: The call to 'f' in the top level can never happen, because its condition is always 0. Remove `PUSH 0 PUSH f CALLIF`. (dead-call)",
+                "warning[W009]: The call to $0 in function 'f' can never happen, because its condition is always 0. Remove `PUSH 0 PUSH $0 CALLIF`. (dead-call)",
+            ],
+            messages(&diagnostics)
+        );
+    }
+
+    #[test]
+    fn dead_calls_of_arguments_as_written() {
+        let body = vec![
+            Instruction::PushData(0),
+            Instruction::PushArg(0),
+            Instruction::CallIf,
+        ];
+        let program = vec![
+            Instruction::Define(
+                "f".to_owned(),
+                2,
+                body.clone(),
+                Attributes {
+                    args_order: ArgsOrder::Stack,
+                    ..Attributes::default()
+                },
+            ),
+            Instruction::Define(
+                "g".to_owned(),
+                2,
+                body,
+                Attributes {
+                    arg_names: vec!["a".to_owned(), "b".to_owned()],
+                    ..Attributes::default()
+                },
+            ),
+        ];
+
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.set_level(Lint::UnusedFunction, Level::Allow);
+        diagnostics.set_level(Lint::UnusedArgument, Level::Allow);
+        check(&program, &mut diagnostics);
+        assert_eq!(
+            vec![
+                "warning[W009]: The call to $1 in function 'f' can never happen, because its condition is always 0. Remove `PUSH 0 PUSH $1 CALLIF`. (dead-call)",
+                "warning[W009]: The call to $b in function 'g' can never happen, because its condition is always 0. Remove `PUSH 0 PUSH $b CALLIF`. (dead-call)",
+            ],
+            messages(&diagnostics)
+        );
+    }
+
+    #[test]
+    fn constant_conditions() {
+        let program = vec![
+            Instruction::PushData(1),
+            Instruction::PushFunction("f".to_owned()),
+            Instruction::CallIf,
+            Instruction::PushData(7),
+            Instruction::PushFunction("f".to_owned()),
+            Instruction::CallIf,
+        ];
+
+        // Allowed by default
+        let mut diagnostics = Diagnostics::default();
+        check(&program, &mut diagnostics);
+        assert!(diagnostics.warnings().is_empty());
+
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.set_level(Lint::ConstantCondition, Level::Warn);
+        check(&program, &mut diagnostics);
+        assert_eq!(
+            vec![
+                "warning[W010]: The call to 'f' in the top level always happens, because its condition is always 1. (constant-condition)",
+                "warning[W010]: The call to 'f' in the top level always happens, because its condition is always 7. Write `PUSH 1 PUSH f CALLIF`, which is the usual way to call a function unconditionally. (constant-condition)",
+            ],
+            messages(&diagnostics)
+        );
+    }
+
+    #[test]
+    fn levels() {
+        let program = vec![Instruction::Define(
+            "f".to_owned(),
+            1,
+            vec![],
+            Attributes::default(),
+        )];
+
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.set_level(Lint::UnusedArgument, Level::Allow);
+        diagnostics.set_level(Lint::UnusedFunction, Level::Deny);
+        check(&program, &mut diagnostics);
+        assert_eq!(
+            vec!["error[W001]: Function 'f' is never used. (unused-function)"],
+            messages(&diagnostics)
+        );
+        assert!(diagnostics.has_errors());
+    }
+
+    #[test]
+    fn parse_lint() {
+        assert_eq!(Lint::UnusedFunction, "W001".parse::<Lint>().unwrap());
+        assert_eq!(Lint::UnusedFunction, "w001".parse::<Lint>().unwrap());
+        assert_eq!(
+            Lint::ShadowedDefinition,
+            "shadowed-definition".parse::<Lint>().unwrap()
+        );
+        assert_eq!(
+            "Unknown lint 'W999'.",
+            format!("{}", "W999".parse::<Lint>().unwrap_err())
+        );
+    }
+}
@@ -0,0 +1,518 @@
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use anyhow::{anyhow, Error};
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+        Notification as NotificationTrait, PublishDiagnostics,
+    },
+    request::{Completion, DocumentSymbolRequest, GotoDefinition, Request as RequestTrait},
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    Diagnostic, DiagnosticSeverity, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, DocumentSymbol, DocumentSymbolParams, DocumentSymbolResponse,
+    GotoDefinitionParams, GotoDefinitionResponse, Location, OneOf, Position,
+    PublishDiagnosticsParams, Range, ServerCapabilities, SymbolKind, TextDocumentSyncCapability,
+    TextDocumentSyncKind, Url,
+};
+
+use crate::{
+    lexer::{lex_line_with_spans, Lexer, Token},
+    parser::Parser,
+    runtime::BUILTIN_FUNCTIONS,
+};
+
+const KEYWORDS: &[&str] = &[
+    "PUSH",
+    "DEFN",
+    "DEFN_PRIV",
+    "CALLIF",
+    "EXIT",
+    "MODULE",
+    "IMPORT",
+    "AS",
+    "CONST",
+    "MACRO",
+    "STACK_ORDER",
+];
+
+/// Runs a language server over stdin and stdout until the client asks it to shut down.
+pub fn run_server() -> Result<(), Error> {
+    let (connection, io_threads) = Connection::stdio();
+
+    let capabilities = serde_json::to_value(ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        ..ServerCapabilities::default()
+    })?;
+    connection.initialize(capabilities)?;
+
+    serve(&connection)?;
+
+    // Drop the connection so that the writer thread can finish
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+fn serve(connection: &Connection) -> Result<(), Error> {
+    let mut documents = HashMap::new();
+
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    return Ok(());
+                }
+                let response = handle_request(&documents, request);
+                connection.sender.send(Message::Response(response))?;
+            }
+            Message::Notification(notification) => {
+                if let Some(uri) = handle_notification(&mut documents, notification)? {
+                    let text = documents.get(&uri).map(String::as_str).unwrap_or("");
+                    let params = PublishDiagnosticsParams {
+                        diagnostics: diagnostics(text),
+                        uri,
+                        version: None,
+                    };
+                    let notification =
+                        Notification::new(PublishDiagnostics::METHOD.to_owned(), params);
+                    connection
+                        .sender
+                        .send(Message::Notification(notification))?;
+                }
+            }
+            Message::Response(_) => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Updates the open documents. Returns the URI of the document whose diagnostics should be republished, if any.
+fn handle_notification(
+    documents: &mut HashMap<Url, String>,
+    notification: Notification,
+) -> Result<Option<Url>, Error> {
+    match notification.method.as_str() {
+        DidOpenTextDocument::METHOD => {
+            let params: DidOpenTextDocumentParams = serde_json::from_value(notification.params)?;
+            let uri = params.text_document.uri;
+            documents.insert(uri.clone(), params.text_document.text);
+            Ok(Some(uri))
+        }
+        DidChangeTextDocument::METHOD => {
+            let params: DidChangeTextDocumentParams = serde_json::from_value(notification.params)?;
+            let uri = params.text_document.uri;
+            // Only full synchronization is supported, so the last change contains the whole document
+            if let Some(change) = params.content_changes.into_iter().last() {
+                documents.insert(uri.clone(), change.text);
+            }
+            Ok(Some(uri))
+        }
+        DidCloseTextDocument::METHOD => {
+            let params: DidCloseTextDocumentParams = serde_json::from_value(notification.params)?;
+            let uri = params.text_document.uri;
+            documents.remove(&uri);
+            Ok(Some(uri))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn handle_request(documents: &HashMap<Url, String>, request: Request) -> Response {
+    match respond(documents, &request) {
+        Ok(result) => Response::new_ok(request.id, result),
+        Err(e) => Response::new_err(request.id, ErrorCode::InvalidParams as i32, format!("{e}")),
+    }
+}
+
+fn respond(
+    documents: &HashMap<Url, String>,
+    request: &Request,
+) -> Result<serde_json::Value, Error> {
+    let get_text = |uri: &Url| match documents.get(uri) {
+        None => Err(anyhow!("Unknown document '{uri}'.")),
+        Some(text) => Ok(text.as_str()),
+    };
+
+    match request.method.as_str() {
+        DocumentSymbolRequest::METHOD => {
+            let params: DocumentSymbolParams = serde_json::from_value(request.params.clone())?;
+            let text = get_text(&params.text_document.uri)?;
+            let response = DocumentSymbolResponse::Nested(document_symbols(text));
+            Ok(serde_json::to_value(response)?)
+        }
+        GotoDefinition::METHOD => {
+            let params: GotoDefinitionParams = serde_json::from_value(request.params.clone())?;
+            let uri = params.text_document_position_params.text_document.uri;
+            let position = params.text_document_position_params.position;
+            let locations = definitions(get_text(&uri)?, position)
+                .into_iter()
+                .map(|range| Location::new(uri.clone(), range))
+                .collect();
+            Ok(serde_json::to_value(GotoDefinitionResponse::Array(
+                locations,
+            ))?)
+        }
+        Completion::METHOD => {
+            let params: CompletionParams = serde_json::from_value(request.params.clone())?;
+            let uri = params.text_document_position.text_document.uri;
+            let response = CompletionResponse::Array(completions(get_text(&uri)?));
+            Ok(serde_json::to_value(response)?)
+        }
+        m => Err(anyhow!("Unsupported method '{m}'.")),
+    }
+}
+
+/// Parses the document and reports every error encountered. After an error, parsing resumes at the next place where it
+/// is likely to succeed.
+fn diagnostics(text: &str) -> Vec<Diagnostic> {
+    let lines = text.lines().collect::<Vec<_>>();
+
+    // Keep track of how many lines the lexer has requested so that errors can be attributed to a line
+    let lines_read = Rc::new(Cell::new(0));
+    let counter = Rc::clone(&lines_read);
+    let reader = lines.clone().into_iter().map(move |line| {
+        counter.set(counter.get() + 1);
+        line.to_owned()
+    });
+    let mut parser = Parser::new(Lexer::new(reader));
+
+    let mut diagnostics = Vec::new();
+    loop {
+        match parser.next_instruction() {
+            Ok(None) => break,
+            Ok(Some(_)) => {}
+            Err(e) => {
+                let i = lines_read.get().max(1) - 1;
+                let line = lines.get(i).copied().unwrap_or("");
+                diagnostics.push(Diagnostic {
+                    range: to_lsp_range(line, i, 0..line.len()),
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    source: Some("plates".to_owned()),
+                    message: format!("{e}"),
+                    ..Diagnostic::default()
+                });
+                parser.recover();
+            }
+        }
+    }
+
+    diagnostics
+}
+
+#[allow(deprecated)] // DocumentSymbol::deprecated must be initialized even though it is deprecated
+fn document_symbols(text: &str) -> Vec<DocumentSymbol> {
+    let tokens = tokenize(text);
+
+    let mut symbols = Vec::new();
+    for (i, window) in tokens.windows(2).enumerate() {
+        let (defn, name, name_range) = match window {
+            [(Token::Defn | Token::DefnPriv, defn), (Token::FunctionName(name), name_range)] => {
+                (defn, name, name_range)
+            }
+            _ => continue,
+        };
+
+        let arg_count = match tokens.get(i + 3) {
+            Some((Token::Word(n), _)) => Some(*n),
+            _ => None,
+        };
+        // Nested definitions are not allowed, so the body ends at the first closing bracket
+        let end = tokens[i..]
+            .iter()
+            .find(|(t, _)| *t == Token::RightCurlyBracket)
+            .map_or(name_range.end, |(_, r)| r.end);
+
+        symbols.push(DocumentSymbol {
+            name: name.clone(),
+            detail: arg_count.map(|n| format!("({n})")),
+            kind: SymbolKind::FUNCTION,
+            tags: None,
+            deprecated: None,
+            range: Range::new(defn.start, end),
+            selection_range: *name_range,
+            children: None,
+        });
+    }
+
+    symbols
+}
+
+/// Finds the definitions of the function whose name is at the given position.
+fn definitions(text: &str, position: Position) -> Vec<Range> {
+    let tokens = tokenize(text);
+
+    let target = tokens.iter().find_map(|(token, range)| match token {
+        Token::FunctionName(f) if range.start <= position && position <= range.end => Some(f),
+        _ => None,
+    });
+    let target = match target {
+        None => return vec![],
+        Some(f) => f,
+    };
+
+    tokens
+        .windows(2)
+        .filter_map(|window| match window {
+            [(Token::Defn | Token::DefnPriv, _), (Token::FunctionName(f), range)]
+                if f == target =>
+            {
+                Some(*range)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn completions(text: &str) -> Vec<CompletionItem> {
+    let keywords = KEYWORDS.iter().map(|k| CompletionItem {
+        label: (*k).to_owned(),
+        kind: Some(CompletionItemKind::KEYWORD),
+        ..CompletionItem::default()
+    });
+    let builtins = BUILTIN_FUNCTIONS.iter().map(|f| CompletionItem {
+        label: (*f).to_owned(),
+        kind: Some(CompletionItemKind::FUNCTION),
+        detail: Some("built-in function".to_owned()),
+        ..CompletionItem::default()
+    });
+    // A function may be defined several times, so only its first definition is offered
+    let mut seen = HashSet::new();
+    let functions = document_symbols(text)
+        .into_iter()
+        .filter(|s| seen.insert(s.name.clone()))
+        .map(|s| CompletionItem {
+            label: s.name,
+            kind: Some(CompletionItemKind::FUNCTION),
+            detail: s.detail,
+            ..CompletionItem::default()
+        });
+
+    keywords.chain(builtins).chain(functions).collect()
+}
+
+/// Lexes the whole document, skipping any lines that contain syntax errors.
+fn tokenize(text: &str) -> Vec<(Token, Range)> {
+    text.lines()
+        .enumerate()
+        .flat_map(|(i, line)| {
+            let tokens = lex_line_with_spans(line).unwrap_or_default();
+            tokens
+                .into_iter()
+                .map(move |(token, span)| (token, to_lsp_range(line, i, span)))
+        })
+        .collect()
+}
+
+/// Converts a byte range within a line to an LSP range, which counts UTF-16 code units.
+fn to_lsp_range(line: &str, line_number: usize, span: std::ops::Range<usize>) -> Range {
+    let column = |byte: usize| line[..byte].encode_utf16().count() as u32;
+    let line_number = line_number as u32;
+    Range::new(
+        Position::new(line_number, column(span.start)),
+        Position::new(line_number, column(span.end)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::{Position, Range};
+
+    use super::{completions, definitions, diagnostics, document_symbols};
+
+    fn range(line: u32, start: u32, end: u32) -> Range {
+        Range::new(Position::new(line, start), Position::new(line, end))
+    }
+
+    #[test]
+    fn no_diagnostics() {
+        let text = "DEFN f (1) {\n    PUSH $0\n}\nPUSH 1 PUSH f CALLIF\n";
+        assert!(diagnostics(text).is_empty());
+    }
+
+    #[test]
+    fn multiple_diagnostics() {
+        let text = "PUSH 1\nPUSH %\nPUSH 2\nPUSH (\nPUSH 3 }\n";
+
+        let actual = diagnostics(text)
+            .into_iter()
+            .map(|d| (d.range, d.message))
+            .collect::<Vec<_>>();
+
+        let expected = vec![
+            (
+                range(1, 0, 6),
+                "Syntax error: Unexpected character '%'.".to_owned(),
+            ),
+            (
+                range(3, 0, 6),
+                "Syntax error: Unexpected token LeftParen.".to_owned(),
+            ),
+            (
+                range(4, 0, 8),
+                "Syntax error: No matching DEFN for '}'.".to_owned(),
+            ),
+        ];
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn unexpected_eof_diagnostic() {
+        let text = "DEFN f (0) {\n    PUSH 1";
+
+        let actual = diagnostics(text)
+            .into_iter()
+            .map(|d| (d.range, d.message))
+            .collect::<Vec<_>>();
+
+        let expected = vec![(
+            range(1, 0, 10),
+            "Syntax error: Unexpected end of file in body of function 'f'.".to_owned(),
+        )];
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn symbols() {
+        let text = "DEFN foo (2) {\n    PUSH $0\n}\n\nDEFN\n  bar (0) { }\n";
+
+        let actual = document_symbols(text)
+            .into_iter()
+            .map(|s| (s.name, s.detail, s.range, s.selection_range))
+            .collect::<Vec<_>>();
+
+        let expected = vec![
+            (
+                "foo".to_owned(),
+                Some("(2)".to_owned()),
+                Range::new(Position::new(0, 0), Position::new(2, 1)),
+                range(0, 5, 8),
+            ),
+            (
+                "bar".to_owned(),
+                Some("(0)".to_owned()),
+                Range::new(Position::new(4, 0), Position::new(5, 13)),
+                range(5, 2, 5),
+            ),
+        ];
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn goto_definition() {
+        let text = "DEFN foo (0) { }\nPUSH 1 PUSH foo CALLIF\nDEFN foo (1) { }\n";
+
+        assert_eq!(
+            vec![range(0, 5, 8), range(2, 5, 8)],
+            definitions(text, Position::new(1, 13))
+        );
+        // Cursor on a keyword
+        assert!(definitions(text, Position::new(1, 2)).is_empty());
+    }
+
+    #[test]
+    fn completion_items() {
+        let text = "DEFN foo (0) { }\nDEFN foo (0) { }\nDEFN bar (1) { }\n";
+
+        let labels = completions(text)
+            .into_iter()
+            .map(|c| c.label)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                "PUSH",
+                "DEFN",
+                "DEFN_PRIV",
+                "CALLIF",
+                "EXIT",
+                "MODULE",
+                "IMPORT",
+                "AS",
+                "CONST",
+                "MACRO",
+                "STACK_ORDER",
+                "__print__",
+                "__input__",
+                "__birl__",
+                "__inc__",
+                "__dec__",
+                "__inc_sat__",
+                "__dec_sat__",
+                "__and__",
+                "__or__",
+                "__xor__",
+                "__not__",
+                "__shl_n__",
+                "__shr_n__",
+                "__add_carry__",
+                "__sub_borrow__",
+                "__pick__",
+                "__roll__",
+                "__mark__",
+                "__count_to_mark__",
+                "__collect__",
+                "__defined__",
+                "__call_n_times__",
+                "__strlen__",
+                "__streq__",
+                "__strrev__",
+                "__num_to_str__",
+                "__str_to_num__",
+                "__assert_eq__",
+                "__assert__",
+                "__panic__",
+                "__stack_dump__",
+                "__gc_stats__",
+                "__badd__",
+                "__bmul__",
+                "__bmod__",
+                "__tcp_connect__",
+                "__tcp_send__",
+                "__tcp_recv__",
+                "__tcp_close__",
+                "__clear__",
+                "__cursor_to__",
+                "__term_size__",
+                "__raw_mode__",
+                "__read_key__",
+                "__poll_key__",
+                "__spawn__",
+                "__send__",
+                "__recv__",
+                "__set_print_policy__",
+                "__write_byte__",
+                "__read_byte__",
+                "foo",
+                "bar"
+            ],
+            labels
+        );
+    }
+
+    #[test]
+    fn completions_of_redefined_functions() {
+        let text = "DEFN foo (0) { }\nDEFN bar (1) { }\nDEFN foo (2) { }\n";
+        let functions = completions(text)
+            .into_iter()
+            .filter(|c| c.detail.as_deref() != Some("built-in function"))
+            .filter(|c| c.detail.is_some())
+            .map(|c| (c.label, c.detail.unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                ("foo".to_owned(), "(0)".to_owned()),
+                ("bar".to_owned(), "(1)".to_owned())
+            ],
+            functions
+        );
+    }
+}
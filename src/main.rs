@@ -1,95 +1,1237 @@
-use anyhow::Error;
-use clap::Parser;
-use colored::Colorize;
-use lexer::Lexer;
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    io::IsTerminal,
+    rc::Rc,
+    sync::mpsc::Receiver,
+};
 
-use crate::{
+use anyhow::{anyhow, Context, Error};
+use clap::{CommandFactory, Parser};
+use colored::Colorize;
+use notify::Watcher;
+use plates::{
+    bytecode, codegen, examples, graph, highlight, json,
+    lexer::Lexer,
+    lint, lsp, optimizer, parser, plugin, print_debug, print_error, print_info, print_verbose,
+    print_warning, printer, prompt,
     reader::{FileReader, InteractiveReader},
-    runtime::Runtime,
+    reload, rename,
+    replay::{self, Replay},
+    runtime::{PrintPolicy, Runtime, Snapshot, StackDiff, Step, Word, WordSize},
+    server, stats, test_runner, tutorial, viz, xref,
 };
 
-mod lexer;
-mod parser;
-mod reader;
-mod runtime;
-
 #[derive(clap::Parser)]
+#[clap(name = "plates", args_conflicts_with_subcommands = true)]
 struct CliArgs {
-    /// Files to run. If none are provided, the REPL will be launched instead.
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    // Without a subcommand, `plates <files>` is the same as `plates run <files>`, and `plates` on its own is the same
+    // as `plates repl`
+    #[clap(flatten)]
+    run: RunArgs,
+
+    #[clap(flatten)]
+    prompt: PromptArgs,
+
+    /// Only print errors, warnings, and the output of the program
+    #[clap(short, long, global = true, action, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Print more details about what the interpreter is doing (use -vv for even more)
+    #[clap(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// When to use colors. With auto, colors are only used if the output is a terminal and NO_COLOR is not set.
+    #[clap(
+        long,
+        global = true,
+        value_enum,
+        value_name = "WHEN",
+        default_value = "auto"
+    )]
+    color: ColorChoice,
+
+    /// Log what the interpreter itself is doing to stderr, at this level or above (error, warn, info, debug, or
+    /// trace). Meant for debugging the interpreter rather than plates programs.
+    #[clap(long, global = true, value_name = "LEVEL")]
+    log_level: Option<tracing::Level>,
+
+    /// Write the logs as JSON, one object per line
+    #[clap(long, global = true, action, requires = "log_level")]
+    log_json: bool,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, clap::ValueEnum)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(clap::Args)]
+struct RunArgs {
+    /// Files to run
     files: Vec<std::path::PathBuf>,
 
-    /// Print debug info (e.g., the state of the stack) after each instruction
-    #[clap(short, long, action)]
-    debug: bool,
+    #[clap(flatten)]
+    options: RunOptions,
+
+    /// Run the files again whenever they change
+    #[clap(long, action, conflicts_with = "exit_status_from_stack")]
+    watch: bool,
+
+    /// With --watch, swap the functions that changed into the program while it is still running instead of starting over
+    #[clap(long, action, requires = "watch")]
+    hot_reload: bool,
+
+    /// Print the instructions of the files (after optimizing them with --opt) in this format instead of running them
+    #[clap(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        requires = "files",
+        conflicts_with = "watch"
+    )]
+    emit: Option<EmitFormat>,
+
+    /// Read the files as lists of instructions in JSON (like the ones from --emit json) instead of source code
+    #[clap(long, action, requires = "files", conflicts_with = "hot_reload")]
+    run_json: bool,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum EmitFormat {
+    /// The JSON format documented in `src/json.rs`
+    Json,
+}
+
+#[derive(clap::Args)]
+struct RunOptions {
+    /// Print debug info (e.g., the state of the stack) after each top-level instruction, or as often as requested
+    #[clap(short, long, value_enum, value_name = "WHEN", num_args = 0..=1, require_equals = true, default_missing_value = "line")]
+    debug: Option<DebugLevel>,
+
+    /// With --debug=instruction or --debug=call, also print the next N instructions on the instruction stack and the
+    /// current arguments
+    #[clap(long, value_name = "N", requires = "debug")]
+    pending: Option<usize>,
+
+    /// With --debug, print the words that were popped and pushed since the last time instead of the whole stack
+    #[clap(long, action, requires = "debug")]
+    debug_diff: bool,
+
+    /// Optimize the program before running it. The files are parsed in their entirety before anything is run.
+    #[clap(long, action)]
+    opt: bool,
+
+    /// Load built-in functions from a plugin (a shared library). Can be given more than once.
+    #[clap(long, value_name = "PATH")]
+    plugin: Vec<std::path::PathBuf>,
+
+    /// The number of bits in a data word (32 or 64). --opt only supports 32-bit words.
+    #[clap(long, value_name = "BITS", default_value = "32")]
+    word_size: WordSize,
+
+    /// Which popped word $0 is in functions without STACK_ORDER: pop (the top one) or stack (the deepest one)
+    #[clap(long, value_name = "ORDER", default_value = "pop")]
+    args_order: parser::ArgsOrder,
+
+    /// What __print__ does with words that are not valid characters: error, skip, or replace (with U+FFFD)
+    #[clap(long, value_name = "POLICY", default_value = "error")]
+    print_policy: PrintPolicy,
+
+    /// When the program stops, print how much memory the runtime is using to stderr
+    #[clap(long, action)]
+    mem_report: bool,
+
+    /// When the program stops, write a chart of the stack depth and call depth after each instruction to a file: an
+    /// SVG image if the file name ends in .svg, or CSV otherwise
+    #[clap(long, value_name = "PATH")]
+    viz: Option<std::path::PathBuf>,
+
+    /// Disable the built-in functions that access the network
+    #[clap(long, action, conflicts_with = "plugin")]
+    sandbox: bool,
+
+    /// When the program completes successfully, print the words left on the stack (or only the top N), starting at the top
+    #[clap(long, value_name = "N", num_args = 0..=1, require_equals = true)]
+    print_stack: Option<Option<usize>>,
+
+    /// When the program completes successfully, exit with the data word on top of the stack (modulo 256) as the status.
+    /// Exit with status 1 if there is an error.
+    #[clap(long, action)]
+    exit_status_from_stack: bool,
+
+    /// When the program completes successfully, warn about functions left on the stack, which were probably meant to
+    /// be called
+    #[clap(long, action)]
+    warn_uncalled: bool,
+
+    /// Save the random words, input, keys, and terminal sizes that the program receives to a file, so that the run
+    /// can be reproduced with --replay
+    #[clap(long, value_name = "PATH", conflicts_with = "replay")]
+    record: Option<std::path::PathBuf>,
+
+    /// Give the program the random words, input, keys, and terminal sizes saved with --record instead of reading them
+    #[clap(long, value_name = "PATH")]
+    replay: Option<std::path::PathBuf>,
+
+    /// The format for --print-stack
+    #[clap(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        default_value = "human",
+        requires = "print_stack"
+    )]
+    stack_format: StackFormat,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, clap::ValueEnum)]
+enum StackFormat {
+    /// One word per line
+    Human,
+    /// A JSON array, in which functions are objects like {"function": "f"}
+    Json,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, clap::ValueEnum)]
+enum StatsFormat {
+    /// A table with a row per function
+    Table,
+    /// A JSON object
+    Json,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, clap::ValueEnum)]
+enum DebugLevel {
+    /// After every instruction, including the instructions in the functions that are called
+    Instruction,
+    /// After every top-level instruction (in the REPL, after every line)
+    Line,
+    /// Whenever a function is called
+    Call,
+}
+
+#[derive(clap::Args)]
+struct PromptArgs {
+    /// The REPL prompt. Supports the placeholders {arrows}, {depth}, {stack}, and colors like {green} and {reset}.
+    #[clap(long, default_value = "{arrows} ")]
+    prompt: prompt::Prompt,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Run files (this is the default when files are given without a subcommand)
+    Run(RunArgs),
+
+    /// Start the REPL (this is the default when nothing is given)
+    Repl {
+        /// Print the state of the stack after each line, or as often as requested
+        #[clap(short, long, value_enum, value_name = "WHEN", num_args = 0..=1, require_equals = true, default_missing_value = "line")]
+        debug: Option<DebugLevel>,
+
+        /// With --debug, print the words that were popped and pushed since the last time instead of the whole stack
+        #[clap(long, action, requires = "debug")]
+        debug_diff: bool,
+
+        #[clap(flatten)]
+        prompt: PromptArgs,
+
+        /// Read the session from stdin and print each line after the prompt, without using any terminal features.
+        /// This is useful for testing.
+        #[clap(long, action)]
+        transcript: bool,
+
+        /// Don't print the lines read in --transcript mode, for clients that show what they send themselves
+        #[clap(long, action, requires = "transcript")]
+        no_echo: bool,
+
+        /// Keep whatever a line left on the stack when it fails, instead of restoring the stack from before the line
+        #[clap(long, action)]
+        no_rollback: bool,
+
+        /// Run this file before the first prompt, instead of $PLATES_INIT or ~/.platesrc
+        #[clap(long, value_name = "PATH")]
+        init: Option<std::path::PathBuf>,
+    },
+
+    /// Serve the REPL over TCP (or a Unix socket), with a separate session for each connection
+    Serve {
+        /// The port to listen on (0 picks a free port)
+        #[clap(long, required_unless_present = "socket", conflicts_with = "socket")]
+        port: Option<u16>,
+
+        /// The address to listen on
+        #[clap(long, default_value = "127.0.0.1")]
+        host: String,
+
+        /// Listen on a Unix socket at this path instead of a TCP port
+        #[clap(long, value_name = "PATH")]
+        socket: Option<std::path::PathBuf>,
+
+        /// The REPL prompt. Supports the same placeholders as the prompt of `plates repl`.
+        #[clap(long, default_value = "{arrows} ", value_parser = check_prompt)]
+        prompt: String,
+
+        /// Keep whatever a line left on the stack when it fails, instead of restoring the stack from before the line
+        #[clap(long, action)]
+        no_rollback: bool,
+
+        /// Run this file at the start of every session
+        #[clap(long, value_name = "PATH")]
+        init: Option<std::path::PathBuf>,
+    },
+
+    /// Print files in the canonical format, with one instruction per line. Comments are not preserved.
+    Fmt {
+        #[clap(required = true)]
+        files: Vec<std::path::PathBuf>,
+
+        /// Print the shortest equivalent source on one line instead, with shorter function names. The new names are
+        /// printed to stderr.
+        #[clap(long, action)]
+        minify: bool,
+
+        /// With --minify, write the new names to this file instead of stderr.
+        #[clap(long, requires = "minify")]
+        rename_map: Option<std::path::PathBuf>,
+    },
+
+    /// Check files for syntax errors and likely mistakes without running them
+    Check {
+        #[clap(required = true)]
+        files: Vec<std::path::PathBuf>,
+
+        /// Don't report a warning (by code or name, e.g. W001 or unused-function)
+        #[clap(long, value_name = "LINT")]
+        allow: Vec<lint::Lint>,
+
+        /// Report a warning, even if it is allowed by --allow
+        #[clap(long, value_name = "LINT")]
+        warn: Vec<lint::Lint>,
+
+        /// Report a warning as an error, even if it is allowed by --allow or --warn
+        #[clap(long, value_name = "LINT")]
+        deny: Vec<lint::Lint>,
+    },
+
+    /// Start a language server that communicates over stdin and stdout
+    Lsp,
+
+    /// Start a debug adapter (Debug Adapter Protocol) that communicates over stdin and stdout
+    Dap,
+
+    /// Start a Jupyter kernel (this is what Jupyter runs, with the connection file it created)
+    #[cfg(feature = "jupyter")]
+    Kernel { connection_file: std::path::PathBuf },
+
+    /// Print a file with syntax highlighting
+    Highlight {
+        file: std::path::PathBuf,
+
+        #[clap(long, value_enum, default_value_t = highlight::Format::Html)]
+        format: highlight::Format,
+    },
+
+    /// Compile files into a single bytecode file
+    Compile {
+        #[clap(required = true)]
+        files: Vec<std::path::PathBuf>,
+
+        /// Where to write the bytecode. Defaults to the first file with the extension `.platesc`.
+        #[clap(short, long)]
+        output: Option<std::path::PathBuf>,
+    },
+
+    /// Print the instructions in a bytecode file as plates source code
+    Disasm { file: std::path::PathBuf },
+
+    /// List the functions defined in files, with their argument counts and docstrings
+    Doc {
+        #[clap(required = true)]
+        files: Vec<std::path::PathBuf>,
+    },
+
+    /// Print the call graph of files in the DOT format of Graphviz, with recursive and unreachable functions marked
+    Graph {
+        #[clap(required = true)]
+        files: Vec<std::path::PathBuf>,
+
+        /// Include the built-in functions that are used
+        #[clap(long, action)]
+        builtins: bool,
+    },
+
+    /// List every place where each function in files is defined, imported, or pushed
+    Xref {
+        #[clap(required = true)]
+        files: Vec<std::path::PathBuf>,
+    },
+
+    /// Rename a function everywhere in files, leaving the rest of the files as they are
+    Rename {
+        /// The full name of the function, with its module
+        old: String,
+
+        /// The new full name of the function
+        new: String,
+
+        #[clap(required = true)]
+        files: Vec<std::path::PathBuf>,
+    },
+
+    /// Print the size and complexity of each function in files
+    Stats {
+        #[clap(required = true)]
+        files: Vec<std::path::PathBuf>,
+
+        #[clap(long, value_enum, value_name = "FORMAT", default_value = "table")]
+        format: StatsFormat,
+    },
+
+    /// Translate files into another language and compile them into a native executable
+    Build {
+        #[clap(required = true)]
+        files: Vec<std::path::PathBuf>,
+
+        /// Where to write the executable (or source code). Defaults to the name of the first file.
+        #[clap(short, long)]
+        output: Option<std::path::PathBuf>,
+
+        /// Write the generated source code instead of compiling it
+        #[clap(long, action)]
+        source_only: bool,
+
+        /// The language to translate into
+        #[clap(long, value_enum, default_value_t = codegen::Backend::Rust)]
+        backend: codegen::Backend,
+    },
+
+    /// List the bundled example programs, or run one of them
+    Examples {
+        /// The example to run
+        name: Option<String>,
+
+        #[clap(flatten)]
+        options: RunOptions,
+    },
+
+    /// Print a completion script for the given shell
+    Completions {
+        #[clap(value_enum)]
+        shell: clap_complete::Shell,
+    },
+
+    /// Learn the language through a series of interactive exercises
+    Tutorial {
+        /// The lesson to start from
+        #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..=tutorial::LESSONS.len() as u64))]
+        lesson: u64,
+
+        #[clap(flatten)]
+        prompt: PromptArgs,
+    },
+
+    /// Run every function whose name starts with `test_` and report which ones failed
+    Test {
+        #[clap(required = true)]
+        files: Vec<std::path::PathBuf>,
+    },
 }
 
 fn main() {
     let args = CliArgs::parse();
-
-    if args.files.is_empty() {
-        run_interactive(args);
+    // Windows consoles only understand the escape codes for colors once virtual terminal processing is enabled
+    #[cfg(windows)]
+    let _ = colored::control::set_virtual_terminal(true);
+    plates::set_verbosity(if args.quiet {
+        -1
     } else {
-        run_from_files(args);
+        args.verbose.min(i8::MAX as u8) as i8
+    });
+    match args.color {
+        ColorChoice::Auto => {}
+        ColorChoice::Always => colored::control::set_override(true),
+        ColorChoice::Never => colored::control::set_override(false),
+    }
+    if let Some(level) = args.log_level {
+        init_logging(level, args.log_json);
     }
+    let options = match &args.command {
+        None => Some(&args.run.options),
+        Some(Command::Run(run)) => Some(&run.options),
+        Some(Command::Examples { options, .. }) => Some(options),
+        _ => None,
+    };
+    if let Some(options) = options {
+        check_options(options);
+    }
+
+    match args.command {
+        Some(Command::Run(run)) if run.files.is_empty() => CliArgs::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "No files were provided to run.",
+            )
+            .exit(),
+        Some(Command::Run(run)) => run_files(&run),
+        Some(Command::Repl {
+            debug,
+            debug_diff,
+            prompt,
+            transcript,
+            no_echo,
+            no_rollback,
+            init,
+        }) => run_interactive(
+            debug,
+            debug_diff,
+            prompt.prompt,
+            transcript,
+            !no_echo,
+            !no_rollback,
+            init,
+        ),
+        Some(Command::Serve {
+            port,
+            host,
+            socket,
+            prompt,
+            no_rollback,
+            init,
+        }) => {
+            if let Err(e) = run_serve(port, &host, socket, prompt, !no_rollback, init) {
+                exit_with_error(&e);
+            }
+        }
+        Some(Command::Fmt {
+            files,
+            minify,
+            rename_map,
+        }) => match parse_files(files) {
+            Err(e) => exit_with_error(&e),
+            Ok(instructions) if minify => {
+                let (source, renames) = printer::minify(&instructions);
+                println!("{source}");
+                // The new names are part of the output, so they are printed even with -q
+                let renames = renames
+                    .iter()
+                    .map(|(new, old)| format!("{new} = {old}\n"))
+                    .collect::<String>();
+                match rename_map {
+                    None => eprint!("{renames}"),
+                    Some(path) => {
+                        if let Err(e) = std::fs::write(path, renames) {
+                            exit_with_error(&anyhow!(e).context("Failed to write file."));
+                        }
+                    }
+                }
+            }
+            Ok(instructions) => print!("{}", printer::print_instructions(&instructions)),
+        },
+        Some(Command::Check {
+            files,
+            allow,
+            warn,
+            deny,
+        }) => {
+            let mut diagnostics = lint::Diagnostics::default();
+            for (lints, level) in [
+                (allow, lint::Level::Allow),
+                (warn, lint::Level::Warn),
+                (deny, lint::Level::Deny),
+            ] {
+                for lint in lints {
+                    diagnostics.set_level(lint, level);
+                }
+            }
+            if !run_check(files, &mut diagnostics) {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Dap) => {
+            if let Err(e) = plates::dap::run_server() {
+                exit_with_error(&e);
+            }
+        }
+        Some(Command::Lsp) => {
+            if let Err(e) = lsp::run_server() {
+                exit_with_error(&e);
+            }
+        }
+        #[cfg(feature = "jupyter")]
+        Some(Command::Kernel { connection_file }) => {
+            if let Err(e) = plates::kernel::run(&connection_file) {
+                exit_with_error(&e);
+            }
+        }
+        Some(Command::Highlight { ref file, format }) => run_highlight(file, format),
+        Some(Command::Compile { files, output }) => {
+            if let Err(e) = run_compile(files, output) {
+                exit_with_error(&e);
+            }
+        }
+        Some(Command::Disasm { ref file }) => match run_disasm(file) {
+            Err(e) => exit_with_error(&e),
+            Ok(source) => print!("{source}"),
+        },
+        Some(Command::Doc { files }) => match parse_files(files) {
+            Err(e) => exit_with_error(&e),
+            Ok(instructions) => print!("{}", run_doc(&instructions)),
+        },
+        Some(Command::Graph { files, builtins }) => match parse_files(files) {
+            Err(e) => exit_with_error(&e),
+            Ok(instructions) => print!("{}", graph::CallGraph::new(&instructions).to_dot(builtins)),
+        },
+        Some(Command::Stats { files, format }) => match parse_files(files) {
+            Err(e) => exit_with_error(&e),
+            Ok(instructions) => {
+                let stats = stats::Stats::new(&instructions);
+                match format {
+                    StatsFormat::Table => print!("{}", stats.to_table()),
+                    StatsFormat::Json => println!("{}", stats.to_json()),
+                }
+            }
+        },
+        Some(Command::Xref { files }) => match run_xref(files) {
+            Err(e) => exit_with_error(&e),
+            Ok(listing) => print!("{listing}"),
+        },
+        Some(Command::Rename { old, new, files }) => {
+            if let Err(e) = run_rename(&old, &new, files) {
+                exit_with_error(&e);
+            }
+        }
+        Some(Command::Build {
+            files,
+            output,
+            source_only,
+            backend,
+        }) => {
+            if let Err(e) = run_build(files, output, source_only, backend) {
+                exit_with_error(&e);
+            }
+        }
+        Some(Command::Test { files }) => match run_tests(files) {
+            Err(e) => exit_with_error(&e),
+            Ok(false) => std::process::exit(1),
+            Ok(true) => {}
+        },
+        Some(Command::Examples { name: None, .. }) => {
+            for (name, description, _) in examples::EXAMPLES {
+                println!("{name:<10} {description}");
+            }
+        }
+        Some(Command::Examples {
+            name: Some(ref name),
+            ref options,
+        }) => match examples::find(name) {
+            None => exit_with_error(&anyhow!(
+                "Unknown example '{name}'. Run `plates examples` to see the list of examples."
+            )),
+            Some(source) => {
+                if !run_from_program(
+                    Program::Source(FileReader::from_source(source)),
+                    options,
+                    None,
+                ) {
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some(Command::Completions { shell }) => clap_complete::generate(
+            shell,
+            &mut CliArgs::command(),
+            "plates",
+            &mut std::io::stdout(),
+        ),
+        Some(Command::Tutorial { lesson, prompt }) => {
+            tutorial::run(lesson as usize - 1, prompt.prompt)
+        }
+        None if args.run.files.is_empty() => run_interactive(
+            args.run.options.debug,
+            args.run.options.debug_diff,
+            args.prompt.prompt,
+            false,
+            false,
+            true,
+            None,
+        ),
+        None => run_files(&args.run),
+    }
+}
+
+/// Prints the error and exits with status 1, for subcommands that failed.
+fn exit_with_error(e: &Error) -> ! {
+    print_error(e);
+    std::process::exit(1);
 }
 
-fn run_interactive(args: CliArgs) {
+fn run_highlight(file: &std::path::Path, format: highlight::Format) {
+    // ANSI output was explicitly requested, so emit escape codes even when stdout is not a terminal
+    if let highlight::Format::Ansi = format {
+        colored::control::set_override(true);
+    }
+
+    match std::fs::read_to_string(file) {
+        Err(e) => exit_with_error(&anyhow!(e).context("Failed to read file.")),
+        Ok(source) => print!("{}", highlight::highlight(&source, format)),
+    }
+}
+
+/// How many top-level instructions `:undo` can go back.
+const UNDO_LIMIT: usize = 100;
+
+/// The exit status of the REPL when the program runs `EXIT`, as opposed to 0 at the end of the input.
+const REPL_EXIT_STATUS: i32 = 3;
+
+/// The top-level instructions that ran successfully in the REPL, along with the state from before each one, from oldest
+/// to newest.
+type History = VecDeque<(parser::Instruction, Snapshot)>;
+
+/// The state of the REPL that commands can read or change.
+#[derive(Default)]
+struct Session {
+    history: History,
+    /// The words that the previous successful line consumed and pushed.
+    last_line: Option<StackDiff>,
+}
+
+fn run_interactive(
+    debug: Option<DebugLevel>,
+    debug_diff: bool,
+    prompt: prompt::Prompt,
+    transcript: bool,
+    echo: bool,
+    rollback: bool,
+    init: Option<std::path::PathBuf>,
+) {
+    if transcript {
+        colored::control::set_override(false);
+    }
     print_info("Welcome to the plates REPL!");
+    // Ctrl+C leaves right away, even in the middle of a line or while a program runs
+    let interrupted = ctrlc::set_handler(move || {
+        if !transcript && std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
+            plates::reader::disable_bracketed_paste();
+        }
+        println!();
+        print_info("Interrupted.");
+        std::process::exit(130);
+    });
+    if let Err(e) = interrupted {
+        print_warning(&format!("Failed to handle Ctrl+C: {e}"));
+    }
 
-    let reader = InteractiveReader::new();
+    let mut runtime = Runtime::new().with_rollback(rollback);
+    if let Some(path) = startup_file(init, transcript) {
+        run_startup_file(&path, &mut runtime);
+    }
+    let mut view = DebugView {
+        pending: None,
+        last_stack: debug_diff.then(|| runtime.stack().to_vec()),
+    };
+    let stack_size = Rc::new(Cell::new(runtime.stack_size()));
+    // Shared with the reader, which handles REPL commands
+    let runtime = Rc::new(RefCell::new(runtime));
+    let reader = if transcript {
+        InteractiveReader::transcript(prompt, Rc::clone(&stack_size)).with_echo(echo)
+    } else {
+        InteractiveReader::new(prompt, Rc::clone(&stack_size))
+    };
+    let session = Rc::new(RefCell::new(Session::default()));
+    let reader = reader.with_command_handler({
+        let runtime = Rc::clone(&runtime);
+        let session = Rc::clone(&session);
+        let stack_size = Rc::clone(&stack_size);
+        move |command| {
+            let mut runtime = runtime.borrow_mut();
+            run_command(command, &mut runtime, &mut session.borrow_mut());
+            stack_size.set(runtime.stack_size());
+        }
+    });
     let lexer = Lexer::new(reader);
     let mut parser = parser::Parser::new(lexer);
-    let mut runtime = Runtime::new();
+    // The stack before the current line, and whether any part of the line failed
+    let mut line_start = None;
+    let mut line_failed = false;
 
-    loop {
+    let (farewell, status) = loop {
         match parser.next_instruction() {
-            Ok(None) => break,
-            Ok(Some(instruction)) => match runtime.run(instruction) {
-                Err(e) => print_error(&e),
-                Ok(true) => break,
-                Ok(false) => {}
-            },
+            Ok(None) => break ("End of input. Goodbye!", 0),
+            Ok(Some(instruction)) => {
+                let mut runtime = runtime.borrow_mut();
+                let snapshot = runtime.snapshot();
+                line_start.get_or_insert_with(|| runtime.stack().to_vec());
+                let result = run_instruction(
+                    &mut runtime,
+                    instruction.clone(),
+                    debug,
+                    &mut view,
+                    None,
+                    None,
+                );
+                print_assertion_failures(&mut runtime);
+                match result {
+                    Err(e) => {
+                        print_error(&e);
+                        line_failed = true;
+                    }
+                    Ok(true) => break ("The program exited.", REPL_EXIT_STATUS),
+                    Ok(false) => {
+                        let history = &mut session.borrow_mut().history;
+                        if history.len() == UNDO_LIMIT {
+                            history.pop_front();
+                        }
+                        history.push_back((instruction, snapshot));
+                    }
+                }
+            }
             Err(e) => {
                 print_error(&e);
                 parser.clear_line();
+                line_failed = true;
             }
         };
 
+        stack_size.set(runtime.borrow().stack_size());
+
+        if parser.full_line_consumed() {
+            if let Some(before) = line_start.take() {
+                if !line_failed {
+                    let runtime = runtime.borrow();
+                    let after = runtime.stack();
+                    session.borrow_mut().last_line = Some(StackDiff::new(&before, after));
+                    parser.set_it(after.last().and_then(push_word));
+                }
+            }
+            line_failed = false;
+        }
+
         // Only show stack once per line
-        if args.debug && parser.full_line_consumed() {
-            print_debug(&runtime.stack_to_string());
+        if debug == Some(DebugLevel::Line) && parser.full_line_consumed() {
+            print_stack(&runtime.borrow(), &mut view);
         }
-    }
+    };
 
-    print_info("Program completed successfully.");
+    print_info(farewell);
+    // Exiting skips destructors, so clean up the reader (e.g., turn off bracketed paste) and the runtime first
+    drop(parser);
+    drop(runtime);
+    std::process::exit(status);
 }
 
-fn run_from_files(args: CliArgs) {
-    let reader = match FileReader::new(args.files) {
-        Err(e) => {
-            print_error(&e);
-            return;
+/// Serves the REPL on a TCP port or Unix socket, with a `plates repl --transcript` process for each connection.
+fn run_serve(
+    port: Option<u16>,
+    host: &str,
+    socket: Option<std::path::PathBuf>,
+    prompt: String,
+    rollback: bool,
+    init: Option<std::path::PathBuf>,
+) -> Result<(), Error> {
+    let exe = std::env::current_exe().context("Environment error: Failed to find plates.")?;
+    let session = move || {
+        let mut command = std::process::Command::new(&exe);
+        command.args(["repl", "--transcript", "--no-echo", "--prompt", &prompt]);
+        if !rollback {
+            command.arg("--no-rollback");
+        }
+        if let Some(init) = &init {
+            command.arg("--init").arg(init);
         }
-        Ok(r) => r,
+        command
     };
-    let lexer = Lexer::new(reader);
-    let mut parser = parser::Parser::new(lexer);
-    let mut runtime = Runtime::new();
+    match (socket, port) {
+        (Some(path), _) => server::serve_unix(&path, session),
+        (None, Some(port)) => server::serve_tcp((host, port), session),
+        (None, None) => unreachable!("clap requires --port or --socket"),
+    }
+}
+
+/// Checks that a prompt template is valid, but keeps it as text so that it can be passed on to `plates repl`.
+fn check_prompt(template: &str) -> Result<String, Error> {
+    template.parse::<prompt::Prompt>()?;
+    Ok(template.to_owned())
+}
+
+/// The file to run when the REPL starts: the one given with `--init`, then `$PLATES_INIT`, then `~/.platesrc` if it
+/// exists. Transcripts skip `~/.platesrc` so that they do not depend on the user's setup.
+fn startup_file(init: Option<std::path::PathBuf>, transcript: bool) -> Option<std::path::PathBuf> {
+    if init.is_some() {
+        return init;
+    }
+    if let Some(path) = std::env::var_os("PLATES_INIT").filter(|path| !path.is_empty()) {
+        return Some(path.into());
+    }
+    if transcript {
+        return None;
+    }
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    let path = std::path::Path::new(&home).join(".platesrc");
+    path.is_file().then_some(path)
+}
 
+/// Runs the startup file up to its first error (if any), which is reported without stopping the REPL from starting.
+fn run_startup_file(path: &std::path::Path, runtime: &mut Runtime) {
+    let context = || format!("Failed to run startup file '{}'.", path.display());
+    let reader = match FileReader::new(vec![path.to_owned()]) {
+        Err(e) => return print_error(&e.context(context())),
+        Ok(reader) => reader,
+    };
+    let mut parser = parser::Parser::new(Lexer::new(reader));
     loop {
-        let instruction = match parser.next_instruction() {
+        let result = parser
+            .next_instruction()
+            .and_then(|instruction| match instruction {
+                None => Ok(true),
+                Some(instruction) => runtime.run(instruction),
+            });
+        print_assertion_failures(runtime);
+        match result {
+            Err(e) => return print_error(&e.context(context())),
+            Ok(true) => break,
+            Ok(false) => {}
+        }
+    }
+    print_verbose(1, &format!("Ran startup file '{}'.", path.display()));
+}
+
+/// The instruction that pushes `word`, if there is one.
+fn push_word(word: &Word) -> Option<parser::Instruction> {
+    match word {
+        Word::Data(n) => Some(parser::Instruction::PushData(*n)),
+        Word::Function(f) => Some(parser::Instruction::PushFunction(f.clone())),
+        #[cfg(feature = "bigint")]
+        Word::Big(n) => Some(parser::Instruction::PushBig(n.to_string())),
+        Word::Marker => None,
+    }
+}
+
+/// Runs a REPL command, like `:doc f`.
+fn run_command(command: &str, runtime: &mut Runtime, session: &mut Session) {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some(":last"), None, _) => match &session.last_line {
+            Some(diff) => {
+                let show = |words: &[Word]| {
+                    let words = words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+                    format!("[{}]", words.join(", "))
+                };
+                println!("Consumed: {}", show(&diff.popped));
+                println!("Pushed: {}", show(&diff.pushed));
+            }
+            None => print_error(&anyhow!("No line has run successfully yet.")),
+        },
+        (Some(":last"), _, _) => print_error(&anyhow!("Usage: :last")),
+        (Some(":undo"), None, _) => match session.history.pop_back() {
+            Some((instruction, snapshot)) => {
+                runtime.restore(snapshot);
+                print_info(&format!(
+                    "Undid {}",
+                    printer::print_instruction(&instruction).trim_end()
+                ));
+            }
+            None => print_error(&anyhow!("Nothing to undo.")),
+        },
+        (Some(":undo"), _, _) => print_error(&anyhow!("Usage: :undo")),
+        (Some(":doc"), Some(f), None) => match runtime.arg_count(f) {
+            Some(arg_count) => print!(
+                "{}",
+                printer::print_doc(f, arg_count, runtime.results(f), runtime.doc(f))
+            ),
+            None if runtime.is_defined(f) => println!("'{f}' is a built-in function."),
+            None => print_error(&anyhow!("Function '{f}' is not defined.")),
+        },
+        (Some(":doc"), _, _) => print_error(&anyhow!("Usage: :doc <function>")),
+        (Some(":functions"), None, _) => {
+            for (f, arg_count) in runtime.functions() {
+                println!("{f} ({arg_count})");
+            }
+        }
+        (Some(":functions"), _, _) => print_error(&anyhow!("Usage: :functions")),
+        (Some(c), _, _) => print_error(&anyhow!("Unknown command '{c}'.")),
+        (None, _, _) => {}
+    }
+}
+
+/// Sends the interpreter's logs to stderr.
+fn init_logging(level: tracing::Level, json: bool) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .with_ansi(colored::control::SHOULD_COLORIZE.should_colorize());
+    if json {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+}
+
+/// Exits with a usage error if the options do not make sense together, in ways that clap cannot check.
+fn check_options(options: &RunOptions) {
+    // Between top-level instructions, the instruction stack is always empty
+    if options.pending.is_some() && options.debug == Some(DebugLevel::Line) {
+        CliArgs::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--pending needs --debug=instruction or --debug=call.",
+            )
+            .exit();
+    }
+}
+
+fn run_files(args: &RunArgs) {
+    if let Some(format) = args.emit {
+        match run_emit(args, format) {
+            Err(e) => exit_with_error(&e),
+            Ok(output) => println!("{output}"),
+        }
+    } else if args.watch {
+        if let Err(e) = run_watch(args) {
+            exit_with_error(&e);
+        }
+    } else {
+        match read_program(args) {
+            Err(e) => exit_with_error(&e),
+            Ok(program) => {
+                if !run_from_program(program, &args.options, None) {
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+fn run_emit(args: &RunArgs, format: EmitFormat) -> Result<String, Error> {
+    let mut program = match read_program(args)? {
+        Program::Source(reader) => parse_all(&mut source_parser(reader, &args.options))?,
+        Program::Parsed(program) => program,
+    };
+    if args.options.opt {
+        check_opt(&args.options)?;
+        program = optimize_program(program);
+    }
+    match format {
+        EmitFormat::Json => Ok(json::encode(&program)),
+    }
+}
+
+/// The instructions of a program, either still to be parsed or already parsed (like the ones from `--run-json`).
+enum Program {
+    Source(FileReader),
+    Parsed(Vec<parser::Instruction>),
+}
+
+/// Reads the files as source code, or as lists of instructions in JSON with `--run-json`.
+fn read_program(args: &RunArgs) -> Result<Program, Error> {
+    if !args.run_json {
+        return Ok(Program::Source(FileReader::new(args.files.clone())?));
+    }
+    let mut program = Vec::new();
+    for path in &args.files {
+        let context = || format!("Failed to read '{}'.", path.display());
+        let json = std::fs::read_to_string(path).with_context(context)?;
+        program.extend(json::decode(&json).with_context(context)?);
+    }
+    Ok(Program::Parsed(program))
+}
+
+fn source_parser(reader: FileReader, args: &RunOptions) -> parser::Parser<Lexer<FileReader>> {
+    parser::Parser::new(Lexer::new(reader).with_word_size(args.word_size))
+        .with_args_order(args.args_order)
+}
+
+fn check_opt(args: &RunOptions) -> Result<(), Error> {
+    match args.word_size {
+        WordSize::Bits32 => Ok(()),
+        _ => Err(anyhow!("--opt only supports 32-bit words.")),
+    }
+}
+
+fn optimize_program(program: Vec<parser::Instruction>) -> Vec<parser::Instruction> {
+    let before = program.len();
+    let program = optimizer::optimize(program);
+    print_verbose(
+        1,
+        &format!(
+            "Optimized the program from {before} to {} top-level instruction(s).",
+            program.len()
+        ),
+    );
+    program
+}
+
+fn run_from_files(args: &RunArgs, hot_reload: Option<&HotReload>) {
+    match read_program(args) {
+        Err(e) => print_error(&e),
+        // Watch mode keeps going after a failed run
+        Ok(program) => {
+            run_from_program(program, &args.options, hot_reload);
+        }
+    }
+}
+
+/// Returns true iff the program completed successfully (possibly by calling EXIT). The runtime is dropped before this
+/// returns, so the caller can exit right away.
+fn run_from_program(program: Program, args: &RunOptions, hot_reload: Option<&HotReload>) -> bool {
+    if args.opt {
+        if let Err(e) = check_opt(args) {
+            exit_with_error(&e);
+        }
+    }
+    let replay = match (&args.record, &args.replay) {
+        (Some(_), _) => Replay::record(),
+        (None, Some(path)) => match read_replay(path) {
+            Err(e) => exit_with_error(&e),
+            Ok(replay) => replay,
+        },
+        (None, None) => Replay::Off,
+    };
+    let mut runtime = Runtime::new()
+        .with_word_size(args.word_size)
+        .with_print_policy(args.print_policy)
+        .with_sandbox(args.sandbox)
+        .with_replay(replay);
+    for path in &args.plugin {
+        match plugin::load(path, &mut runtime) {
+            Err(e) => exit_with_error(&e),
+            Ok(n) => print_verbose(
+                1,
+                &format!("Loaded {n} function(s) from plugin '{}'.", path.display()),
+            ),
+        }
+    }
+    let mut recorder = args.viz.as_ref().map(|_| viz::Recorder::default());
+    let start = std::time::Instant::now();
+    let completed = run_program(program, args, &mut runtime, recorder.as_mut(), hot_reload);
+    print_verbose(1, &format!("Ran for {:.3?}.", start.elapsed()));
+    if let (Some(path), Some(recorder)) = (&args.viz, recorder) {
+        let chart = match path.extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("svg") => recorder.to_svg(),
+            _ => recorder.to_csv(),
+        };
+        if let Err(e) = std::fs::write(path, chart) {
+            print_error(
+                &anyhow!(e).context(format!("Failed to write chart '{}'.", path.display())),
+            );
+        }
+    }
+    for e in runtime.join_processes() {
+        print_error(&e);
+    }
+    if args.mem_report {
+        eprintln!("{}", runtime.memory_stats());
+    }
+    if let Some(path) = &args.record {
+        let recording = replay::to_string(&runtime.take_recording());
+        if let Err(e) = std::fs::write(path, recording) {
+            print_error(
+                &anyhow!(e).context(format!("Failed to write recording '{}'.", path.display())),
+            );
+        }
+    }
+
+    if args.exit_status_from_stack {
+        let status = if completed {
+            exit_status_from_stack(&runtime).unwrap_or_else(|e| {
+                print_error(&e);
+                1
+            })
+        } else {
+            1
+        };
+        // Exiting skips destructors, so clean up the runtime (e.g., turn off raw mode) first
+        drop(runtime);
+        std::process::exit(status);
+    }
+    completed
+}
+
+fn read_replay(path: &std::path::Path) -> Result<Replay, Error> {
+    let context = || format!("Failed to read recording '{}'.", path.display());
+    let recording = std::fs::read_to_string(path).with_context(context)?;
+    Replay::play(&recording).with_context(context)
+}
+
+/// Returns the top data word modulo 256.
+fn exit_status_from_stack(runtime: &Runtime) -> Result<i32, Error> {
+    const ERR: &str = "--exit-status-from-stack needs a data word on top of the stack";
+    match runtime.stack().last() {
+        None => Err(anyhow!("{ERR}, but the stack is empty.")),
+        Some(Word::Function(f)) => Err(anyhow!("{ERR}, but the top is the function '{f}'.")),
+        Some(Word::Marker) => Err(anyhow!("{ERR}, but the top is a marker.")),
+        Some(Word::Data(n)) => Ok((n % 256) as i32),
+        #[cfg(feature = "bigint")]
+        Some(Word::Big(n)) => Ok((n % 256u32).iter_u32_digits().next().unwrap_or(0) as i32),
+    }
+}
+
+/// Returns true iff the program completed successfully (possibly by calling EXIT).
+fn run_program(
+    program: Program,
+    args: &RunOptions,
+    runtime: &mut Runtime,
+    mut recorder: Option<&mut viz::Recorder>,
+    hot_reload: Option<&HotReload>,
+) -> bool {
+    let mut view = DebugView {
+        pending: args.pending,
+        last_stack: args.debug_diff.then(|| runtime.stack().to_vec()),
+    };
+    let program = match program {
+        Program::Source(reader) if args.opt => match parse_all(&mut source_parser(reader, args)) {
             Err(e) => {
                 print_error(&e);
-                return;
+                return false;
             }
-            Ok(None) => break,
-            Ok(Some(x)) => x,
+            Ok(program) => Program::Parsed(program),
+        },
+        program => program,
+    };
+    let instructions: Box<dyn Iterator<Item = Result<parser::Instruction, Error>>> = match program {
+        Program::Source(reader) => {
+            let mut parser = source_parser(reader, args);
+            Box::new(std::iter::from_fn(move || {
+                parser.next_instruction().transpose()
+            }))
+        }
+        Program::Parsed(program) if args.opt => {
+            Box::new(optimize_program(program).into_iter().map(Ok))
+        }
+        Program::Parsed(program) => Box::new(program.into_iter().map(Ok)),
+    };
+
+    for instruction in instructions {
+        let instruction = match instruction {
+            Err(e) => {
+                print_error(&e);
+                return false;
+            }
+            Ok(x) => x,
         };
 
-        let should_exit = match runtime.run(instruction) {
+        print_verbose(
+            2,
+            &format!("Running {}", printer::print_instruction(&instruction)),
+        );
+        let result = run_instruction(
+            runtime,
+            instruction,
+            args.debug,
+            &mut view,
+            recorder.as_deref_mut(),
+            hot_reload,
+        );
+        print_assertion_failures(runtime);
+        let should_exit = match result {
             Err(e) => {
                 print_error(&e);
-                return;
+                return false;
             }
             Ok(x) => x,
         };
@@ -97,27 +1239,384 @@ fn run_from_files(args: CliArgs) {
             break;
         }
 
-        if args.debug {
-            print_debug(&runtime.stack_to_string());
+        if args.debug == Some(DebugLevel::Line) {
+            print_stack(runtime, &mut view);
         }
     }
 
+    if args.warn_uncalled {
+        warn_uncalled(runtime);
+    }
+    if let Some(limit) = args.print_stack {
+        print_final_stack(runtime, limit, args.stack_format);
+    }
     print_info("Program completed successfully.");
+    true
 }
 
-fn print_error(e: &Error) {
-    let mut msg = format!("{e}");
-    for cause in e.chain().skip(1) {
-        msg += &format!("\n\nCaused by:\n    {cause}");
+/// Warns about every function left on the stack, once each, starting at the top.
+fn warn_uncalled(runtime: &Runtime) {
+    let mut reported = Vec::new();
+    for word in runtime.stack().iter().rev() {
+        if let Word::Function(f) = word {
+            if !reported.contains(&f) {
+                print_warning(&lint::uncalled_function(f).to_string());
+                reported.push(f);
+            }
+        }
     }
+}
 
-    eprintln!("{}", msg.bold().red());
+fn print_final_stack(runtime: &Runtime, limit: Option<usize>, format: StackFormat) {
+    let words = runtime
+        .stack()
+        .iter()
+        .rev()
+        .take(limit.unwrap_or(usize::MAX));
+    match format {
+        StackFormat::Human => {
+            for word in words {
+                println!("{word}");
+            }
+        }
+        StackFormat::Json => {
+            let words = words
+                .map(|word| match word {
+                    Word::Data(n) => serde_json::json!(n),
+                    Word::Function(f) => serde_json::json!({ "function": f }),
+                    Word::Marker => serde_json::json!({ "marker": true }),
+                    #[cfg(feature = "bigint")]
+                    Word::Big(n) => serde_json::json!(n.to_string()),
+                })
+                .collect::<Vec<_>>();
+            println!("{}", serde_json::Value::Array(words));
+        }
+    }
 }
 
-fn print_info(msg: &str) {
-    println!("{}", msg.bold());
+/// Runs a top-level instruction, printing the stack after each step if the debug level asks for it, recording the
+/// depths after each step for `--viz`, and checking for changes to the files after each step for `--hot-reload`.
+/// Returns true iff the program should exit.
+fn run_instruction(
+    runtime: &mut Runtime,
+    instruction: parser::Instruction,
+    debug: Option<DebugLevel>,
+    view: &mut DebugView,
+    mut recorder: Option<&mut viz::Recorder>,
+    hot_reload: Option<&HotReload>,
+) -> Result<bool, Error> {
+    if (debug.is_none() || debug == Some(DebugLevel::Line))
+        && recorder.is_none()
+        && hot_reload.is_none()
+    {
+        return runtime.run(instruction);
+    }
+
+    runtime.push_instruction(instruction);
+    loop {
+        match runtime.step()? {
+            Step::Done => return Ok(false),
+            Step::Exit => return Ok(true),
+            Step::Ran { called } => {
+                if let Some(recorder) = recorder.as_deref_mut() {
+                    recorder.record(runtime.stack_size(), runtime.call_stack().count());
+                }
+                if called && debug == Some(DebugLevel::Call)
+                    || debug == Some(DebugLevel::Instruction)
+                {
+                    print_stack(runtime, view);
+                }
+                if let Some(hot_reload) = hot_reload {
+                    hot_reload.check(runtime);
+                }
+            }
+        }
+    }
+}
+
+/// Runs the files, then runs them again from scratch every time one of them changes.
+fn run_watch(args: &RunArgs) -> Result<(), Error> {
+    let files = args
+        .files
+        .iter()
+        .map(|f| f.canonicalize())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!(e).context("Failed to read file."))?;
+
+    // Watch the directories rather than the files themselves, since many editors save by replacing the file
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    for file in &files {
+        if let Some(dir) = file.parent() {
+            watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
+        }
+    }
+
+    loop {
+        // Clear the screen
+        print!("\x1b[2J\x1b[H");
+        let hot_reload = args.hot_reload.then_some(HotReload {
+            files: &files,
+            events: &receiver,
+            options: &args.options,
+        });
+        run_from_files(args, hot_reload.as_ref());
+        print_info("Waiting for changes...");
+
+        while !is_change(&receiver.recv()??, &files) {}
+        settle(&receiver);
+    }
+}
+
+/// Whether a file event means that one of the files changed.
+fn is_change(event: &notify::Event, files: &[std::path::PathBuf]) -> bool {
+    !event.kind.is_access() && event.paths.iter().any(|p| files.contains(p))
+}
+
+/// Saving a file often triggers several events in a row, so this waits for them to settle and drops them.
+fn settle(events: &Receiver<notify::Result<notify::Event>>) {
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    while events.try_recv().is_ok() {}
+}
+
+/// For `--hot-reload`, the files of a program that is running, and the events that say when they change.
+struct HotReload<'a> {
+    files: &'a [std::path::PathBuf],
+    events: &'a Receiver<notify::Result<notify::Event>>,
+    options: &'a RunOptions,
+}
+
+impl HotReload<'_> {
+    /// Swaps the functions that changed into the runtime if any of the files changed since the last check.
+    fn check(&self, runtime: &mut Runtime) {
+        let mut changed = false;
+        while let Ok(event) = self.events.try_recv() {
+            changed |= event.is_ok_and(|event| is_change(&event, self.files));
+        }
+        if !changed {
+            return;
+        }
+        settle(self.events);
+        let program = FileReader::new(self.files.to_vec())
+            .and_then(|reader| parse_all(&mut source_parser(reader, self.options)));
+        match program {
+            Err(e) => print_error(&e.context("Failed to reload the files.")),
+            Ok(program) => print_info(&reload::reload(runtime, program).to_string()),
+        }
+    }
+}
+
+fn run_compile(
+    files: Vec<std::path::PathBuf>,
+    output: Option<std::path::PathBuf>,
+) -> Result<(), Error> {
+    let output = output.unwrap_or_else(|| files[0].with_extension("platesc"));
+    let instructions = parse_files(files)?;
+    std::fs::write(output, bytecode::encode(&instructions))
+        .map_err(|e| anyhow!(e).context("Failed to write file."))
+}
+
+fn run_disasm(file: &std::path::Path) -> Result<String, Error> {
+    let bytes = std::fs::read(file).map_err(|e| anyhow!(e).context("Failed to read file."))?;
+    let instructions = bytecode::decode(&bytes)?;
+    Ok(printer::print_instructions(&instructions))
+}
+
+/// Describes every function defined at the top level of a program. Only the last definition of each function counts.
+fn run_doc(instructions: &[parser::Instruction]) -> String {
+    let definitions = instructions
+        .iter()
+        .filter_map(|i| match i {
+            parser::Instruction::Define(f, arg_count, _, attributes) => {
+                Some((f, arg_count, attributes))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    definitions
+        .iter()
+        .enumerate()
+        .filter(|(i, (f, _, _))| definitions[i + 1..].iter().all(|(g, _, _)| g != f))
+        .map(|(_, (f, arg_count, attributes))| {
+            printer::print_doc(
+                f,
+                **arg_count,
+                attributes.results,
+                attributes.doc.as_deref(),
+            )
+        })
+        .collect()
+}
+
+fn run_build(
+    files: Vec<std::path::PathBuf>,
+    output: Option<std::path::PathBuf>,
+    source_only: bool,
+    backend: codegen::Backend,
+) -> Result<(), Error> {
+    let output = match output {
+        Some(o) => o,
+        None if source_only => files[0].with_extension(backend.source_extension()),
+        None => files[0].with_extension(std::env::consts::EXE_EXTENSION),
+    };
+    let program = codegen::lower(&parse_files(files)?)?;
+    let source = backend.generate(&program)?;
+
+    if source_only {
+        return std::fs::write(output, source)
+            .map_err(|e| anyhow!(e).context("Failed to write file."));
+    }
+
+    let dir = std::env::temp_dir().join(format!("plates-build-{}", std::process::id()));
+    let source_file = dir.join("main").with_extension(backend.source_extension());
+    std::fs::create_dir_all(&dir)
+        .and_then(|_| std::fs::write(&source_file, source))
+        .map_err(|e| anyhow!(e).context("Failed to write file."))?;
+    let result = backend.compile(&source_file, &output);
+    // Clean up even if compilation failed
+    let _ = std::fs::remove_dir_all(dir);
+    result
+}
+
+/// Returns true iff all the tests passed.
+fn run_tests(files: Vec<std::path::PathBuf>) -> Result<bool, Error> {
+    let program = parse_files(files)?;
+    let results = test_runner::run_tests(&program);
+
+    for result in &results {
+        if result.passed() {
+            println!("test {} ... {}", result.name, "ok".green());
+        } else {
+            println!("test {} ... {}", result.name, "FAILED".red());
+            for failure in &result.failures {
+                println!("    {failure}");
+            }
+        }
+    }
+
+    let failed = results.iter().filter(|r| !r.passed()).count();
+    print_info(&format!(
+        "{} passed; {} failed.",
+        results.len() - failed,
+        failed
+    ));
+    Ok(failed == 0)
+}
+
+/// Parses the given files and reports every syntax error, rather than stopping at the first one. If there are none,
+/// also reports any warnings. Returns true iff there were no errors.
+fn run_check(files: Vec<std::path::PathBuf>, diagnostics: &mut lint::Diagnostics) -> bool {
+    let mut parser = match FileReader::new(files) {
+        Err(e) => {
+            print_error(&e);
+            return false;
+        }
+        Ok(reader) => parser::Parser::new(Lexer::new(reader)),
+    };
+
+    let mut program = Vec::new();
+    let mut errors = 0;
+    loop {
+        match parser.next_instruction() {
+            Ok(None) => break,
+            Ok(Some(instruction)) => program.push(instruction),
+            Err(e) => {
+                print_error(&e);
+                errors += 1;
+                parser.recover();
+            }
+        }
+    }
+    if errors > 0 {
+        print_info(&format!("Found {errors} error(s)."));
+        return false;
+    }
+
+    for (lint, message) in parser.take_warnings() {
+        diagnostics.warn(lint, message);
+    }
+    lint::check(&program, diagnostics);
+    for warning in diagnostics.warnings() {
+        match warning.level {
+            lint::Level::Deny => print_error(&anyhow!("{warning}")),
+            _ => print_warning(&warning.to_string()),
+        }
+    }
+    match diagnostics.warnings().len() {
+        0 => print_info("No errors found."),
+        n => print_info(&format!("Found {n} warning(s).")),
+    }
+    !diagnostics.has_errors()
+}
+
+/// Parses the given files in their entirety without running them.
+fn parse_files(files: Vec<std::path::PathBuf>) -> Result<Vec<parser::Instruction>, Error> {
+    let reader = FileReader::new(files)?;
+    parse_all(&mut parser::Parser::new(Lexer::new(reader)))
+}
+
+fn run_xref(files: Vec<std::path::PathBuf>) -> Result<String, Error> {
+    let reader = FileReader::new(files)?;
+    let mut parser = parser::Parser::new(Lexer::new(reader)).with_references();
+    parse_all(&mut parser)?;
+    Ok(xref::listing(&parser.take_references()))
+}
+
+fn run_rename(old: &str, new: &str, files: Vec<std::path::PathBuf>) -> Result<(), Error> {
+    let reader = FileReader::new(files)?;
+    let mut parser = parser::Parser::new(Lexer::new(reader)).with_references();
+    parse_all(&mut parser)?;
+    let replacements = rename::plan(&parser.take_references(), old, new)?;
+    let changed = rename::rewrite_files(&replacements)?;
+    print_info(&format!(
+        "Renamed {} occurrence(s) of '{old}' in {changed} file(s).",
+        replacements.len()
+    ));
+    Ok(())
+}
+
+fn parse_all(
+    parser: &mut parser::Parser<Lexer<FileReader>>,
+) -> Result<Vec<parser::Instruction>, Error> {
+    let mut instructions = Vec::new();
+    while let Some(instruction) = parser.next_instruction()? {
+        instructions.push(instruction);
+    }
+    Ok(instructions)
+}
+
+fn print_assertion_failures(runtime: &mut Runtime) {
+    for failure in runtime.take_assertion_failures() {
+        print_error(&anyhow!(failure));
+    }
+}
+
+/// What `--debug` shows besides the stack.
+struct DebugView {
+    /// The number of entries of the instruction stack to show (`--pending`).
+    pending: Option<usize>,
+    /// With `--debug-diff`, the stack as it was last shown, so that only the changes since then are shown.
+    last_stack: Option<Vec<Word>>,
 }
 
-fn print_debug(msg: &str) {
-    println!("{}", msg.italic().truecolor(128, 128, 128));
+/// Prints the stack for `--debug` (or the changes to it for `--debug-diff`), along with the top entries of the
+/// instruction stack if requested. Very deep stacks are truncated.
+fn print_stack(runtime: &Runtime, view: &mut DebugView) {
+    const LIMIT: usize = 20;
+    match &mut view.last_stack {
+        Some(last_stack) => {
+            println!("{}", StackDiff::new(last_stack, runtime.stack()));
+            *last_stack = runtime.stack().to_vec();
+        }
+        None => {
+            let colors = colored::control::SHOULD_COLORIZE.should_colorize();
+            println!("{}", runtime.stack_to_debug_string(LIMIT, colors));
+        }
+    }
+    if let Some(n) = view.pending {
+        print_debug(&runtime.pending_to_debug_string(n));
+    }
+    if view.last_stack.is_none() || view.pending.is_some() {
+        println!();
+    }
 }
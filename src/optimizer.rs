@@ -0,0 +1,674 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::parser::Instruction;
+
+/// Functions whose bodies are longer than this are never inlined, so that inlining does not blow up the program.
+const INLINE_LIMIT: usize = 16;
+
+/// Rewrites a whole program into an equivalent one that does less work at run time. Words are assumed to be 32 bits.
+///
+/// Each block (the top level, and each function body) is optimized separately:
+/// - Instructions after an `EXIT` are removed, since they can never run.
+/// - `PUSH 0 PUSH f CALLIF` is removed, since the function is never called.
+/// - Calls to built-in functions whose operands are all literals are replaced by the result.
+/// - Unconditional calls to small functions that only call built-in functions are replaced by the body of the
+///   function, with the arguments substituted in. The arguments must be literals.
+///
+/// Calling a function replaces the arguments, so calls are only removed if the arguments cannot be read before the
+/// next call. These rules are applied repeatedly until nothing changes.
+pub fn optimize(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let context = Context::new(&instructions);
+
+    let mut defined = HashSet::new();
+    let top_level = instructions
+        .into_iter()
+        .map(|instruction| match instruction {
+            Instruction::Define(f, arg_count, body, attributes) => {
+                let body = optimize_block(body, &context, &Scope::Function(&defined));
+                defined.insert(f.clone());
+                Instruction::Define(f, arg_count, body, attributes)
+            }
+            i => i,
+        })
+        .collect();
+
+    optimize_block(top_level, &context, &Scope::TopLevel)
+}
+
+/// Facts about the whole program.
+struct Context {
+    /// Whether any function reads an argument after a `CALLIF`, which means that it can see the arguments of
+    /// whichever function was called last.
+    reads_args_after_call: bool,
+    /// The functions that can be inlined, with their argument count and body.
+    inlinable: HashMap<String, (u32, Vec<Instruction>)>,
+}
+
+impl Context {
+    fn new(instructions: &[Instruction]) -> Context {
+        let mut reads_args_after_call = false;
+        let mut definitions = HashMap::<&str, Vec<(u32, &Vec<Instruction>, bool)>>::new();
+        for instruction in instructions {
+            if let Instruction::Define(f, arg_count, body, attributes) = instruction {
+                definitions
+                    .entry(f)
+                    .or_default()
+                    .push((*arg_count, body, attributes.private));
+                reads_args_after_call |= body
+                    .iter()
+                    .skip_while(|i| **i != Instruction::CallIf)
+                    .any(|i| matches!(i, Instruction::PushArg(_)));
+            }
+        }
+
+        let inlinable = definitions
+            .into_iter()
+            .filter_map(|(f, definitions)| match definitions[..] {
+                // Inlining a private function would skip the check that its caller is allowed to call it
+                [(arg_count, body, false)] if is_inlinable(arg_count, body) => {
+                    Some((f.to_owned(), (arg_count, body.clone())))
+                }
+                _ => None,
+            })
+            .collect();
+
+        Context {
+            reads_args_after_call,
+            inlinable,
+        }
+    }
+}
+
+/// Returns true iff a function can be inlined. It must be short, it must not call any custom functions (which also
+/// rules out recursion), and it must only read its own arguments.
+fn is_inlinable(arg_count: u32, body: &[Instruction]) -> bool {
+    let first_call = body
+        .iter()
+        .position(|i| *i == Instruction::CallIf)
+        .unwrap_or(body.len());
+
+    body.len() <= INLINE_LIMIT
+        && body
+            .iter()
+            .enumerate()
+            .all(|(i, instruction)| match instruction {
+                Instruction::PushFunction(f) => f.starts_with("__"),
+                Instruction::PushArg(n) => i < first_call && *n < arg_count as usize,
+                _ => true,
+            })
+}
+
+/// Where a block of code is.
+enum Scope<'a> {
+    TopLevel,
+    /// The body of a function, with the names of the functions defined before it.
+    Function(&'a HashSet<String>),
+}
+
+fn optimize_block(
+    mut block: Vec<Instruction>,
+    context: &Context,
+    scope: &Scope,
+) -> Vec<Instruction> {
+    while remove_after_exit(&mut block)
+        || remove_false_conditions(&mut block)
+        || fold_builtin_calls(&mut block, context, scope)
+        || inline_calls(&mut block, context, scope)
+    {}
+
+    block
+}
+
+/// Removes everything after the first `EXIT`. Returns true iff something was removed.
+fn remove_after_exit(block: &mut Vec<Instruction>) -> bool {
+    match block.iter().position(|x| *x == Instruction::Exit) {
+        Some(i) if i + 1 < block.len() => {
+            block.truncate(i + 1);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Removes the first `PUSH 0 PUSH f CALLIF`. Returns true iff something was removed.
+fn remove_false_conditions(block: &mut Vec<Instruction>) -> bool {
+    let position = block.windows(3).position(|window| {
+        matches!(
+            window,
+            [
+                Instruction::PushData(0),
+                Instruction::PushFunction(_),
+                Instruction::CallIf
+            ]
+        )
+    });
+
+    match position {
+        None => false,
+        Some(i) => {
+            block.drain(i..i + 3);
+            true
+        }
+    }
+}
+
+/// If `block[end]` is a `CALLIF` with a literal nonzero condition, returns the function being called.
+fn unconditional_call(block: &[Instruction], end: usize) -> Option<&str> {
+    match (&block[..end], &block[end]) {
+        ([.., Instruction::PushData(n), Instruction::PushFunction(f)], Instruction::CallIf)
+            if *n != 0 =>
+        {
+            Some(f)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the `count` literals pushed just before the condition of the call at `block[end]`, or `None` if they are
+/// not all literals.
+fn literal_operands(block: &[Instruction], end: usize, count: usize) -> Option<&[Instruction]> {
+    let operands = &block[(end - 2).checked_sub(count)?..end - 2];
+    operands
+        .iter()
+        .all(|i| matches!(i, Instruction::PushData(_) | Instruction::PushFunction(_)))
+        .then_some(operands)
+}
+
+/// Returns true iff the call at `block[end]` can be removed without anyone noticing that it did not replace the
+/// arguments. That is the case if the arguments are not read in the rest of the block and they cannot be read once
+/// the block is done (either because the block is the top level, because no function reads arguments after a call,
+/// or because another call in the block replaces the arguments anyway).
+fn can_remove_call(block: &[Instruction], end: usize, context: &Context, scope: &Scope) -> bool {
+    let rest = &block[end + 1..];
+    !rest.iter().any(|i| matches!(i, Instruction::PushArg(_)))
+        && (matches!(scope, Scope::TopLevel)
+            || !context.reads_args_after_call
+            || (0..rest.len()).any(|i| unconditional_call(rest, i).is_some()))
+}
+
+/// Replaces the first call to a built-in function with literal operands by its result. Returns true iff something
+/// was replaced.
+fn fold_builtin_calls(block: &mut Vec<Instruction>, context: &Context, scope: &Scope) -> bool {
+    for end in 0..block.len() {
+        let f = match unconditional_call(block, end) {
+            None => continue,
+            Some(f) => f,
+        };
+        let arity = match arity(f) {
+            None => continue,
+            Some(n) => n,
+        };
+        // The operands are ordered with the top of the stack last
+        let operands = match literal_operands(block, end, arity) {
+            None => continue,
+            Some(operands) => operands
+                .iter()
+                .map(|i| match i {
+                    Instruction::PushData(n) => u32::try_from(*n).ok(),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>(),
+        };
+        let result = match operands.and_then(|operands| evaluate(f, &operands)) {
+            None => continue,
+            Some(result) => result,
+        };
+        if !can_remove_call(block, end, context, scope) {
+            continue;
+        }
+
+        block.splice(
+            end - 2 - arity..=end,
+            result.into_iter().map(|n| Instruction::PushData(n.into())),
+        );
+        return true;
+    }
+
+    false
+}
+
+/// Replaces the first call to an inlinable function by the body of the function. Returns true iff something was
+/// replaced.
+fn inline_calls(block: &mut Vec<Instruction>, context: &Context, scope: &Scope) -> bool {
+    for end in 0..block.len() {
+        let f = match unconditional_call(block, end) {
+            None => continue,
+            Some(f) => f,
+        };
+        let (arg_count, body) = match context.inlinable.get(f) {
+            None => continue,
+            Some(x) => x,
+        };
+        // Otherwise, the call would fail because the function is not defined yet
+        let defined = match scope {
+            Scope::TopLevel => block[..end]
+                .iter()
+                .any(|i| matches!(i, Instruction::Define(g, _, _, _) if g == f)),
+            Scope::Function(defined) => defined.contains(f),
+        };
+        if !defined {
+            continue;
+        }
+        // The first argument is the top of the stack, so it is the last operand
+        let args = match literal_operands(block, end, *arg_count as usize) {
+            None => continue,
+            Some(operands) => operands.iter().rev().cloned().collect::<Vec<_>>(),
+        };
+        if !can_remove_call(block, end, context, scope) {
+            continue;
+        }
+
+        let body = body
+            .iter()
+            .map(|i| match i {
+                Instruction::PushArg(n) => args[*n].clone(),
+                i => i.clone(),
+            })
+            .collect::<Vec<_>>();
+        block.splice(end - 2 - args.len()..=end, body);
+        return true;
+    }
+
+    false
+}
+
+/// Returns the number of operands of a built-in function that can be evaluated at compile time.
+fn arity(f: &str) -> Option<usize> {
+    match f {
+        "__birl__" => Some(2),
+        "__inc__" | "__dec__" | "__inc_sat__" | "__dec_sat__" | "__not__" => Some(1),
+        "__and__" | "__or__" | "__xor__" | "__shl_n__" | "__shr_n__" | "__add_carry__"
+        | "__sub_borrow__" => Some(2),
+        _ => None,
+    }
+}
+
+/// Evaluates a built-in function. The operands are ordered from the bottom of the stack to the top, and so is the
+/// result. Returns `None` if the function would fail.
+pub(crate) fn evaluate(f: &str, operands: &[u32]) -> Option<Vec<u32>> {
+    match (f, operands) {
+        ("__birl__", [b, a]) => Some(vec![(!a | b).rotate_left(1)]),
+        ("__inc__", [n]) => Some(vec![n.wrapping_add(1)]),
+        ("__dec__", [n]) => Some(vec![n.wrapping_sub(1)]),
+        ("__inc_sat__", [n]) => Some(vec![n.saturating_add(1)]),
+        ("__dec_sat__", [n]) => Some(vec![n.saturating_sub(1)]),
+        ("__and__", [b, a]) => Some(vec![b & a]),
+        ("__or__", [b, a]) => Some(vec![b | a]),
+        ("__xor__", [b, a]) => Some(vec![b ^ a]),
+        ("__not__", [n]) => Some(vec![!n]),
+        ("__shl_n__", [n, count]) => n.checked_shl(*count).map(|result| vec![result]),
+        ("__shr_n__", [n, count]) => n.checked_shr(*count).map(|result| vec![result]),
+        ("__add_carry__", [b, a]) => {
+            let (sum, carry) = b.overflowing_add(*a);
+            Some(vec![sum, carry.into()])
+        }
+        ("__sub_borrow__", [b, a]) => {
+            let (difference, borrow) = b.overflowing_sub(*a);
+            Some(vec![difference, borrow.into()])
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::optimize;
+    use crate::parser::{Attributes, Instruction};
+
+    macro_rules! test_optimize {
+        ( $( $name:ident: ($before:expr, $after:expr) ),* $(,)? ) => {
+            $(
+                #[test]
+                fn $name() {
+                    assert_eq!($after, optimize($before));
+                }
+            )*
+        };
+    }
+
+    fn push_f(f: &str) -> Instruction {
+        Instruction::PushFunction(f.to_owned())
+    }
+
+    test_optimize![
+        unchanged: (
+            vec![
+                Instruction::PushRandom,
+                Instruction::PushData(1),
+                push_f("foo"),
+                Instruction::CallIf,
+            ],
+            vec![
+                Instruction::PushRandom,
+                Instruction::PushData(1),
+                push_f("foo"),
+                Instruction::CallIf,
+            ]
+        ),
+        after_exit: (
+            vec![Instruction::PushData(1), Instruction::Exit, Instruction::PushData(2), Instruction::Exit],
+            vec![Instruction::PushData(1), Instruction::Exit]
+        ),
+        false_condition: (
+            vec![
+                Instruction::PushData(5),
+                Instruction::PushData(0),
+                push_f("undefined"),
+                Instruction::CallIf,
+                Instruction::PushData(6),
+            ],
+            vec![Instruction::PushData(5), Instruction::PushData(6)]
+        ),
+        false_condition_with_argument_function: (
+            vec![Instruction::PushData(0), Instruction::PushArg(0), Instruction::CallIf],
+            vec![Instruction::PushData(0), Instruction::PushArg(0), Instruction::CallIf]
+        ),
+        fold_birl: (
+            vec![
+                Instruction::PushData(0b100010),
+                Instruction::PushData(0b101000),
+                Instruction::PushData(1),
+                push_f("__birl__"),
+                Instruction::CallIf,
+            ],
+            vec![Instruction::PushData(0b11111111_11111111_11111111_11101111)]
+        ),
+        fold_repeatedly: (
+            // The result of __birl__ is zero, so foo is never called
+            vec![
+                Instruction::PushData(7),
+                Instruction::PushData(0),
+                Instruction::PushData(0xFFFFFFFF),
+                Instruction::PushData(1),
+                push_f("__birl__"),
+                Instruction::CallIf,
+                push_f("foo"),
+                Instruction::CallIf,
+            ],
+            vec![Instruction::PushData(7)]
+        ),
+        fold_inc_dec: (
+            vec![
+                Instruction::PushData(0xFFFFFFFF),
+                Instruction::PushData(1),
+                push_f("__inc__"),
+                Instruction::CallIf,
+                Instruction::PushData(1),
+                push_f("__dec_sat__"),
+                Instruction::CallIf,
+            ],
+            vec![Instruction::PushData(0)]
+        ),
+        fold_shift: (
+            vec![
+                Instruction::PushData(3),
+                Instruction::PushData(4),
+                Instruction::PushData(1),
+                push_f("__shl_n__"),
+                Instruction::CallIf,
+            ],
+            vec![Instruction::PushData(48)]
+        ),
+        no_fold_with_failing_shift: (
+            vec![
+                Instruction::PushData(3),
+                Instruction::PushData(32),
+                Instruction::PushData(1),
+                push_f("__shr_n__"),
+                Instruction::CallIf,
+            ],
+            vec![
+                Instruction::PushData(3),
+                Instruction::PushData(32),
+                Instruction::PushData(1),
+                push_f("__shr_n__"),
+                Instruction::CallIf,
+            ]
+        ),
+        fold_add_carry: (
+            vec![
+                Instruction::PushData(0xFFFFFFFF),
+                Instruction::PushData(2),
+                Instruction::PushData(1),
+                push_f("__add_carry__"),
+                Instruction::CallIf,
+            ],
+            vec![Instruction::PushData(1), Instruction::PushData(1)]
+        ),
+        no_fold_with_function_operand: (
+            vec![
+                push_f("foo"),
+                Instruction::PushData(1),
+                Instruction::PushData(1),
+                push_f("__birl__"),
+                Instruction::CallIf,
+            ],
+            vec![
+                push_f("foo"),
+                Instruction::PushData(1),
+                Instruction::PushData(1),
+                push_f("__birl__"),
+                Instruction::CallIf,
+            ]
+        ),
+        no_fold_with_missing_operand: (
+            vec![Instruction::PushData(1), Instruction::PushData(1), push_f("__birl__"), Instruction::CallIf],
+            vec![Instruction::PushData(1), Instruction::PushData(1), push_f("__birl__"), Instruction::CallIf]
+        ),
+        fold_inside_definition: (
+            vec![Instruction::Define(
+                "f".to_owned(),
+                0,
+                vec![Instruction::PushData(0), push_f("g"), Instruction::CallIf, Instruction::Exit, Instruction::PushData(1)],
+                Attributes::default()
+            )],
+            vec![Instruction::Define("f".to_owned(), 0, vec![Instruction::Exit], Attributes::default())]
+        ),
+        // Calling __birl__ clears the arguments, so $0 is undefined afterwards
+        no_fold_before_argument: (
+            vec![Instruction::Define(
+                "f".to_owned(),
+                1,
+                vec![
+                    Instruction::PushData(1),
+                    Instruction::PushData(2),
+                    Instruction::PushData(1),
+                    push_f("__birl__"),
+                    Instruction::CallIf,
+                    Instruction::PushArg(0),
+                ],
+                Attributes::default()
+            )],
+            vec![Instruction::Define(
+                "f".to_owned(),
+                1,
+                vec![
+                    Instruction::PushData(1),
+                    Instruction::PushData(2),
+                    Instruction::PushData(1),
+                    push_f("__birl__"),
+                    Instruction::CallIf,
+                    Instruction::PushArg(0),
+                ],
+                Attributes::default()
+            )]
+        ),
+        // g can see the arguments left behind by f, so calling __birl__ must still clear them
+        no_fold_if_arguments_read_after_call: (
+            vec![
+                Instruction::Define(
+                    "f".to_owned(),
+                    0,
+                    vec![
+                        Instruction::PushData(1),
+                        Instruction::PushData(2),
+                        Instruction::PushData(1),
+                        push_f("__birl__"),
+                        Instruction::CallIf,
+                    ],
+                    Attributes::default()
+                ),
+                Instruction::Define(
+                    "g".to_owned(),
+                    1,
+                    vec![Instruction::PushData(1), push_f("f"), Instruction::CallIf, Instruction::PushArg(0)],
+                    Attributes::default()
+                ),
+            ],
+            vec![
+                Instruction::Define(
+                    "f".to_owned(),
+                    0,
+                    vec![
+                        Instruction::PushData(1),
+                        Instruction::PushData(2),
+                        Instruction::PushData(1),
+                        push_f("__birl__"),
+                        Instruction::CallIf,
+                    ],
+                    Attributes::default()
+                ),
+                Instruction::Define(
+                    "g".to_owned(),
+                    1,
+                    vec![Instruction::PushData(1), push_f("f"), Instruction::CallIf, Instruction::PushArg(0)],
+                    Attributes::default()
+                ),
+            ]
+        ),
+        inline: (
+            vec![
+                Instruction::Define("swap".to_owned(), 2, vec![Instruction::PushArg(0), Instruction::PushArg(1)], Attributes::default()),
+                Instruction::PushData(1),
+                Instruction::PushData(2),
+                Instruction::PushData(1),
+                push_f("swap"),
+                Instruction::CallIf,
+            ],
+            vec![
+                Instruction::Define("swap".to_owned(), 2, vec![Instruction::PushArg(0), Instruction::PushArg(1)], Attributes::default()),
+                Instruction::PushData(2),
+                Instruction::PushData(1),
+            ]
+        ),
+        // The runtime must still check where private functions are called from
+        no_inline_private: (
+            vec![
+                Instruction::Define("one".to_owned(), 0, vec![Instruction::PushData(1)], Attributes { private: true, ..Attributes::default() }),
+                Instruction::PushData(1),
+                push_f("one"),
+                Instruction::CallIf,
+            ],
+            vec![
+                Instruction::Define("one".to_owned(), 0, vec![Instruction::PushData(1)], Attributes { private: true, ..Attributes::default() }),
+                Instruction::PushData(1),
+                push_f("one"),
+                Instruction::CallIf,
+            ]
+        ),
+        inline_and_fold: (
+            vec![
+                Instruction::Define(
+                    "not".to_owned(),
+                    1,
+                    vec![
+                        Instruction::PushData(0),
+                        Instruction::PushArg(0),
+                        Instruction::PushData(1),
+                        push_f("__birl__"),
+                        Instruction::CallIf,
+                    ],
+                    Attributes::default()
+                ),
+                Instruction::PushData(0xFFFFFFFF),
+                Instruction::PushData(1),
+                push_f("not"),
+                Instruction::CallIf,
+                push_f("foo"),
+                Instruction::CallIf,
+            ],
+            vec![Instruction::Define(
+                "not".to_owned(),
+                1,
+                vec![
+                    Instruction::PushData(0),
+                    Instruction::PushArg(0),
+                    Instruction::PushData(1),
+                    push_f("__birl__"),
+                    Instruction::CallIf,
+                ],
+                Attributes::default()
+            )]
+        ),
+        inline_exit: (
+            vec![
+                Instruction::Define("quit".to_owned(), 0, vec![Instruction::Exit], Attributes::default()),
+                Instruction::PushData(1),
+                push_f("quit"),
+                Instruction::CallIf,
+                Instruction::PushData(2),
+            ],
+            vec![Instruction::Define("quit".to_owned(), 0, vec![Instruction::Exit], Attributes::default()), Instruction::Exit]
+        ),
+        no_inline_before_definition: (
+            vec![
+                Instruction::PushData(1),
+                push_f("f"),
+                Instruction::CallIf,
+                Instruction::Define("f".to_owned(), 0, vec![Instruction::PushData(2)], Attributes::default()),
+            ],
+            vec![
+                Instruction::PushData(1),
+                push_f("f"),
+                Instruction::CallIf,
+                Instruction::Define("f".to_owned(), 0, vec![Instruction::PushData(2)], Attributes::default()),
+            ]
+        ),
+        no_inline_redefined: (
+            vec![
+                Instruction::Define("f".to_owned(), 0, vec![Instruction::PushData(2)], Attributes::default()),
+                Instruction::PushData(1),
+                push_f("f"),
+                Instruction::CallIf,
+                Instruction::Define("f".to_owned(), 0, vec![Instruction::PushData(3)], Attributes::default()),
+            ],
+            vec![
+                Instruction::Define("f".to_owned(), 0, vec![Instruction::PushData(2)], Attributes::default()),
+                Instruction::PushData(1),
+                push_f("f"),
+                Instruction::CallIf,
+                Instruction::Define("f".to_owned(), 0, vec![Instruction::PushData(3)], Attributes::default()),
+            ]
+        ),
+        no_inline_custom_call: (
+            vec![
+                Instruction::Define("f".to_owned(), 0, vec![Instruction::PushData(1), push_f("f"), Instruction::CallIf], Attributes::default()),
+                Instruction::PushData(1),
+                push_f("f"),
+                Instruction::CallIf,
+            ],
+            vec![
+                Instruction::Define("f".to_owned(), 0, vec![Instruction::PushData(1), push_f("f"), Instruction::CallIf], Attributes::default()),
+                Instruction::PushData(1),
+                push_f("f"),
+                Instruction::CallIf,
+            ]
+        ),
+        no_inline_random_argument: (
+            vec![
+                Instruction::Define("f".to_owned(), 1, vec![Instruction::PushArg(0), Instruction::PushArg(0)], Attributes::default()),
+                Instruction::PushRandom,
+                Instruction::PushData(1),
+                push_f("f"),
+                Instruction::CallIf,
+            ],
+            vec![
+                Instruction::Define("f".to_owned(), 1, vec![Instruction::PushArg(0), Instruction::PushArg(0)], Attributes::default()),
+                Instruction::PushRandom,
+                Instruction::PushData(1),
+                push_f("f"),
+                Instruction::CallIf,
+            ]
+        ),
+    ];
+}
@@ -1,24 +1,152 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    str::FromStr,
+};
+
 use anyhow::{anyhow, Error};
+use tracing::debug;
+
+use crate::{
+    lexer::{Token, TokenStream},
+    lint::Lint,
+};
 
-use crate::lexer::{Token, TokenStream};
+/// How many macros can be expanded inside each other before giving up, since a macro that invokes itself would
+/// otherwise expand forever.
+const MAX_MACRO_DEPTH: usize = 100;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Instruction {
-    PushData(u32),
+    PushData(u64),
+    /// A data word that is too large for [`Instruction::PushData`], in decimal. Only supported with the `bigint`
+    /// feature.
+    PushBig(String),
     PushFunction(String),
     PushRandom,
     PushArg(usize),
-    Define(String, u32, Vec<Instruction>),
+    Define(String, u32, Vec<Instruction>, Attributes),
     CallIf,
     Exit,
 }
 
+/// An item in the body of a macro. The macros that a macro invokes are only expanded when it is invoked itself, and
+/// arguments are only resolved then, against the function that the macro is invoked in.
+#[derive(Clone, Debug)]
+enum MacroItem {
+    Instruction(Instruction),
+    Argument(usize),
+    NamedArgument(String),
+    Invoke(String),
+}
+
+/// Which of the popped words `$0` refers to.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ArgsOrder {
+    /// `$0` is the word that was on top of the stack.
+    #[default]
+    Pop,
+    /// `$0` is the deepest of the popped words, so the arguments read from left to right like the stack.
+    Stack,
+}
+
+impl FromStr for ArgsOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pop" => Ok(ArgsOrder::Pop),
+            "stack" => Ok(ArgsOrder::Stack),
+            _ => Err(anyhow!(
+                "Invalid argument order '{s}'. Expected pop or stack."
+            )),
+        }
+    }
+}
+
+/// Information about a function definition besides its name, argument count, and body.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Attributes {
+    pub doc: Option<String>,
+    /// Whether the function can only be called from functions defined in the same file (with `DEFN_PRIV`).
+    pub private: bool,
+    /// The file that the function was defined in, if known.
+    pub origin: Option<String>,
+    /// The number of words that the function is declared to leave on the stack, as in `DEFN f (2 -> 1)`.
+    pub results: Option<u32>,
+    /// The names of the arguments, if they were named in the signature (as in `DEFN f (a b)`). The last name is `$0`.
+    pub arg_names: Vec<String>,
+    /// How `$0`, `$1`, and so on were numbered in the source. The body always uses [`ArgsOrder::Pop`].
+    pub args_order: ArgsOrder,
+}
+
+/// How a function is referred to in the source.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReferenceKind {
+    Definition,
+    Push,
+    Import,
+}
+
+/// A place in a file where a function is named, as recorded by [`Parser::with_references`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Reference {
+    /// The full name of the function, with its module.
+    pub name: String,
+    /// The name as it is written at this place, which can be shorter because of modules and imports.
+    pub written: String,
+    pub kind: ReferenceKind,
+    pub file: String,
+    /// The line, counting from one.
+    pub line: usize,
+    /// The column in characters, counting from one.
+    pub column: usize,
+}
+
 pub struct Parser<T>
 where
     T: TokenStream,
 {
     token_stream: T,
     depth: usize,
+    /// A token that was read ahead of time and should be returned by the next call to `next_token`.
+    peeked: Option<Token>,
+    /// While parsing a DEFN, the number of curly brackets that are open (0 in the signature, 1 in the body).
+    open_brackets: Option<usize>,
+    /// The value of `open_brackets` when the last error occurred.
+    open_brackets_at_error: Option<usize>,
+    /// A syntax error that was found while recovering from another one.
+    deferred_error: Option<Error>,
+    /// Warnings that have not been retrieved with `take_warnings` yet.
+    warnings: Vec<(Lint, String)>,
+    /// The file that the last top-level token came from, if known. Modules and imports end with the file.
+    file: Option<String>,
+    /// The file and line of the first top-level EXIT, if it was in a file.
+    top_level_exit: Option<(String, usize)>,
+    /// The last file that was reported as unreachable because of `top_level_exit`.
+    unreachable_file: Option<String>,
+    /// The module declared with `MODULE` in the current file, if any.
+    module: Option<String>,
+    /// The functions defined so far in the current module, without the module prefix.
+    module_functions: HashSet<String>,
+    /// The names introduced with `IMPORT` in the current file, with what they refer to.
+    aliases: HashMap<String, String>,
+    /// The constants defined with `CONST`, with the instruction that pushes their value. Unlike modules and imports,
+    /// these carry over to the following files.
+    constants: HashMap<String, Instruction>,
+    /// The names of the arguments of the DEFN being parsed, if it named them.
+    arg_names: Vec<String>,
+    /// The order of the arguments in DEFNs without `STACK_ORDER`.
+    args_order: ArgsOrder,
+    /// While parsing a DEFN whose arguments are in stack order, its argument count.
+    stack_order_args: Option<u32>,
+    /// What `PUSH $it` pushes, as set by the REPL.
+    it: Option<Instruction>,
+    /// The macros defined with `MACRO`, which also carry over to the following files.
+    macros: HashMap<String, Vec<MacroItem>>,
+    /// The instructions from the last macro invocation that have not been returned yet.
+    expansion: VecDeque<Instruction>,
+    /// The references to functions found so far, if they are being recorded.
+    references: Option<Vec<Reference>>,
 }
 
 impl<T> Parser<T>
@@ -29,28 +157,211 @@ where
         Parser {
             token_stream,
             depth: 0,
+            peeked: None,
+            open_brackets: None,
+            open_brackets_at_error: None,
+            deferred_error: None,
+            warnings: Vec::new(),
+            file: None,
+            top_level_exit: None,
+            unreachable_file: None,
+            module: None,
+            module_functions: HashSet::new(),
+            aliases: HashMap::new(),
+            constants: HashMap::new(),
+            arg_names: Vec::new(),
+            args_order: ArgsOrder::default(),
+            stack_order_args: None,
+            it: None,
+            macros: HashMap::new(),
+            expansion: VecDeque::new(),
+            references: None,
         }
     }
 
     pub fn next_instruction(&mut self) -> Result<Option<Instruction>, Error> {
+        if let Some(e) = self.deferred_error.take() {
+            return Err(e);
+        }
+
         match self.consume_instruction(false, "") {
             Err(e) => {
                 // Reset the depth in case the error occurred in the middle
                 // of a definition or something
                 self.depth = 0;
+                self.open_brackets_at_error = self.open_brackets.take();
+                let e = self.locate(e);
+                debug!(error = %e, "syntax error");
                 Err(e)
             }
-            Ok(x) => Ok(x),
+            Ok(x) => {
+                if let Some(instruction) = &x {
+                    debug!(?instruction, "parsed");
+                    self.check_exit(instruction);
+                }
+                Ok(x)
+            }
+        }
+    }
+
+    /// Returns the warnings found while parsing since the last time this was called.
+    pub fn take_warnings(&mut self) -> Vec<(Lint, String)> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Discards tokens after an error until parsing is likely to succeed again: after the end of the DEFN if the error
+    /// occurred in one, or at the next keyword otherwise. This lets all the syntax errors in a file be reported at
+    /// once. If the lexer finds an error while skipping, it is returned by the next call to `next_instruction`.
+    pub fn recover(&mut self) {
+        let mut open_brackets = match self.open_brackets_at_error.take() {
+            None => {
+                while let Some(token) = self.skip_token() {
+                    if matches!(
+                        token,
+                        Token::Push
+                            | Token::Defn
+                            | Token::DefnPriv
+                            | Token::CallIf
+                            | Token::Exit
+                            | Token::Module
+                            | Token::Import
+                            | Token::Const
+                            | Token::Macro
+                    ) {
+                        self.peeked = Some(token);
+                        return;
+                    }
+                }
+                return;
+            }
+            Some(n) => n,
+        };
+
+        while let Some(token) = self.skip_token() {
+            match token {
+                Token::LeftCurlyBracket => open_brackets += 1,
+                Token::RightCurlyBracket if open_brackets <= 1 => return,
+                Token::RightCurlyBracket => open_brackets -= 1,
+                // Definitions cannot be nested, so this is probably the start of the next one
+                Token::Defn | Token::DefnPriv | Token::Macro => {
+                    self.peeked = Some(token);
+                    return;
+                }
+                _ => {}
+            }
         }
     }
 
+    /// Returns the next token while recovering from an error, or `None` if there are no more or the lexer failed.
+    fn skip_token(&mut self) -> Option<Token> {
+        match self.next_token() {
+            Ok(token) => token,
+            Err(e) => {
+                self.deferred_error = Some(self.locate(e));
+                None
+            }
+        }
+    }
+
+    /// Returns the file and line of the last token, if they are known.
+    pub fn location(&self) -> Option<(String, usize)> {
+        self.token_stream.location()
+    }
+
+    /// Adds the location of the last token to an error, if it is known.
+    fn locate(&self, e: Error) -> Error {
+        match self.token_stream.location() {
+            None => e,
+            Some((file, line)) => e.context(format!("Error in {file} on line {line}.")),
+        }
+    }
+
+    /// Records every place in a file where a function is defined, pushed, or imported. Constants and macros are not
+    /// functions, so they are not recorded, but the functions pushed in the body of a macro are (once, where the macro
+    /// is defined).
+    pub fn with_references(mut self) -> Self {
+        self.references = Some(Vec::new());
+        self
+    }
+
+    /// Returns the references recorded since the last time this was called.
+    pub fn take_references(&mut self) -> Vec<Reference> {
+        self.references
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Records a reference to `name` at the last token, if references are being recorded and the location is known.
+    fn record(&mut self, name: &str, written: &str, kind: ReferenceKind) {
+        let (Some(references), Some((file, line)), Some(column)) = (
+            self.references.as_mut(),
+            self.token_stream.location(),
+            self.token_stream.column(),
+        ) else {
+            return;
+        };
+        references.push(Reference {
+            name: name.to_owned(),
+            written: written.to_owned(),
+            kind,
+            file,
+            line,
+            column,
+        });
+    }
+
+    /// Sets the order of the arguments in the DEFNs that do not say `STACK_ORDER`.
+    pub fn with_args_order(mut self, args_order: ArgsOrder) -> Self {
+        self.args_order = args_order;
+        self
+    }
+
+    /// Sets the instruction that `PUSH $it` stands for (like `PUSH 5`), or makes `$it` an error if `None`.
+    pub fn set_it(&mut self, it: Option<Instruction>) {
+        self.it = it;
+    }
+
     /// Clears the underlying lexer.
     pub fn clear_line(&mut self) {
+        self.peeked = None;
+        self.expansion.clear();
         self.token_stream.clear_line();
     }
 
     pub fn full_line_consumed(&mut self) -> bool {
-        self.token_stream.full_line_consumed()
+        self.peeked.is_none() && self.expansion.is_empty() && self.token_stream.full_line_consumed()
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>, Error> {
+        match self.peeked.take() {
+            Some(t) => Ok(Some(t)),
+            None => self.token_stream.next_token(self.depth),
+        }
+    }
+
+    /// Discards tokens until the next keyword or the end of the line, so that parsing can resume after an error.
+    fn skip_to_keyword(&mut self) {
+        while !self.full_line_consumed() {
+            match self.next_token() {
+                Ok(Some(
+                    t @ (Token::Push
+                    | Token::Defn
+                    | Token::DefnPriv
+                    | Token::CallIf
+                    | Token::Exit
+                    | Token::Module
+                    | Token::Import
+                    | Token::Const
+                    | Token::Macro),
+                )) => {
+                    self.peeked = Some(t);
+                    return;
+                }
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => return,
+            }
+        }
     }
 
     fn consume_instruction(
@@ -58,20 +369,58 @@ where
         inside_defn: bool,
         func_name: &str,
     ) -> Result<Option<Instruction>, Error> {
-        match self.token_stream.next_token(self.depth)? {
+        if let Some(instruction) = self.expansion.pop_front() {
+            return Ok(Some(instruction));
+        }
+        let token = self.next_token()?;
+        if !inside_defn {
+            self.check_file();
+        }
+        match token {
             None if inside_defn => Err(anyhow!(
                 "Syntax error: Unexpected end of file in body of function '{func_name}'."
             )),
             None => Ok(None),
             Some(Token::Push) => self.consume_push(inside_defn),
             // Block nested DEFNs
-            Some(Token::Defn) if inside_defn => {
+            Some(Token::Defn | Token::DefnPriv) if inside_defn => {
                 Err(anyhow!("Syntax error: Nested definitions are not allowed."))
             }
-            Some(Token::Defn) => self.consume_defn(),
+            Some(Token::Defn) => self.consume_defn(false),
+            Some(Token::DefnPriv) => self.consume_defn(true),
+            Some(Token::Module | Token::Import | Token::Const | Token::Macro) if inside_defn => {
+                Err(anyhow!(
+                    "Syntax error: MODULE, IMPORT, CONST, and MACRO are only allowed outside functions."
+                ))
+            }
+            Some(Token::Module) => {
+                self.consume_module()?;
+                self.consume_instruction(inside_defn, func_name)
+            }
+            Some(Token::Import) => {
+                self.consume_import()?;
+                self.consume_instruction(inside_defn, func_name)
+            }
+            Some(Token::Const) => {
+                self.consume_const()?;
+                self.consume_instruction(inside_defn, func_name)
+            }
+            Some(Token::Macro) => {
+                self.consume_macro()?;
+                self.consume_instruction(inside_defn, func_name)
+            }
+            Some(Token::MacroCall(m)) => {
+                let expansion = self.expand(&m, inside_defn, 0)?;
+                self.expansion.extend(expansion);
+                self.consume_instruction(inside_defn, func_name)
+            }
             Some(Token::CallIf) => Ok(Some(Instruction::CallIf)),
             Some(Token::Exit) => Ok(Some(Instruction::Exit)),
             Some(Token::RightCurlyBracket) if inside_defn => Ok(None),
+            Some(Token::RightCurlyBracket) => {
+                self.skip_to_keyword();
+                Err(anyhow!("Syntax error: No matching DEFN for '}}'."))
+            }
             Some(t) => Err(anyhow!("Syntax error: Unexpected token {:?}.", t)),
         }
     }
@@ -80,7 +429,7 @@ where
         // Increase the depth in case there was a newline between PUSH and the word
         self.depth += 1;
 
-        let instruction = match self.token_stream.next_token(self.depth)? {
+        let instruction = match self.next_token()? {
             None => {
                 return Err(anyhow!(
                     "Syntax error: Unexpected end of file after token {:?}.",
@@ -88,15 +437,27 @@ where
                 ))
             }
             Some(Token::Word(n)) => Instruction::PushData(n),
-            Some(Token::FunctionName(f)) => Instruction::PushFunction(f),
+            Some(Token::BigWord(n)) => Instruction::PushBig(n),
+            Some(Token::FunctionName(f)) => match self.constants.get(&f) {
+                Some(value) => value.clone(),
+                None => {
+                    let name = self.resolve(f.clone());
+                    self.record(&name, &f, ReferenceKind::Push);
+                    Instruction::PushFunction(name)
+                }
+            },
             Some(Token::Asterisk) => Instruction::PushRandom,
             // Arguments are only allowed inside functions
-            Some(Token::Argument(_)) if !inside_defn => {
+            Some(Token::Argument(_) | Token::NamedArgument(_)) if !inside_defn => {
                 return Err(anyhow!(
                     "Syntax error: Cannot use arguments outside functions."
                 ))
             }
-            Some(Token::Argument(n)) => Instruction::PushArg(n),
+            Some(Token::Argument(n)) => self.push_arg(n)?,
+            Some(Token::NamedArgument(name)) => self.push_named_arg(&name)?,
+            Some(Token::It) => self.it.clone().ok_or_else(|| {
+                anyhow!("Syntax error: $it can only be used in the REPL, after a line that leaves something on the stack.")
+            })?,
             Some(t) => return Err(anyhow!("Syntax error: Unexpected token {:?}.", t)),
         };
 
@@ -105,16 +466,42 @@ where
         Ok(Some(instruction))
     }
 
-    fn consume_defn(&mut self) -> Result<Option<Instruction>, Error> {
+    /// Returns the instruction that pushes `$n` in the function that is being defined.
+    fn push_arg(&self, n: usize) -> Result<Instruction, Error> {
+        match self.stack_order_args {
+            None => Ok(Instruction::PushArg(n)),
+            Some(arg_count) => match (arg_count as usize).checked_sub(n + 1) {
+                Some(i) => Ok(Instruction::PushArg(i)),
+                None => Err(anyhow!(
+                    "Syntax error: Cannot use ${n} in a function with {arg_count} argument(s) in stack order."
+                )),
+            },
+        }
+    }
+
+    /// Returns the instruction that pushes the argument called `name` in the function that is being defined.
+    fn push_named_arg(&self, name: &str) -> Result<Instruction, Error> {
+        match self.arg_names.iter().position(|a| a == name) {
+            Some(i) => Ok(Instruction::PushArg(self.arg_names.len() - 1 - i)),
+            None => Err(anyhow!("Syntax error: Unknown argument '${name}'.")),
+        }
+    }
+
+    fn consume_defn(&mut self, private: bool) -> Result<Option<Instruction>, Error> {
         // Increase depth in case there was a newline between DEFN and the function name
         self.depth += 1;
+        self.open_brackets = Some(0);
 
         // Get function name
-        let func_name = match self.token_stream.next_token(self.depth)? {
+        let func_name = match self.next_token()? {
             None => {
                 return Err(anyhow!(
                     "Syntax error: Unexpected end of file after token {:?}.",
-                    Token::Defn
+                    if private {
+                        Token::DefnPriv
+                    } else {
+                        Token::Defn
+                    }
                 ))
             }
             Some(Token::FunctionName(f)) => f,
@@ -123,21 +510,79 @@ where
         if func_name.starts_with("__") {
             return Err(anyhow!("Syntax error: Cannot define function '{}' because the prefix '__' is reserved for built-in functions.", func_name));
         }
+        if self.constants.contains_key(&func_name) {
+            return Err(anyhow!(
+                "Syntax error: Cannot define function '{func_name}' because there is a constant with the same name."
+            ));
+        }
+        if self.module.is_some() && !func_name.contains("::") {
+            self.module_functions.insert(func_name.clone());
+        }
+        let written = func_name;
+        let func_name = self.resolve(written.clone());
+        self.record(&func_name, &written, ReferenceKind::Definition);
 
-        // Get argument count
+        // Get argument count, or the names of the arguments from the bottom of the stack to the top
         self.expect(
             Token::LeftParen,
             format!("Syntax error: Unexpected end of file in signature of function '{func_name}'."),
         )?;
-        let arg_count = match self.token_stream.next_token(self.depth)? {
+        self.arg_names.clear();
+        let arg_count = match self.next_token()? {
             None => {
                 return Err(anyhow!(
                     "Syntax error: Unexpected end of file in signature of function '{func_name}'."
                 ))
             }
-            Some(Token::Word(n)) => n,
+            Some(Token::Word(n)) => match u32::try_from(n) {
+                Err(_) => return Err(anyhow!("Syntax error: Invalid argument count '{n}'.")),
+                Ok(n) => n,
+            },
+            Some(Token::FunctionName(name)) => {
+                let mut token = Some(Token::FunctionName(name));
+                while let Some(Token::FunctionName(name)) = token {
+                    if name == "it" || name.contains("::") {
+                        return Err(anyhow!("Syntax error: Invalid argument name '{name}'."));
+                    }
+                    if self.arg_names.contains(&name) {
+                        return Err(anyhow!(
+                            "Syntax error: Duplicate argument name '{name}' in function '{func_name}'."
+                        ));
+                    }
+                    self.arg_names.push(name);
+                    token = self.next_token()?;
+                }
+                self.peeked = token;
+                self.arg_names.len() as u32
+            }
             Some(t) => return Err(anyhow!("Syntax error: Unexpected token {:?}.", t)),
         };
+        let args_order = match self.next_token()? {
+            Some(Token::StackOrder) => ArgsOrder::Stack,
+            t => {
+                self.peeked = t;
+                self.args_order
+            }
+        };
+        self.stack_order_args = (args_order == ArgsOrder::Stack).then_some(arg_count);
+        let results = match self.next_token()? {
+            Some(Token::Arrow) => match self.next_token()? {
+                None => {
+                    return Err(anyhow!(
+                    "Syntax error: Unexpected end of file in signature of function '{func_name}'."
+                ))
+                }
+                Some(Token::Word(n)) => match u32::try_from(n) {
+                    Err(_) => return Err(anyhow!("Syntax error: Invalid result count '{n}'.")),
+                    Ok(n) => Some(n),
+                },
+                Some(t) => return Err(anyhow!("Syntax error: Unexpected token {:?}.", t)),
+            },
+            t => {
+                self.peeked = t;
+                None
+            }
+        };
         self.expect(
             Token::RightParen,
             format!("Syntax error: Unexpected end of file in signature of function '{func_name}'."),
@@ -148,9 +593,27 @@ where
             format!("Syntax error: Unexpected end of file in signature of function '{func_name}'."),
         )?;
 
-        // Get body
+        // Get docstring and body
+        self.open_brackets = Some(1);
+        let doc = match self.next_token()? {
+            Some(Token::String(doc)) => Some(doc),
+            t => {
+                self.peeked = t;
+                None
+            }
+        };
         let body = self.consume_defn_body(&func_name)?;
-        let instruction = Instruction::Define(func_name, arg_count, body);
+        self.open_brackets = None;
+        self.stack_order_args = None;
+        let attributes = Attributes {
+            doc,
+            private,
+            origin: self.file.clone(),
+            results,
+            arg_names: std::mem::take(&mut self.arg_names),
+            args_order,
+        };
+        let instruction = Instruction::Define(func_name, arg_count, body, attributes);
 
         // Reset depth
         self.depth -= 1;
@@ -160,16 +623,288 @@ where
 
     fn consume_defn_body(&mut self, func_name: &str) -> Result<Vec<Instruction>, Error> {
         let mut body = Vec::new();
+        // Where the first EXIT was, if there has been one
+        let mut exit = None;
+        let mut warned = false;
         loop {
-            match self.consume_instruction(true, func_name)? {
+            let instruction = match self.consume_instruction(true, func_name)? {
                 None => return Ok(body),
-                Some(instruction) => body.push(instruction),
+                Some(x) => x,
+            };
+
+            match &exit {
+                None if instruction == Instruction::Exit => {
+                    exit = Some(self.token_stream.location());
+                }
+                Some(location) if !warned => {
+                    let exit = match location {
+                        None => "an EXIT".to_owned(),
+                        Some((file, line)) => format!("the EXIT on line {line} of {file}"),
+                    };
+                    self.warnings.push((
+                        Lint::UnreachableCode,
+                        format!("The instructions after {exit} in function '{func_name}' can never run."),
+                    ));
+                    warned = true;
+                }
+                _ => {}
             }
+            body.push(instruction);
+        }
+    }
+
+    /// Warns when a file comes after a top-level EXIT in an earlier file, since the program can never get to it.
+    fn check_exit(&mut self, instruction: &Instruction) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        match &self.top_level_exit {
+            None if *instruction == Instruction::Exit => {
+                self.top_level_exit = self.token_stream.location();
+            }
+            Some((exit_file, line))
+                if exit_file != file && self.unreachable_file.as_ref() != Some(file) =>
+            {
+                self.warnings.push((
+                    Lint::UnreachableCode,
+                    format!(
+                        "The EXIT on line {line} of {exit_file} always stops the program, so {file} can never run."
+                    ),
+                ));
+                self.unreachable_file = Some(file.clone());
+            }
+            _ => {}
+        }
+    }
+
+    /// Forgets the module and imports when a new file starts.
+    fn check_file(&mut self) {
+        let file = self.token_stream.location().map(|(file, _)| file);
+        if file != self.file {
+            self.file = file;
+            self.module = None;
+            self.module_functions.clear();
+            self.aliases.clear();
+        }
+    }
+
+    fn consume_module(&mut self) -> Result<(), Error> {
+        self.depth += 1;
+        let module = match self.next_token()? {
+            None => {
+                return Err(anyhow!(
+                    "Syntax error: Unexpected end of file after token {:?}.",
+                    Token::Module
+                ))
+            }
+            Some(Token::FunctionName(m)) if m.starts_with("__") => {
+                return Err(anyhow!("Syntax error: Cannot declare module '{m}' because the prefix '__' is reserved for built-in functions."))
+            }
+            Some(Token::FunctionName(m)) => m,
+            Some(t) => return Err(anyhow!("Syntax error: Unexpected token {:?}.", t)),
+        };
+        self.depth -= 1;
+
+        self.module = Some(module);
+        self.module_functions.clear();
+        Ok(())
+    }
+
+    /// Parses `MACRO name { ... }`. The body can contain `PUSH`, `CALLIF`, `EXIT`, and invocations of other macros.
+    fn consume_macro(&mut self) -> Result<(), Error> {
+        self.depth += 1;
+        self.open_brackets = Some(0);
+        let name = match self.next_token()? {
+            None => {
+                return Err(anyhow!(
+                    "Syntax error: Unexpected end of file after token {:?}.",
+                    Token::Macro
+                ))
+            }
+            Some(Token::FunctionName(m)) if self.macros.contains_key(&m) => {
+                return Err(anyhow!("Syntax error: Macro '{m}' is already defined."))
+            }
+            Some(Token::FunctionName(m)) => m,
+            Some(t) => return Err(anyhow!("Syntax error: Unexpected token {:?}.", t)),
+        };
+        let eof = format!("Syntax error: Unexpected end of file in body of macro '{name}'.");
+        self.expect(Token::LeftCurlyBracket, eof.clone())?;
+
+        self.open_brackets = Some(1);
+        let mut body = Vec::new();
+        loop {
+            let item = match self.next_token()? {
+                None => return Err(anyhow!(eof)),
+                Some(Token::RightCurlyBracket) => break,
+                Some(Token::Push) => {
+                    // Arguments are resolved when the macro is invoked, against the function that it is invoked in
+                    self.depth += 1;
+                    let token = self.next_token()?;
+                    self.depth -= 1;
+                    match token {
+                        None => return Err(anyhow!(eof)),
+                        Some(Token::Argument(n)) => MacroItem::Argument(n),
+                        Some(Token::NamedArgument(name)) => MacroItem::NamedArgument(name),
+                        Some(token) => {
+                            self.peeked = Some(token);
+                            match self.consume_push(true)? {
+                                None => return Err(anyhow!(eof)),
+                                Some(instruction) => MacroItem::Instruction(instruction),
+                            }
+                        }
+                    }
+                }
+                Some(Token::CallIf) => MacroItem::Instruction(Instruction::CallIf),
+                Some(Token::Exit) => MacroItem::Instruction(Instruction::Exit),
+                Some(Token::MacroCall(m)) => MacroItem::Invoke(m),
+                Some(t) => return Err(anyhow!("Syntax error: Unexpected token {:?}.", t)),
+            };
+            body.push(item);
+        }
+        self.open_brackets = None;
+        self.depth -= 1;
+
+        self.macros.insert(name, body);
+        Ok(())
+    }
+
+    /// Returns the instructions that an invocation of a macro stands for.
+    fn expand(
+        &self,
+        name: &str,
+        inside_defn: bool,
+        depth: usize,
+    ) -> Result<Vec<Instruction>, Error> {
+        if depth >= MAX_MACRO_DEPTH {
+            return Err(anyhow!("Syntax error: Macros were expanded more than {MAX_MACRO_DEPTH} levels deep, so macro '{name}' probably invokes itself."));
+        }
+        let body = self
+            .macros
+            .get(name)
+            .ok_or_else(|| anyhow!("Syntax error: Undefined macro '{name}'."))?;
+
+        let mut instructions = Vec::new();
+        for item in body {
+            match item {
+                MacroItem::Argument(_) | MacroItem::NamedArgument(_) if !inside_defn => {
+                    return Err(anyhow!(
+                        "Syntax error: Cannot use arguments outside functions (in macro '{name}')."
+                    ))
+                }
+                MacroItem::Argument(n) => instructions.push(self.push_arg(*n)?),
+                MacroItem::NamedArgument(a) => instructions.push(self.push_named_arg(a)?),
+                MacroItem::Instruction(instruction) => instructions.push(instruction.clone()),
+                MacroItem::Invoke(m) => {
+                    instructions.extend(self.expand(m, inside_defn, depth + 1)?)
+                }
+            }
+        }
+        Ok(instructions)
+    }
+
+    /// Parses `CONST name value`, where the value is a word or another constant.
+    fn consume_const(&mut self) -> Result<(), Error> {
+        self.depth += 1;
+        let eof = || {
+            anyhow!(
+                "Syntax error: Unexpected end of file after token {:?}.",
+                Token::Const
+            )
+        };
+        let name = match self.next_token()? {
+            None => return Err(eof()),
+            Some(Token::FunctionName(c)) if c.starts_with("__") || c.contains("::") => {
+                return Err(anyhow!("Syntax error: Invalid constant name '{c}'."))
+            }
+            Some(Token::FunctionName(c)) if self.constants.contains_key(&c) => {
+                return Err(anyhow!("Syntax error: Constant '{c}' is already defined."))
+            }
+            Some(Token::FunctionName(c)) => c,
+            Some(t) => return Err(anyhow!("Syntax error: Unexpected token {:?}.", t)),
+        };
+        let value = match self.next_token()? {
+            None => return Err(eof()),
+            Some(Token::Word(n)) => Instruction::PushData(n),
+            Some(Token::BigWord(n)) => Instruction::PushBig(n),
+            Some(Token::FunctionName(c)) => match self.constants.get(&c) {
+                None => return Err(anyhow!("Syntax error: Undefined constant '{c}'.")),
+                Some(value) => value.clone(),
+            },
+            Some(t) => return Err(anyhow!("Syntax error: Unexpected token {:?}.", t)),
+        };
+        self.depth -= 1;
+
+        self.constants.insert(name, value);
+        Ok(())
+    }
+
+    /// Parses `IMPORT name` or `IMPORT name AS alias`, where the name is a module or a function in a module. Without
+    /// `AS`, the alias is the last part of the name.
+    fn consume_import(&mut self) -> Result<(), Error> {
+        self.depth += 1;
+        let target = match self.next_token()? {
+            None => {
+                return Err(anyhow!(
+                    "Syntax error: Unexpected end of file after token {:?}.",
+                    Token::Import
+                ))
+            }
+            Some(Token::FunctionName(f)) => {
+                let target = self.resolve(f.clone());
+                self.record(&target, &f, ReferenceKind::Import);
+                target
+            }
+            Some(t) => return Err(anyhow!("Syntax error: Unexpected token {:?}.", t)),
+        };
+        // AS must be on the same line, so that the REPL does not wait for another line to find out
+        let alias = if self.full_line_consumed() {
+            None
+        } else {
+            match self.next_token()? {
+                Some(Token::As) => match self.next_token()? {
+                    None => {
+                        return Err(anyhow!(
+                            "Syntax error: Unexpected end of file after token {:?}.",
+                            Token::As
+                        ))
+                    }
+                    Some(Token::FunctionName(a)) if a.contains("::") => {
+                        return Err(anyhow!("Syntax error: Invalid alias '{a}'."))
+                    }
+                    Some(Token::FunctionName(a)) => Some(a),
+                    Some(t) => return Err(anyhow!("Syntax error: Unexpected token {:?}.", t)),
+                },
+                t => {
+                    self.peeked = t;
+                    None
+                }
+            }
+        };
+        self.depth -= 1;
+
+        let alias = alias.unwrap_or_else(|| match target.rsplit_once("::") {
+            None => target.clone(),
+            Some((_, last)) => last.to_owned(),
+        });
+        self.aliases.insert(alias, target);
+        Ok(())
+    }
+
+    /// Finds the full name of a function as it is written in the current file: either a function defined earlier in
+    /// the current module, an alias introduced by `IMPORT`, or a name whose first part is such an alias.
+    fn resolve(&self, f: String) -> String {
+        match (&self.module, f.split_once("::")) {
+            (Some(module), None) if self.module_functions.contains(&f) => format!("{module}::{f}"),
+            (_, None) => self.aliases.get(&f).cloned().unwrap_or(f),
+            (_, Some((first, rest))) => match self.aliases.get(first) {
+                None => f,
+                Some(target) => format!("{target}::{rest}"),
+            },
         }
     }
 
     fn expect(&mut self, token: Token, eof_msg: String) -> Result<(), Error> {
-        match self.token_stream.next_token(self.depth)? {
+        match self.next_token()? {
             None => Err(anyhow!(eof_msg)),
             Some(t) if t == token => Ok(()),
             Some(t) => Err(anyhow!("Syntax error: Unexpected token {:?}.", t)),
@@ -181,7 +916,8 @@ where
 mod tests {
     use crate::{
         lexer::Token,
-        parser::{Instruction, Parser},
+        lint::Lint,
+        parser::{ArgsOrder, Attributes, Instruction, Parser},
     };
 
     macro_rules! assert_ok_and_eq {
@@ -244,7 +980,7 @@ mod tests {
                 Token::LeftCurlyBracket,
                 Token::RightCurlyBracket,
             ],
-            Instruction::Define("foo".to_owned(), 0, vec![])
+            Instruction::Define("foo".to_owned(), 0, vec![], Attributes::default())
         ),
         define_with_args: (
             vec![
@@ -260,7 +996,96 @@ mod tests {
                 Token::Argument(1),
                 Token::RightCurlyBracket,
             ],
-            Instruction::Define("swap".to_owned(), 2, vec![Instruction::PushArg(0), Instruction::PushArg(1)])
+            Instruction::Define("swap".to_owned(), 2, vec![Instruction::PushArg(0), Instruction::PushArg(1)], Attributes::default())
+        ),
+        define_with_doc: (
+            vec![
+                Token::Defn,
+                Token::FunctionName("one".to_owned()),
+                Token::LeftParen,
+                Token::Word(0),
+                Token::RightParen,
+                Token::LeftCurlyBracket,
+                Token::String("Pushes 1.".to_owned()),
+                Token::Push,
+                Token::Word(1),
+                Token::RightCurlyBracket,
+            ],
+            Instruction::Define(
+                "one".to_owned(),
+                0,
+                vec![Instruction::PushData(1)],
+                Attributes { doc: Some("Pushes 1.".to_owned()), ..Attributes::default() }
+            )
+        ),
+        define_with_stack_effect: (
+            vec![
+                Token::Defn,
+                Token::FunctionName("add".to_owned()),
+                Token::LeftParen,
+                Token::Word(2),
+                Token::Arrow,
+                Token::Word(1),
+                Token::RightParen,
+                Token::LeftCurlyBracket,
+                Token::RightCurlyBracket,
+            ],
+            Instruction::Define(
+                "add".to_owned(),
+                2,
+                vec![],
+                Attributes { results: Some(1), ..Attributes::default() }
+            )
+        ),
+        define_in_stack_order: (
+            vec![
+                Token::Defn,
+                Token::FunctionName("f".to_owned()),
+                Token::LeftParen,
+                Token::Word(3),
+                Token::StackOrder,
+                Token::RightParen,
+                Token::LeftCurlyBracket,
+                Token::Push,
+                Token::Argument(0),
+                Token::Push,
+                Token::Argument(2),
+                Token::RightCurlyBracket,
+            ],
+            Instruction::Define(
+                "f".to_owned(),
+                3,
+                vec![Instruction::PushArg(2), Instruction::PushArg(0)],
+                Attributes { args_order: ArgsOrder::Stack, ..Attributes::default() }
+            )
+        ),
+        define_with_named_arguments: (
+            vec![
+                Token::Defn,
+                Token::FunctionName("f".to_owned()),
+                Token::LeftParen,
+                Token::FunctionName("a".to_owned()),
+                Token::FunctionName("b".to_owned()),
+                Token::FunctionName("c".to_owned()),
+                Token::RightParen,
+                Token::LeftCurlyBracket,
+                Token::Push,
+                Token::NamedArgument("a".to_owned()),
+                Token::Push,
+                Token::NamedArgument("c".to_owned()),
+                Token::Push,
+                Token::Argument(0),
+                Token::RightCurlyBracket,
+            ],
+            Instruction::Define(
+                "f".to_owned(),
+                3,
+                vec![Instruction::PushArg(2), Instruction::PushArg(0), Instruction::PushArg(0)],
+                Attributes {
+                    arg_names: vec!["a".to_owned(), "b".to_owned(), "c".to_owned()],
+                    ..Attributes::default()
+                }
+            )
         ),
         callif: (vec![Token::CallIf], Instruction::CallIf),
         exit: (vec![Token::Exit], Instruction::Exit),
@@ -286,6 +1111,59 @@ mod tests {
             ],
             "Syntax error: Nested definitions are not allowed."
         ),
+        argument_out_of_range_in_stack_order: (
+            vec![
+                Token::Defn,
+                Token::FunctionName("f".to_owned()),
+                Token::LeftParen,
+                Token::Word(2),
+                Token::StackOrder,
+                Token::RightParen,
+                Token::LeftCurlyBracket,
+                Token::Push,
+                Token::Argument(2),
+                Token::RightCurlyBracket,
+            ],
+            "Syntax error: Cannot use $2 in a function with 2 argument(s) in stack order."
+        ),
+        duplicate_argument_name: (
+            vec![
+                Token::Defn,
+                Token::FunctionName("f".to_owned()),
+                Token::LeftParen,
+                Token::FunctionName("a".to_owned()),
+                Token::FunctionName("a".to_owned()),
+                Token::RightParen,
+                Token::LeftCurlyBracket,
+                Token::RightCurlyBracket,
+            ],
+            "Syntax error: Duplicate argument name 'a' in function 'f'."
+        ),
+        unknown_named_argument: (
+            vec![
+                Token::Defn,
+                Token::FunctionName("f".to_owned()),
+                Token::LeftParen,
+                Token::FunctionName("a".to_owned()),
+                Token::RightParen,
+                Token::LeftCurlyBracket,
+                Token::Push,
+                Token::NamedArgument("b".to_owned()),
+                Token::RightCurlyBracket,
+            ],
+            "Syntax error: Unknown argument '$b'."
+        ),
+        missing_result_count: (
+            vec![
+                Token::Defn,
+                Token::FunctionName("add".to_owned()),
+                Token::LeftParen,
+                Token::Word(2),
+                Token::Arrow,
+                Token::RightParen,
+            ],
+            "Syntax error: Unexpected token RightParen."
+        ),
         unexpected_token: (
             vec![Token::LeftCurlyBracket],
             "Syntax error: Unexpected token LeftCurlyBracket."
@@ -294,6 +1172,20 @@ mod tests {
             vec![Token::Push, Token::RightParen],
             "Syntax error: Unexpected token RightParen."
         ),
+        string_outside_docstring: (
+            vec![
+                Token::Defn,
+                Token::FunctionName("foo".to_owned()),
+                Token::LeftParen,
+                Token::Word(0),
+                Token::RightParen,
+                Token::LeftCurlyBracket,
+                Token::CallIf,
+                Token::String("doc".to_owned()),
+                Token::RightCurlyBracket,
+            ],
+            "Syntax error: Unexpected token String(\"doc\")."
+        ),
         unexpected_token_after_define0: (
             vec![
                 Token::Defn,
@@ -411,4 +1303,597 @@ mod tests {
             "Syntax error: Unexpected end of file in body of function 'foo'."
         ),
     ];
+
+    #[test]
+    fn unmatched_right_curly_bracket() {
+        let tokens = vec![
+            Token::Push,
+            Token::Word(1),
+            Token::RightCurlyBracket,
+            Token::Word(2),
+            Token::LeftParen,
+            Token::Push,
+            Token::Word(3),
+            Token::RightCurlyBracket,
+        ];
+        let mut parser = Parser::new(tokens.into_iter());
+
+        assert_ok_and_eq!(parser.next_instruction(), Some(Instruction::PushData(1)));
+        assert_err_with_msg!(
+            parser.next_instruction(),
+            "Syntax error: No matching DEFN for '}'."
+        );
+        assert_ok_and_eq!(parser.next_instruction(), Some(Instruction::PushData(3)));
+        assert_err_with_msg!(
+            parser.next_instruction(),
+            "Syntax error: No matching DEFN for '}'."
+        );
+        assert_ok_and_eq!(parser.next_instruction(), None);
+    }
+
+    #[test]
+    fn default_args_order() {
+        let tokens = vec![
+            Token::Defn,
+            Token::FunctionName("f".to_owned()),
+            Token::LeftParen,
+            Token::Word(2),
+            Token::RightParen,
+            Token::LeftCurlyBracket,
+            Token::Push,
+            Token::Argument(0),
+            Token::RightCurlyBracket,
+        ];
+        let mut parser = Parser::new(tokens.into_iter()).with_args_order(ArgsOrder::Stack);
+
+        assert_ok_and_eq!(
+            parser.next_instruction(),
+            Some(Instruction::Define(
+                "f".to_owned(),
+                2,
+                vec![Instruction::PushArg(1)],
+                Attributes {
+                    args_order: ArgsOrder::Stack,
+                    ..Attributes::default()
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn recover() {
+        let tokens = vec![
+            // Error in the signature of a DEFN
+            Token::Defn,
+            Token::FunctionName("f".to_owned()),
+            Token::LeftParen,
+            Token::Asterisk,
+            Token::RightParen,
+            Token::LeftCurlyBracket,
+            Token::Push,
+            Token::Word(1),
+            Token::RightCurlyBracket,
+            Token::Push,
+            Token::Word(2),
+            // Error in the body of a DEFN
+            Token::Defn,
+            Token::FunctionName("g".to_owned()),
+            Token::LeftParen,
+            Token::Word(0),
+            Token::RightParen,
+            Token::LeftCurlyBracket,
+            Token::Defn,
+            Token::FunctionName("h".to_owned()),
+            Token::LeftParen,
+            Token::Word(0),
+            Token::RightParen,
+            Token::LeftCurlyBracket,
+            Token::RightCurlyBracket,
+            Token::RightCurlyBracket,
+            // Error at the top level
+            Token::Push,
+            Token::LeftParen,
+            Token::Word(3),
+            Token::CallIf,
+        ];
+        let mut parser = Parser::new(tokens.into_iter());
+
+        assert_err_with_msg!(
+            parser.next_instruction(),
+            "Syntax error: Unexpected token Asterisk."
+        );
+        parser.recover();
+        assert_ok_and_eq!(parser.next_instruction(), Some(Instruction::PushData(2)));
+        assert_err_with_msg!(
+            parser.next_instruction(),
+            "Syntax error: Nested definitions are not allowed."
+        );
+        parser.recover();
+        assert_err_with_msg!(
+            parser.next_instruction(),
+            "Syntax error: Unexpected token LeftParen."
+        );
+        parser.recover();
+        assert_ok_and_eq!(parser.next_instruction(), Some(Instruction::CallIf));
+        assert_ok_and_eq!(parser.next_instruction(), None);
+    }
+
+    fn name(f: &str) -> Token {
+        Token::FunctionName(f.to_owned())
+    }
+
+    fn parse_all(tokens: Vec<Token>) -> Vec<Instruction> {
+        let mut parser = Parser::new(tokens.into_iter());
+        let mut instructions = vec![];
+        while let Some(instruction) = parser.next_instruction().unwrap() {
+            instructions.push(instruction);
+        }
+        instructions
+    }
+
+    #[test]
+    fn module() {
+        let tokens = vec![
+            Token::Module,
+            name("math"),
+            Token::Defn,
+            name("square"),
+            Token::LeftParen,
+            Token::Word(1),
+            Token::RightParen,
+            Token::LeftCurlyBracket,
+            Token::RightCurlyBracket,
+            Token::Push,
+            name("square"),
+            Token::Push,
+            name("cube"),
+            Token::Push,
+            name("other::square"),
+        ];
+        assert_eq!(
+            vec![
+                Instruction::Define("math::square".to_owned(), 1, vec![], Attributes::default()),
+                Instruction::PushFunction("math::square".to_owned()),
+                // Only functions that were already defined in the module get its prefix
+                Instruction::PushFunction("cube".to_owned()),
+                Instruction::PushFunction("other::square".to_owned()),
+            ],
+            parse_all(tokens)
+        );
+    }
+
+    #[test]
+    fn import() {
+        let tokens = vec![
+            Token::Import,
+            name("math"),
+            Token::As,
+            name("m"),
+            Token::Import,
+            name("math::cube"),
+            Token::Import,
+            name("m::square"),
+            Token::As,
+            name("sq"),
+            Token::Push,
+            name("m::half"),
+            Token::Push,
+            name("cube"),
+            Token::Push,
+            name("sq"),
+            Token::Push,
+            name("math"),
+        ];
+        assert_eq!(
+            vec![
+                Instruction::PushFunction("math::half".to_owned()),
+                Instruction::PushFunction("math::cube".to_owned()),
+                Instruction::PushFunction("math::square".to_owned()),
+                Instruction::PushFunction("math".to_owned()),
+            ],
+            parse_all(tokens)
+        );
+    }
+
+    test_parse_failure![
+        import_inside_defn: (
+            vec![
+                Token::Defn,
+                name("f"),
+                Token::LeftParen,
+                Token::Word(0),
+                Token::RightParen,
+                Token::LeftCurlyBracket,
+                Token::Import,
+                name("math"),
+            ],
+            "Syntax error: MODULE, IMPORT, CONST, and MACRO are only allowed outside functions."
+        ),
+        reserved_module: (
+            vec![Token::Module, name("__math")],
+            "Syntax error: Cannot declare module '__math' because the prefix '__' is reserved for built-in functions."
+        ),
+        qualified_alias: (
+            vec![Token::Import, name("math"), Token::As, name("a::b")],
+            "Syntax error: Invalid alias 'a::b'."
+        ),
+    ];
+
+    #[test]
+    fn constants() {
+        let tokens = vec![
+            Token::Const,
+            name("ESC"),
+            Token::Word(27),
+            Token::Const,
+            name("QUIT"),
+            name("ESC"),
+            Token::Push,
+            name("QUIT"),
+            Token::Defn,
+            name("f"),
+            Token::LeftParen,
+            Token::Word(0),
+            Token::RightParen,
+            Token::LeftCurlyBracket,
+            Token::Push,
+            name("ESC"),
+            Token::RightCurlyBracket,
+        ];
+        assert_eq!(
+            vec![
+                Instruction::PushData(27),
+                Instruction::Define(
+                    "f".to_owned(),
+                    0,
+                    vec![Instruction::PushData(27)],
+                    Attributes::default()
+                ),
+            ],
+            parse_all(tokens)
+        );
+    }
+
+    test_parse_failure![
+        constant_redefined: (
+            vec![Token::Const, name("N"), Token::Word(1), Token::Const, name("N"), Token::Word(2)],
+            "Syntax error: Constant 'N' is already defined."
+        ),
+        undefined_constant: (
+            vec![Token::Const, name("N"), name("M")],
+            "Syntax error: Undefined constant 'M'."
+        ),
+        constant_inside_defn: (
+            vec![
+                Token::Defn,
+                name("f"),
+                Token::LeftParen,
+                Token::Word(0),
+                Token::RightParen,
+                Token::LeftCurlyBracket,
+                Token::Const,
+            ],
+            "Syntax error: MODULE, IMPORT, CONST, and MACRO are only allowed outside functions."
+        ),
+        function_named_like_constant: (
+            vec![Token::Const, name("N"), Token::Word(1), Token::Defn, name("N")],
+            "Syntax error: Cannot define function 'N' because there is a constant with the same name."
+        ),
+        reserved_constant: (
+            vec![Token::Const, name("__N"), Token::Word(1)],
+            "Syntax error: Invalid constant name '__N'."
+        ),
+    ];
+
+    #[test]
+    fn macros() {
+        let macro_call = |m: &str| Token::MacroCall(m.to_owned());
+        let tokens = vec![
+            Token::Macro,
+            name("twice"),
+            Token::LeftCurlyBracket,
+            Token::Push,
+            Token::Argument(0),
+            Token::Push,
+            Token::Argument(0),
+            Token::RightCurlyBracket,
+            Token::Macro,
+            name("one"),
+            Token::LeftCurlyBracket,
+            Token::Push,
+            Token::Word(1),
+            Token::RightCurlyBracket,
+            macro_call("one"),
+            Token::Defn,
+            name("f"),
+            Token::LeftParen,
+            Token::Word(1),
+            Token::RightParen,
+            Token::LeftCurlyBracket,
+            macro_call("twice"),
+            macro_call("one"),
+            Token::RightCurlyBracket,
+        ];
+        assert_eq!(
+            vec![
+                Instruction::PushData(1),
+                Instruction::Define(
+                    "f".to_owned(),
+                    1,
+                    vec![
+                        Instruction::PushArg(0),
+                        Instruction::PushArg(0),
+                        Instruction::PushData(1),
+                    ],
+                    Attributes::default()
+                ),
+            ],
+            parse_all(tokens)
+        );
+    }
+
+    #[test]
+    fn macro_arguments() {
+        let macros = || {
+            vec![
+                Token::Macro,
+                name("first"),
+                Token::LeftCurlyBracket,
+                Token::Push,
+                Token::Argument(0),
+                Token::RightCurlyBracket,
+                Token::Macro,
+                name("named"),
+                Token::LeftCurlyBracket,
+                Token::Push,
+                Token::NamedArgument("a".to_owned()),
+                Token::RightCurlyBracket,
+            ]
+        };
+        let stack_order = [
+            Token::Defn,
+            name("f"),
+            Token::LeftParen,
+            Token::Word(2),
+            Token::StackOrder,
+            Token::RightParen,
+            Token::LeftCurlyBracket,
+            Token::MacroCall("first".to_owned()),
+            Token::Push,
+            Token::Argument(0),
+            Token::RightCurlyBracket,
+        ];
+        let named = [
+            Token::Defn,
+            name("g"),
+            Token::LeftParen,
+            name("a"),
+            name("b"),
+            Token::RightParen,
+            Token::LeftCurlyBracket,
+            Token::MacroCall("named".to_owned()),
+            Token::RightCurlyBracket,
+        ];
+        // The arguments are resolved in the function that the macro is invoked in, as if they were written there
+        assert_eq!(
+            vec![
+                Instruction::Define(
+                    "f".to_owned(),
+                    2,
+                    vec![Instruction::PushArg(1), Instruction::PushArg(1)],
+                    Attributes {
+                        args_order: ArgsOrder::Stack,
+                        ..Attributes::default()
+                    }
+                ),
+                Instruction::Define(
+                    "g".to_owned(),
+                    2,
+                    vec![Instruction::PushArg(1)],
+                    Attributes {
+                        arg_names: vec!["a".to_owned(), "b".to_owned()],
+                        ..Attributes::default()
+                    }
+                ),
+            ],
+            parse_all(
+                macros()
+                    .into_iter()
+                    .chain(stack_order)
+                    .chain(named)
+                    .collect()
+            )
+        );
+
+        let out_of_range = [
+            Token::Defn,
+            name("h"),
+            Token::LeftParen,
+            Token::Word(0),
+            Token::StackOrder,
+            Token::RightParen,
+            Token::LeftCurlyBracket,
+            Token::MacroCall("first".to_owned()),
+        ];
+        let mut parser = Parser::new(macros().into_iter().chain(out_of_range));
+        assert_err_with_msg!(
+            parser.next_instruction(),
+            "Syntax error: Cannot use $0 in a function with 0 argument(s) in stack order."
+        );
+        let unknown_name = [
+            Token::Defn,
+            name("h"),
+            Token::LeftParen,
+            Token::Word(1),
+            Token::RightParen,
+            Token::LeftCurlyBracket,
+            Token::MacroCall("named".to_owned()),
+        ];
+        let mut parser = Parser::new(macros().into_iter().chain(unknown_name));
+        assert_err_with_msg!(
+            parser.next_instruction(),
+            "Syntax error: Unknown argument '$a'."
+        );
+    }
+
+    #[test]
+    fn it() {
+        let tokens = vec![Token::Push, Token::It, Token::Push, Token::It];
+        let mut parser = Parser::new(tokens.into_iter());
+        assert_err_with_msg!(
+            parser.next_instruction(),
+            "Syntax error: $it can only be used in the REPL, after a line that leaves something on the stack."
+        );
+        parser.set_it(Some(Instruction::PushFunction("f".to_owned())));
+        assert_ok_and_eq!(
+            parser.next_instruction(),
+            Some(Instruction::PushFunction("f".to_owned()))
+        );
+        assert_ok_and_eq!(parser.next_instruction(), None);
+    }
+
+    #[test]
+    fn nested_macros() {
+        let tokens = vec![
+            Token::Macro,
+            name("a"),
+            Token::LeftCurlyBracket,
+            Token::Push,
+            Token::Word(1),
+            Token::MacroCall("b".to_owned()),
+            Token::RightCurlyBracket,
+            Token::Macro,
+            name("b"),
+            Token::LeftCurlyBracket,
+            Token::Push,
+            Token::Word(2),
+            Token::RightCurlyBracket,
+            Token::MacroCall("a".to_owned()),
+            Token::Exit,
+        ];
+        assert_eq!(
+            vec![
+                Instruction::PushData(1),
+                Instruction::PushData(2),
+                Instruction::Exit
+            ],
+            parse_all(tokens)
+        );
+    }
+
+    test_parse_failure![
+        macro_redefined: (
+            vec![
+                Token::Macro,
+                name("m"),
+                Token::LeftCurlyBracket,
+                Token::RightCurlyBracket,
+                Token::Macro,
+                name("m"),
+            ],
+            "Syntax error: Macro 'm' is already defined."
+        ),
+        undefined_macro: (
+            vec![Token::MacroCall("m".to_owned())],
+            "Syntax error: Undefined macro 'm'."
+        ),
+        recursive_macro: (
+            vec![
+                Token::Macro,
+                name("m"),
+                Token::LeftCurlyBracket,
+                Token::MacroCall("m".to_owned()),
+                Token::RightCurlyBracket,
+                Token::MacroCall("m".to_owned()),
+            ],
+            "Syntax error: Macros were expanded more than 100 levels deep, so macro 'm' probably invokes itself."
+        ),
+        macro_argument_outside_defn: (
+            vec![
+                Token::Macro,
+                name("m"),
+                Token::LeftCurlyBracket,
+                Token::Push,
+                Token::Argument(0),
+                Token::RightCurlyBracket,
+                Token::MacroCall("m".to_owned()),
+            ],
+            "Syntax error: Cannot use arguments outside functions (in macro 'm')."
+        ),
+        defn_inside_macro: (
+            vec![Token::Macro, name("m"), Token::LeftCurlyBracket, Token::Defn],
+            "Syntax error: Unexpected token Defn."
+        ),
+    ];
+
+    #[test]
+    fn recover_from_unfinished_defn() {
+        let tokens = vec![
+            Token::Defn,
+            Token::FunctionName("f".to_owned()),
+            Token::LeftParen,
+            Token::Word(0),
+            Token::RightParen,
+            Token::LeftCurlyBracket,
+            Token::Push,
+            Token::Argument(0),
+            Token::Push,
+            Token::RightParen,
+            Token::Push,
+            Token::Word(1),
+            Token::Defn,
+            Token::FunctionName("g".to_owned()),
+            Token::LeftParen,
+            Token::Word(0),
+            Token::RightParen,
+            Token::LeftCurlyBracket,
+            Token::RightCurlyBracket,
+        ];
+        let mut parser = Parser::new(tokens.into_iter());
+
+        assert_err_with_msg!(
+            parser.next_instruction(),
+            "Syntax error: Unexpected token RightParen."
+        );
+        parser.recover();
+        assert_ok_and_eq!(
+            parser.next_instruction(),
+            Some(Instruction::Define(
+                "g".to_owned(),
+                0,
+                vec![],
+                Attributes::default()
+            ))
+        );
+        assert_ok_and_eq!(parser.next_instruction(), None);
+    }
+
+    #[test]
+    fn unreachable_code() {
+        let tokens = vec![
+            Token::Defn,
+            Token::FunctionName("f".to_owned()),
+            Token::LeftParen,
+            Token::Word(0),
+            Token::RightParen,
+            Token::LeftCurlyBracket,
+            Token::Exit,
+            Token::CallIf,
+            Token::Exit,
+            Token::RightCurlyBracket,
+            Token::Exit,
+            Token::CallIf,
+        ];
+        let mut parser = Parser::new(tokens.into_iter());
+
+        while parser.next_instruction().unwrap().is_some() {}
+        assert_eq!(
+            vec![(
+                Lint::UnreachableCode,
+                "The instructions after an EXIT in function 'f' can never run.".to_owned()
+            )],
+            parser.take_warnings()
+        );
+        assert!(parser.take_warnings().is_empty());
+    }
 }
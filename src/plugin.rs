@@ -0,0 +1,208 @@
+//! Loading built-in functions from dynamic libraries (`--plugin`).
+//!
+//! A plugin is a shared library that exports the following C functions:
+//!
+//! ```c
+//! // Must return PLATES_PLUGIN_ABI_VERSION (currently 1).
+//! uint32_t plates_plugin_abi_version(void);
+//!
+//! // Returns the functions in the plugin and stores how many there are in `count`. The array and the names must stay
+//! // valid for as long as the library is loaded.
+//! const struct plates_builtin *plates_plugin_builtins(uint32_t *count);
+//!
+//! struct plates_builtin {
+//!     // Must be of the form "__host_<name>__".
+//!     const char *name;
+//!     uint32_t arity;
+//!     // Receives the arguments (`args[0]` is the word that was on top of the stack) and writes the words to push
+//!     // into `out`, with the top one last. Returns the number of words written, or a negative number on error.
+//!     int32_t (*call)(const uint64_t *args, uint32_t arity, uint64_t *out, uint32_t out_capacity);
+//! };
+//! ```
+//!
+//! Only data words can be passed to or returned from plugin functions.
+
+use std::{
+    ffi::{c_char, CStr},
+    path::Path,
+    rc::Rc,
+};
+
+use anyhow::{anyhow, Context, Error};
+use libloading::{Library, Symbol};
+
+use crate::runtime::{Runtime, Word};
+
+pub const ABI_VERSION: u32 = 1;
+
+/// The maximum number of words that a plugin function can push in one call.
+const OUT_CAPACITY: usize = 256;
+
+pub type PluginFn =
+    unsafe extern "C" fn(args: *const u64, arity: u32, out: *mut u64, out_capacity: u32) -> i32;
+
+#[repr(C)]
+pub struct PluginBuiltin {
+    pub name: *const c_char,
+    pub arity: u32,
+    pub call: PluginFn,
+}
+
+/// Loads the plugin at `path` and registers all of its functions in the runtime. Returns the number of functions.
+pub fn load(path: &Path, runtime: &mut Runtime) -> Result<usize, Error> {
+    let context = || format!("Failed to load plugin '{}'.", path.display());
+
+    // SAFETY: loading a library runs its initialization code. Plugins are trusted just like the interpreter itself.
+    let library = unsafe { Library::new(path) }.with_context(context)?;
+    let library = Rc::new(library);
+    let builtins = unsafe {
+        let version: Symbol<unsafe extern "C" fn() -> u32> = library
+            .get(b"plates_plugin_abi_version")
+            .with_context(context)?;
+        if version() != ABI_VERSION {
+            return Err(anyhow!(
+                "Plugin error: Unsupported ABI version {} (expected {ABI_VERSION}).",
+                version()
+            ))
+            .with_context(context);
+        }
+
+        let builtins: Symbol<unsafe extern "C" fn(*mut u32) -> *const PluginBuiltin> = library
+            .get(b"plates_plugin_builtins")
+            .with_context(context)?;
+        let mut count = 0;
+        let array = builtins(&mut count);
+        if array.is_null() {
+            &[]
+        } else {
+            std::slice::from_raw_parts(array, count as usize)
+        }
+    };
+
+    for builtin in builtins {
+        // SAFETY: the plugin promises that the names are valid C strings that live as long as the library
+        let name = unsafe { CStr::from_ptr(builtin.name) }
+            .to_str()
+            .map_err(|_| anyhow!("Plugin error: A function name is not valid UTF-8."))
+            .with_context(context)?;
+        register(
+            runtime,
+            name,
+            builtin.arity,
+            builtin.call,
+            Rc::clone(&library),
+        )
+        .with_context(context)?;
+    }
+    Ok(builtins.len())
+}
+
+/// Registers a single plugin function. `library` is kept alive for as long as the function is registered.
+fn register<T: 'static>(
+    runtime: &mut Runtime,
+    name: &str,
+    arity: u32,
+    call: PluginFn,
+    library: Rc<T>,
+) -> Result<(), Error> {
+    runtime.register_builtin(name, arity as usize, move |stack| {
+        // Keep the library loaded for as long as the function exists
+        let _ = &library;
+        // The arguments start with the one on top of the stack, like `$0`. They are only popped once the plugin succeeds.
+        let args = (0..arity as usize)
+            .map(|i| match stack.peek(i) {
+                Some(Word::Data(n)) => Ok(*n),
+                _ => Err(anyhow!("Plugin functions only accept data words.")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut out = [0; OUT_CAPACITY];
+        // SAFETY: `args` has `arity` elements and `out` has `OUT_CAPACITY` elements
+        let written = unsafe { call(args.as_ptr(), arity, out.as_mut_ptr(), OUT_CAPACITY as u32) };
+        let results = match usize::try_from(written) {
+            Ok(n) if n <= OUT_CAPACITY => &out[..n],
+            Ok(_) => return Err(anyhow!("The plugin wrote too many words.")),
+            Err(_) => return Err(anyhow!("The plugin reported an error.")),
+        };
+        for _ in 0..arity {
+            stack.pop();
+        }
+        for n in results {
+            stack.push(Word::Data(*n))?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Instruction;
+
+    unsafe extern "C" fn sum(
+        args: *const u64,
+        arity: u32,
+        out: *mut u64,
+        _out_capacity: u32,
+    ) -> i32 {
+        let args = std::slice::from_raw_parts(args, arity as usize);
+        if args.contains(&0) {
+            return -1;
+        }
+        *out = args.iter().sum();
+        *out.add(1) = arity as u64;
+        2
+    }
+
+    fn run(
+        runtime: &mut Runtime,
+        instructions: Vec<Instruction>,
+    ) -> Result<Option<Vec<u64>>, Error> {
+        for instruction in instructions {
+            runtime.run(instruction)?;
+        }
+        Ok(runtime.stack_data())
+    }
+
+    fn call(f: &str) -> Vec<Instruction> {
+        vec![
+            Instruction::PushData(1),
+            Instruction::PushFunction(f.to_owned()),
+            Instruction::CallIf,
+        ]
+    }
+
+    #[test]
+    fn plugin_function() {
+        let mut runtime = Runtime::new();
+        let library = Rc::new(());
+        register(&mut runtime, "__host_sum__", 3, sum, Rc::clone(&library)).unwrap();
+        assert_eq!(2, Rc::strong_count(&library));
+
+        let program = [
+            vec![
+                Instruction::PushData(1),
+                Instruction::PushData(2),
+                Instruction::PushData(3),
+            ],
+            call("__host_sum__"),
+        ]
+        .concat();
+        assert_eq!(Some(vec![6, 3]), run(&mut runtime, program).unwrap());
+
+        let program = [vec![Instruction::PushData(0)], call("__host_sum__")].concat();
+        assert_eq!(
+            "Runtime error: Host function '__host_sum__' failed: The plugin reported an error.",
+            format!("{}", run(&mut runtime, program).unwrap_err())
+        );
+    }
+
+    #[test]
+    fn missing_library() {
+        let error = load(Path::new("/nonexistent/libplugin.so"), &mut Runtime::new()).unwrap_err();
+        assert_eq!(
+            "Failed to load plugin '/nonexistent/libplugin.so'.",
+            format!("{error}")
+        );
+    }
+}
@@ -0,0 +1,275 @@
+//! Conditions on the state of a running program, like `depth > 100` or `top == 0`, for conditional breakpoints in the
+//! debugger.
+//!
+//! A condition compares two operands with `==`, `!=`, `<`, `<=`, `>`, or `>=`, and conditions can be combined with `and`
+//! and `or` (`and` first). An operand is a number or one of these:
+//! - `depth`: the number of words on the stack.
+//! - `calls`: the number of functions that are running.
+//! - `top`: the word on top of the stack.
+//! - `stack[N]`: the word N places below the top of the stack, so `stack[0]` is `top`.
+//! - `$N`: an argument of the function that was called last.
+//!
+//! A comparison with a word that is missing or that is not a number (like a function) is false.
+
+use std::fmt::Display;
+
+use anyhow::{anyhow, Error};
+
+use crate::runtime::{Runtime, Word};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Operand {
+    Number(u64),
+    Depth,
+    Calls,
+    /// A word of the stack, counting from the top.
+    Stack(usize),
+    Arg(usize),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Expr {
+    Compare(Operand, Comparison, Operand),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+/// A parsed condition, which displays as it was written.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Predicate {
+    source: String,
+    expr: Expr,
+}
+
+impl Predicate {
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        let source = source.trim();
+        let error = |reason: &str| anyhow!("Syntax error: Invalid condition '{source}'. {reason}");
+        let tokens = tokens(source).map_err(|c| error(&format!("Unexpected '{c}'.")))?;
+        let mut parser = ExprParser { tokens, next: 0 };
+        let expr = parser.or().map_err(|e| error(&e))?;
+        if let Some(token) = parser.peek() {
+            return Err(error(&format!("Unexpected '{token}'.")));
+        }
+        Ok(Predicate {
+            source: source.to_owned(),
+            expr,
+        })
+    }
+
+    /// Returns true iff the condition holds for the current state of the runtime.
+    pub fn eval(&self, runtime: &Runtime) -> bool {
+        eval(&self.expr, runtime)
+    }
+}
+
+impl Display for Predicate {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "{}", self.source)
+    }
+}
+
+/// Splits a condition into words, numbers, `$N`, brackets, and comparison operators. Fails with the first character
+/// that cannot be part of a condition.
+fn tokens(source: &str) -> Result<Vec<&str>, char> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let same_kind: fn(char) -> bool = match c {
+            c if c.is_whitespace() => continue,
+            '[' | ']' => |_| false,
+            '=' | '!' | '<' | '>' => |c| c == '=',
+            c if c.is_alphanumeric() || c == '_' || c == '$' => |c| c.is_alphanumeric() || c == '_',
+            c => return Err(c),
+        };
+        let mut end = start + c.len_utf8();
+        while let Some(&(i, c)) = chars.peek() {
+            if !same_kind(c) {
+                break;
+            }
+            end = i + c.len_utf8();
+            chars.next();
+        }
+        tokens.push(&source[start..end]);
+    }
+    Ok(tokens)
+}
+
+struct ExprParser<'a> {
+    tokens: Vec<&'a str>,
+    next: usize,
+}
+
+impl<'a> ExprParser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.next).copied()
+    }
+
+    fn advance(&mut self) -> Option<&'a str> {
+        let token = self.peek();
+        self.next += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some("or") {
+            self.advance();
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.comparison()?;
+        while self.peek() == Some("and") {
+            self.advance();
+            expr = Expr::And(Box::new(expr), Box::new(self.comparison()?));
+        }
+        Ok(expr)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.operand()?;
+        let comparison = match self.advance() {
+            Some("==") => Comparison::Eq,
+            Some("!=") => Comparison::Ne,
+            Some("<") => Comparison::Lt,
+            Some("<=") => Comparison::Le,
+            Some(">") => Comparison::Gt,
+            Some(">=") => Comparison::Ge,
+            _ => return Err("Expected ==, !=, <, <=, >, or >=.".to_owned()),
+        };
+        Ok(Expr::Compare(left, comparison, self.operand()?))
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        let expected = "Expected a number, depth, calls, top, stack[N], or $N.";
+        let operand = match self.advance() {
+            Some("depth") => Operand::Depth,
+            Some("calls") => Operand::Calls,
+            Some("top") => Operand::Stack(0),
+            Some("stack") => {
+                let index = match (self.advance(), self.advance(), self.advance()) {
+                    (Some("["), Some(index), Some("]")) => index.parse().ok(),
+                    _ => None,
+                };
+                Operand::Stack(index.ok_or(expected)?)
+            }
+            Some(token) if token.starts_with('$') => {
+                Operand::Arg(token[1..].parse().map_err(|_| expected)?)
+            }
+            Some(token) => Operand::Number(token.parse().map_err(|_| expected)?),
+            None => return Err(expected.to_owned()),
+        };
+        Ok(operand)
+    }
+}
+
+fn eval(expr: &Expr, runtime: &Runtime) -> bool {
+    match expr {
+        Expr::And(left, right) => eval(left, runtime) && eval(right, runtime),
+        Expr::Or(left, right) => eval(left, runtime) || eval(right, runtime),
+        Expr::Compare(left, comparison, right) => {
+            let (Some(left), Some(right)) = (value(*left, runtime), value(*right, runtime)) else {
+                return false;
+            };
+            match comparison {
+                Comparison::Eq => left == right,
+                Comparison::Ne => left != right,
+                Comparison::Lt => left < right,
+                Comparison::Le => left <= right,
+                Comparison::Gt => left > right,
+                Comparison::Ge => left >= right,
+            }
+        }
+    }
+}
+
+fn value(operand: Operand, runtime: &Runtime) -> Option<u64> {
+    let word = match operand {
+        Operand::Number(n) => return Some(n),
+        Operand::Depth => return Some(runtime.stack_size() as u64),
+        Operand::Calls => return Some(runtime.call_stack().count() as u64),
+        Operand::Stack(i) => runtime.stack().iter().rev().nth(i)?,
+        Operand::Arg(i) => runtime.args().get(i)?,
+    };
+    match word {
+        Word::Data(n) => Some(*n),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::Lexer, parser::Parser, reader::FileReader};
+
+    fn runtime(source: &str) -> Runtime {
+        let mut runtime = Runtime::new();
+        let mut parser = Parser::new(Lexer::new(FileReader::from_source(source)));
+        while let Some(instruction) = parser.next_instruction().unwrap() {
+            runtime.run(instruction).unwrap();
+        }
+        runtime
+    }
+
+    #[test]
+    fn eval() {
+        let runtime = runtime("PUSH 7 PUSH f PUSH 0");
+        for (condition, expected) in [
+            ("depth == 3", true),
+            ("depth > 100", false),
+            ("top == 0", true),
+            ("stack[2] >= 7", true),
+            ("stack[2] < 7", false),
+            // Functions and missing words are not numbers
+            ("stack[1] != 0", false),
+            ("stack[3] == 0", false),
+            ("$0 == 0", false),
+            ("calls == 0", true),
+            ("depth > 100 or top == 0", true),
+            ("top == 0 and depth < 3 or calls == 0", true),
+        ] {
+            let predicate = Predicate::parse(condition).unwrap();
+            assert_eq!(expected, predicate.eval(&runtime), "{condition}");
+        }
+    }
+
+    #[test]
+    fn invalid_conditions() {
+        for (condition, message) in [
+            ("depth", "Expected ==, !=, <, <=, >, or >=."),
+            ("depth => 1", "Expected ==, !=, <, <=, >, or >=."),
+            (
+                "top == f",
+                "Expected a number, depth, calls, top, stack[N], or $N.",
+            ),
+            (
+                "stack[x] == 1",
+                "Expected a number, depth, calls, top, stack[N], or $N.",
+            ),
+            (
+                "depth == 1 and",
+                "Expected a number, depth, calls, top, stack[N], or $N.",
+            ),
+            ("depth == 1 top == 1", "Unexpected 'top'."),
+            ("(depth == 1)", "Unexpected '('."),
+        ] {
+            assert_eq!(
+                format!("Syntax error: Invalid condition '{condition}'. {message}"),
+                format!("{}", Predicate::parse(condition).unwrap_err())
+            );
+        }
+    }
+}
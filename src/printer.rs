@@ -0,0 +1,537 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    lexer::{lex_line_with_spans, Token},
+    parser::{ArgsOrder, Instruction},
+};
+
+const INDENT: &str = "    ";
+
+/// Renders instructions as plates source code, with one instruction per line. The bodies of `DEFN`s are indented, so
+/// parsing the output gives back the same instructions.
+pub fn print_instructions(instructions: &[Instruction]) -> String {
+    let mut output = String::new();
+    for instruction in instructions {
+        write_instruction(instruction, &mut output);
+    }
+    output
+}
+
+/// Renders a single instruction as plates source code, without a trailing newline. A `DEFN` still takes up several
+/// lines.
+pub fn print_instruction(instruction: &Instruction) -> String {
+    let mut output = String::new();
+    write_instruction(instruction, &mut output);
+    output.pop();
+    output
+}
+
+fn write_instruction(instruction: &Instruction, output: &mut String) {
+    match instruction {
+        Instruction::PushData(n) => *output += &format!("PUSH {n}\n"),
+        Instruction::PushBig(n) => *output += &format!("PUSH {n}\n"),
+        Instruction::PushFunction(f) => *output += &format!("PUSH {f}\n"),
+        Instruction::PushRandom => *output += "PUSH *\n",
+        Instruction::PushArg(n) => *output += &format!("PUSH ${n}\n"),
+        Instruction::Define(f, arg_count, body, attributes) => {
+            let keyword = if attributes.private {
+                "DEFN_PRIV"
+            } else {
+                "DEFN"
+            };
+            let names = &attributes.arg_names;
+            let stack_order = attributes.args_order == ArgsOrder::Stack;
+            let mut signature = match names.len() {
+                0 => arg_count.to_string(),
+                _ => names.join(" "),
+            };
+            if stack_order {
+                signature += " STACK_ORDER";
+            }
+            if let Some(results) = attributes.results {
+                signature += &format!(" -> {results}");
+            }
+            *output += &format!("{keyword} {f} ({signature}) {{\n");
+            if let Some(doc) = &attributes.doc {
+                *output += &format!("{INDENT}\"{}\"\n", escape(doc));
+            }
+            for instruction in body {
+                let mut text = String::new();
+                match instruction {
+                    // $0 is the last name
+                    Instruction::PushArg(i) if *i < names.len() => {
+                        text = format!("PUSH ${}\n", names[names.len() - 1 - i]);
+                    }
+                    // In stack order, $0 is the deepest argument
+                    Instruction::PushArg(i) if stack_order && *i < *arg_count as usize => {
+                        text = format!("PUSH ${}\n", *arg_count as usize - 1 - i);
+                    }
+                    _ => write_instruction(instruction, &mut text),
+                }
+                for line in text.lines() {
+                    *output += &format!("{INDENT}{line}\n");
+                }
+            }
+            *output += "}\n";
+        }
+        Instruction::CallIf => *output += "CALLIF\n",
+        Instruction::Exit => *output += "EXIT\n",
+    }
+}
+
+/// Renders instructions as the shortest source code that parses back to the same instructions (apart from docstrings,
+/// argument names, and the like), on a single line. Defined functions get the shortest free names, with the most used
+/// functions getting the shortest ones. Also returns the new names, as `(new, old)` pairs in the order that the
+/// functions were defined.
+pub fn minify(instructions: &[Instruction]) -> (String, Vec<(String, String)>) {
+    let mut defined = Vec::new();
+    let mut uses = HashMap::<&str, usize>::new();
+    count_uses(instructions, &mut defined, &mut uses);
+    // Functions that are pushed but not defined, like built-in functions, keep their names
+    let taken = uses
+        .keys()
+        .filter(|f| !defined.contains(f))
+        .copied()
+        .collect::<HashSet<_>>();
+
+    let mut by_uses = defined.clone();
+    by_uses.sort_by_key(|f| std::cmp::Reverse(uses[f]));
+    let mut short_names = (1..)
+        .flat_map(names_of_length)
+        .filter(|name| is_function_name(name) && !taken.contains(name.as_str()));
+    let renames = by_uses
+        .into_iter()
+        .map(|f| {
+            (
+                f,
+                short_names.next().expect("there are infinitely many names"),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut tokens = Vec::new();
+    for instruction in instructions {
+        minify_instruction(instruction, &renames, &mut tokens);
+    }
+    let mut source = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 && needs_space(&tokens[i - 1], token) {
+            source.push(' ');
+        }
+        source += token;
+    }
+    let map = defined
+        .into_iter()
+        .map(|f| (renames[f].clone(), f.to_owned()))
+        .collect();
+    (source, map)
+}
+
+/// Finds the functions that are defined, in order, and counts how many times each function is named.
+fn count_uses<'a>(
+    instructions: &'a [Instruction],
+    defined: &mut Vec<&'a str>,
+    uses: &mut HashMap<&'a str, usize>,
+) {
+    for instruction in instructions {
+        match instruction {
+            Instruction::PushFunction(f) => *uses.entry(f).or_default() += 1,
+            Instruction::Define(f, _, body, _) => {
+                if !defined.contains(&f.as_str()) {
+                    defined.push(f);
+                }
+                *uses.entry(f).or_default() += 1;
+                count_uses(body, defined, uses);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Every name with `length` characters that starts with a letter, in order.
+fn names_of_length(length: usize) -> impl Iterator<Item = String> {
+    const FIRST: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    const REST: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789_";
+    let mut names = FIRST.chars().map(String::from).collect::<Vec<_>>();
+    for _ in 1..length {
+        names = names
+            .iter()
+            .flat_map(|name| REST.chars().map(move |c| format!("{name}{c}")))
+            .collect();
+    }
+    names.into_iter()
+}
+
+/// Whether `name` is read back as a function name, rather than as a keyword.
+fn is_function_name(name: &str) -> bool {
+    matches!(lex_line_with_spans(name).as_deref(), Ok([(Token::FunctionName(f), _)]) if f == name)
+}
+
+fn minify_instruction(
+    instruction: &Instruction,
+    renames: &HashMap<&str, String>,
+    tokens: &mut Vec<String>,
+) {
+    let rename = |f: &String| renames.get(f.as_str()).unwrap_or(f).clone();
+    match instruction {
+        Instruction::PushData(n) => tokens.extend(["PUSH".to_owned(), n.to_string()]),
+        Instruction::PushBig(n) => tokens.extend(["PUSH".to_owned(), n.clone()]),
+        Instruction::PushFunction(f) => tokens.extend(["PUSH".to_owned(), rename(f)]),
+        Instruction::PushRandom => tokens.extend(["PUSH".to_owned(), "*".to_owned()]),
+        // The body is always numbered in pop order
+        Instruction::PushArg(n) => tokens.extend(["PUSH".to_owned(), format!("${n}")]),
+        Instruction::Define(f, arg_count, body, _) => {
+            tokens.extend([
+                "DEFN".to_owned(),
+                rename(f),
+                "(".to_owned(),
+                arg_count.to_string(),
+                ")".to_owned(),
+                "{".to_owned(),
+            ]);
+            for instruction in body {
+                minify_instruction(instruction, renames, tokens);
+            }
+            tokens.push("}".to_owned());
+        }
+        Instruction::CallIf => tokens.push("CALLIF".to_owned()),
+        Instruction::Exit => tokens.push("EXIT".to_owned()),
+    }
+}
+
+/// Whether a space is needed between two tokens, so that they are not read as one.
+fn needs_space(previous: &str, token: &str) -> bool {
+    let is_symbol = |c: char| c.is_alphanumeric() || c == '_';
+    let (Some(last), Some(first)) = (previous.chars().last(), token.chars().next()) else {
+        return false;
+    };
+    if !is_symbol(last) || !is_symbol(first) {
+        return false;
+    }
+    // A number (or an argument like `$0`) ends at the first character that is not a digit
+    let after_number = previous.starts_with(|c: char| c.is_ascii_digit() || c == '$');
+    !after_number || first.is_ascii_digit()
+}
+
+/// Describes a function for `plates doc` and the REPL's `:doc`: its signature, followed by its docstring (if any)
+/// indented on the next lines.
+pub fn print_doc(f: &str, arg_count: u32, results: Option<u32>, doc: Option<&str>) -> String {
+    let mut output = match results {
+        None => format!("{f} ({arg_count})\n"),
+        Some(results) => format!("{f} ({arg_count} -> {results})\n"),
+    };
+    for line in doc.unwrap_or_default().lines() {
+        output += &format!("{INDENT}{line}\n");
+    }
+    output
+}
+
+/// Escapes a string so that it can be written as a string literal.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{
+        lexer::Lexer,
+        parser::{Attributes, Parser},
+        reader::FileReader,
+    };
+
+    fn function_name() -> impl Strategy<Value = String> {
+        prop_oneof![
+            "[a-zA-Z][a-zA-Z0-9_]{0,8}",
+            "_[a-zA-Z0-9][a-zA-Z0-9_]{0,8}",
+            proptest::sample::select(crate::runtime::BUILTIN_FUNCTIONS).prop_map(str::to_owned),
+        ]
+        // Keywords are not function names
+        .prop_filter("keyword", |f| {
+            ![
+                "PUSH",
+                "DEFN",
+                "DEFN_PRIV",
+                "CALLIF",
+                "EXIT",
+                "MODULE",
+                "IMPORT",
+                "AS",
+                "CONST",
+                "MACRO",
+                "STACK_ORDER",
+            ]
+            .contains(&f.as_str())
+        })
+    }
+
+    /// An instruction that can appear in the body of a function.
+    fn body_instruction() -> impl Strategy<Value = Instruction> {
+        prop_oneof![
+            any::<u32>().prop_map(|n| Instruction::PushData(n.into())),
+            function_name().prop_map(Instruction::PushFunction),
+            Just(Instruction::PushRandom),
+            (0..10usize).prop_map(Instruction::PushArg),
+            Just(Instruction::CallIf),
+            Just(Instruction::Exit),
+        ]
+    }
+
+    /// An instruction that can appear at the top level.
+    fn instruction() -> impl Strategy<Value = Instruction> {
+        prop_oneof![
+            any::<u32>().prop_map(|n| Instruction::PushData(n.into())),
+            function_name().prop_map(Instruction::PushFunction),
+            Just(Instruction::PushRandom),
+            Just(Instruction::CallIf),
+            Just(Instruction::Exit),
+            (
+                function_name().prop_filter("reserved", |f| !f.starts_with("__")),
+                any::<u32>(),
+                proptest::collection::vec(body_instruction(), 0..10),
+                proptest::option::of("[ -~\n]{0,20}"),
+                any::<bool>(),
+                proptest::option::of(any::<u32>()),
+                any::<bool>(),
+            )
+                .prop_map(
+                    |(f, arg_count, body, doc, private, results, stack_order)| {
+                        // Arguments past the argument count cannot be written in stack order
+                        let in_range = body.iter().all(
+                            |i| !matches!(i, Instruction::PushArg(n) if *n >= arg_count as usize),
+                        );
+                        let args_order = if stack_order && in_range {
+                            ArgsOrder::Stack
+                        } else {
+                            ArgsOrder::Pop
+                        };
+                        Instruction::Define(
+                            f,
+                            arg_count,
+                            body,
+                            Attributes {
+                                doc,
+                                private,
+                                origin: None,
+                                results,
+                                arg_names: vec![],
+                                args_order,
+                            },
+                        )
+                    }
+                ),
+        ]
+    }
+
+    fn parse(source: &str) -> Vec<Instruction> {
+        let mut parser = Parser::new(Lexer::new(FileReader::from_source(source)));
+        let mut instructions = vec![];
+        while let Some(instruction) = parser.next_instruction().unwrap() {
+            instructions.push(instruction);
+        }
+        instructions
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(instructions in proptest::collection::vec(instruction(), 0..20)) {
+            prop_assert_eq!(&instructions, &parse(&print_instructions(&instructions)));
+        }
+    }
+
+    /// Renames the functions like `minify` did, and forgets what it leaves out.
+    fn rename_and_forget(
+        instructions: &[Instruction],
+        map: &[(String, String)],
+    ) -> Vec<Instruction> {
+        let rename = |f: &String| match map.iter().find(|(_, old)| old == f) {
+            None => f.clone(),
+            Some((new, _)) => new.clone(),
+        };
+        instructions
+            .iter()
+            .map(|instruction| match instruction {
+                Instruction::PushFunction(f) => Instruction::PushFunction(rename(f)),
+                Instruction::Define(f, arg_count, body, _) => Instruction::Define(
+                    rename(f),
+                    *arg_count,
+                    rename_and_forget(body, map),
+                    Attributes::default(),
+                ),
+                instruction => instruction.clone(),
+            })
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn minify_round_trip(instructions in proptest::collection::vec(instruction(), 0..20)) {
+            let (source, map) = minify(&instructions);
+            prop_assert!(!source.contains('\n'));
+            prop_assert_eq!(rename_and_forget(&instructions, &map), parse(&source));
+        }
+    }
+
+    #[test]
+    fn minify_program() {
+        let source = "
+            // Prints the numbers from 0 to 4, one per line
+            DEFN print_line (1) {
+                \"Prints a number.\"
+                PUSH 0 PUSH 10 PUSH $0
+                PUSH 1 PUSH __num_to_str__ CALLIF
+                PUSH 1 PUSH __print__ CALLIF
+                PUSH 1 PUSH __print__ CALLIF
+            }
+            DEFN_PRIV unused (a b) { PUSH $a PUSH * }
+            PUSH 5 PUSH print_line PUSH 1 PUSH __call_n_times__ CALLIF
+        ";
+        let (source, map) = minify(&parse(source));
+        assert_eq!(
+            "DEFN a(1){PUSH 0PUSH 10PUSH$0PUSH 1PUSH __num_to_str__ CALLIF PUSH 1PUSH __print__ CALLIF PUSH 1PUSH __print__ CALLIF}DEFN b(2){PUSH$1PUSH*}PUSH 5PUSH a PUSH 1PUSH __call_n_times__ CALLIF",
+            source
+        );
+        assert_eq!(
+            vec![
+                ("a".to_owned(), "print_line".to_owned()),
+                ("b".to_owned(), "unused".to_owned())
+            ],
+            map
+        );
+    }
+
+    #[test]
+    fn minified_names_skip_keywords_and_taken_names() {
+        let mut instructions = vec![Instruction::PushFunction("a".to_owned())];
+        for i in 0..2000 {
+            instructions.push(Instruction::Define(
+                format!("f{i}"),
+                0,
+                vec![],
+                Attributes::default(),
+            ));
+        }
+        let (_, map) = minify(&instructions);
+        let names = map.into_iter().map(|(new, _)| new).collect::<Vec<_>>();
+        assert_eq!("b", names[0]);
+        assert!(!names.contains(&"a".to_owned()));
+        assert!(!names.contains(&"AS".to_owned()));
+        assert!(names.contains(&"AT".to_owned()));
+    }
+
+    #[test]
+    fn print_all_instructions() {
+        let instructions = vec![
+            Instruction::Define(
+                "f".to_owned(),
+                2,
+                vec![Instruction::PushArg(1), Instruction::Exit],
+                Attributes {
+                    doc: Some("Says \"hi\".\nThen stops.".to_owned()),
+                    private: true,
+                    origin: None,
+                    results: Some(1),
+                    arg_names: vec![],
+                    args_order: ArgsOrder::Pop,
+                },
+            ),
+            Instruction::Define("empty".to_owned(), 0, vec![], Attributes::default()),
+            Instruction::PushData(42),
+            Instruction::PushRandom,
+            Instruction::PushFunction("f".to_owned()),
+            Instruction::CallIf,
+        ];
+        let expected = concat!(
+            "DEFN_PRIV f (2 -> 1) {\n",
+            "    \"Says \\\"hi\\\".\\nThen stops.\"\n",
+            "    PUSH $1\n",
+            "    EXIT\n",
+            "}\n",
+            "DEFN empty (0) {\n",
+            "}\n",
+            "PUSH 42\n",
+            "PUSH *\n",
+            "PUSH f\n",
+            "CALLIF\n",
+        );
+
+        assert_eq!(expected, print_instructions(&instructions));
+    }
+
+    #[test]
+    fn print_named_arguments() {
+        let source = "DEFN swap (a b -> 2) {\n    PUSH $b\n    PUSH $a\n}\n";
+        let instructions = parse(source);
+        assert_eq!(
+            vec![Instruction::PushArg(0), Instruction::PushArg(1)],
+            match &instructions[..] {
+                [Instruction::Define(_, 2, body, _)] => body.clone(),
+                _ => panic!("Expected one definition, found {instructions:?}."),
+            }
+        );
+        assert_eq!(source, print_instructions(&instructions));
+    }
+
+    #[test]
+    fn print_stack_order() {
+        let source = "DEFN sub (2 STACK_ORDER -> 1) {\n    PUSH $1\n    PUSH $0\n}\n";
+        let instructions = parse(source);
+        assert_eq!(
+            vec![Instruction::PushArg(0), Instruction::PushArg(1)],
+            match &instructions[..] {
+                [Instruction::Define(_, 2, body, _)] => body.clone(),
+                _ => panic!("Expected one definition, found {instructions:?}."),
+            }
+        );
+        assert_eq!(source, print_instructions(&instructions));
+    }
+
+    #[test]
+    fn print_docs() {
+        assert_eq!("f (0)\n", print_doc("f", 0, None, None));
+        assert_eq!("add (2 -> 1)\n", print_doc("add", 2, Some(1), None));
+        assert_eq!(
+            "swap (2)\n    Swaps the top two words.\n    Returns nothing else.\n",
+            print_doc(
+                "swap",
+                2,
+                None,
+                Some("Swaps the top two words.\nReturns nothing else.")
+            )
+        );
+    }
+
+    #[test]
+    fn print_nested_definitions() {
+        // The parser does not allow this, but bytecode could still contain it
+        let instruction = Instruction::Define(
+            "outer".to_owned(),
+            0,
+            vec![
+                Instruction::Define(
+                    "inner".to_owned(),
+                    1,
+                    vec![Instruction::PushArg(0)],
+                    Attributes::default(),
+                ),
+                Instruction::PushFunction("inner".to_owned()),
+            ],
+            Attributes::default(),
+        );
+        let expected = concat!(
+            "DEFN outer (0) {\n",
+            "    DEFN inner (1) {\n",
+            "        PUSH $0\n",
+            "    }\n",
+            "    PUSH inner\n",
+            "}",
+        );
+
+        assert_eq!(expected, print_instruction(&instruction));
+        assert_eq!("PUSH *", print_instruction(&Instruction::PushRandom));
+    }
+}
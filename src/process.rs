@@ -0,0 +1,155 @@
+//! Processes: runtimes that run in their own threads and communicate by sending each other words.
+//!
+//! Every runtime belongs to a process. The processes that were started from the same runtime (directly or not) share a
+//! registry of mailboxes, so any of them can send a word to any other.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use anyhow::{anyhow, Error};
+
+use crate::runtime::Word;
+
+/// A word along with the ID of the process that sent it.
+type Message = (u64, Word);
+
+#[derive(Debug, Default)]
+struct Registry {
+    next_id: u64,
+    mailboxes: HashMap<u64, Sender<Message>>,
+}
+
+#[derive(Debug)]
+pub struct Process {
+    id: u64,
+    mailbox: Receiver<Message>,
+    registry: Arc<Mutex<Registry>>,
+    /// The processes started by this one that have not been joined yet.
+    children: RefCell<Vec<(u64, JoinHandle<Vec<Error>>)>>,
+}
+
+impl Default for Process {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Process {
+    /// Creates the first process (with ID 0) of a new registry.
+    pub fn new() -> Self {
+        Self::register(Arc::new(Mutex::new(Registry::default())))
+    }
+
+    fn register(registry: Arc<Mutex<Registry>>) -> Self {
+        let (sender, mailbox) = mpsc::channel();
+        let id = {
+            let mut registry = registry.lock().unwrap();
+            let id = registry.next_id;
+            registry.next_id += 1;
+            registry.mailboxes.insert(id, sender);
+            id
+        };
+        Process {
+            id,
+            mailbox,
+            registry,
+            children: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Runs `body` in a new thread with a new process, and returns the ID of that process. `body` returns the errors
+    /// that occurred in the process and in the processes it started.
+    pub fn spawn(&self, body: impl FnOnce(Process) -> Vec<Error> + Send + 'static) -> u64 {
+        let child = Self::register(Arc::clone(&self.registry));
+        let id = child.id;
+        let handle = std::thread::spawn(move || body(child));
+        self.children.borrow_mut().push((id, handle));
+        id
+    }
+
+    /// Sends a word to the process with the given ID. If that process has already finished, the word is dropped.
+    pub fn send(&self, to: u64, word: Word) -> Result<(), Error> {
+        let registry = self.registry.lock().unwrap();
+        let mailbox = registry
+            .mailboxes
+            .get(&to)
+            .ok_or_else(|| anyhow!("Runtime error: Process {to} does not exist."))?;
+        let _ = mailbox.send((self.id, word));
+        Ok(())
+    }
+
+    /// Waits for a word to be sent to this process and returns it along with the ID of the sender.
+    pub fn recv(&self) -> Result<(Word, u64), Error> {
+        let (from, word) = self
+            .mailbox
+            .recv()
+            .map_err(|_| anyhow!("Runtime error: Failed to receive a message."))?;
+        Ok((word, from))
+    }
+
+    /// Waits for every process started by this one to finish and returns the errors that occurred in them.
+    pub fn join(&self) -> Vec<Error> {
+        self.children
+            .take()
+            .into_iter()
+            .flat_map(|(id, handle)| match handle.join() {
+                Ok(errors) => errors,
+                Err(_) => vec![anyhow!("Process {id} panicked.")],
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages() {
+        let parent = Process::new();
+        let child = parent.spawn(|process| {
+            let (word, from) = process.recv().unwrap();
+            process.send(from, word).unwrap();
+            vec![]
+        });
+        assert_eq!(1, child);
+
+        parent.send(child, Word::Data(42)).unwrap();
+        assert_eq!((Word::Data(42), child), parent.recv().unwrap());
+        assert!(parent.join().is_empty());
+    }
+
+    #[test]
+    fn unknown_process() {
+        let process = Process::new();
+        assert_eq!(
+            "Runtime error: Process 7 does not exist.",
+            format!("{}", process.send(7, Word::Data(1)).unwrap_err())
+        );
+    }
+
+    #[test]
+    fn join_errors() {
+        let parent = Process::new();
+        parent.spawn(|_| vec![anyhow!("a")]);
+        parent.spawn(|_| panic!());
+
+        let errors = parent
+            .join()
+            .iter()
+            .map(|e| format!("{e}"))
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["a", "Process 2 panicked."], errors);
+    }
+}
@@ -0,0 +1,168 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+
+const COLORS: &[(&str, &str)] = &[
+    ("black", "\x1b[30m"),
+    ("red", "\x1b[31m"),
+    ("green", "\x1b[32m"),
+    ("yellow", "\x1b[33m"),
+    ("blue", "\x1b[34m"),
+    ("magenta", "\x1b[35m"),
+    ("cyan", "\x1b[36m"),
+    ("white", "\x1b[37m"),
+    ("bold", "\x1b[1m"),
+    ("reset", "\x1b[0m"),
+];
+
+/// The REPL prompt, parsed from a template. The template can contain the following placeholders:
+/// - `{arrows}`: one `>` per unfinished DEFN or curly bracket, plus one
+/// - `{depth}`: the number of unfinished DEFNs or curly brackets
+/// - `{stack}`: the number of words on the stack
+/// - `{red}`, `{green}`, `{bold}`, `{reset}`, etc.: change the color of the rest of the prompt
+///
+/// Use `{{` and `}}` for literal curly brackets.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Prompt {
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Part {
+    Text(String),
+    Arrows,
+    Depth,
+    Stack,
+    Color(&'static str),
+}
+
+impl Default for Prompt {
+    fn default() -> Self {
+        Prompt {
+            parts: vec![Part::Arrows, Part::Text(" ".to_owned())],
+        }
+    }
+}
+
+impl FromStr for Prompt {
+    type Err = Error;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let name = match rest.find('}') {
+                        None => return Err(anyhow!("Prompt error: Unclosed '{{'.")),
+                        Some(i) => &rest[..i],
+                    };
+                    chars = rest[name.len() + 1..].chars();
+
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(match name {
+                        "arrows" => Part::Arrows,
+                        "depth" => Part::Depth,
+                        "stack" => Part::Stack,
+                        _ => match COLORS.iter().find(|(color, _)| *color == name) {
+                            None => {
+                                return Err(anyhow!(
+                                    "Prompt error: Unknown placeholder '{{{name}}}'."
+                                ))
+                            }
+                            Some((_, code)) => Part::Color(code),
+                        },
+                    });
+                }
+                '}' => return Err(anyhow!("Prompt error: Unmatched '}}'.")),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+
+        Ok(Prompt { parts })
+    }
+}
+
+impl Prompt {
+    /// Fills in the placeholders. Colors are left out unless `colors` is true.
+    pub fn render(&self, depth: usize, stack_size: usize, colors: bool) -> String {
+        let mut output = String::new();
+        let mut colored = false;
+        for part in &self.parts {
+            match part {
+                Part::Text(s) => output += s,
+                Part::Arrows => output += &">".repeat(depth + 1),
+                Part::Depth => output += &depth.to_string(),
+                Part::Stack => output += &stack_size.to_string(),
+                Part::Color(code) if colors => {
+                    output += code;
+                    colored = true;
+                }
+                Part::Color(_) => {}
+            }
+        }
+        // Don't let the color leak into the user's input
+        if colored {
+            output += "\x1b[0m";
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default() {
+        assert_eq!(Prompt::default(), "{arrows} ".parse::<Prompt>().unwrap());
+        assert_eq!(">>> ", Prompt::default().render(2, 5, true));
+    }
+
+    #[test]
+    fn placeholders() {
+        let prompt = "[{stack}] {depth}{{}}{arrows}".parse::<Prompt>().unwrap();
+        assert_eq!("[3] 1{}>>", prompt.render(1, 3, true));
+    }
+
+    #[test]
+    fn colors() {
+        let prompt = "{green}plates{reset}{bold}>".parse::<Prompt>().unwrap();
+        assert_eq!(
+            "\x1b[32mplates\x1b[0m\x1b[1m>\x1b[0m",
+            prompt.render(0, 0, true)
+        );
+        assert_eq!("plates>", prompt.render(0, 0, false));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            "Prompt error: Unknown placeholder '{purple}'.",
+            format!("{}", "{purple}".parse::<Prompt>().unwrap_err())
+        );
+        assert_eq!(
+            "Prompt error: Unclosed '{'.",
+            format!("{}", "{stack".parse::<Prompt>().unwrap_err())
+        );
+        assert_eq!(
+            "Prompt error: Unmatched '}'.",
+            format!("{}", "stack}".parse::<Prompt>().unwrap_err())
+        );
+    }
+}
@@ -1,71 +1,366 @@
 use std::{
+    cell::Cell,
+    collections::VecDeque,
     fs,
-    io::{self, Write},
+    io::{self, BufRead, IsTerminal, Write},
     path::PathBuf,
+    rc::Rc,
 };
 
 use anyhow::{anyhow, Error};
 
+use crate::{
+    lexer::{lex_line_with_spans, Token},
+    prompt::Prompt,
+};
+
 pub trait LineStream {
-    /// depth starts at zero and increases by one for each unfinished DEFN.
-    fn next_line(&mut self, depth: usize) -> Option<String>;
+    /// Returns the next line, or `None` at the end of the input. depth starts at zero and increases by one for each
+    /// unfinished DEFN.
+    fn next_line(&mut self, depth: usize) -> Result<Option<String>, Error>;
+
+    /// Returns the name of the file that the last line came from, and the number of that line (counting from one).
+    fn location(&self) -> Option<(String, usize)> {
+        None
+    }
 }
 
-pub struct InteractiveReader {}
+const ERR_INPUT: &str = "Environment error: Failed to read input.";
+const ERR_OUTPUT: &str = "Environment error: Failed to write output.";
 
-impl InteractiveReader {
-    pub fn new() -> Self {
-        InteractiveReader {}
+/// Sent by the terminal before and after pasted text when bracketed paste mode is enabled.
+const PASTE_START: &str = "\x1b[200~";
+const PASTE_END: &str = "\x1b[201~";
+
+type CommandHandler = Box<dyn FnMut(&str)>;
+
+/// Renders the prompt to show before reading a line, given the number of unfinished curly brackets and DEFNs.
+pub type PromptProvider = Box<dyn FnMut(usize) -> String>;
+
+/// Reads stdin one line at a time, without keeping it locked or reading ahead, so that the programs run by the REPL can
+/// read the lines that follow (with `__input__` or `__read_byte__`).
+#[derive(Default)]
+struct StdinLines {
+    line: Vec<u8>,
+    consumed: usize,
+}
+
+impl io::Read for StdinLines {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = io::Read::read(&mut self.fill_buf()?, buf)?;
+        self.consume(n);
+        Ok(n)
     }
 }
 
-impl LineStream for InteractiveReader {
-    fn next_line(&mut self, depth: usize) -> Option<String> {
-        print!("{} ", ">".repeat(depth + 1));
-        io::stdout().flush().expect("Failed to flush stdout");
+impl BufRead for StdinLines {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.consumed == self.line.len() {
+            self.line.clear();
+            self.consumed = 0;
+            io::stdin().lock().read_until(b'\n', &mut self.line)?;
+        }
+        Ok(&self.line[self.consumed..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.consumed = (self.consumed + amount).min(self.line.len());
+    }
+}
+
+/// Reads lines for a REPL, showing a prompt before each one. The input and output can be anything (the standard
+/// streams, a socket, or a buffer in a test), and the prompt can be replaced with [`InteractiveReader::with_prompt`].
+pub struct InteractiveReader {
+    input: Box<dyn BufRead>,
+    /// Where prompts (and echoed lines) are written.
+    output: Box<dyn Write>,
+    /// Lines that have been read but not returned yet.
+    buffer: VecDeque<String>,
+    bracketed_paste: bool,
+    prompt: PromptProvider,
+    /// Whether to print each line after reading it, so that the output reads like a transcript of the session.
+    echo: bool,
+    /// Called with the lines that start with `:` outside definitions, instead of returning them.
+    command_handler: Option<CommandHandler>,
+    /// Whether the end of the input has been reached, after which no more prompts are shown.
+    eof: bool,
+}
+
+impl InteractiveReader {
+    /// Reads from stdin and shows `prompt` on stdout. `stack_size` is kept up to date by the REPL so that it can be
+    /// shown in the prompt.
+    pub fn new(prompt: Prompt, stack_size: Rc<Cell<usize>>) -> Self {
+        // Only ask for bracketed paste and colors if there is a terminal to understand them
+        let terminal = io::stdin().is_terminal() && io::stdout().is_terminal();
+        let colors = terminal && colored::control::SHOULD_COLORIZE.should_colorize();
+        let reader =
+            InteractiveReader::from_io(Box::new(StdinLines::default()), Box::new(io::stdout()))
+                .with_prompt(move |depth| prompt.render(depth, stack_size.get(), colors));
+        match terminal {
+            true => reader.with_bracketed_paste(),
+            false => reader,
+        }
+    }
+
+    /// Like [`InteractiveReader::new`], but prints every line after reading it and never uses terminal features. This
+    /// is meant for scripted sessions.
+    pub fn transcript(prompt: Prompt, stack_size: Rc<Cell<usize>>) -> Self {
+        let mut reader =
+            InteractiveReader::from_io(Box::new(StdinLines::default()), Box::new(io::stdout()))
+                .with_prompt(move |depth| prompt.render(depth, stack_size.get(), false));
+        reader.echo = true;
+        reader
+    }
+
+    /// Reads from `input` and writes the default prompt to `output`, without any terminal features.
+    pub fn from_io(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        let prompt = Prompt::default();
+        InteractiveReader {
+            input,
+            output,
+            buffer: VecDeque::new(),
+            bracketed_paste: false,
+            prompt: Box::new(move |depth| prompt.render(depth, 0, false)),
+            echo: false,
+            command_handler: None,
+            eof: false,
+        }
+    }
+
+    /// Shows the prompt returned by `prompt` instead.
+    pub fn with_prompt(mut self, prompt: impl FnMut(usize) -> String + 'static) -> Self {
+        self.prompt = Box::new(prompt);
+        self
+    }
+
+    /// Asks the terminal to mark pasted text, so that it can be read in its entirety before anything runs.
+    fn with_bracketed_paste(mut self) -> Self {
+        let _ = write!(self.output, "\x1b[?2004h");
+        self.bracketed_paste = true;
+        self
+    }
+
+    /// Prints each line after reading it (or stops printing it).
+    pub fn with_echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
 
+    /// Handles REPL commands (lines that start with `:`, like `:doc f`) with the given function.
+    pub fn with_command_handler(mut self, handler: impl FnMut(&str) + 'static) -> Self {
+        self.command_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Reads one line, or `None` at the end of the input. Windows line endings are converted to `\n`. After an error,
+    /// the input is treated as if it had ended, except for lines that are not valid UTF-8 (which are skipped).
+    fn read_line(&mut self) -> Result<Option<String>, Error> {
         let mut line = String::new();
-        io::stdin()
-            .read_line(&mut line)
-            .expect("Failed to read from stdin");
+        let bytes_read = match self.input.read_line(&mut line) {
+            Err(e) => {
+                if e.kind() != io::ErrorKind::InvalidData {
+                    self.eof = true;
+                }
+                return Err(anyhow!(e).context(ERR_INPUT));
+            }
+            Ok(n) => n,
+        };
+        if line.ends_with("\r\n") {
+            line.truncate(line.len() - 2);
+            line.push('\n');
+        }
+        if self.echo && bytes_read > 0 {
+            write!(self.output, "{line}").map_err(|e| anyhow!(e).context(ERR_OUTPUT))?;
+            if !line.ends_with('\n') {
+                writeln!(self.output).map_err(|e| anyhow!(e).context(ERR_OUTPUT))?;
+            }
+        }
+        Ok((bytes_read > 0).then_some(line))
+    }
+
+    /// Reads lines into the buffer until every curly bracket is closed. Pasted text is read in its entirety before
+    /// showing another prompt.
+    fn fill_buffer(&mut self, depth: usize) -> Result<(), Error> {
+        let mut open_brackets = 0;
+        loop {
+            if self.eof {
+                return Ok(());
+            }
+            let prompt = (self.prompt)(depth + open_brackets);
+            write!(self.output, "{prompt}")
+                .and_then(|_| self.output.flush())
+                .map_err(|e| anyhow!(e).context(ERR_OUTPUT))?;
+
+            let line = match self.read_line() {
+                Err(e) => {
+                    let _ = writeln!(self.output);
+                    return Err(e);
+                }
+                Ok(None) => {
+                    // Finish the line with the prompt on it
+                    writeln!(self.output).map_err(|e| anyhow!(e).context(ERR_OUTPUT))?;
+                    self.eof = true;
+                    return Ok(());
+                }
+                Ok(Some(line)) => line,
+            };
+            if depth + open_brackets == 0 && line.trim_start().starts_with(':') {
+                if let Some(handler) = &mut self.command_handler {
+                    handler(line.trim());
+                    continue;
+                }
+            }
+            let mut lines = vec![line.replacen(PASTE_START, "", 1)];
+            if line.contains(PASTE_START) {
+                while !lines.last().unwrap().contains(PASTE_END) {
+                    match self.read_line()? {
+                        None => break,
+                        Some(line) => lines.push(line),
+                    }
+                }
+                let last = lines.pop().unwrap();
+                lines.push(last.replacen(PASTE_END, "", 1));
+            }
+
+            for line in lines {
+                open_brackets = (open_brackets as isize + bracket_balance(&line)).max(0) as usize;
+                self.buffer.push_back(line);
+            }
+            if open_brackets == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Drop for InteractiveReader {
+    fn drop(&mut self) {
+        if self.bracketed_paste {
+            let _ = write!(self.output, "\x1b[?2004l");
+            let _ = self.output.flush();
+        }
+    }
+}
+
+/// Turns off bracketed paste mode on stdout, which [`InteractiveReader::new`] turns on when there is a terminal. This
+/// is done when the reader is dropped, but can also be done by hand if the process is about to exit without dropping
+/// it.
+pub fn disable_bracketed_paste() {
+    print!("\x1b[?2004l");
+    let _ = io::stdout().flush();
+}
 
-        Some(line)
+impl LineStream for InteractiveReader {
+    fn next_line(&mut self, depth: usize) -> Result<Option<String>, Error> {
+        if self.buffer.is_empty() {
+            self.fill_buffer(depth)?;
+        }
+        Ok(self.buffer.pop_front())
     }
 }
 
+/// Returns the number of curly brackets opened minus the number closed. Lines that cannot be lexed count as balanced,
+/// since the parser will reject them anyway.
+fn bracket_balance(line: &str) -> isize {
+    match lex_line_with_spans(line) {
+        Err(_) => 0,
+        Ok(tokens) => tokens
+            .iter()
+            .map(|(t, _)| match t {
+                Token::LeftCurlyBracket => 1,
+                Token::RightCurlyBracket => -1,
+                _ => 0,
+            })
+            .sum(),
+    }
+}
+
+/// Reads files one line at a time, one file after the other.
 pub struct FileReader {
-    file_lines: Box<dyn Iterator<Item = String>>,
+    /// The name and contents of each file that has not been read to the end yet. Sources that were not read from a
+    /// file have no name.
+    files: VecDeque<(Option<String>, Box<dyn BufRead>)>,
+    /// The number of the last line that was read from the first file (counting from one).
+    line_number: usize,
+    /// The location of the last line of the file that was last read to the end, so that errors at the end of a file
+    /// (such as an unclosed bracket) can still point to it.
+    last_location: Option<(String, usize)>,
 }
 
 impl FileReader {
+    /// Opens every file right away, so that missing files are reported before anything runs. The contents are only
+    /// read when they are needed.
     pub fn new(files: Vec<PathBuf>) -> Result<Self, Error> {
-        let mut combined_file_contents = String::new();
+        let mut sources = Vec::<(Option<String>, Box<dyn BufRead>)>::new();
         for file in files {
-            let contents = match fs::read_to_string(file) {
+            let input = match fs::File::open(&file) {
                 Err(e) => return Err(anyhow!(e).context("Failed to read file.")),
-                Ok(s) => s,
+                Ok(f) => io::BufReader::new(f),
             };
-            combined_file_contents = combined_file_contents + "\n" + &contents;
+            sources.push((Some(file.display().to_string()), Box::new(input)));
         }
 
-        // Eagerly convert each line into a String
-        // TODO: make this lazy?
-        let file_lines = combined_file_contents
-            .lines()
-            .map(String::from)
-            .collect::<Vec<_>>()
-            .into_iter();
+        Ok(FileReader::from_sources(sources))
+    }
 
-        let reader = FileReader {
-            file_lines: Box::new(file_lines),
-        };
-        Ok(reader)
+    /// Reads from a string instead of a file.
+    pub fn from_source(source: &str) -> Self {
+        FileReader::from_sources(vec![(None, Box::new(io::Cursor::new(source.to_owned())))])
+    }
+
+    fn from_sources(sources: Vec<(Option<String>, Box<dyn BufRead>)>) -> Self {
+        FileReader {
+            files: sources.into(),
+            line_number: 0,
+            last_location: None,
+        }
     }
 }
 
 impl LineStream for FileReader {
-    fn next_line(&mut self, _: usize) -> Option<String> {
-        self.file_lines.next()
+    fn next_line(&mut self, _: usize) -> Result<Option<String>, Error> {
+        loop {
+            let Some((name, input)) = self.files.front_mut() else {
+                return Ok(None);
+            };
+            let mut line = String::new();
+            match input.read_line(&mut line) {
+                Ok(0) => {
+                    let (name, _) = self.files.pop_front().unwrap();
+                    // An empty file leaves the location of the previous file's last line
+                    if self.line_number > 0 {
+                        self.last_location = name.map(|name| (name, self.line_number));
+                    }
+                    self.line_number = 0;
+                }
+                Ok(_) => {
+                    self.line_number += 1;
+                    if line.ends_with('\n') {
+                        line.pop();
+                        if line.ends_with('\r') {
+                            line.pop();
+                        }
+                    }
+                    return Ok(Some(line));
+                }
+                Err(e) => {
+                    let name = name.as_deref().unwrap_or("input");
+                    let e =
+                        anyhow!(e).context(format!("Environment error: Failed to read {name}."));
+                    self.files.clear();
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn location(&self) -> Option<(String, usize)> {
+        match self.files.front() {
+            Some((name, _)) if self.line_number > 0 => {
+                name.clone().map(|name| (name, self.line_number))
+            }
+            _ => self.last_location.clone(),
+        }
     }
 }
 
@@ -74,7 +369,186 @@ impl<T> LineStream for T
 where
     T: Iterator<Item = String>,
 {
-    fn next_line(&mut self, _: usize) -> Option<String> {
-        self.next()
+    fn next_line(&mut self, _: usize) -> Result<Option<String>, Error> {
+        Ok(self.next())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn read_all(input: &str, bracketed_paste: bool) -> Vec<String> {
+        let mut reader = InteractiveReader::from_io(
+            Box::new(Cursor::new(input.to_owned())),
+            Box::new(io::sink()),
+        );
+        if bracketed_paste {
+            reader = reader.with_bracketed_paste();
+        }
+        let mut lines = vec![];
+        while let Some(line) = reader.next_line(0).unwrap() {
+            lines.push(line);
+            // Everything up to the end of the block should have been read at once
+            if reader.buffer.is_empty() {
+                lines.push("|".to_owned());
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn continue_until_brackets_balanced() {
+        assert_eq!(
+            vec![
+                "DEFN f (0) {\n",
+                "    PUSH 1 // }\n",
+                "}\n",
+                "|",
+                "PUSH 2\n",
+                "|"
+            ],
+            read_all("DEFN f (0) {\n    PUSH 1 // }\n}\nPUSH 2\n", false)
+        );
+    }
+
+    #[test]
+    fn commands() {
+        let commands = Rc::new(std::cell::RefCell::new(vec![]));
+        let mut reader = InteractiveReader::from_io(
+            Box::new(Cursor::new(":doc f\nDEFN f (0) {\n:doc g\n}\n".to_owned())),
+            Box::new(io::sink()),
+        )
+        .with_command_handler({
+            let commands = Rc::clone(&commands);
+            move |command| commands.borrow_mut().push(command.to_owned())
+        });
+
+        // Commands are not recognized inside definitions
+        let mut lines = vec![];
+        while let Some(line) = reader.next_line(0).unwrap() {
+            lines.push(line);
+        }
+        assert_eq!(vec!["DEFN f (0) {\n", ":doc g\n", "}\n"], lines);
+        assert_eq!(vec![":doc f"], *commands.borrow());
+    }
+
+    #[test]
+    fn bracketed_paste() {
+        assert_eq!(
+            vec!["PUSH 1\n", "PUSH 2\n", "\n", "|", "PUSH 3\n", "|"],
+            read_all("\x1b[200~PUSH 1\nPUSH 2\n\x1b[201~\nPUSH 3\n", true)
+        );
+    }
+
+    #[test]
+    fn read_files() {
+        let mut reader = FileReader::from_sources(vec![
+            (
+                Some("a".to_owned()),
+                Box::new(Cursor::new("PUSH 1\r\n\nPUSH 2")),
+            ),
+            (Some("empty".to_owned()), Box::new(Cursor::new(""))),
+            (None, Box::new(Cursor::new("PUSH 3\n"))),
+            (Some("b".to_owned()), Box::new(Cursor::new("PUSH 4\n"))),
+        ]);
+        assert_eq!(None, reader.location());
+
+        let mut lines = vec![];
+        while let Some(line) = reader.next_line(0).unwrap() {
+            lines.push((line, reader.location()));
+        }
+        assert_eq!(
+            vec![
+                ("PUSH 1".to_owned(), Some(("a".to_owned(), 1))),
+                ("".to_owned(), Some(("a".to_owned(), 2))),
+                ("PUSH 2".to_owned(), Some(("a".to_owned(), 3))),
+                ("PUSH 3".to_owned(), None),
+                ("PUSH 4".to_owned(), Some(("b".to_owned(), 1))),
+            ],
+            lines
+        );
+        // The end of the input still points to the last line
+        assert_eq!(Some(("b".to_owned(), 1)), reader.location());
+    }
+
+    #[test]
+    fn windows_line_endings() {
+        assert_eq!(
+            vec!["DEFN f (0) {\n", "}\n", "|", "PUSH 1\n", "|"],
+            read_all("DEFN f (0) {\r\n}\r\nPUSH 1\r\n", false)
+        );
+        assert_eq!(
+            vec!["PUSH 1\n", "PUSH 2\n", "", "|"],
+            read_all("\x1b[200~PUSH 1\r\nPUSH 2\r\n\x1b[201~", true)
+        );
+    }
+
+    /// Output that can still be read after the reader has taken it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn prompts() {
+        let output = Shared::default();
+        let mut reader = InteractiveReader::from_io(
+            Box::new(Cursor::new("DEFN f (0) {\n}\nPUSH 1\n".to_owned())),
+            Box::new(output.clone()),
+        )
+        .with_prompt(|depth| format!("{depth}> "));
+        while reader.next_line(0).unwrap().is_some() {}
+        // The last prompt is finished with a newline at the end of the input
+        assert_eq!(b"0> 1> 0> 0> \n", &output.0.borrow()[..]);
+    }
+
+    /// Input that fails once it runs out, like a terminal that goes away.
+    struct Broken(&'static [u8]);
+
+    impl io::Read for Broken {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::Error::other("device not configured"));
+            }
+            let n = buf.len().min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn input_errors() {
+        let input = Broken(b"PUSH 1\n\xff\nPUSH 2\n");
+        let mut reader =
+            InteractiveReader::from_io(Box::new(io::BufReader::new(input)), Box::new(io::sink()));
+        assert_eq!("PUSH 1\n", reader.next_line(0).unwrap().unwrap());
+        // Lines that are not valid UTF-8 are skipped
+        assert_eq!(ERR_INPUT, reader.next_line(0).unwrap_err().to_string());
+        assert_eq!("PUSH 2\n", reader.next_line(0).unwrap().unwrap());
+        // Other errors end the input
+        let e = reader.next_line(0).unwrap_err();
+        assert_eq!(ERR_INPUT, e.to_string());
+        assert_eq!("device not configured", e.root_cause().to_string());
+        assert_eq!(None, reader.next_line(0).unwrap());
+    }
+
+    #[test]
+    fn end_of_input() {
+        assert_eq!(
+            vec!["DEFN f (0) {\n", "|"],
+            read_all("DEFN f (0) {\n", false)
+        );
     }
 }
@@ -0,0 +1,145 @@
+//! Hot reloading (`--watch --hot-reload`, and `reload` in the debug adapter): swapping the changed functions of a
+//! program that is running for their new definitions, without starting over.
+//!
+//! Only the functions that the program has already defined are replaced, since the others will be defined once the
+//! program gets to them. A function that is defined more than once is left alone, since there is no telling which
+//! definition should replace it. The body of a function is copied onto the instruction stack when it is called, so
+//! calls that are already running finish with the old body and only later calls use the new one.
+
+use std::{collections::HashMap, fmt::Display};
+
+use crate::{parser::Instruction, runtime::Runtime};
+
+/// What [`reload`] changed.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Reloaded {
+    /// The functions whose definitions were replaced, in the order they are defined in the files.
+    pub replaced: Vec<String>,
+    /// The replaced functions that were running.
+    pub running: Vec<String>,
+}
+
+/// Replaces every function that the runtime defines differently than `program` (the new version of the files).
+pub fn reload(runtime: &mut Runtime, program: Vec<Instruction>) -> Reloaded {
+    let mut counts = HashMap::<String, usize>::new();
+    for instruction in &program {
+        if let Instruction::Define(f, _, _, _) = instruction {
+            *counts.entry(f.clone()).or_default() += 1;
+        }
+    }
+
+    let mut reloaded = Reloaded::default();
+    for instruction in program {
+        let Instruction::Define(f, arg_count, body, attributes) = instruction else {
+            continue;
+        };
+        let Some((old_arg_count, old_body)) = runtime.get_function(&f) else {
+            continue;
+        };
+        if (old_arg_count, old_body) == (arg_count, body.as_slice()) || counts[&f] > 1 {
+            continue;
+        }
+        if runtime.call_stack().any(|g| g == f) {
+            reloaded.running.push(f.clone());
+        }
+        runtime.define(f.clone(), arg_count, body, attributes);
+        reloaded.replaced.push(f);
+    }
+    reloaded
+}
+
+impl Display for Reloaded {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.replaced.is_empty() {
+            return write!(formatter, "No functions changed.");
+        }
+        write!(formatter, "Reloaded {}.", self.replaced.join(", "))?;
+        if !self.running.is_empty() {
+            write!(
+                formatter,
+                " The calls to {} that are already running finish with the old definition.",
+                self.running.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        lexer::Lexer,
+        parser::Parser,
+        reader::FileReader,
+        runtime::{Step, Word},
+    };
+
+    fn parse(source: &str) -> Vec<Instruction> {
+        let mut parser = Parser::new(Lexer::new(FileReader::from_source(source)));
+        let mut instructions = vec![];
+        while let Some(instruction) = parser.next_instruction().unwrap() {
+            instructions.push(instruction);
+        }
+        instructions
+    }
+
+    #[test]
+    fn reload_running_program() {
+        let mut runtime = Runtime::new();
+        for instruction in parse(
+            "DEFN f (0) { PUSH 1 PUSH 2 }
+            DEFN g (1) { PUSH $0 }
+            DEFN twice (0) { PUSH 3 }
+            DEFN twice (0) { PUSH 4 }",
+        ) {
+            runtime.run(instruction).unwrap();
+        }
+        // Stop in the middle of a call to f
+        for instruction in parse("PUSH 1 PUSH f CALLIF").into_iter().rev() {
+            runtime.push_instruction(instruction);
+        }
+        for _ in 0..4 {
+            runtime.step().unwrap();
+        }
+        assert_eq!(vec!["f"], runtime.call_stack().collect::<Vec<_>>());
+
+        let reloaded = reload(
+            &mut runtime,
+            parse(
+                "DEFN f (0) { PUSH 5 }
+                DEFN g (1) { PUSH $0 }
+                DEFN twice (0) { PUSH 6 }
+                DEFN twice (0) { PUSH 7 }
+                DEFN h (0) { }
+                PUSH 1 PUSH f CALLIF",
+            ),
+        );
+        assert_eq!(
+            Reloaded {
+                replaced: vec!["f".to_owned()],
+                running: vec!["f".to_owned()],
+            },
+            reloaded
+        );
+        assert_eq!(
+            "Reloaded f. The calls to f that are already running finish with the old definition.",
+            reloaded.to_string()
+        );
+        assert!(!runtime.is_defined("h"));
+
+        // The call that was running finishes with the old body, and the next one uses the new body
+        while runtime.step().unwrap() != Step::Done {}
+        for instruction in parse("PUSH 1 PUSH f CALLIF") {
+            runtime.run(instruction).unwrap();
+        }
+        assert_eq!(
+            &[Word::Data(1), Word::Data(2), Word::Data(5)],
+            runtime.stack()
+        );
+        assert_eq!(
+            "No functions changed.",
+            reload(&mut runtime, parse("DEFN f (0) { PUSH 5 }")).to_string()
+        );
+    }
+}
@@ -0,0 +1,213 @@
+//! Renaming functions across files (`plates rename`).
+//!
+//! Only the tokens that refer to the function are rewritten, so the formatting and comments of the files are left
+//! alone. The places to rewrite are found by the parser (see [`Parser::with_references`](crate::parser::Parser)), so
+//! names that are shortened by modules and imports are handled too.
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::{anyhow, Context, Error};
+
+use crate::{
+    lexer::{lex_line_with_spans, Token},
+    parser::{Reference, ReferenceKind},
+};
+
+/// A token to rewrite.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Replacement {
+    pub file: String,
+    /// The line, counting from one.
+    pub line: usize,
+    /// The column in characters, counting from one.
+    pub column: usize,
+    pub old: String,
+    pub new: String,
+}
+
+/// Finds the tokens to rewrite in order to rename the function `old` to `new` (both full names, with their modules).
+/// Fails if `new` is not a valid name for a function, if it is already used, or if `old` is not defined.
+pub fn plan(references: &[Reference], old: &str, new: &str) -> Result<Vec<Replacement>, Error> {
+    match lex_line_with_spans(new).as_deref() {
+        Ok([(Token::FunctionName(name), _)]) if name == new => {}
+        _ => return Err(anyhow!("Rename error: Invalid function name '{new}'.")),
+    }
+    if new.starts_with("__") {
+        return Err(anyhow!("Rename error: Cannot rename function '{old}' to '{new}' because the prefix '__' is reserved for built-in functions."));
+    }
+    if references.iter().any(|r| r.name == new) {
+        return Err(anyhow!(
+            "Rename error: Cannot rename function '{old}' to '{new}' because '{new}' is already used."
+        ));
+    }
+    if !references
+        .iter()
+        .any(|r| r.name == old && r.kind == ReferenceKind::Definition)
+    {
+        return Err(anyhow!("Rename error: Function '{old}' is not defined."));
+    }
+
+    let (old_module, old_last) = split_module(old);
+    let (new_module, new_last) = split_module(new);
+    let mut replacements = Vec::new();
+    for r in references.iter().filter(|r| r.name == old) {
+        let replacement = if r.written == old {
+            new.to_owned()
+        } else if r.written == old_last && old_module == new_module {
+            // Shortened by the module or by an import of the function itself
+            new_last.to_owned()
+        } else if r.written.ends_with(&format!("::{old_last}")) && old_module == new_module {
+            // Shortened by an import of the module
+            format!(
+                "{}{new_last}",
+                &r.written[..r.written.len() - old_last.len()]
+            )
+        } else if old_module == new_module {
+            // Written as an alias, which will follow the function once the import is renamed
+            continue;
+        } else {
+            return Err(anyhow!(
+                "Rename error: Cannot move function '{old}' to another module because it is written as '{}' in {} on line {}.",
+                r.written,
+                r.file,
+                r.line
+            ));
+        };
+        replacements.push(Replacement {
+            file: r.file.clone(),
+            line: r.line,
+            column: r.column,
+            old: r.written.clone(),
+            new: replacement,
+        });
+    }
+    Ok(replacements)
+}
+
+/// Splits a full name into its module (if any) and the name within the module.
+fn split_module(name: &str) -> (Option<&str>, &str) {
+    match name.rsplit_once("::") {
+        None => (None, name),
+        Some((module, last)) => (Some(module), last),
+    }
+}
+
+/// Applies the replacements for one file to its contents.
+pub fn apply(source: &str, replacements: &[&Replacement]) -> String {
+    let mut lines = source
+        .split_inclusive('\n')
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    let mut replacements = replacements.to_vec();
+    // Later tokens first, so that the columns of the earlier ones stay correct
+    replacements.sort_by_key(|r| std::cmp::Reverse((r.line, r.column)));
+    for r in replacements {
+        let Some(line) = lines.get_mut(r.line - 1) else {
+            continue;
+        };
+        let Some((start, _)) = line.char_indices().nth(r.column - 1) else {
+            continue;
+        };
+        if line[start..].starts_with(&r.old) {
+            line.replace_range(start..start + r.old.len(), &r.new);
+        }
+    }
+    lines.concat()
+}
+
+/// Rewrites the files, and returns the number of files that changed.
+pub fn rewrite_files(replacements: &[Replacement]) -> Result<usize, Error> {
+    let mut by_file = BTreeMap::<&str, Vec<&Replacement>>::new();
+    for r in replacements {
+        by_file.entry(&r.file).or_default().push(r);
+    }
+    for (file, replacements) in &by_file {
+        let path = Path::new(file);
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("Environment error: Failed to read {file}."))?;
+        fs::write(path, apply(&source, replacements))
+            .with_context(|| format!("Environment error: Failed to write {file}."))?;
+    }
+    Ok(by_file.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reference(
+        name: &str,
+        written: &str,
+        kind: ReferenceKind,
+        line: usize,
+        column: usize,
+    ) -> Reference {
+        Reference {
+            name: name.to_owned(),
+            written: written.to_owned(),
+            kind,
+            file: "main.plates".to_owned(),
+            line,
+            column,
+        }
+    }
+
+    #[test]
+    fn rename() {
+        let source = "MODULE math\n\
+            DEFN square (1) { PUSH $0 PUSH $0 PUSH 2 PUSH __birl__ CALLIF } // square it\n\
+            PUSH 3 PUSH 1 PUSH square CALLIF PUSH math::square\n";
+        let references = [
+            reference("math::square", "square", ReferenceKind::Definition, 2, 6),
+            reference("math::square", "square", ReferenceKind::Push, 3, 20),
+            reference("math::square", "math::square", ReferenceKind::Push, 3, 39),
+        ];
+        let replacements = plan(&references, "math::square", "math::sq").unwrap();
+        assert_eq!(
+            "MODULE math\n\
+            DEFN sq (1) { PUSH $0 PUSH $0 PUSH 2 PUSH __birl__ CALLIF } // square it\n\
+            PUSH 3 PUSH 1 PUSH sq CALLIF PUSH math::sq\n",
+            apply(source, &replacements.iter().collect::<Vec<_>>())
+        );
+    }
+
+    #[test]
+    fn aliases_are_kept() {
+        let references = [
+            reference("math::square", "square", ReferenceKind::Definition, 2, 6),
+            reference("math::square", "math::square", ReferenceKind::Import, 4, 8),
+            reference("math::square", "sq", ReferenceKind::Push, 5, 6),
+            reference("math::square", "m::square", ReferenceKind::Push, 6, 6),
+        ];
+        let replacements = plan(&references, "math::square", "math::pow2").unwrap();
+        let new = replacements
+            .iter()
+            .map(|r| r.new.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["pow2", "math::pow2", "m::pow2"], new);
+        assert_eq!(
+            "Rename error: Cannot move function 'math::square' to another module because it is written as 'square' in main.plates on line 2.",
+            format!("{}", plan(&references, "math::square", "square").unwrap_err())
+        );
+    }
+
+    #[test]
+    fn invalid_names() {
+        let references = [
+            reference("f", "f", ReferenceKind::Definition, 1, 6),
+            reference("g", "g", ReferenceKind::Definition, 2, 6),
+        ];
+        for (new, message) in [
+            ("__f", "Rename error: Cannot rename function 'f' to '__f' because the prefix '__' is reserved for built-in functions."),
+            ("g", "Rename error: Cannot rename function 'f' to 'g' because 'g' is already used."),
+            ("PUSH", "Rename error: Invalid function name 'PUSH'."),
+            ("a b", "Rename error: Invalid function name 'a b'."),
+        ] {
+            assert_eq!(message, format!("{}", plan(&references, "f", new).unwrap_err()));
+        }
+        assert_eq!(
+            "Rename error: Function 'h' is not defined.",
+            format!("{}", plan(&references, "h", "i").unwrap_err())
+        );
+    }
+}
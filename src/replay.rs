@@ -0,0 +1,140 @@
+//! Recording and replaying the nondeterministic inputs of a program (random words, input, keys, and the terminal
+//! size), so that a run can be reproduced exactly.
+
+use std::{collections::VecDeque, fmt::Display, str::FromStr};
+
+use anyhow::{anyhow, Error};
+
+/// One nondeterministic input that a program received.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// A word pushed by `PUSH *`.
+    Random(u64),
+    /// A line read by `__input__`, including the line ending (if any).
+    Line(String),
+    /// A byte read by `__read_byte__`, or `None` at the end of the input.
+    Byte(Option<u8>),
+    /// A key read by `__read_key__`.
+    Key(u64),
+    /// The result of `__poll_key__`.
+    PollKey(Option<u64>),
+    /// The number of columns and rows returned by `__term_size__`.
+    TermSize(u16, u16),
+}
+
+impl Display for Event {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::Random(n) => write!(formatter, "random {n}"),
+            Event::Line(line) => {
+                write!(formatter, "line {}", serde_json::Value::from(line.as_str()))
+            }
+            Event::Byte(None) => write!(formatter, "byte eof"),
+            Event::Byte(Some(b)) => write!(formatter, "byte {b}"),
+            Event::Key(key) => write!(formatter, "key {key}"),
+            Event::PollKey(None) => write!(formatter, "poll none"),
+            Event::PollKey(Some(key)) => write!(formatter, "poll {key}"),
+            Event::TermSize(columns, rows) => write!(formatter, "size {columns} {rows}"),
+        }
+    }
+}
+
+impl FromStr for Event {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("Invalid replay event '{s}'.");
+        let (kind, rest) = s.split_once(' ').ok_or_else(invalid)?;
+        let event = match (kind, rest) {
+            ("random", n) => Event::Random(n.parse().map_err(|_| invalid())?),
+            ("line", line) => Event::Line(serde_json::from_str(line).map_err(|_| invalid())?),
+            ("byte", "eof") => Event::Byte(None),
+            ("byte", b) => Event::Byte(Some(b.parse().map_err(|_| invalid())?)),
+            ("key", key) => Event::Key(key.parse().map_err(|_| invalid())?),
+            ("poll", "none") => Event::PollKey(None),
+            ("poll", key) => Event::PollKey(Some(key.parse().map_err(|_| invalid())?)),
+            ("size", size) => {
+                let (columns, rows) = size.split_once(' ').ok_or_else(invalid)?;
+                Event::TermSize(
+                    columns.parse().map_err(|_| invalid())?,
+                    rows.parse().map_err(|_| invalid())?,
+                )
+            }
+            _ => return Err(invalid()),
+        };
+        Ok(event)
+    }
+}
+
+/// Whether the runtime is recording its inputs, replaying them, or neither.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum Replay {
+    #[default]
+    Off,
+    /// The inputs received so far, oldest first.
+    Record(Vec<Event>),
+    /// The inputs that have not been replayed yet, oldest first.
+    Play(VecDeque<Event>),
+}
+
+impl Replay {
+    /// Starts a new recording.
+    pub fn record() -> Self {
+        Replay::Record(vec![])
+    }
+
+    /// Parses a recording written by [`to_string`], with one event per line.
+    pub fn play(recording: &str) -> Result<Self, Error> {
+        let events = recording
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                line.trim_end()
+                    .parse()
+                    .map_err(|e: Error| anyhow!("Line {}: {e}", i + 1))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Replay::Play(events))
+    }
+}
+
+/// Writes a recording with one event per line.
+pub fn to_string(events: &[Event]) -> String {
+    events.iter().map(|event| format!("{event}\n")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let events = vec![
+            Event::Random(42),
+            Event::Line("héllo \"world\"\n".to_owned()),
+            Event::Line(String::new()),
+            Event::Byte(Some(255)),
+            Event::Byte(None),
+            Event::Key(97),
+            Event::PollKey(None),
+            Event::PollKey(Some(13)),
+            Event::TermSize(80, 24),
+        ];
+        let text = to_string(&events);
+        assert_eq!(Replay::Play(events.into()), Replay::play(&text).unwrap());
+    }
+
+    #[test]
+    fn invalid_events() {
+        for line in [
+            "random", "random x", "line abc", "byte 256", "size 80", "time 3",
+        ] {
+            let e = Replay::play(&format!("random 1\n{line}\n")).unwrap_err();
+            assert_eq!(
+                format!("Line 2: Invalid replay event '{line}'."),
+                e.to_string()
+            );
+        }
+    }
+}
@@ -1,20 +1,179 @@
 use anyhow::{anyhow, Error};
+use colored::Colorize;
+#[cfg(feature = "bigint")]
+use num_bigint::BigUint;
 use rand::{rngs::ThreadRng, Rng};
-use std::{collections::HashMap, fmt::Display, io::Write};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    io::{BufRead, Read, Write},
+    net::TcpStream,
+    rc::Rc,
+    str::FromStr,
+};
+use tracing::{debug, debug_span, trace, Span};
 
-use crate::parser::Instruction;
+use crate::{
+    parser::{Attributes, Instruction},
+    process::Process,
+    replay::{Event, Replay},
+    terminal::{Crossterm, Terminal},
+};
 
 const ERR_UNDERFLOW: &str = "Runtime error: Stack underflow.";
 const ERR_UNDEFINED: &str = "Runtime error: Undefined argument or function.";
 const ERR_TYPE: &str = "Runtime error: Wrong type.";
 const ERR_UTF32: &str = "Runtime error: Invalid UTF-32 code point.";
+const ERR_UNTERMINATED: &str = "Runtime error: Unterminated string.";
+const ERR_NUMBER: &str = "Runtime error: Invalid number.";
+const ERR_OVERFLOW: &str = "Runtime error: Number too large.";
+#[cfg(feature = "bigint")]
+const ERR_DIVISION: &str = "Runtime error: Division by zero.";
+#[cfg(not(feature = "bigint"))]
+const ERR_NO_BIGINT: &str =
+    "Runtime error: Big integers are not supported. Build plates with the 'bigint' feature to use them.";
+const ERR_TOO_DEEP: &str = "Runtime error: Too many pending instructions.";
 const ERR_STDOUT: &str = "Environment error: Failed to flush stdout.";
 const ERR_STDIN: &str = "Environment error: Failed to read from stdin.";
+const ERR_TERMINAL: &str = "Environment error: Failed to control the terminal.";
+const ERR_SANDBOX: &str = "Runtime error: Network access is disabled in the sandbox.";
 
+/// The maximum number of entries on the instruction stack. Programs that recurse forever (other than through a CALLIF at
+/// the very end of a function) would otherwise use up all the memory.
+pub const MAX_INSTRUCTION_STACK: usize = 1_000_000;
+
+/// Names of all the built-in functions.
+pub const BUILTIN_FUNCTIONS: &[&str] = &[
+    "__print__",
+    "__input__",
+    "__birl__",
+    "__inc__",
+    "__dec__",
+    "__inc_sat__",
+    "__dec_sat__",
+    "__and__",
+    "__or__",
+    "__xor__",
+    "__not__",
+    "__shl_n__",
+    "__shr_n__",
+    "__add_carry__",
+    "__sub_borrow__",
+    "__pick__",
+    "__roll__",
+    "__mark__",
+    "__count_to_mark__",
+    "__collect__",
+    "__defined__",
+    "__call_n_times__",
+    "__strlen__",
+    "__streq__",
+    "__strrev__",
+    "__num_to_str__",
+    "__str_to_num__",
+    "__assert_eq__",
+    "__assert__",
+    "__panic__",
+    "__stack_dump__",
+    "__gc_stats__",
+    "__badd__",
+    "__bmul__",
+    "__bmod__",
+    "__tcp_connect__",
+    "__tcp_send__",
+    "__tcp_recv__",
+    "__tcp_close__",
+    "__clear__",
+    "__cursor_to__",
+    "__term_size__",
+    "__raw_mode__",
+    "__read_key__",
+    "__poll_key__",
+    "__spawn__",
+    "__send__",
+    "__recv__",
+    "__set_print_policy__",
+    "__write_byte__",
+    "__read_byte__",
+];
+
+/// The number of bits in a data word. Literals, arithmetic, and random numbers all wrap or are checked at this size.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum WordSize {
+    #[default]
+    Bits32,
+    Bits64,
+}
+
+impl WordSize {
+    /// The largest data word.
+    pub fn max(self) -> u64 {
+        match self {
+            WordSize::Bits32 => u32::MAX.into(),
+            WordSize::Bits64 => u64::MAX,
+        }
+    }
+
+    /// The number of bits in a data word.
+    pub fn bits(self) -> u32 {
+        match self {
+            WordSize::Bits32 => 32,
+            WordSize::Bits64 => 64,
+        }
+    }
+}
+
+impl FromStr for WordSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "32" => Ok(WordSize::Bits32),
+            "64" => Ok(WordSize::Bits64),
+            _ => Err(anyhow!("Invalid word size '{s}'. Expected 32 or 64.")),
+        }
+    }
+}
+
+/// What `__print__` does with words that are not valid UTF-32 characters.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PrintPolicy {
+    /// Stop with an error.
+    #[default]
+    Error,
+    /// Print nothing for them.
+    Skip,
+    /// Print the replacement character (U+FFFD) instead.
+    Replace,
+}
+
+impl FromStr for PrintPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(PrintPolicy::Error),
+            "skip" => Ok(PrintPolicy::Skip),
+            "replace" => Ok(PrintPolicy::Replace),
+            _ => Err(anyhow!(
+                "Invalid print policy '{s}'. Expected error, skip, or replace."
+            )),
+        }
+    }
+}
+
+/// A value on the stack.
 #[derive(Clone, Debug, Eq, PartialEq)]
-enum Word {
-    Data(u32),
+pub enum Word {
+    Data(u64),
     Function(String),
+    /// A data word that is larger than the word size. Smaller numbers are always stored as [`Word::Data`], so that
+    /// equal numbers are equal words.
+    #[cfg(feature = "bigint")]
+    Big(BigUint),
+    /// Pushed by `__mark__` to show where a variable number of words starts.
+    Marker,
 }
 
 impl Display for Word {
@@ -22,25 +181,359 @@ impl Display for Word {
         match self {
             Word::Data(n) => write!(formatter, "{n}"),
             Word::Function(f) => write!(formatter, "function {f}"),
+            #[cfg(feature = "bigint")]
+            Word::Big(n) => write!(formatter, "{n}"),
+            Word::Marker => write!(formatter, "marker"),
+        }
+    }
+}
+
+/// Mutable access to the stack of a runtime, from [`Runtime::stack_mut`]. Use [`Runtime::stack`] to only read it.
+pub struct Stack<'a> {
+    words: &'a mut Vec<Word>,
+    word_size: WordSize,
+}
+
+impl Stack<'_> {
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Returns the word `n` places below the top (so `peek(0)` is the top), or `None` if the stack is too short.
+    pub fn peek(&self, n: usize) -> Option<&Word> {
+        self.words.iter().rev().nth(n)
+    }
+
+    /// Iterates over the words from the bottom to the top.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Word> {
+        self.words.iter()
+    }
+
+    /// Pushes a word, unless it is a data word that does not fit in the runtime's word size.
+    pub fn push(&mut self, word: Word) -> Result<(), Error> {
+        if matches!(word, Word::Data(n) if n > self.word_size.max()) {
+            return Err(anyhow!(ERR_OVERFLOW));
+        }
+        self.words.push(word);
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<Word> {
+        self.words.pop()
+    }
+}
+
+/// Names of host functions start with this and end with `__`.
+pub const HOST_PREFIX: &str = "__host_";
+
+/// A built-in function provided by the application that embeds the runtime. It pops its arguments from the stack and
+/// pushes its results itself, and it can look at the rest of the stack too.
+pub type HostFn = dyn Fn(&mut Stack) -> Result<(), Error>;
+
+#[derive(Clone)]
+struct HostFunction {
+    arity: usize,
+    f: Rc<HostFn>,
+}
+
+impl std::fmt::Debug for HostFunction {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "HostFunction({})", self.arity)
+    }
+}
+
+/// A snapshot of how much memory the runtime is using.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MemoryStats {
+    pub value_stack: usize,
+    pub instruction_stack: usize,
+    pub functions: usize,
+    /// A rough estimate of the number of bytes used by the stacks, the function table, and the arguments.
+    pub bytes: usize,
+}
+
+impl Display for MemoryStats {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(formatter, "Memory usage:")?;
+        writeln!(
+            formatter,
+            "    value stack:       {} word(s)",
+            self.value_stack
+        )?;
+        writeln!(
+            formatter,
+            "    instruction stack: {} entries",
+            self.instruction_stack
+        )?;
+        writeln!(
+            formatter,
+            "    function table:    {} function(s)",
+            self.functions
+        )?;
+        write!(formatter, "    approximate size:  {} bytes", self.bytes)
+    }
+}
+
+/// The outcome of [`Runtime::step`].
+#[derive(Debug, Eq, PartialEq)]
+pub enum Step {
+    /// An entry of the instruction stack was run. `called` is true iff it called a function.
+    Ran { called: bool },
+    /// The instruction stack was already empty.
+    Done,
+    /// The program should exit.
+    Exit,
+}
+
+/// Where `__print__` writes and `__input__` reads.
+pub struct Io {
+    output: Box<dyn Write>,
+    /// `None` reads from stdin.
+    input: Option<Box<dyn BufRead>>,
+}
+
+impl std::fmt::Debug for Io {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Io").finish_non_exhaustive()
+    }
+}
+
+impl Default for Io {
+    fn default() -> Self {
+        Io {
+            output: Box::new(std::io::stdout()),
+            input: None,
+        }
+    }
+}
+
+impl Io {
+    /// Returns true iff the output and input were replaced with [`Runtime::with_io`].
+    fn is_redirected(&self) -> bool {
+        self.input.is_some()
+    }
+
+    fn read_line(&mut self, line: &mut String) -> std::io::Result<usize> {
+        match &mut self.input {
+            None => std::io::stdin().read_line(line),
+            Some(input) => input.read_line(line),
+        }
+    }
+
+    /// Reads one byte, or `None` at the end of the input.
+    fn read_byte(&mut self) -> std::io::Result<Option<u8>> {
+        let mut byte = [0];
+        let n = match &mut self.input {
+            None => std::io::stdin().read(&mut byte)?,
+            Some(input) => input.read(&mut byte)?,
+        };
+        Ok((n > 0).then_some(byte[0]))
+    }
+}
+
+/// The result of [`Runtime::run_budgeted`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    /// The budget ran out before the instruction stack was empty.
+    Pending,
+    /// The instruction stack is empty.
+    Done,
+    /// The program should exit.
+    Exit,
+}
+
+/// A copy of the stack and the defined functions, taken with [`Runtime::snapshot`] and put back with
+/// [`Runtime::restore`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Snapshot {
+    value_stack: Vec<Word>,
+    function_table: HashMap<String, (u32, Vec<Instruction>)>,
+    attributes: HashMap<String, Attributes>,
+}
+
+/// The words that were popped from the stack and the words that were pushed onto it between two points in time, from
+/// the bottom of the stack to the top. Only the words above the deepest change count, so a word that was popped and
+/// pushed back counts as both.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StackDiff {
+    pub popped: Vec<Word>,
+    pub pushed: Vec<Word>,
+}
+
+impl StackDiff {
+    pub fn new(before: &[Word], after: &[Word]) -> Self {
+        let common = before.iter().zip(after).take_while(|(a, b)| a == b).count();
+        StackDiff {
+            popped: before[common..].to_vec(),
+            pushed: after[common..].to_vec(),
+        }
+    }
+}
+
+impl Display for StackDiff {
+    /// Shows the diff like `-2 [3, function f] +1 [6]`, or as `(no change)`.
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let words = |words: &[Word]| {
+            let words = words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+            format!("[{}]", words.join(", "))
+        };
+        let mut parts = Vec::new();
+        if !self.popped.is_empty() {
+            parts.push(format!("-{} {}", self.popped.len(), words(&self.popped)));
+        }
+        if !self.pushed.is_empty() {
+            parts.push(format!("+{} {}", self.pushed.len(), words(&self.pushed)));
+        }
+        match parts.is_empty() {
+            true => write!(formatter, "(no change)"),
+            false => write!(formatter, "{}", parts.join(" ")),
         }
     }
 }
 
+/// An entry in the instruction stack.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Frame {
+    Instruction(Instruction),
+    /// The iterations of `__call_n_times__` that have not been started yet.
+    Loop {
+        f: String,
+        index: u64,
+        count: u64,
+    },
+}
+
+/// An entry of the call stack: a function, the size of the instruction stack just before its body was pushed, and the
+/// span that its instructions are logged in.
+type Call = (String, usize, Span);
+
+/// How to undo a step.
+#[derive(Clone, Debug)]
+struct StepEntry {
+    /// The words that the step popped, and the number of words that it pushed in their place.
+    popped_words: Vec<Word>,
+    pushed_words: usize,
+    /// The entry of the instruction stack that ran, and the number of entries that it pushed.
+    frame: Frame,
+    pushed_frames: usize,
+    /// The entries of the call stack that were removed (the last one first), and the number that were added.
+    popped_calls: Vec<Call>,
+    pushed_calls: usize,
+    /// The arguments from before the step, if it changed them.
+    args: Option<Vec<Word>>,
+    print_policy: PrintPolicy,
+    /// If the step was a DEFN, the function that it defined.
+    defined: Option<Replaced>,
+}
+
+/// A function that a DEFN defined, with its previous definition and attributes (if any).
+#[derive(Clone, Debug)]
+struct Replaced {
+    f: String,
+    definition: Option<(u32, Vec<Instruction>)>,
+    attributes: Option<Attributes>,
+}
+
+/// How to undo something that the runtime did (see [`Runtime::with_journal`]).
+#[derive(Clone, Debug)]
+enum JournalEntry {
+    Step(Box<StepEntry>),
+    /// A call to [`Runtime::push_instruction`], along with the entries that it removed from the call stack.
+    Push(Vec<Call>),
+}
+
+/// What [`Runtime::step_back`] undid.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Undone {
+    Step,
+    /// An instruction that was pushed with [`Runtime::push_instruction`], which is no longer on the instruction stack.
+    Push(Instruction),
+}
+
+/// The most recent entries, oldest first.
+#[derive(Clone, Debug, Default)]
+struct Journal {
+    entries: VecDeque<JournalEntry>,
+    capacity: usize,
+    /// The value stack from before the step that is running.
+    before: Vec<Word>,
+    /// The entries that were removed from the call stack since the last journal entry.
+    popped_calls: Vec<Call>,
+}
+
+impl Journal {
+    fn record(&mut self, entry: JournalEntry) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+}
+
+/// Removes the innermost entry of the call stack, keeping it in the journal if there is one.
+fn pop_call(call_stack: &mut Vec<Call>, journal: &mut Option<Journal>) {
+    let call = call_stack.pop();
+    if let (Some(journal), Some(call)) = (journal, call) {
+        journal.popped_calls.push(call);
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Runtime {
     value_stack: Vec<Word>,
     function_table: HashMap<String, (u32, Vec<Instruction>)>,
+    /// The attributes of the functions in `function_table` that have any.
+    attributes: HashMap<String, Attributes>,
+    host_functions: HashMap<String, HostFunction>,
     rng: ThreadRng,
-    instruction_stack: Vec<Instruction>,
+    word_size: WordSize,
+    print_policy: PrintPolicy,
+    instruction_stack: Vec<Frame>,
     args_array: Vec<Word>,
+    /// The user-defined functions that are still running, along with the size of the instruction stack just before
+    /// their body was pushed and the span that their instructions are logged in. Used to explain why the instruction
+    /// stack grew too large.
+    call_stack: Vec<Call>,
+    /// Messages from calls to `__assert_eq__` that failed.
+    assertion_failures: Vec<String>,
+    /// Whether built-in functions that access the network are disabled.
+    sandbox: bool,
+    /// The open TCP connections, by handle.
+    connections: HashMap<u64, Rc<TcpStream>>,
+    next_connection: u64,
+    terminal: Rc<dyn Terminal>,
+    process: Rc<Process>,
+    io: Rc<RefCell<Io>>,
+    /// Whether to restore the value stack when a top-level instruction fails.
+    rollback: bool,
+    /// With `rollback`, the value stack from before the current top-level instruction.
+    snapshot: Option<Vec<Word>>,
+    /// In an `Rc` so that it can stay borrowed while an input is read. Processes started with `__spawn__` run on other
+    /// threads and do not share it, so their inputs are neither recorded nor replayed.
+    replay: Rc<RefCell<Replay>>,
+    /// How to undo the most recent steps, if enabled with [`Runtime::with_journal`].
+    journal: Option<Journal>,
 }
 
 impl PartialEq for Runtime {
     fn eq(&self, other: &Self) -> bool {
         self.value_stack == other.value_stack
             && self.function_table == other.function_table
+            && self.attributes == other.attributes
+            && self.word_size == other.word_size
             && self.instruction_stack == other.instruction_stack
             && self.args_array == other.args_array
+            && self.assertion_failures == other.assertion_failures
+    }
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -49,509 +542,3765 @@ impl Runtime {
         Runtime {
             value_stack: Vec::new(),
             function_table: HashMap::new(),
+            attributes: HashMap::new(),
+            host_functions: HashMap::new(),
             rng: rand::thread_rng(),
+            word_size: WordSize::default(),
+            print_policy: PrintPolicy::default(),
             instruction_stack: Vec::new(),
             args_array: Vec::new(),
+            call_stack: Vec::new(),
+            assertion_failures: Vec::new(),
+            sandbox: false,
+            connections: HashMap::new(),
+            next_connection: 1,
+            terminal: Rc::new(Crossterm::default()),
+            process: Rc::new(Process::new()),
+            io: Rc::new(RefCell::new(Io::default())),
+            rollback: false,
+            snapshot: None,
+            replay: Rc::new(RefCell::new(Replay::Off)),
+            journal: None,
         }
     }
 
-    pub fn stack_to_string(&mut self) -> String {
-        let words = self
-            .value_stack
-            .iter()
-            .map(|w| w.to_string())
-            .collect::<Vec<_>>();
-        format!("[{}]  <-- top", words.join(", "))
-    }
-
-    /// Returns true iff the program should exit.
-    pub fn run(&mut self, instruction: Instruction) -> Result<bool, Error> {
-        self.instruction_stack.push(instruction);
-
-        loop {
-            match self.instruction_stack.pop() {
-                None => return Ok(false),
-                Some(instruction) => match self.run_instruction(instruction) {
-                    Err(e) => {
-                        self.instruction_stack.clear();
-                        return Err(e);
-                    }
-                    Ok(true) => return Ok(true),
-                    Ok(false) => continue,
-                },
-            };
-        }
+    pub fn with_word_size(self, word_size: WordSize) -> Self {
+        Runtime { word_size, ..self }
     }
 
-    fn run_instruction(&mut self, instruction: Instruction) -> Result<bool, Error> {
-        match instruction {
-            Instruction::Exit => Ok(true),
-            Instruction::PushData(n) => self.run_pushdata(n),
-            Instruction::PushFunction(f) => self.run_pushfunction(f),
-            Instruction::PushRandom => self.run_pushrandom(),
-            Instruction::PushArg(n) => self.run_pusharg(n),
-            Instruction::Define(f, arg_count, body) => self.run_define(f, arg_count, body),
-            Instruction::CallIf => self.run_callif(),
+    pub fn with_print_policy(self, print_policy: PrintPolicy) -> Self {
+        Runtime {
+            print_policy,
+            ..self
         }
     }
 
-    fn run_pushdata(&mut self, n: u32) -> Result<bool, Error> {
-        self.value_stack.push(Word::Data(n));
-        Ok(false)
+    pub fn with_sandbox(self, sandbox: bool) -> Self {
+        Runtime { sandbox, ..self }
     }
 
-    fn run_pushfunction(&mut self, f: String) -> Result<bool, Error> {
-        self.value_stack.push(Word::Function(f));
-        Ok(false)
+    /// Makes every top-level instruction all-or-nothing: if it fails, the value stack goes back to what it was before
+    /// the instruction, even if some of the functions it called had already changed it. The REPL uses this so that a
+    /// failed line can simply be fixed and tried again.
+    pub fn with_rollback(self, rollback: bool) -> Self {
+        Runtime { rollback, ..self }
     }
 
-    fn run_pushrandom(&mut self) -> Result<bool, Error> {
-        let n = self.rng.gen();
-        self.value_stack.push(Word::Data(n));
-        Ok(false)
+    /// Records the random words, input, keys, and terminal sizes that the program receives (see
+    /// [`Runtime::take_recording`]), or replays the ones from an earlier recording instead of reading them.
+    pub fn with_replay(self, replay: Replay) -> Self {
+        Runtime {
+            replay: Rc::new(RefCell::new(replay)),
+            ..self
+        }
     }
 
-    fn run_pusharg(&mut self, n: usize) -> Result<bool, Error> {
-        let value = match self.args_array.get(n) {
-            None => return Err(anyhow!(ERR_UNDEFINED)),
-            Some(x) => x.clone(),
+    /// Records how to undo each step, so that up to `capacity` of the most recent steps (and instructions pushed with
+    /// [`Runtime::push_instruction`]) can be undone with [`Runtime::step_back`]. Each step then takes time in proportion
+    /// to the size of the stack. What the program printed or read, and other effects outside the runtime, are not
+    /// undone.
+    pub fn with_journal(self, capacity: usize) -> Self {
+        let journal = Journal {
+            capacity,
+            ..Journal::default()
         };
-        self.value_stack.push(value);
-        Ok(false)
+        Runtime {
+            journal: Some(journal),
+            ..self
+        }
     }
 
-    fn run_define(
-        &mut self,
-        f: String,
-        arg_count: u32,
-        body: Vec<Instruction>,
-    ) -> Result<bool, Error> {
-        self.function_table.insert(f, (arg_count, body));
-        Ok(false)
+    /// Replaces the terminal used by the built-in functions that draw on the screen and read keys.
+    pub fn with_terminal(self, terminal: Rc<dyn Terminal>) -> Self {
+        Runtime { terminal, ..self }
     }
 
-    fn run_callif(&mut self) -> Result<bool, Error> {
-        let (f, n) = match (self.value_stack.pop(), self.value_stack.pop()) {
-            // It should be impossible to get (None, Some(_)), but check both cases to make the compiler happy
-            (None, _) | (_, None) => return Err(anyhow!(ERR_UNDERFLOW)),
-            (Some(Word::Data(_)), _) | (_, Some(Word::Function(_))) => {
-                return Err(anyhow!(ERR_TYPE))
-            }
-            (Some(Word::Function(f)), Some(Word::Data(n))) => (f, n),
+    /// Makes `__print__` write to `output` and `__input__` read from `input` instead of stdout and stdin.
+    pub fn with_io(self, output: impl Write + 'static, input: impl BufRead + 'static) -> Self {
+        let io = Io {
+            output: Box::new(output),
+            input: Some(Box::new(input)),
         };
-
-        if n == 0 {
-            Ok(false)
-        } else {
-            self.call_function(&f)
+        Runtime {
+            io: Rc::new(RefCell::new(io)),
+            ..self
         }
     }
 
-    /// This does not run the function in its entirety, it just pushes the body of the function onto the stack.
-    fn call_function(&mut self, f: &str) -> Result<bool, Error> {
-        // Clear the args array so that args from a previous function call don't leak to a subsequent function call.
-        // Clearing the array before each call should be enough to guarantee this since it is a syntax error to use
-        // arguments outside a function.
-        self.args_array.clear();
+    /// Returns the words on the stack, from the bottom to the top.
+    pub fn stack(&self) -> &[Word] {
+        &self.value_stack
+    }
 
-        if f.starts_with("__") {
-            self.call_builtin_function(f)
-        } else {
-            self.call_custom_function(f)
+    /// Gives access to the stack for pushing and popping words, such as between calls to [`Runtime::run_budgeted`].
+    pub fn stack_mut(&mut self) -> Stack<'_> {
+        Stack {
+            words: &mut self.value_stack,
+            word_size: self.word_size,
         }
     }
 
-    fn call_builtin_function(&mut self, f: &str) -> Result<bool, Error> {
-        match f {
-            "__print__" => self.call_print(),
-            "__input__" => self.call_input(),
-            "__birl__" => self.call_birl(),
-            _ => Err(anyhow!(ERR_UNDEFINED)),
-        }
+    /// Waits for the processes started with `__spawn__` to finish and returns the errors that occurred in them.
+    pub fn join_processes(&mut self) -> Vec<Error> {
+        self.process.join()
     }
 
-    fn call_custom_function(&mut self, f: &str) -> Result<bool, Error> {
-        let (arg_count, body) = match self.function_table.get(f) {
-            None => return Err(anyhow!(ERR_UNDEFINED)),
-            Some(body) => body,
-        };
+    /// Makes a native function available to plates programs. Its name must start with `__host_` and end with `__`,
+    /// and it cannot be registered twice. It is only called if the stack has at least `arity` words, and it gets the
+    /// stack to pop its arguments and push its results. If it returns an error, the program stops with that error, so
+    /// it should check its arguments with [`Stack::peek`] before popping them.
+    pub fn register_builtin(
+        &mut self,
+        name: &str,
+        arity: usize,
+        f: impl Fn(&mut Stack) -> Result<(), Error> + 'static,
+    ) -> Result<(), Error> {
+        let valid = name
+            .strip_prefix(HOST_PREFIX)
+            .and_then(|rest| rest.strip_suffix("__"))
+            .is_some_and(|rest| {
+                !rest.is_empty() && rest.chars().all(|c| c.is_alphanumeric() || c == '_')
+            });
+        if !valid {
+            return Err(anyhow!(
+                "Invalid host function name '{name}'. Host functions must be named '{HOST_PREFIX}<name>__'."
+            ));
+        }
+        if self.host_functions.contains_key(name) {
+            return Err(anyhow!("Host function '{name}' is already registered."));
+        }
 
-        for _ in 0..*arg_count {
-            let n = match self.value_stack.pop() {
-                None => return Err(anyhow!(ERR_UNDERFLOW)),
-                Some(x) => x,
-            };
+        self.host_functions.insert(
+            name.to_owned(),
+            HostFunction {
+                arity,
+                f: Rc::new(f),
+            },
+        );
+        Ok(())
+    }
 
-            self.args_array.push(n);
-        }
+    pub fn stack_size(&self) -> usize {
+        self.value_stack.len()
+    }
 
-        for instruction in body.iter().rev() {
-            self.instruction_stack.push(instruction.clone());
+    /// Returns the inputs recorded so far and starts a new recording. Returns nothing if the runtime is not recording.
+    pub fn take_recording(&mut self) -> Vec<Event> {
+        match &mut *self.replay.borrow_mut() {
+            Replay::Record(events) => std::mem::take(events),
+            _ => vec![],
         }
-
-        Ok(false)
     }
 
-    fn call_print(&mut self) -> Result<bool, Error> {
-        loop {
-            let n = self.pop_data_from_stack()?;
+    /// Returns the messages from failed calls to `__assert_eq__` since the last time this was called.
+    pub fn take_assertion_failures(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.assertion_failures)
+    }
 
-            if n == 0 {
-                if std::io::stdout().flush().is_err() {
-                    return Err(anyhow!(ERR_STDOUT));
-                }
-                return Ok(false);
-            }
+    /// Returns the data words on the stack (from bottom to top), or `None` if there are any function words.
+    pub fn stack_data(&self) -> Option<Vec<u64>> {
+        self.value_stack
+            .iter()
+            .map(|w| match w {
+                Word::Data(n) => Some(*n),
+                _ => None,
+            })
+            .collect()
+    }
 
-            let c = match char::from_u32(n) {
-                None => return Err(anyhow!(ERR_UTF32)),
-                Some(c) => c,
-            };
+    /// Returns the number of arguments of a function that has been defined, or `None` if there is no such function.
+    pub fn arg_count(&self, f: &str) -> Option<u32> {
+        self.get_function(f).map(|(arg_count, _)| arg_count)
+    }
 
-            print!("{c}");
-        }
+    /// Iterates over the functions that have been defined (not including built-in functions), with their argument
+    /// counts, sorted by name.
+    pub fn functions(&self) -> impl Iterator<Item = (&str, u32)> {
+        let mut functions = self
+            .function_table
+            .iter()
+            .map(|(f, (arg_count, _))| (f.as_str(), *arg_count))
+            .collect::<Vec<_>>();
+        functions.sort();
+        functions.into_iter()
     }
 
-    fn call_input(&mut self) -> Result<bool, Error> {
-        let mut line = String::new();
-        if std::io::stdin().read_line(&mut line).is_err() {
-            return Err(anyhow!(ERR_STDIN));
-        }
+    /// Returns the argument count and body of a function that has been defined, or `None` if there is no such
+    /// function.
+    pub fn get_function(&self, f: &str) -> Option<(u32, &[Instruction])> {
+        self.function_table
+            .get(f)
+            .map(|(arg_count, body)| (*arg_count, body.as_slice()))
+    }
 
-        for c in line.chars().rev() {
-            let n = c as u32;
-            self.value_stack.push(Word::Data(n));
+    /// Defines a function right away, replacing any previous definition, as if a DEFN had run. Calls that are already
+    /// running are not affected.
+    pub fn define(
+        &mut self,
+        f: String,
+        arg_count: u32,
+        body: Vec<Instruction>,
+        attributes: Attributes,
+    ) {
+        if attributes == Attributes::default() {
+            self.attributes.remove(&f);
+        } else {
+            self.attributes.insert(f.clone(), attributes);
         }
-
-        Ok(false)
+        self.function_table.insert(f, (arg_count, body));
     }
 
-    fn call_birl(&mut self) -> Result<bool, Error> {
-        let (a, b) = match (self.value_stack.pop(), self.value_stack.pop()) {
-            // It should be impossible to get (None, Some(_)), but check both cases to make the compiler happy
-            (_, None) | (None, _) => return Err(anyhow!(ERR_UNDERFLOW)),
-            (Some(Word::Function(_)), _) | (_, Some(Word::Function(_))) => {
-                return Err(anyhow!(ERR_TYPE))
-            }
-            (Some(Word::Data(a)), Some(Word::Data(b))) => (a, b),
-        };
+    /// Removes a function that has been defined, along with its docstring and other attributes. Returns false if there
+    /// was no such function.
+    pub fn remove_function(&mut self, f: &str) -> bool {
+        self.attributes.remove(f);
+        self.function_table.remove(f).is_some()
+    }
 
-        let result = (!a | b).rotate_left(1);
-        self.value_stack.push(Word::Data(result));
+    /// Returns the docstring of a function that has been defined, if it has one.
+    pub fn doc(&self, f: &str) -> Option<&str> {
+        self.attributes.get(f)?.doc.as_deref()
+    }
 
-        Ok(false)
+    /// Returns the declared number of results of a function that has been defined, if it has a stack-effect annotation.
+    pub fn results(&self, f: &str) -> Option<u32> {
+        self.attributes.get(f)?.results
     }
 
-    /// Pops from the stack. If the stack was empty or the value at the top was a function, returns an error.
-    ///
-    /// This should only be used for single values, not when you need to pop multiple things.
-    fn pop_data_from_stack(&mut self) -> Result<u32, Error> {
-        match self.value_stack.pop() {
-            None => Err(anyhow!(ERR_UNDERFLOW)),
-            Some(Word::Function(_)) => Err(anyhow!(ERR_TYPE)),
-            Some(Word::Data(n)) => Ok(n),
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            value_stack: self.value_stack.clone(),
+            function_table: self.function_table.clone(),
+            attributes: self.attributes.clone(),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
+    /// Puts back the stack and the functions from a snapshot. Everything else (e.g., open connections) is unchanged.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.value_stack = snapshot.value_stack;
+        self.function_table = snapshot.function_table;
+        self.attributes = snapshot.attributes;
+    }
 
-    use super::*;
+    /// Returns true iff `f` is a built-in function or a function that has been defined.
+    pub fn is_defined(&self, f: &str) -> bool {
+        BUILTIN_FUNCTIONS.contains(&f)
+            || self.host_functions.contains_key(f)
+            || self.function_table.contains_key(f)
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        let word_bytes = |w: &Word| match w {
+            Word::Data(_) | Word::Marker => 0,
+            Word::Function(f) => f.capacity(),
+            #[cfg(feature = "bigint")]
+            Word::Big(n) => n.bits().div_ceil(8) as usize,
+        };
+        let frame_bytes = |frame: &Frame| match frame {
+            Frame::Instruction(instruction) => instruction_bytes(instruction),
+            Frame::Loop { f, .. } => f.capacity(),
+        };
+
+        let bytes = self.value_stack.capacity() * std::mem::size_of::<Word>()
+            + self.value_stack.iter().map(word_bytes).sum::<usize>()
+            + self.args_array.capacity() * std::mem::size_of::<Word>()
+            + self.args_array.iter().map(word_bytes).sum::<usize>()
+            + self.instruction_stack.capacity() * std::mem::size_of::<Frame>()
+            + self
+                .instruction_stack
+                .iter()
+                .map(frame_bytes)
+                .sum::<usize>()
+            + self.function_table.capacity()
+                * std::mem::size_of::<(String, (u32, Vec<Instruction>))>()
+            + self
+                .function_table
+                .iter()
+                .map(|(f, (_, body))| f.capacity() + body_bytes(body))
+                .sum::<usize>()
+            + self
+                .attributes
+                .iter()
+                .map(|(f, attributes)| f.capacity() + attributes_bytes(attributes))
+                .sum::<usize>();
+
+        MemoryStats {
+            value_stack: self.value_stack.len(),
+            instruction_stack: self.instruction_stack.len(),
+            functions: self.function_table.len(),
+            bytes,
+        }
+    }
+
+    pub fn stack_to_string(&mut self) -> String {
+        let words = self
+            .value_stack
+            .iter()
+            .map(|w| w.to_string())
+            .collect::<Vec<_>>();
+        format!("[{}]  <-- top", words.join(", "))
+    }
+
+    /// Renders the stack with one word per line, starting from the top. Each word is labelled with its position from
+    /// the top, and data words are shown in hexadecimal, in decimal, and as a character (if printable). Only the top
+    /// `limit` words are shown. Function words are highlighted if `colors` is true.
+    pub fn stack_to_debug_string(&self, limit: usize, colors: bool) -> String {
+        if self.value_stack.is_empty() {
+            return "(empty stack)".to_owned();
+        }
+
+        let shown = self.value_stack.len().min(limit);
+        if shown == 0 {
+            return format!("({} word(s) not shown)", self.value_stack.len());
+        }
+        let index_width = (shown - 1).to_string().len();
+        let mut lines = vec![];
+        for (i, word) in self.value_stack.iter().rev().take(shown).enumerate() {
+            let text = match word {
+                Word::Data(n) => {
+                    let c = match to_char(*n).filter(|c| !c.is_control()) {
+                        None => String::new(),
+                        Some(c) => format!(" {c:?}"),
+                    };
+                    match self.word_size {
+                        WordSize::Bits32 => format!("0x{n:08X} {n:>10}{c}"),
+                        WordSize::Bits64 => format!("0x{n:016X} {n:>20}{c}"),
+                    }
+                }
+                #[cfg(feature = "bigint")]
+                Word::Big(n) => format!("{n} (big)"),
+                Word::Function(f) if colors => format!("function {}", f.yellow()),
+                Word::Function(f) => format!("function {f}"),
+                Word::Marker => "marker".to_owned(),
+            };
+            let top = if i == 0 { "  <-- top" } else { "" };
+            lines.push(format!("{i:>index_width$} | {text}{top}"));
+        }
+        if shown < self.value_stack.len() {
+            lines.push(format!("... and {} more", self.value_stack.len() - shown));
+        }
+        lines.join("\n")
+    }
+
+    /// Renders the top `limit` entries of the instruction stack (starting with the one that will run next), followed by
+    /// the arguments of the function that was called last.
+    pub fn pending_to_debug_string(&self, limit: usize) -> String {
+        let mut lines = vec!["Pending instructions:".to_owned()];
+        for pending in self.pending_instructions(limit) {
+            lines.push(format!("    {pending}"));
+        }
+        if self.instruction_stack.is_empty() {
+            lines.push("    (none)".to_owned());
+        } else if self.instruction_stack.len() > limit {
+            lines.push(format!(
+                "    ... and {} more",
+                self.instruction_stack.len() - limit
+            ));
+        }
+
+        let args = self
+            .args_array
+            .iter()
+            .enumerate()
+            .map(|(i, w)| format!("${i} = {w}"))
+            .collect::<Vec<_>>();
+        if args.is_empty() {
+            lines.push("Arguments: (none)".to_owned());
+        } else {
+            lines.push(format!("Arguments: {}", args.join(", ")));
+        }
+        lines.join("\n")
+    }
+
+    /// Describes the top `limit` entries of the instruction stack, starting with the one that will run next.
+    pub fn pending_instructions(&self, limit: usize) -> Vec<String> {
+        self.instruction_stack
+            .iter()
+            .rev()
+            .take(limit)
+            .map(|frame| match frame {
+                Frame::Instruction(Instruction::Define(f, arg_count, _, _)) => {
+                    format!("DEFN {f} ({arg_count}) {{ ... }}")
+                }
+                Frame::Instruction(instruction) => crate::printer::print_instruction(instruction),
+                Frame::Loop { f, index, count } => {
+                    format!(
+                        "__call_n_times__ with {f} (iterations {index} to {} left)",
+                        count - 1
+                    )
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the arguments of the function that was called last, starting with `$0`.
+    pub fn args(&self) -> &[Word] {
+        &self.args_array
+    }
+
+    /// Returns the functions whose bodies have not finished running, starting with the one that was called first.
+    pub fn call_stack(&self) -> impl Iterator<Item = &str> {
+        let height = self.instruction_stack.len();
+        self.call_stack
+            .iter()
+            .filter(move |(_, h, _)| *h < height)
+            .map(|(f, _, _)| f.as_str())
+    }
+
+    /// Returns the function that the next entry of the instruction stack defines, if it is a DEFN.
+    pub fn next_definition(&self) -> Option<&str> {
+        match self.instruction_stack.last()? {
+            Frame::Instruction(Instruction::Define(f, ..)) => Some(f),
+            _ => None,
+        }
+    }
+
+    /// Returns the function that the next entry of the instruction stack calls, if it is a CALLIF with a nonzero
+    /// condition or an iteration of `__call_n_times__`.
+    pub fn next_call(&self) -> Option<&str> {
+        match (self.instruction_stack.last()?, self.value_stack.as_slice()) {
+            (Frame::Loop { f, .. }, _) => Some(f),
+            (Frame::Instruction(Instruction::CallIf), [.., Word::Data(n), Word::Function(f)])
+                if *n != 0 =>
+            {
+                Some(f)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns true iff the program should exit.
+    pub fn run(&mut self, instruction: Instruction) -> Result<bool, Error> {
+        self.push_instruction(instruction);
+
+        loop {
+            match self.step()? {
+                Step::Done => return Ok(false),
+                Step::Exit => return Ok(true),
+                Step::Ran { .. } => continue,
+            }
+        }
+    }
+
+    /// Runs at most `instruction_budget` entries of the instruction stack, so that the caller can do other work before
+    /// resuming the program with another call.
+    pub fn run_budgeted(&mut self, instruction_budget: usize) -> Result<Status, Error> {
+        for _ in 0..instruction_budget {
+            match self.step()? {
+                Step::Done => return Ok(Status::Done),
+                Step::Exit => return Ok(Status::Exit),
+                Step::Ran { .. } => {}
+            }
+        }
+        if self.instruction_stack.is_empty() {
+            Ok(Status::Done)
+        } else {
+            Ok(Status::Pending)
+        }
+    }
+
+    /// Adds an instruction to the instruction stack without running it. Use [`Runtime::step`] to run it.
+    pub fn push_instruction(&mut self, instruction: Instruction) {
+        // The new instruction is not part of any function whose body has finished
+        let height = self.instruction_stack.len();
+        while matches!(self.call_stack.last(), Some((_, h, _)) if *h >= height) {
+            pop_call(&mut self.call_stack, &mut self.journal);
+        }
+        if self.rollback && height == 0 {
+            self.snapshot = Some(self.value_stack.clone());
+        }
+        self.instruction_stack.push(Frame::Instruction(instruction));
+        if let Some(journal) = &mut self.journal {
+            let popped_calls = std::mem::take(&mut journal.popped_calls);
+            journal.record(JournalEntry::Push(popped_calls));
+        }
+    }
+
+    /// Starts a journal entry for the next step, if there is a journal and something to run.
+    fn begin_entry(&mut self) -> Option<StepEntry> {
+        let journal = self.journal.as_mut()?;
+        let frame = self.instruction_stack.last()?.clone();
+        journal.before.clone_from(&self.value_stack);
+        journal.popped_calls.clear();
+        let args = matches!(
+            frame,
+            Frame::Instruction(Instruction::CallIf) | Frame::Loop { .. }
+        )
+        .then(|| self.args_array.clone());
+        let defined = match &frame {
+            Frame::Instruction(Instruction::Define(f, ..)) => Some(Replaced {
+                f: f.clone(),
+                definition: self.function_table.get(f).cloned(),
+                attributes: self.attributes.get(f).cloned(),
+            }),
+            _ => None,
+        };
+        Some(StepEntry {
+            popped_words: Vec::new(),
+            pushed_words: 0,
+            frame,
+            // For now, the sizes of the stacks before the step
+            pushed_frames: self.instruction_stack.len() - 1,
+            popped_calls: Vec::new(),
+            pushed_calls: self.call_stack.len(),
+            args,
+            print_policy: self.print_policy,
+            defined,
+        })
+    }
+
+    /// Finishes the journal entry for a step that ran, by comparing the runtime with how it was before.
+    fn finish_entry(&mut self, mut entry: StepEntry) {
+        let Some(journal) = &mut self.journal else {
+            return;
+        };
+        let common = journal
+            .before
+            .iter()
+            .zip(&self.value_stack)
+            .take_while(|(a, b)| a == b)
+            .count();
+        entry.popped_words = journal.before[common..].to_vec();
+        entry.pushed_words = self.value_stack.len() - common;
+        entry.pushed_frames = self.instruction_stack.len() - entry.pushed_frames;
+        entry.popped_calls = std::mem::take(&mut journal.popped_calls);
+        entry.pushed_calls = self.call_stack.len() + entry.popped_calls.len() - entry.pushed_calls;
+        if entry.args.as_ref() == Some(&self.args_array) {
+            entry.args = None;
+        }
+        journal.record(JournalEntry::Step(Box::new(entry)));
+    }
+
+    /// Undoes the most recent step or pushed instruction in the journal (see [`Runtime::with_journal`]). Returns `None`
+    /// if there is nothing left to undo.
+    pub fn step_back(&mut self) -> Option<Undone> {
+        let entry = self.journal.as_mut()?.entries.pop_back()?;
+        match entry {
+            JournalEntry::Push(popped_calls) => {
+                let instruction = match self.instruction_stack.pop() {
+                    Some(Frame::Instruction(instruction)) => instruction,
+                    _ => unreachable!("the pushed instruction is on top"),
+                };
+                self.call_stack.extend(popped_calls.into_iter().rev());
+                Some(Undone::Push(instruction))
+            }
+            JournalEntry::Step(entry) => {
+                let entry = *entry;
+                self.value_stack
+                    .truncate(self.value_stack.len() - entry.pushed_words);
+                self.value_stack.extend(entry.popped_words);
+                self.instruction_stack
+                    .truncate(self.instruction_stack.len() - entry.pushed_frames);
+                self.instruction_stack.push(entry.frame);
+                self.call_stack
+                    .truncate(self.call_stack.len() - entry.pushed_calls);
+                self.call_stack.extend(entry.popped_calls.into_iter().rev());
+                if let Some(args) = entry.args {
+                    self.args_array = args;
+                }
+                self.print_policy = entry.print_policy;
+                if let Some(Replaced {
+                    f,
+                    definition,
+                    attributes,
+                }) = entry.defined
+                {
+                    match definition {
+                        Some(definition) => self.function_table.insert(f.clone(), definition),
+                        None => self.function_table.remove(&f),
+                    };
+                    match attributes {
+                        Some(attributes) => self.attributes.insert(f, attributes),
+                        None => self.attributes.remove(&f),
+                    };
+                }
+                Some(Undone::Step)
+            }
+        }
+    }
+
+    /// Runs the next entry on the instruction stack. After an error, the instruction stack is cleared (and the value
+    /// stack is rolled back, if enabled with [`Runtime::with_rollback`]). After an `EXIT`, the instruction stack is
+    /// cleared too, so that the rest of the program does not run if the runtime is used again.
+    pub fn step(&mut self) -> Result<Step, Error> {
+        let span = self.current_span();
+        let _guard = span.enter();
+        let entry = self.begin_entry();
+        let (result, called) = match self.instruction_stack.pop() {
+            None => {
+                self.snapshot = None;
+                return Ok(Step::Done);
+            }
+            Some(Frame::Instruction(instruction)) => {
+                trace!(?instruction, stack = self.value_stack.len(), "step");
+                // CALLIF pops the function and then the condition
+                let called = instruction == Instruction::CallIf
+                    && matches!(self.value_stack.iter().rev().nth(1), Some(Word::Data(n)) if *n != 0);
+                (self.run_instruction(instruction), called)
+            }
+            Some(Frame::Loop { f, index, count }) => (self.run_loop(f, index, count), true),
+        };
+        match result {
+            Err(e) => {
+                debug!(error = %e, "instruction failed");
+                self.instruction_stack.clear();
+                self.call_stack.clear();
+                // The failed instruction cannot be undone, so neither can the ones before it
+                if let Some(journal) = &mut self.journal {
+                    journal.entries.clear();
+                }
+                if let Some(snapshot) = self.snapshot.take() {
+                    self.value_stack = snapshot;
+                }
+                Err(e)
+            }
+            Ok(true) => {
+                debug!("program exited");
+                self.instruction_stack.clear();
+                self.call_stack.clear();
+                // The frames that were dropped cannot be put back, so the steps before them cannot be undone
+                if let Some(journal) = &mut self.journal {
+                    journal.entries.clear();
+                }
+                self.snapshot = None;
+                Ok(Step::Exit)
+            }
+            Ok(false) => {
+                if let Some(entry) = entry {
+                    self.finish_entry(entry);
+                }
+                Ok(Step::Ran { called })
+            }
+        }
+    }
+
+    fn run_instruction(&mut self, instruction: Instruction) -> Result<bool, Error> {
+        match instruction {
+            Instruction::Exit => Ok(true),
+            Instruction::PushData(n) => self.run_pushdata(n),
+            Instruction::PushBig(n) => self.run_pushbig(n),
+            Instruction::PushFunction(f) => self.run_pushfunction(f),
+            Instruction::PushRandom => self.run_pushrandom(),
+            Instruction::PushArg(n) => self.run_pusharg(n),
+            Instruction::Define(f, arg_count, body, attributes) => {
+                self.run_define(f, arg_count, body, attributes)
+            }
+            Instruction::CallIf => self.run_callif(),
+        }
+    }
+
+    fn run_pushdata(&mut self, n: u64) -> Result<bool, Error> {
+        if n > self.word_size.max() {
+            return Err(anyhow!(ERR_OVERFLOW));
+        }
+        self.value_stack.push(Word::Data(n));
+        Ok(false)
+    }
+
+    #[cfg(feature = "bigint")]
+    fn run_pushbig(&mut self, n: String) -> Result<bool, Error> {
+        let n = n.parse::<BigUint>().map_err(|_| anyhow!(ERR_NUMBER))?;
+        let word = self.big_to_word(n);
+        self.value_stack.push(word);
+        Ok(false)
+    }
+
+    #[cfg(not(feature = "bigint"))]
+    fn run_pushbig(&mut self, _: String) -> Result<bool, Error> {
+        Err(anyhow!(ERR_NO_BIGINT))
+    }
+
+    fn run_pushfunction(&mut self, f: String) -> Result<bool, Error> {
+        self.value_stack.push(Word::Function(f));
+        Ok(false)
+    }
+
+    fn run_pushrandom(&mut self) -> Result<bool, Error> {
+        let n = self.replayable(
+            "a random word",
+            Event::Random,
+            |event| match event {
+                Event::Random(n) => Some(n),
+                _ => None,
+            },
+            |runtime| Ok(runtime.rng.gen::<u64>() & runtime.word_size.max()),
+        )?;
+        self.value_stack.push(Word::Data(n));
+        Ok(false)
+    }
+
+    fn run_pusharg(&mut self, n: usize) -> Result<bool, Error> {
+        let value = match self.args_array.get(n) {
+            None => return Err(anyhow!(ERR_UNDEFINED)),
+            Some(x) => x.clone(),
+        };
+        self.value_stack.push(value);
+        Ok(false)
+    }
+
+    fn run_define(
+        &mut self,
+        f: String,
+        arg_count: u32,
+        body: Vec<Instruction>,
+        attributes: Attributes,
+    ) -> Result<bool, Error> {
+        self.define(f, arg_count, body, attributes);
+        Ok(false)
+    }
+
+    fn run_callif(&mut self) -> Result<bool, Error> {
+        let (f, n) = match self.peek_words("CALLIF", 2)? {
+            [_, word @ (Word::Data(_) | Word::Marker)] => {
+                return Err(type_error("CALLIF", "a function", word))
+            }
+            #[cfg(feature = "bigint")]
+            [_, word @ Word::Big(_)] => return Err(type_error("CALLIF", "a function", word)),
+            [word @ (Word::Function(_) | Word::Marker), _] => {
+                return Err(type_error("the condition of CALLIF", "a data word", word))
+            }
+            [Word::Data(n), Word::Function(f)] => (f.clone(), *n),
+            // Big words are never zero
+            #[cfg(feature = "bigint")]
+            [Word::Big(_), Word::Function(f)] => (f.clone(), 1),
+            _ => unreachable!(),
+        };
+        let words = self.value_stack.split_off(self.value_stack.len() - 2);
+
+        if n == 0 {
+            return Ok(false);
+        }
+        debug!(function = f, "call");
+        // Functions leave the stack alone when they fail, so putting back the condition and the function undoes the
+        // whole instruction
+        self.call_function(&f)
+            .inspect_err(|_| self.value_stack.extend(words))
+    }
+
+    /// This does not run the function in its entirety, it just pushes the body of the function onto the stack.
+    fn call_function(&mut self, f: &str) -> Result<bool, Error> {
+        // Clear the args array so that args from a previous function call don't leak to a subsequent function call.
+        // Clearing the array before each call should be enough to guarantee this since it is a syntax error to use
+        // arguments outside a function.
+        self.args_array.clear();
+
+        if f.starts_with("__") {
+            self.call_builtin_function(f)
+        } else {
+            self.call_custom_function(f)
+        }
+    }
+
+    fn call_builtin_function(&mut self, f: &str) -> Result<bool, Error> {
+        match f {
+            "__print__" => self.call_print(),
+            "__input__" => self.call_input(),
+            "__birl__" => self.call_birl(),
+            "__inc__" => self.call_unary(f, |n, max| n.wrapping_add(1) & max),
+            "__dec__" => self.call_unary(f, |n, max| n.wrapping_sub(1) & max),
+            "__inc_sat__" => self.call_unary(f, |n, max| n.saturating_add(1).min(max)),
+            "__dec_sat__" => self.call_unary(f, |n, _| n.saturating_sub(1)),
+            "__and__" => self.call_binary(f, |a, b| b & a),
+            "__or__" => self.call_binary(f, |a, b| b | a),
+            "__xor__" => self.call_binary(f, |a, b| b ^ a),
+            "__not__" => self.call_unary(f, |n, max| !n & max),
+            "__shl_n__" => self.call_shift(f, |n, count| n << count),
+            "__shr_n__" => self.call_shift(f, |n, count| n >> count),
+            "__add_carry__" => self.call_with_carry(f, |a, b, max| {
+                let sum = b.wrapping_add(a) & max;
+                (sum, sum < b)
+            }),
+            "__sub_borrow__" => {
+                self.call_with_carry(f, |a, b, max| (b.wrapping_sub(a) & max, a > b))
+            }
+            "__pick__" => self.call_pick(),
+            "__roll__" => self.call_roll(),
+            "__mark__" => {
+                self.value_stack.push(Word::Marker);
+                Ok(false)
+            }
+            "__count_to_mark__" => self.call_count_to_mark(false),
+            "__collect__" => self.call_count_to_mark(true),
+            "__defined__" => self.call_defined(),
+            "__call_n_times__" => self.call_call_n_times(),
+            "__strlen__" => self.call_strlen(),
+            "__streq__" => self.call_streq(),
+            "__strrev__" => self.call_strrev(),
+            "__num_to_str__" => self.call_num_to_str(),
+            "__str_to_num__" => self.call_str_to_num(),
+            "__assert_eq__" => self.call_assert_eq(),
+            "__assert__" => self.call_assert(),
+            "__panic__" => self.call_panic(),
+            "__stack_dump__" => self.call_stack_dump(),
+            "__gc_stats__" => self.call_gc_stats(),
+            #[cfg(feature = "bigint")]
+            "__badd__" => self.call_big(f, |a, b| Some(b + a)),
+            #[cfg(feature = "bigint")]
+            "__bmul__" => self.call_big(f, |a, b| Some(b * a)),
+            #[cfg(feature = "bigint")]
+            "__bmod__" => self.call_big(f, |a, b| (a != BigUint::ZERO).then(|| b % a)),
+            #[cfg(not(feature = "bigint"))]
+            "__badd__" | "__bmul__" | "__bmod__" => Err(anyhow!(ERR_NO_BIGINT)),
+            "__tcp_connect__" | "__tcp_send__" | "__tcp_recv__" | "__tcp_close__"
+                if self.sandbox =>
+            {
+                Err(anyhow!(ERR_SANDBOX))
+            }
+            "__tcp_connect__" => self.call_tcp_connect(),
+            "__tcp_send__" => self.call_tcp_send(),
+            "__tcp_recv__" => self.call_tcp_recv(),
+            "__tcp_close__" => self.call_tcp_close(),
+            "__clear__" => self.call_clear(),
+            "__cursor_to__" => self.call_cursor_to(),
+            "__term_size__" => self.call_term_size(),
+            "__raw_mode__" => self.call_raw_mode(),
+            "__read_key__" => self.call_read_key(),
+            "__poll_key__" => self.call_poll_key(),
+            "__set_print_policy__" => self.call_set_print_policy(),
+            "__write_byte__" => self.call_write_byte(),
+            "__read_byte__" => self.call_read_byte(),
+            "__spawn__" => self.call_spawn(),
+            "__send__" => self.call_send(),
+            "__recv__" => self.call_recv(),
+            _ => self.call_host_function(f),
+        }
+    }
+
+    fn call_host_function(&mut self, f: &str) -> Result<bool, Error> {
+        let host_function = match self.host_functions.get(f) {
+            None => return Err(anyhow!(ERR_UNDEFINED)),
+            Some(h) => h.clone(),
+        };
+        self.check_stack(f, host_function.arity)?;
+        (host_function.f)(&mut self.stack_mut())
+            .map_err(|e| anyhow!("Runtime error: Host function '{f}' failed: {e}"))?;
+        Ok(false)
+    }
+
+    fn call_custom_function(&mut self, f: &str) -> Result<bool, Error> {
+        let (arg_count, body) = match self.function_table.get(f) {
+            None => return Err(anyhow!(ERR_UNDEFINED)),
+            Some(body) => body,
+        };
+        self.check_visibility(f)?;
+        self.check_stack(&format!("function '{f}'"), *arg_count as usize)?;
+
+        // Forget about the functions whose bodies have finished (or are about to, in the case of a tail call)
+        let height = self.instruction_stack.len();
+        while matches!(self.call_stack.last(), Some((_, h, _)) if *h >= height) {
+            pop_call(&mut self.call_stack, &mut self.journal);
+        }
+        if !body.is_empty() {
+            let span = debug_span!(parent: &self.current_span(), "call", function = f);
+            self.call_stack.push((f.to_owned(), height, span));
+            if height + body.len() > MAX_INSTRUCTION_STACK {
+                return Err(self.too_deep());
+            }
+        }
+
+        // $0 is the word that was on top
+        let args = self
+            .value_stack
+            .split_off(self.value_stack.len() - *arg_count as usize);
+        self.args_array.extend(args.into_iter().rev());
+
+        for instruction in body.iter().rev() {
+            self.instruction_stack
+                .push(Frame::Instruction(instruction.clone()));
+        }
+
+        Ok(false)
+    }
+
+    /// Checks that a private function is only called from functions defined in the same file. Code outside functions
+    /// only counts as being in the same file if neither has a name (as in the REPL).
+    fn check_visibility(&self, f: &str) -> Result<(), Error> {
+        let Some(Attributes {
+            private: true,
+            origin,
+            ..
+        }) = self.attributes.get(f)
+        else {
+            return Ok(());
+        };
+
+        // The function whose body contains the CALLIF. Its entry is still there if the CALLIF was its last instruction.
+        let height = self.instruction_stack.len();
+        let caller_origin = self
+            .call_stack
+            .iter()
+            .rev()
+            .find(|(_, h, _)| *h <= height)
+            .and_then(|(caller, _, _)| self.attributes.get(caller))
+            .and_then(|attributes| attributes.origin.as_ref());
+        if caller_origin == origin.as_ref() {
+            return Ok(());
+        }
+        let file = origin.as_deref().unwrap_or("the same input");
+        Err(anyhow!(
+            "Runtime error: Function '{f}' is private, so it can only be called from functions defined in {file}."
+        ))
+    }
+
+    /// Gets an input from `read`, recording it if the runtime is recording. When replaying, the next recorded input is
+    /// used instead, as long as it is the `expected` kind of input (which `unwrap` checks).
+    fn replayable<T: Clone>(
+        &mut self,
+        expected: &str,
+        wrap: fn(T) -> Event,
+        unwrap: fn(Event) -> Option<T>,
+        read: impl FnOnce(&mut Self) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let replay = Rc::clone(&self.replay);
+        let mut replay = replay.borrow_mut();
+        match &mut *replay {
+            Replay::Off => read(self),
+            Replay::Record(events) => {
+                let input = read(self)?;
+                events.push(wrap(input.clone()));
+                Ok(input)
+            }
+            Replay::Play(events) => match events.pop_front() {
+                None => Err(anyhow!(
+                    "Runtime error: The program asked for {expected}, but the replay has ended."
+                )),
+                Some(event) => unwrap(event.clone()).ok_or_else(|| {
+                    anyhow!("Runtime error: The program asked for {expected}, but the replay has '{event}' next.")
+                }),
+            },
+        }
+    }
+
+    /// The span of the user-defined function whose body contains the next entry on the instruction stack, if any.
+    fn current_span(&self) -> Span {
+        let height = self.instruction_stack.len();
+        self.call_stack
+            .iter()
+            .rev()
+            .find(|(_, h, _)| *h < height)
+            .map_or_else(Span::none, |(_, _, span)| span.clone())
+    }
+
+    /// Builds the error for when the instruction stack is full, naming the functions that keep calling each other if
+    /// there is such a cycle at the top of the call stack.
+    fn too_deep(&self) -> Error {
+        let names = self
+            .call_stack
+            .iter()
+            .map(|(f, _, _)| f.as_str())
+            .collect::<Vec<_>>();
+        let Some(cycle) = repeating_cycle(&names) else {
+            return anyhow!("{ERR_TOO_DEEP} The limit is {MAX_INSTRUCTION_STACK}.");
+        };
+        let explanation = match cycle {
+            [f] => format!("Function '{f}' keeps calling itself."),
+            _ => {
+                let path = cycle
+                    .iter()
+                    .chain(&cycle[..1])
+                    .map(|f| format!("'{f}'"))
+                    .collect::<Vec<_>>()
+                    .join(" -> ");
+                format!("These functions keep calling each other: {path}.")
+            }
+        };
+        anyhow!("{ERR_TOO_DEEP} The limit is {MAX_INSTRUCTION_STACK}. {explanation}")
+    }
+
+    fn call_print(&mut self) -> Result<bool, Error> {
+        // Check the whole string before printing any of it, so that nothing is printed or popped if it is invalid
+        let mut string = String::new();
+        let mut length = None;
+        for (i, word) in self.value_stack.iter().rev().enumerate() {
+            let n = match word {
+                Word::Data(0) => {
+                    length = Some(i);
+                    break;
+                }
+                Word::Data(n) => *n,
+                #[cfg(feature = "bigint")]
+                Word::Big(_) => return Err(anyhow!(ERR_OVERFLOW)),
+                Word::Function(_) | Word::Marker => {
+                    return Err(type_error("__print__", "a data word", word))
+                }
+            };
+            match (to_char(n), self.print_policy) {
+                (Some(c), _) => string.push(c),
+                (None, PrintPolicy::Error) => return Err(anyhow!(ERR_UTF32)),
+                (None, PrintPolicy::Skip) => {}
+                (None, PrintPolicy::Replace) => string.push(char::REPLACEMENT_CHARACTER),
+            }
+        }
+        let Some(length) = length else {
+            return Err(self.underflow("__print__", self.value_stack.len() + 1));
+        };
+
+        let mut io = self.io.borrow_mut();
+        if write!(io.output, "{string}").is_err() || io.output.flush().is_err() {
+            return Err(anyhow!(ERR_STDOUT));
+        }
+        drop(io);
+        self.value_stack
+            .truncate(self.value_stack.len() - length - 1);
+        Ok(false)
+    }
+
+    fn call_set_print_policy(&mut self) -> Result<bool, Error> {
+        let policy = match self.value_stack.last() {
+            None => return Err(self.underflow("__set_print_policy__", 1)),
+            Some(Word::Data(0)) => PrintPolicy::Error,
+            Some(Word::Data(1)) => PrintPolicy::Skip,
+            Some(Word::Data(2)) => PrintPolicy::Replace,
+            Some(Word::Data(n)) => {
+                return Err(anyhow!(
+                    "Runtime error: Invalid print policy {n}. Expected 0 (error), 1 (skip), or 2 (replace)."
+                ))
+            }
+            Some(word) => return Err(type_error("__set_print_policy__", "a data word", word)),
+        };
+        self.value_stack.pop();
+        self.print_policy = policy;
+        Ok(false)
+    }
+
+    fn call_write_byte(&mut self) -> Result<bool, Error> {
+        let n = self.peek_data("__write_byte__")?;
+        let mut io = self.io.borrow_mut();
+        if io.output.write_all(&[n as u8]).is_err() || io.output.flush().is_err() {
+            return Err(anyhow!(ERR_STDOUT));
+        }
+        drop(io);
+        self.value_stack.pop();
+        Ok(false)
+    }
+
+    fn call_read_byte(&mut self) -> Result<bool, Error> {
+        let byte = self.replayable(
+            "a byte of input",
+            Event::Byte,
+            |event| match event {
+                Event::Byte(byte) => Some(byte),
+                _ => None,
+            },
+            |runtime| {
+                runtime
+                    .io
+                    .borrow_mut()
+                    .read_byte()
+                    .map_err(|_| anyhow!(ERR_STDIN))
+            },
+        )?;
+        // 256 cannot be a byte, so it marks the end of the input
+        let n = byte.map_or(256, u64::from);
+        self.value_stack.push(Word::Data(n));
+        Ok(false)
+    }
+
+    fn call_input(&mut self) -> Result<bool, Error> {
+        let line = self.replayable(
+            "a line of input",
+            Event::Line,
+            |event| match event {
+                Event::Line(line) => Some(line),
+                _ => None,
+            },
+            |runtime| {
+                let mut line = String::new();
+                match runtime.io.borrow_mut().read_line(&mut line) {
+                    Err(_) => Err(anyhow!(ERR_STDIN)),
+                    Ok(_) => Ok(line),
+                }
+            },
+        )?;
+
+        for c in line.chars().rev() {
+            let n = c as u64;
+            self.value_stack.push(Word::Data(n));
+        }
+
+        Ok(false)
+    }
+
+    fn call_birl(&mut self) -> Result<bool, Error> {
+        let (a, b) = self.peek_data_pair("__birl__")?;
+        self.value_stack.truncate(self.value_stack.len() - 2);
+
+        let result = match self.word_size {
+            WordSize::Bits32 => (!(a as u32) | b as u32).rotate_left(1).into(),
+            WordSize::Bits64 => (!a | b).rotate_left(1),
+        };
+        self.value_stack.push(Word::Data(result));
+
+        Ok(false)
+    }
+
+    /// Pops the data words `a` and then `b`, and pushes `op(a, b)`.
+    fn call_binary(&mut self, f: &str, op: fn(u64, u64) -> u64) -> Result<bool, Error> {
+        let (a, b) = self.peek_data_pair(f)?;
+        self.value_stack.truncate(self.value_stack.len() - 2);
+        self.value_stack.push(Word::Data(op(a, b)));
+        Ok(false)
+    }
+
+    /// Pops the data words `a` and then `b`, and pushes the result of `op(a, b, max)` followed by 1 if it carried or
+    /// borrowed, or 0 otherwise. `max` is the largest data word.
+    fn call_with_carry(
+        &mut self,
+        f: &str,
+        op: fn(u64, u64, u64) -> (u64, bool),
+    ) -> Result<bool, Error> {
+        let (a, b) = self.peek_data_pair(f)?;
+        self.value_stack.truncate(self.value_stack.len() - 2);
+        let (result, carry) = op(a, b, self.word_size.max());
+        self.value_stack.push(Word::Data(result));
+        self.value_stack.push(Word::Data(carry.into()));
+        Ok(false)
+    }
+
+    /// Pops a count and then a data word `n`, and pushes `shift(n, count)` (cut off at the word size).
+    fn call_shift(&mut self, f: &str, shift: fn(u64, u32) -> u64) -> Result<bool, Error> {
+        let (count, n) = self.peek_data_pair(f)?;
+        let bits = self.word_size.bits();
+        let count = match u32::try_from(count) {
+            Ok(count) if count < bits => count,
+            _ => {
+                return Err(anyhow!(
+                "Runtime error: Cannot shift by {count} bits. {f} needs a count less than {bits}."
+            ))
+            }
+        };
+        self.value_stack.truncate(self.value_stack.len() - 2);
+        let result = shift(n, count) & self.word_size.max();
+        self.value_stack.push(Word::Data(result));
+        Ok(false)
+    }
+
+    /// Replaces the data word on top of the stack with `op(n, max)`, where `max` is the largest data word.
+    fn call_unary(&mut self, f: &str, op: fn(u64, u64) -> u64) -> Result<bool, Error> {
+        let n = self.peek_data(f)?;
+        let result = op(n, self.word_size.max());
+        *self.value_stack.last_mut().unwrap() = Word::Data(result);
+        Ok(false)
+    }
+
+    fn call_pick(&mut self) -> Result<bool, Error> {
+        let i = self.peek_index("__pick__")?;
+        self.value_stack.pop();
+        self.value_stack.push(self.value_stack[i].clone());
+        Ok(false)
+    }
+
+    fn call_roll(&mut self) -> Result<bool, Error> {
+        let i = self.peek_index("__roll__")?;
+        self.value_stack.pop();
+        let word = self.value_stack.remove(i);
+        self.value_stack.push(word);
+        Ok(false)
+    }
+
+    /// Pushes the number of words above the nearest marker, after removing the marker if `remove` is true.
+    fn call_count_to_mark(&mut self, remove: bool) -> Result<bool, Error> {
+        let Some(i) = self.value_stack.iter().rposition(|w| *w == Word::Marker) else {
+            let f = if remove {
+                "__collect__"
+            } else {
+                "__count_to_mark__"
+            };
+            return Err(anyhow!(
+                "Runtime error: {f} needs a marker on the stack, but there is none. Use __mark__ to push one."
+            ));
+        };
+        let count = self.value_stack.len() - i - 1;
+        if remove {
+            self.value_stack.remove(i);
+        }
+        self.value_stack.push(Word::Data(count as u64));
+        Ok(false)
+    }
+
+    /// Reads the index `n` on top of the stack (without popping it) and returns the position in the value stack of the
+    /// word that is `n` places below it, counting from 0.
+    fn peek_index(&self, context: &str) -> Result<usize, Error> {
+        let n = self.peek_data(context)?;
+        let below = self.value_stack.len() - 1;
+        match usize::try_from(n) {
+            Ok(n) if n < below => Ok(below - 1 - n),
+            _ => Err(self.underflow(
+                context,
+                usize::try_from(n.saturating_add(2)).unwrap_or(usize::MAX),
+            )),
+        }
+    }
+
+    fn call_defined(&mut self) -> Result<bool, Error> {
+        let f = self.pop_function_from_stack("__defined__")?;
+
+        self.value_stack
+            .push(Word::Data(self.is_defined(&f) as u64));
+
+        Ok(false)
+    }
+
+    fn call_call_n_times(&mut self) -> Result<bool, Error> {
+        let (f, count) = match self.peek_words("__call_n_times__", 2)? {
+            [_, word @ (Word::Data(_) | Word::Marker)] => {
+                return Err(type_error("__call_n_times__", "a function", word))
+            }
+            #[cfg(feature = "bigint")]
+            [_, word @ Word::Big(_)] => {
+                return Err(type_error("__call_n_times__", "a function", word))
+            }
+            [word @ (Word::Function(_) | Word::Marker), _] => {
+                return Err(type_error(
+                    "the count of __call_n_times__",
+                    "a data word",
+                    word,
+                ))
+            }
+            [Word::Data(count), Word::Function(f)] => (f.clone(), *count),
+            #[cfg(feature = "bigint")]
+            [Word::Big(_), Word::Function(_)] => return Err(anyhow!(ERR_OVERFLOW)),
+            _ => unreachable!(),
+        };
+        self.value_stack.truncate(self.value_stack.len() - 2);
+
+        if count > 0 {
+            self.instruction_stack
+                .push(Frame::Loop { f, index: 0, count });
+        }
+
+        Ok(false)
+    }
+
+    /// Runs one iteration of `__call_n_times__`: pushes the index and calls the function.
+    fn run_loop(&mut self, f: String, index: u64, count: u64) -> Result<bool, Error> {
+        // Like a CALLIF at the end of a function, the last iteration does not leave anything behind
+        if index + 1 < count {
+            self.instruction_stack.push(Frame::Loop {
+                f: f.clone(),
+                index: index + 1,
+                count,
+            });
+        }
+
+        self.value_stack.push(Word::Data(index));
+        self.call_function(&f)
+    }
+
+    fn call_strlen(&mut self) -> Result<bool, Error> {
+        let n = self.string_length(0, "__strlen__")?;
+        self.value_stack.push(Word::Data(n as u64));
+        Ok(false)
+    }
+
+    fn call_streq(&mut self) -> Result<bool, Error> {
+        let a = self.string_length(0, "__streq__")?;
+        let b = self.string_length(a + 1, "__streq__")?;
+
+        let top = self.value_stack.len();
+        let equal = self.value_stack[top - a..] == self.value_stack[top - a - 1 - b..top - a - 1];
+        self.value_stack.truncate(top - a - b - 2);
+        self.value_stack.push(Word::Data(equal as u64));
+
+        Ok(false)
+    }
+
+    fn call_strrev(&mut self) -> Result<bool, Error> {
+        let n = self.string_length(0, "__strrev__")?;
+        let top = self.value_stack.len();
+        self.value_stack[top - n..].reverse();
+        Ok(false)
+    }
+
+    fn call_num_to_str(&mut self) -> Result<bool, Error> {
+        let n = self.pop_data_from_stack("__num_to_str__")?;
+
+        self.value_stack.push(Word::Data(0));
+        for c in n.to_string().chars().rev() {
+            self.value_stack.push(Word::Data(c as u64));
+        }
+
+        Ok(false)
+    }
+
+    fn call_str_to_num(&mut self) -> Result<bool, Error> {
+        let length = self.string_length(0, "__str_to_num__")?;
+        if length == 0 {
+            return Err(anyhow!(ERR_NUMBER));
+        }
+
+        let top = self.value_stack.len();
+        let mut n: u64 = 0;
+        for word in self.value_stack[top - length..].iter().rev() {
+            let digit = match word {
+                Word::Data(c) => to_char(*c).and_then(|c| c.to_digit(10)),
+                _ => None,
+            };
+            let digit = match digit {
+                None => return Err(anyhow!(ERR_NUMBER)),
+                Some(d) => d,
+            };
+            n = match n
+                .checked_mul(10)
+                .and_then(|n| n.checked_add(digit.into()))
+                .filter(|n| *n <= self.word_size.max())
+            {
+                None => return Err(anyhow!(ERR_OVERFLOW)),
+                Some(n) => n,
+            };
+        }
+
+        self.value_stack.truncate(top - length - 1);
+        self.value_stack.push(Word::Data(n));
+
+        Ok(false)
+    }
+
+    fn call_assert_eq(&mut self) -> Result<bool, Error> {
+        let [left, right] = self.pop_words("__assert_eq__")?;
+
+        if left != right {
+            self.assertion_failures
+                .push(format!("Assertion failed: {left} != {right}."));
+        }
+
+        Ok(false)
+    }
+
+    fn call_assert(&mut self) -> Result<bool, Error> {
+        let condition = self.peek_data("__assert__")?;
+        let (message, length) = self.peek_string(1, "__assert__")?;
+
+        if condition == 0 {
+            return Err(anyhow!("Runtime error: Assertion failed: {message}"));
+        }
+        self.value_stack
+            .truncate(self.value_stack.len() - length - 2);
+        Ok(false)
+    }
+
+    fn call_panic(&mut self) -> Result<bool, Error> {
+        let (message, _) = self.peek_string(0, "__panic__")?;
+        Err(anyhow!("Runtime error: Panicked: {message}"))
+    }
+
+    fn call_stack_dump(&mut self) -> Result<bool, Error> {
+        eprintln!("{}", self.stack_to_string());
+        Ok(false)
+    }
+
+    fn call_gc_stats(&mut self) -> Result<bool, Error> {
+        let stats = self.memory_stats();
+        for n in [
+            stats.value_stack,
+            stats.instruction_stack,
+            stats.functions,
+            stats.bytes,
+        ] {
+            self.value_stack
+                .push(Word::Data((n as u64).min(self.word_size.max())));
+        }
+        Ok(false)
+    }
+
+    fn call_tcp_connect(&mut self) -> Result<bool, Error> {
+        let (address, length) = self.peek_string(0, "__tcp_connect__")?;
+        let stream = TcpStream::connect(&address)
+            .map_err(|e| anyhow!("Runtime error: Failed to connect to '{address}': {e}"))?;
+        self.value_stack
+            .truncate(self.value_stack.len() - length - 1);
+
+        let handle = self.next_connection;
+        self.next_connection += 1;
+        self.connections.insert(handle, Rc::new(stream));
+        self.value_stack.push(Word::Data(handle));
+        Ok(false)
+    }
+
+    fn call_tcp_send(&mut self) -> Result<bool, Error> {
+        let (_, stream) = self.peek_connection("__tcp_send__")?;
+        let (message, length) = self.peek_string(1, "__tcp_send__")?;
+        (&*stream)
+            .write_all(message.as_bytes())
+            .map_err(|e| anyhow!("Runtime error: Failed to send data: {e}"))?;
+        self.value_stack
+            .truncate(self.value_stack.len() - length - 2);
+        Ok(false)
+    }
+
+    /// Reads one line (up to and including the newline) from a connection and pushes it as a string. At the end of
+    /// the stream, the string is empty.
+    fn call_tcp_recv(&mut self) -> Result<bool, Error> {
+        let (_, stream) = self.peek_connection("__tcp_recv__")?;
+
+        // Read one byte at a time so that nothing after the newline is consumed
+        let mut line = Vec::new();
+        let mut byte = [0];
+        while line.last() != Some(&b'\n') {
+            match (&*stream).read(&mut byte) {
+                Ok(0) => break,
+                Ok(_) => line.push(byte[0]),
+                Err(e) => return Err(anyhow!("Runtime error: Failed to receive data: {e}")),
+            }
+        }
+
+        self.value_stack.pop();
+        self.value_stack.push(Word::Data(0));
+        for c in String::from_utf8_lossy(&line).chars().rev() {
+            self.value_stack.push(Word::Data(c as u64));
+        }
+        Ok(false)
+    }
+
+    fn call_tcp_close(&mut self) -> Result<bool, Error> {
+        let (handle, _) = self.peek_connection("__tcp_close__")?;
+        self.connections.remove(&handle);
+        self.value_stack.pop();
+        Ok(false)
+    }
+
+    fn call_clear(&mut self) -> Result<bool, Error> {
+        self.terminal.clear().map_err(|_| anyhow!(ERR_TERMINAL))?;
+        Ok(false)
+    }
+
+    fn call_cursor_to(&mut self) -> Result<bool, Error> {
+        let (column, row) = match self.value_stack[..] {
+            [.., Word::Data(row), Word::Data(column)] => (column, row),
+            [.., ref row, ref column] => {
+                let word = if let Word::Data(_) = column {
+                    row
+                } else {
+                    column
+                };
+                return Err(type_error("__cursor_to__", "a data word", word));
+            }
+            _ => return Err(self.underflow("__cursor_to__", 2)),
+        };
+        let (Ok(column), Ok(row)) = (u16::try_from(column), u16::try_from(row)) else {
+            return Err(anyhow!(ERR_OVERFLOW));
+        };
+
+        self.terminal
+            .cursor_to(column, row)
+            .map_err(|_| anyhow!(ERR_TERMINAL))?;
+        self.value_stack.truncate(self.value_stack.len() - 2);
+        Ok(false)
+    }
+
+    fn call_term_size(&mut self) -> Result<bool, Error> {
+        let (columns, rows) = self.replayable(
+            "the terminal size",
+            |(columns, rows)| Event::TermSize(columns, rows),
+            |event| match event {
+                Event::TermSize(columns, rows) => Some((columns, rows)),
+                _ => None,
+            },
+            |runtime| runtime.terminal.size().map_err(|_| anyhow!(ERR_TERMINAL)),
+        )?;
+        self.value_stack.push(Word::Data(rows.into()));
+        self.value_stack.push(Word::Data(columns.into()));
+        Ok(false)
+    }
+
+    fn call_raw_mode(&mut self) -> Result<bool, Error> {
+        let enabled = self.peek_data("__raw_mode__")? != 0;
+        self.terminal
+            .set_raw_mode(enabled)
+            .map_err(|_| anyhow!(ERR_TERMINAL))?;
+        self.value_stack.pop();
+        Ok(false)
+    }
+
+    fn call_read_key(&mut self) -> Result<bool, Error> {
+        let key = self.replayable(
+            "a key",
+            Event::Key,
+            |event| match event {
+                Event::Key(key) => Some(key),
+                _ => None,
+            },
+            |runtime| {
+                runtime
+                    .terminal
+                    .read_key()
+                    .map_err(|_| anyhow!(ERR_TERMINAL))
+            },
+        )?;
+        self.value_stack.push(Word::Data(key));
+        Ok(false)
+    }
+
+    fn call_poll_key(&mut self) -> Result<bool, Error> {
+        let key = self.replayable(
+            "a key poll",
+            Event::PollKey,
+            |event| match event {
+                Event::PollKey(key) => Some(key),
+                _ => None,
+            },
+            |runtime| {
+                runtime
+                    .terminal
+                    .poll_key()
+                    .map_err(|_| anyhow!(ERR_TERMINAL))
+            },
+        )?;
+        match key {
+            None => self.value_stack.push(Word::Data(0)),
+            Some(key) => {
+                self.value_stack.push(Word::Data(key));
+                self.value_stack.push(Word::Data(1));
+            }
+        }
+        Ok(false)
+    }
+
+    fn call_spawn(&mut self) -> Result<bool, Error> {
+        let f = match self.value_stack.last() {
+            None => return Err(self.underflow("__spawn__", 1)),
+            Some(Word::Function(f)) => f.clone(),
+            Some(word) => return Err(type_error("__spawn__", "a function", word)),
+        };
+        if !self.function_table.contains_key(&f) {
+            return Err(anyhow!(ERR_UNDEFINED));
+        }
+        // The output cannot be shared with another thread, and the new process should not print somewhere else
+        if self.io.borrow().is_redirected() {
+            return Err(anyhow!(
+                "Runtime error: __spawn__ cannot be used when the output is captured."
+            ));
+        }
+        self.value_stack.pop();
+
+        // Host functions cannot be shared between threads, so the new runtime only gets the functions defined in plates
+        let function_table = self.function_table.clone();
+        let attributes = self.attributes.clone();
+        let word_size = self.word_size;
+        let print_policy = self.print_policy;
+        let sandbox = self.sandbox;
+        let parent = self.process.id();
+        let id = self.process.spawn(move |process| {
+            let id = process.id();
+            let mut runtime = Runtime {
+                value_stack: vec![Word::Data(parent)],
+                function_table,
+                attributes,
+                word_size,
+                print_policy,
+                sandbox,
+                process: Rc::new(process),
+                ..Runtime::new()
+            };
+
+            let mut errors = Vec::new();
+            let result = [
+                Instruction::PushData(1),
+                Instruction::PushFunction(f),
+                Instruction::CallIf,
+            ]
+            .into_iter()
+            .try_for_each(|instruction| runtime.run(instruction).map(|_| ()));
+            if let Err(e) = result {
+                errors.push(e.context(format!("Process {id} stopped with an error.")));
+            }
+            errors.extend(runtime.join_processes());
+            errors
+        });
+        self.value_stack.push(Word::Data(id));
+        Ok(false)
+    }
+
+    fn call_send(&mut self) -> Result<bool, Error> {
+        let (to, word) = match &self.value_stack[..] {
+            [.., word, Word::Data(to)] => (*to, word.clone()),
+            [.., _, word] => return Err(type_error("__send__", "a process ID", word)),
+            _ => return Err(self.underflow("__send__", 2)),
+        };
+        self.process.send(to, word)?;
+        self.value_stack.truncate(self.value_stack.len() - 2);
+        Ok(false)
+    }
+
+    fn call_recv(&mut self) -> Result<bool, Error> {
+        let (word, from) = self.process.recv()?;
+        self.value_stack.push(word);
+        self.value_stack.push(Word::Data(from));
+        Ok(false)
+    }
+
+    /// Returns the connection handle on top of the stack (without popping it) along with the connection.
+    fn peek_connection(&self, context: &str) -> Result<(u64, Rc<TcpStream>), Error> {
+        let handle = match self.value_stack.last() {
+            None => return Err(self.underflow(context, 1)),
+            Some(Word::Data(n)) => *n,
+            Some(word) => return Err(type_error(context, "a connection handle", word)),
+        };
+        let stream = self
+            .connections
+            .get(&handle)
+            .cloned()
+            .ok_or_else(|| anyhow!("Runtime error: Invalid connection handle {handle}."))?;
+        Ok((handle, stream))
+    }
+
+    /// Pops `a` and then `b` (each of which can be a regular data word or a big one) and pushes `op(a, b)`. If `op`
+    /// returns `None`, the divisor was zero.
+    #[cfg(feature = "bigint")]
+    fn call_big(
+        &mut self,
+        name: &str,
+        op: fn(BigUint, BigUint) -> Option<BigUint>,
+    ) -> Result<bool, Error> {
+        let to_big = |word: &Word| match word {
+            Word::Data(n) => Ok(BigUint::from(*n)),
+            Word::Big(n) => Ok(n.clone()),
+            Word::Function(_) | Word::Marker => Err(type_error(name, "a data word", word)),
+        };
+        let (a, b) = match self.peek_words(name, 2)? {
+            [b, a] => (to_big(a)?, to_big(b)?),
+            _ => unreachable!(),
+        };
+
+        let result = op(a, b).ok_or_else(|| anyhow!(ERR_DIVISION))?;
+        let word = self.big_to_word(result);
+        self.value_stack.truncate(self.value_stack.len() - 2);
+        self.value_stack.push(word);
+        Ok(false)
+    }
+
+    #[cfg(feature = "bigint")]
+    fn big_to_word(&self, n: BigUint) -> Word {
+        match u64::try_from(&n) {
+            Ok(n) if n <= self.word_size.max() => Word::Data(n),
+            _ => Word::Big(n),
+        }
+    }
+
+    /// Returns the string that starts `skip` words below the top of the stack, along with its length (not counting
+    /// the zero terminator). The stack is not modified.
+    fn peek_string(&self, skip: usize, context: &str) -> Result<(String, usize), Error> {
+        let length = self.string_length(skip, context)?;
+        let top = self.value_stack.len() - skip;
+        let string = self.value_stack[top - length..top]
+            .iter()
+            .rev()
+            .map(|word| match word {
+                Word::Data(c) => to_char(*c),
+                _ => None,
+            })
+            .collect::<Option<String>>()
+            .ok_or_else(|| anyhow!(ERR_UTF32))?;
+        Ok((string, length))
+    }
+
+    /// Returns the number of characters (not counting the zero terminator) in the string that starts `skip` words
+    /// below the top of the stack. The stack is not modified, even if there is an error.
+    fn string_length(&self, skip: usize, context: &str) -> Result<usize, Error> {
+        let words = &self.value_stack[..self.value_stack.len() - skip];
+        if words.is_empty() {
+            return Err(self.underflow(context, skip + 1));
+        }
+
+        for (i, word) in words.iter().rev().enumerate() {
+            match word {
+                Word::Data(0) => return Ok(i),
+                Word::Data(_) => {}
+                #[cfg(feature = "bigint")]
+                Word::Big(_) => {}
+                Word::Function(_) | Word::Marker => {
+                    return Err(type_error(context, "a string", word))
+                }
+            }
+        }
+
+        Err(anyhow!(ERR_UNTERMINATED))
+    }
+
+    /// Pops from the stack. If the stack was empty or the value at the top was a function, returns an error. The
+    /// context (e.g., the name of a built-in function) is mentioned in the error.
+    ///
+    /// This should only be used for single values, not when you need to pop multiple things.
+    fn pop_data_from_stack(&mut self, context: &str) -> Result<u64, Error> {
+        let n = self.peek_data(context)?;
+        self.value_stack.pop();
+        Ok(n)
+    }
+
+    /// Like [`Runtime::pop_data_from_stack`], but without popping, for built-in functions that can still fail after
+    /// reading their argument.
+    fn peek_data(&self, context: &str) -> Result<u64, Error> {
+        match self.value_stack.last() {
+            None => Err(self.underflow(context, 1)),
+            Some(word @ (Word::Function(_) | Word::Marker)) => {
+                Err(type_error(context, "a data word", word))
+            }
+            Some(Word::Data(n)) => Ok(*n),
+            #[cfg(feature = "bigint")]
+            Some(Word::Big(_)) => Err(anyhow!(ERR_OVERFLOW)),
+        }
+    }
+
+    /// Returns the data words `a` (on top) and `b` (below it) without popping them.
+    fn peek_data_pair(&self, context: &str) -> Result<(u64, u64), Error> {
+        match self.peek_words(context, 2)? {
+            [_, word @ (Word::Function(_) | Word::Marker)]
+            | [word @ (Word::Function(_) | Word::Marker), _] => {
+                Err(type_error(context, "a data word", word))
+            }
+            [Word::Data(b), Word::Data(a)] => Ok((*a, *b)),
+            #[cfg(feature = "bigint")]
+            [Word::Big(_), _] | [_, Word::Big(_)] => Err(anyhow!(ERR_OVERFLOW)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Pops `N` words, returning them from the bottom to the top. If there are not enough words, nothing is popped.
+    fn pop_words<const N: usize>(&mut self, context: &str) -> Result<[Word; N], Error> {
+        self.check_stack(context, N)?;
+        let words = self.value_stack.split_off(self.value_stack.len() - N);
+        Ok(words.try_into().unwrap_or_else(|_| unreachable!()))
+    }
+
+    /// Returns the top `n` words (from the bottom to the top) without popping them, so that built-in functions can check
+    /// their arguments before changing the stack.
+    fn peek_words(&self, context: &str, n: usize) -> Result<&[Word], Error> {
+        self.check_stack(context, n)?;
+        Ok(&self.value_stack[self.value_stack.len() - n..])
+    }
+
+    /// Returns an error if the stack has fewer than `needed` words.
+    fn check_stack(&self, context: &str, needed: usize) -> Result<(), Error> {
+        if self.value_stack.len() < needed {
+            return Err(self.underflow(context, needed));
+        }
+        Ok(())
+    }
+
+    /// Returns an [`ERR_UNDERFLOW`] error that says how many words the context (e.g., `CALLIF`) needed.
+    fn underflow(&self, context: &str, needed: usize) -> Error {
+        anyhow!(
+            "{ERR_UNDERFLOW} {context} needs {needed} word(s), but the stack only has {}.",
+            self.value_stack.len()
+        )
+    }
+
+    /// Pops a function from the stack. Like [`Runtime::pop_data_from_stack`], the context is mentioned in the error if
+    /// the stack was empty or the value at the top was not a function.
+    fn pop_function_from_stack(&mut self, context: &str) -> Result<String, Error> {
+        let f = match self.value_stack.last() {
+            None => return Err(self.underflow(context, 1)),
+            Some(Word::Function(f)) => f.clone(),
+            Some(word) => return Err(type_error(context, "a function", word)),
+        };
+        self.value_stack.pop();
+        Ok(f)
+    }
+}
+
+/// Returns an [`ERR_TYPE`] error that says what was expected and what was found instead. The context is what needed
+/// the word, like `CALLIF` or the name of a built-in function.
+fn type_error(context: &str, expected: &str, found: &Word) -> Error {
+    let found = match found {
+        Word::Function(f) => format!("the function '{f}'"),
+        Word::Marker => "a marker".to_owned(),
+        word => format!("the data word {word}"),
+    };
+    anyhow!("{ERR_TYPE} Expected {expected} for {context}, but found {found}.")
+}
+
+/// Interprets a data word as a Unicode code point.
+fn to_char(n: u64) -> Option<char> {
+    u32::try_from(n).ok().and_then(char::from_u32)
+}
+
+/// Returns the number of bytes that an instruction uses outside of itself (i.e., on the heap).
+fn instruction_bytes(instruction: &Instruction) -> usize {
+    match instruction {
+        Instruction::PushFunction(f) => f.capacity(),
+        Instruction::Define(f, _, body, attributes) => {
+            f.capacity() + body_bytes(body) + attributes_bytes(attributes)
+        }
+        _ => 0,
+    }
+}
+
+fn attributes_bytes(attributes: &Attributes) -> usize {
+    [&attributes.doc, &attributes.origin]
+        .into_iter()
+        .flatten()
+        .map(String::capacity)
+        .sum()
+}
+
+fn body_bytes(body: &Vec<Instruction>) -> usize {
+    body.capacity() * std::mem::size_of::<Instruction>()
+        + body.iter().map(instruction_bytes).sum::<usize>()
+}
+
+/// Returns the shortest sequence of calls that repeats at least twice in a row at the end of `calls`, starting from the
+/// point where the repetition began.
+fn repeating_cycle<'a>(calls: &'a [&'a str]) -> Option<&'a [&'a str]> {
+    let n = calls.len();
+    let length =
+        (1..=n / 2).find(|&length| calls[n - length..] == calls[n - 2 * length..n - length])?;
+    let mut start = n - 2 * length;
+    while start > 0 && calls[start - 1] == calls[start - 1 + length] {
+        start -= 1;
+    }
+    Some(&calls[start..start + length])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::terminal::{FakeTerminal, KEY_UP};
+
+    macro_rules! assert_ok_and_eq {
+        ( $actual:expr, $expected:expr ) => {
+            let actual_val = $actual;
+            assert!(actual_val.is_ok());
+            let actual_val = actual_val.unwrap();
+            assert_eq!($expected, actual_val);
+        };
+    }
+
+    macro_rules! assert_err_with_msg {
+        ( $value:expr, $msg:expr ) => {
+            match $value {
+                Ok(x) => panic!("Expected an error but received 'Ok({x:?})'."),
+                Err(e) => assert_eq!($msg, format!("{e}")),
+            };
+        };
+    }
+
+    #[test]
+    fn new_runtime() {
+        let expected = Runtime {
+            value_stack: vec![],
+            function_table: HashMap::new(),
+            attributes: HashMap::new(),
+            host_functions: HashMap::new(),
+            rng: rand::thread_rng(),
+            word_size: WordSize::Bits32,
+            print_policy: PrintPolicy::Error,
+            instruction_stack: vec![],
+            args_array: vec![],
+            call_stack: vec![],
+            assertion_failures: vec![],
+            sandbox: false,
+            connections: HashMap::new(),
+            next_connection: 1,
+            terminal: Rc::new(Crossterm::default()),
+            process: Rc::new(Process::new()),
+            io: Rc::new(RefCell::new(Io::default())),
+            rollback: false,
+            snapshot: None,
+            replay: Rc::new(RefCell::new(Replay::Off)),
+            journal: None,
+        };
+        assert_eq!(expected, Runtime::new());
+    }
+
+    #[test]
+    fn push_data() {
+        let mut actual = Runtime::new();
+        let mut expected = Runtime::new();
+
+        // Empty stack
+        assert_ok_and_eq!(actual.run(Instruction::PushData(123)), false);
+        expected.value_stack.push(Word::Data(123));
+        assert_eq!(expected, actual);
+
+        // Non-empty stack
+        assert_ok_and_eq!(actual.run(Instruction::PushData(456)), false);
+        expected.value_stack.push(Word::Data(456));
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn push_data_word_size() {
+        let mut runtime = Runtime::new();
+        assert_err_with_msg!(runtime.run(Instruction::PushData(1 << 32)), ERR_OVERFLOW);
+
+        let mut runtime = Runtime::new().with_word_size(WordSize::Bits64);
+        assert_ok_and_eq!(runtime.run(Instruction::PushData(1 << 32)), false);
+        assert_eq!(vec![Word::Data(1 << 32)], runtime.value_stack);
+    }
+
+    #[test]
+    fn parse_word_size() {
+        assert_eq!(WordSize::Bits32, "32".parse::<WordSize>().unwrap());
+        assert_eq!(WordSize::Bits64, "64".parse::<WordSize>().unwrap());
+        assert_eq!(
+            "Invalid word size '16'. Expected 32 or 64.",
+            format!("{}", "16".parse::<WordSize>().unwrap_err())
+        );
+    }
+
+    #[test]
+    fn push_undefined_function() {
+        let mut runtime = Runtime {
+            value_stack: vec![Word::Function("foo".to_owned())],
+            ..Runtime::new()
+        };
+        let instruction = Instruction::PushFunction("bar".to_owned());
+        let after = Runtime {
+            value_stack: vec![
+                Word::Function("foo".to_owned()),
+                Word::Function("bar".to_owned()),
+            ],
+            ..Runtime::new()
+        };
+
+        assert_ok_and_eq!(runtime.run(instruction), false);
+        assert_eq!(after, runtime);
+    }
+
+    #[test]
+    fn push_defined_function() {
+        let mut runtime = Runtime {
+            function_table: HashMap::from([("foo".to_owned(), (0, vec![]))]),
+            ..Runtime::new()
+        };
+        let instruction = Instruction::PushFunction("foo".to_owned());
+        let after = Runtime {
+            value_stack: vec![Word::Function("foo".to_owned())],
+            function_table: HashMap::from([("foo".to_owned(), (0, vec![]))]),
+            ..Runtime::new()
+        };
+
+        assert_ok_and_eq!(runtime.run(instruction), false);
+        assert_eq!(after, runtime);
+    }
+
+    #[test]
+    fn push_random() {
+        let mut runtime = Runtime {
+            value_stack: vec![Word::Function("foo".to_owned()), Word::Data(123)],
+            ..Runtime::new()
+        };
+        let instruction = Instruction::PushRandom;
+        let after = Runtime::new();
+
+        assert_ok_and_eq!(runtime.run(instruction), false);
+
+        // Compare runtime without value stack
+        let updated_value_stack = runtime.value_stack;
+        runtime.value_stack = after.value_stack.clone();
+        assert_eq!(runtime, after);
+
+        // Check value stack: right length, first few values untouched, new value is any data word
+        assert_eq!(updated_value_stack.len(), 3);
+        assert_eq!(
+            vec![Word::Function("foo".to_owned()), Word::Data(123)],
+            updated_value_stack[..2]
+        );
+        assert!(matches!(updated_value_stack[2], Word::Data(_)));
+    }
+
+    #[test]
+    fn push_valid_args() {
+        let mut runtime = Runtime {
+            value_stack: vec![Word::Data(123), Word::Function("foo".to_owned())],
+            function_table: HashMap::from([(
+                "swap".to_owned(),
+                (1, vec![Instruction::PushArg(0), Instruction::PushArg(1)]),
+            )]),
+            args_array: vec![Word::Data(0), Word::Data(1)],
+            ..Runtime::new()
+        };
+        let mut after = runtime.clone();
+
+        assert_ok_and_eq!(runtime.run(Instruction::PushArg(0)), false);
+        after.value_stack.push(Word::Data(0));
+        assert_eq!(after, runtime);
+
+        assert_ok_and_eq!(runtime.run(Instruction::PushArg(1)), false);
+        after.value_stack.push(Word::Data(1));
+        assert_eq!(after, runtime);
+    }
+
+    #[test]
+    fn push_invalid_args() {
+        let mut runtime = Runtime {
+            value_stack: vec![Word::Data(123), Word::Function("foo".to_owned())],
+            function_table: HashMap::from([(
+                "swap".to_owned(),
+                (1, vec![Instruction::PushArg(0), Instruction::PushArg(1)]),
+            )]),
+            args_array: vec![Word::Data(0), Word::Data(1)],
+            ..Runtime::new()
+        };
+        let after = runtime.clone();
+
+        assert_err_with_msg!(runtime.run(Instruction::PushArg(2)), ERR_UNDEFINED);
+        assert_eq!(after, runtime);
+    }
+
+    #[test]
+    fn custom_function_underflow() {
+        let mut runtime = Runtime {
+            value_stack: [vec![Word::Data(5)], call("swap")].concat(),
+            function_table: HashMap::from([(
+                "swap".to_owned(),
+                (2, vec![Instruction::PushArg(0), Instruction::PushArg(1)]),
+            )]),
+            ..Runtime::new()
+        };
+
+        // Nothing is popped, not even the condition and the function
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Stack underflow. function 'swap' needs 2 word(s), but the stack only has 1."
+        );
+        assert_eq!(
+            [vec![Word::Data(5)], call("swap")].concat(),
+            runtime.value_stack
+        );
+        assert!(runtime.args_array.is_empty());
+    }
+
+    #[test]
+    fn define() {
+        let mut runtime = Runtime::new();
+        let instruction = Instruction::Define(
+            "foo".to_owned(),
+            2,
+            vec![Instruction::PushData(123), Instruction::PushData(456)],
+            Attributes::default(),
+        );
+        let after = Runtime {
+            function_table: HashMap::from([(
+                "foo".to_owned(),
+                (
+                    2,
+                    vec![Instruction::PushData(123), Instruction::PushData(456)],
+                ),
+            )]),
+            ..Runtime::new()
+        };
+
+        assert_ok_and_eq!(runtime.run(instruction), false);
+        assert_eq!(after, runtime);
+    }
+
+    #[test]
+    fn define_with_doc() {
+        let mut runtime = Runtime::new();
+        let define = |doc: Option<&str>| {
+            let attributes = Attributes {
+                doc: doc.map(str::to_owned),
+                ..Attributes::default()
+            };
+            Instruction::Define("foo".to_owned(), 1, vec![], attributes)
+        };
+
+        assert_ok_and_eq!(runtime.run(define(Some("Does nothing."))), false);
+        assert_eq!(Some(1), runtime.arg_count("foo"));
+        assert_eq!(Some("Does nothing."), runtime.doc("foo"));
+
+        // Redefining the function without a docstring removes the old one
+        assert_ok_and_eq!(runtime.run(define(None)), false);
+        assert_eq!(None, runtime.doc("foo"));
+        assert_eq!(None, runtime.arg_count("bar"));
+    }
+
+    #[test]
+    fn stack_api() {
+        let mut runtime = Runtime::new();
+        let mut stack = runtime.stack_mut();
+        assert!(stack.is_empty());
+        assert_ok_and_eq!(stack.push(Word::Data(1)), ());
+        assert_ok_and_eq!(stack.push(Word::Function("f".to_owned())), ());
+        assert_err_with_msg!(stack.push(Word::Data(1 << 32)), ERR_OVERFLOW);
+        assert_eq!(2, stack.len());
+        assert_eq!(Some(&Word::Function("f".to_owned())), stack.peek(0));
+        assert_eq!(Some(&Word::Data(1)), stack.peek(1));
+        assert_eq!(None, stack.peek(2));
+        assert_eq!(
+            vec![&Word::Data(1), &Word::Function("f".to_owned())],
+            stack.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(Some(Word::Function("f".to_owned())), stack.pop());
+        assert_eq!(&[Word::Data(1)], runtime.stack());
+    }
+
+    #[test]
+    fn function_api() {
+        let mut runtime = Runtime::new();
+        let define = |f: &str, arg_count, body| {
+            let attributes = Attributes {
+                doc: Some("Docs.".to_owned()),
+                ..Attributes::default()
+            };
+            Instruction::Define(f.to_owned(), arg_count, body, attributes)
+        };
+        assert_ok_and_eq!(
+            runtime.run(define("g", 2, vec![Instruction::PushArg(1)])),
+            false
+        );
+        assert_ok_and_eq!(runtime.run(define("f", 0, vec![])), false);
+
+        assert_eq!(
+            vec![("f", 0), ("g", 2)],
+            runtime.functions().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some((2, &[Instruction::PushArg(1)][..])),
+            runtime.get_function("g")
+        );
+        assert_eq!(None, runtime.get_function("__print__"));
+
+        assert!(runtime.remove_function("g"));
+        assert!(!runtime.remove_function("g"));
+        assert_eq!(None, runtime.get_function("g"));
+        assert_eq!(None, runtime.doc("g"));
+        assert_eq!(vec![("f", 0)], runtime.functions().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn private_functions() {
+        let define = |f: &str, body, private, origin: &str| {
+            let attributes = Attributes {
+                private,
+                origin: Some(origin.to_owned()),
+                ..Attributes::default()
+            };
+            Instruction::Define(f.to_owned(), 0, body, attributes)
+        };
+        let call = |f: &str| {
+            vec![
+                Instruction::PushData(1),
+                Instruction::PushFunction(f.to_owned()),
+                Instruction::CallIf,
+            ]
+        };
+        let mut runtime = Runtime::new();
+        for instruction in [
+            define("helper", vec![Instruction::PushData(7)], true, "lib.plates"),
+            // The call to the helper is the last instruction, so the caller's body is finished by then
+            define("api", call("helper"), false, "lib.plates"),
+            define("sneaky", call("helper"), false, "main.plates"),
+        ] {
+            runtime.run(instruction).unwrap();
+        }
+        let mut run = |f: &str| {
+            call(f)
+                .into_iter()
+                .try_for_each(|instruction| runtime.run(instruction).map(|_| ()))
+        };
+
+        run("api").unwrap();
+        assert_err_with_msg!(
+            run("sneaky"),
+            "Runtime error: Function 'helper' is private, so it can only be called from functions defined in lib.plates."
+        );
+        assert_err_with_msg!(
+            run("helper"),
+            "Runtime error: Function 'helper' is private, so it can only be called from functions defined in lib.plates."
+        );
+        // Each failed CALLIF leaves its condition and function behind
+        assert_eq!(
+            vec![
+                Word::Data(7),
+                Word::Data(1),
+                Word::Function("helper".to_owned()),
+                Word::Data(1),
+                Word::Function("helper".to_owned()),
+            ],
+            runtime.value_stack
+        );
+    }
+
+    #[test]
+    fn callif_true() {
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Function("bar".to_owned()),
+                Word::Data(789),
+                Word::Data(1),
+                Word::Function("foo".to_owned()),
+            ],
+            function_table: HashMap::from([(
+                "foo".to_owned(),
+                (
+                    0,
+                    vec![Instruction::PushData(123), Instruction::PushData(456)],
+                ),
+            )]),
+            ..Runtime::new()
+        };
+        let after = Runtime {
+            value_stack: vec![
+                Word::Function("bar".to_owned()),
+                Word::Data(789),
+                Word::Data(123),
+                Word::Data(456),
+            ],
+            ..runtime.clone()
+        };
+
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        assert_eq!(after, runtime);
+    }
+
+    #[test]
+    fn callif_false() {
+        let mut runtime = Runtime {
+            value_stack: vec![Word::Data(0), Word::Function("foo".to_owned())],
+            function_table: HashMap::from([(
+                "foo".to_owned(),
+                (
+                    1,
+                    vec![Instruction::PushData(123), Instruction::PushData(456)],
+                ),
+            )]),
+            ..Runtime::new()
+        };
+        let after = Runtime {
+            value_stack: vec![],
+            ..runtime.clone()
+        };
+
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        assert_eq!(after, runtime);
+    }
+
+    #[test]
+    fn stack_diff() {
+        let f = Word::Function("f".to_owned());
+        let before = [Word::Data(1), Word::Data(3), f.clone()];
+        let after = [Word::Data(1), Word::Data(6)];
+        let diff = StackDiff::new(&before, &after);
+        assert_eq!(vec![Word::Data(3), f], diff.popped);
+        assert_eq!(vec![Word::Data(6)], diff.pushed);
+        assert_eq!("-2 [3, function f] +1 [6]", diff.to_string());
+        assert_eq!(
+            "+1 [3]",
+            StackDiff::new(&before[..1], &before[..2]).to_string()
+        );
+        assert_eq!("(no change)", StackDiff::new(&before, &before).to_string());
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let mut runtime = Runtime::new();
+        runtime.run(Instruction::PushData(1)).unwrap();
+        let snapshot = runtime.snapshot();
+
+        runtime.run(Instruction::PushData(2)).unwrap();
+        runtime
+            .run(Instruction::Define(
+                "f".to_owned(),
+                0,
+                vec![],
+                Attributes {
+                    doc: Some("Does nothing.".to_owned()),
+                    ..Attributes::default()
+                },
+            ))
+            .unwrap();
+        runtime.restore(snapshot);
+
+        assert_eq!(vec![Word::Data(1)], runtime.value_stack);
+        assert!(!runtime.is_defined("f"));
+        assert_eq!(None, runtime.doc("f"));
+    }
+
+    #[test]
+    fn rollback() {
+        let define = Instruction::Define(
+            "f".to_owned(),
+            0,
+            vec![
+                Instruction::PushData(2),
+                Instruction::PushData(1),
+                Instruction::PushFunction("undefined".to_owned()),
+                Instruction::CallIf,
+            ],
+            Attributes::default(),
+        );
+        let stack = vec![Word::Data(9), Word::Data(1), Word::Function("f".to_owned())];
+
+        for (rollback, after) in [
+            (
+                false,
+                vec![
+                    Word::Data(9),
+                    Word::Data(2),
+                    Word::Data(1),
+                    Word::Function("undefined".to_owned()),
+                ],
+            ),
+            (true, stack.clone()),
+        ] {
+            let mut runtime = Runtime {
+                value_stack: stack.clone(),
+                ..Runtime::new().with_rollback(rollback)
+            };
+            runtime.run(define.clone()).unwrap();
+
+            assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
+            assert_eq!(after, runtime.value_stack, "rollback: {rollback}");
+        }
+    }
+
+    #[test]
+    fn callif_undefined() {
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Data(1),
+                Word::Function("undefined".to_owned()),
+                Word::Data(0),
+                Word::Function("undefined".to_owned()),
+            ],
+            ..Runtime::new()
+        };
+        let mut expected = runtime.clone();
+
+        // No problem if the function isn't called
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        expected.value_stack.remove(3);
+        expected.value_stack.remove(2);
+        assert_eq!(expected, runtime);
+
+        // Error if the function is called, in which case the stack is left alone
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
+        assert_eq!(expected, runtime);
+    }
+
+    #[test]
+    fn nested_functions_success() {
+        let a: u32 = 0b10101010_11001100_11110000_11111111;
+        let b: u32 = 0;
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Data(b.into()),
+                Word::Data(a.into()),
+                Word::Data(1),
+                Word::Function("foo".to_owned()),
+            ],
+            function_table: HashMap::from([
+                (
+                    "foo".to_owned(),
+                    (
+                        2,
+                        vec![
+                            Instruction::PushArg(1),
+                            Instruction::PushArg(0),
+                            Instruction::PushData(1),
+                            Instruction::PushFunction("bar".to_owned()),
+                            Instruction::CallIf,
+                            Instruction::PushData(123),
+                        ],
+                    ),
+                ),
+                (
+                    "bar".to_owned(),
+                    (
+                        0,
+                        vec![
+                            Instruction::PushData(1),
+                            Instruction::PushFunction("__birl__".to_owned()),
+                            Instruction::CallIf,
+                        ],
+                    ),
+                ),
+            ]),
+            ..Runtime::new()
+        };
+        let after = Runtime {
+            value_stack: vec![Word::Data((!a | b).rotate_left(1).into()), Word::Data(123)],
+            ..runtime.clone()
+        };
+
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        assert_eq!(after, runtime);
+    }
+
+    #[test]
+    fn undefined_arg_after_nested_custom_function() {
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Data(3),
+                Word::Data(1),
+                Word::Function("bad".to_owned()),
+            ],
+            function_table: HashMap::from([
+                (
+                    "bad".to_owned(),
+                    (
+                        1,
+                        vec![
+                            Instruction::PushArg(0),
+                            Instruction::PushData(1),
+                            Instruction::PushFunction("empty".to_owned()),
+                            Instruction::CallIf,
+                            Instruction::PushArg(0),
+                        ],
+                    ),
+                ),
+                ("empty".to_owned(), (0, vec![])),
+            ]),
+            ..Runtime::new()
+        };
+        let after = Runtime {
+            value_stack: vec![Word::Data(3)],
+            ..runtime.clone()
+        };
+
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
+        assert_eq!(after, runtime);
+    }
+
+    #[test]
+    fn undefined_arg_after_nested_builtin_function() {
+        let a: u32 = 0b11111111_11111111_11111111_11111111;
+        let b: u32 = 0b10101010_11001100_11110000_11111111;
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Data(b.into()),
+                Word::Data(a.into()),
+                Word::Data(1),
+                Word::Function("bad".to_owned()),
+            ],
+            function_table: HashMap::from([(
+                "bad".to_owned(),
+                (
+                    2,
+                    vec![
+                        Instruction::PushArg(1),
+                        Instruction::PushArg(0),
+                        Instruction::PushData(1),
+                        Instruction::PushFunction("__birl__".to_owned()),
+                        Instruction::CallIf,
+                        Instruction::PushArg(0),
+                    ],
+                ),
+            )]),
+            ..Runtime::new()
+        };
+        let after = Runtime {
+            value_stack: vec![Word::Data((!a | b).rotate_left(1).into())],
+            ..runtime.clone()
+        };
+
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
+        assert_eq!(after, runtime);
+    }
+
+    #[test]
+    fn undefined_arg_in_nested_function() {
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Data(4),
+                Word::Data(1),
+                Word::Function("foo".to_owned()),
+            ],
+            function_table: HashMap::from([
+                (
+                    "foo".to_owned(),
+                    (
+                        1,
+                        vec![
+                            Instruction::PushData(1),
+                            Instruction::PushFunction("bar".to_owned()),
+                            Instruction::CallIf,
+                        ],
+                    ),
+                ),
+                ("bar".to_owned(), (0, vec![Instruction::PushArg(0)])),
+            ]),
+            ..Runtime::new()
+        };
+        let after = Runtime {
+            value_stack: vec![],
+            ..runtime.clone()
+        };
+
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
+        // Ignore the args array: it doesn't matter whether or not it's cleared right away, as long as args don't leak
+        // to the next function call (there should be a test for that, like `undefined_arg_after_error`).
+        runtime.args_array = vec![];
+        assert_eq!(after, runtime);
+    }
+
+    #[test]
+    fn undefined_arg_in_successive_functions() {
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Data(123),
+                Word::Data(1),
+                Word::Function("bar".to_owned()),
+                Word::Data(456),
+                Word::Data(1),
+                Word::Function("foo".to_owned()),
+            ],
+            function_table: HashMap::from([
+                ("foo".to_owned(), (1, vec![])),
+                ("bar".to_owned(), (0, vec![Instruction::PushArg(0)])),
+            ]),
+            ..Runtime::new()
+        };
+        let after_foo = Runtime {
+            value_stack: vec![
+                Word::Data(123),
+                Word::Data(1),
+                Word::Function("bar".to_owned()),
+            ],
+            ..runtime.clone()
+        };
+        let after_bar = Runtime {
+            value_stack: vec![Word::Data(123)],
+            ..runtime.clone()
+        };
+
+        // First call (to foo): no problem
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        // Ignore the args array: it doesn't matter whether or not it's cleared right away, as long as args don't leak
+        // to the next function call (there should be a test for that, like `undefined_arg_after_error`).
+        runtime.args_array = vec![];
+        assert_eq!(after_foo, runtime);
+
+        // Second call (to bar): args from foo should be cleared, so $1 is invalid
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
+        // Ignore the args array: it doesn't matter whether or not it's cleared right away, as long as args don't leak
+        // to the next function call (there should be a test for that, like `undefined_arg_after_error`).
+        runtime.args_array = vec![];
+        assert_eq!(after_bar, runtime);
+    }
+
+    /// Checks that arguments don't leak from one function call to the next after an error.
+    #[test]
+    fn undefined_arg_after_error() {
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Data(42),
+                Word::Data(1),
+                Word::Function("bar".to_owned()),
+                Word::Data(123),
+                Word::Data(1),
+                Word::Function("foo".to_owned()),
+            ],
+            function_table: HashMap::from([
+                ("foo".to_owned(), (1, vec![Instruction::PushArg(999)])),
+                ("bar".to_owned(), (0, vec![Instruction::PushArg(0)])),
+            ]),
+            ..Runtime::new()
+        };
+        let after_foo = Runtime {
+            value_stack: vec![
+                Word::Data(42),
+                Word::Data(1),
+                Word::Function("bar".to_owned()),
+            ],
+            ..runtime.clone()
+        };
+        let after_bar = Runtime {
+            value_stack: vec![Word::Data(42)],
+            ..runtime.clone()
+        };
+
+        // foo should fail because it's accessing an an argument that doesn't exist
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
+        // Ignore the args array: it doesn't matter whether or not it's cleared right away, as long as args don't leak
+        // to the next function call.
+        runtime.args_array = vec![];
+        assert_eq!(after_foo, runtime);
+
+        // bar should fail because there's no argument 0 (even though there was an argument 0 in foo)
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
+        // Ignore the args array: it doesn't matter whether or not it's cleared right away, as long as args don't leak
+        // to the next function call.
+        runtime.args_array = vec![];
+        assert_eq!(after_bar, runtime);
+    }
+
+    #[test]
+    fn callif_exit() {
+        let mut runtime = Runtime {
+            value_stack: vec![Word::Data(1), Word::Function("exit".to_owned())],
+            function_table: HashMap::from([(
+                "exit".to_owned(),
+                (
+                    0,
+                    vec![
+                        Instruction::PushData(123),
+                        Instruction::Exit,
+                        Instruction::PushData(456),
+                    ],
+                ),
+            )]),
+            ..Runtime::new()
+        };
+        // The rest of the function is dropped
+        let after = Runtime {
+            value_stack: vec![Word::Data(123)],
+            ..runtime.clone()
+        };
+
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), true);
+        assert_eq!(after, runtime);
+    }
+
+    #[test]
+    fn callif_error() {
+        let mut runtime = Runtime {
+            value_stack: vec![Word::Data(1), Word::Function("evil".to_owned())],
+            function_table: HashMap::from([(
+                "evil".to_owned(),
+                (
+                    0,
+                    vec![
+                        Instruction::PushData(123),
+                        Instruction::PushArg(1),
+                        Instruction::PushData(456),
+                    ],
+                ),
+            )]),
+            ..Runtime::new()
+        };
+        let after = Runtime {
+            value_stack: vec![Word::Data(123)],
+            instruction_stack: vec![],
+            ..runtime.clone()
+        };
+
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
+        assert_eq!(after, runtime);
+    }
+
+    #[test]
+    fn infinite_recursion() {
+        // Each function calls the other and then has more work to do, so the instruction stack keeps growing
+        let body = |g: &str| {
+            vec![
+                Instruction::PushData(1),
+                Instruction::PushFunction(g.to_owned()),
+                Instruction::CallIf,
+                Instruction::PushData(0),
+            ]
+        };
+        let mut runtime = Runtime {
+            value_stack: vec![Word::Data(1), Word::Function("main".to_owned())],
+            function_table: HashMap::from([
+                ("main".to_owned(), (0, body("ping"))),
+                ("ping".to_owned(), (0, body("pong"))),
+                ("pong".to_owned(), (0, body("ping"))),
+            ]),
+            ..Runtime::new()
+        };
+
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            format!(
+                "{ERR_TOO_DEEP} The limit is {MAX_INSTRUCTION_STACK}. These functions keep calling each other: \
+                 'ping' -> 'pong' -> 'ping'."
+            )
+        );
+        assert!(runtime.instruction_stack.is_empty());
+        assert!(runtime.call_stack.is_empty());
+    }
+
+    #[test]
+    fn tail_recursion_does_not_grow() {
+        let mut runtime = Runtime {
+            value_stack: vec![Word::Data(1), Word::Function("forever".to_owned())],
+            function_table: HashMap::from([(
+                "forever".to_owned(),
+                (
+                    0,
+                    vec![
+                        Instruction::PushData(1),
+                        Instruction::PushFunction("forever".to_owned()),
+                        Instruction::CallIf,
+                    ],
+                ),
+            )]),
+            ..Runtime::new()
+        };
+        runtime.push_instruction(Instruction::CallIf);
+
+        for _ in 0..30_000 {
+            runtime.step().unwrap();
+            assert!(runtime.instruction_stack.len() <= 3);
+            assert!(runtime.call_stack.len() <= 1);
+        }
+    }
+
+    #[test]
+    fn repeating_cycle() {
+        assert_eq!(None, super::repeating_cycle(&[]));
+        assert_eq!(None, super::repeating_cycle(&["a", "b", "c"]));
+        assert_eq!(
+            Some(&["f"][..]),
+            super::repeating_cycle(&["main", "f", "f"])
+        );
+        assert_eq!(
+            Some(&["b", "c"][..]),
+            super::repeating_cycle(&["a", "b", "c", "b", "c"])
+        );
+        assert_eq!(
+            Some(&["b", "c"][..]),
+            super::repeating_cycle(&["a", "b", "c", "b", "c", "b"])
+        );
+    }
+
+    #[test]
+    fn callif_empty_stack() {
+        let mut runtime = Runtime::new();
+
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Stack underflow. CALLIF needs 2 word(s), but the stack only has 0."
+        );
+        assert_eq!(Runtime::new(), runtime);
+    }
+
+    #[test]
+    fn callif_almost_empty_stack() {
+        let mut runtime = Runtime {
+            value_stack: vec![Word::Function("foo".to_owned())],
+            ..Runtime::new()
+        };
+
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Stack underflow. CALLIF needs 2 word(s), but the stack only has 1."
+        );
+        assert_eq!(vec![Word::Function("foo".to_owned())], runtime.value_stack);
+    }
+
+    #[test]
+    fn callif_data_first() {
+        let mut runtime = Runtime {
+            value_stack: vec![Word::Function("empty".to_owned()), Word::Data(1)],
+            ..Runtime::new()
+        };
+
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Wrong type. Expected a function for CALLIF, but found the data word 1."
+        );
+        assert_eq!(before, runtime);
+    }
+
+    #[test]
+    fn callif_function_second() {
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Function("empty".to_owned()),
+                Word::Function("empty".to_owned()),
+            ],
+            ..Runtime::new()
+        };
+
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Wrong type. Expected a data word for the condition of CALLIF, but found the function 'empty'."
+        );
+        assert_eq!(before, runtime);
+    }
+
+    #[test]
+    fn builtin_birl() {
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Data(0b100010),
+                Word::Data(0b101000),
+                Word::Data(1),
+                Word::Function("__birl__".to_owned()),
+            ],
+            ..Runtime::new()
+        };
+        let after = Runtime {
+            value_stack: vec![Word::Data(0b11111111_11111111_11111111_11101111)],
+            ..Runtime::new()
+        };
+
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        assert_eq!(after, runtime);
+    }
+
+    #[test]
+    fn builtin_birl_64_bits() {
+        let mut runtime = Runtime {
+            value_stack: [
+                vec![Word::Data(0b100010), Word::Data(0b101000)],
+                call("__birl__"),
+            ]
+            .concat(),
+            ..Runtime::new().with_word_size(WordSize::Bits64)
+        };
+
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        assert_eq!(vec![Word::Data(0xFFFFFFFF_FFFFFFEF)], runtime.value_stack);
+    }
+
+    #[test]
+    fn builtin_inc_dec() {
+        let max32 = u32::MAX as u64;
+        for (word_size, f, n, expected) in [
+            (WordSize::Bits32, "__inc__", 41, 42),
+            (WordSize::Bits32, "__inc__", max32, 0),
+            (WordSize::Bits64, "__inc__", max32, max32 + 1),
+            (WordSize::Bits64, "__inc__", u64::MAX, 0),
+            (WordSize::Bits32, "__dec__", 42, 41),
+            (WordSize::Bits32, "__dec__", 0, max32),
+            (WordSize::Bits64, "__dec__", 0, u64::MAX),
+            (WordSize::Bits32, "__inc_sat__", 41, 42),
+            (WordSize::Bits32, "__inc_sat__", max32, max32),
+            (WordSize::Bits64, "__inc_sat__", u64::MAX, u64::MAX),
+            (WordSize::Bits32, "__dec_sat__", 42, 41),
+            (WordSize::Bits32, "__dec_sat__", 0, 0),
+        ] {
+            let mut runtime = Runtime {
+                value_stack: [vec![Word::Data(7), Word::Data(n)], call(f)].concat(),
+                ..Runtime::new().with_word_size(word_size)
+            };
+            assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+            assert_eq!(
+                vec![Word::Data(7), Word::Data(expected)],
+                runtime.value_stack,
+                "{f} {n}"
+            );
+        }
+
+        let mut runtime = Runtime {
+            value_stack: [vec![Word::Function("f".to_owned())], call("__inc__")].concat(),
+            ..Runtime::new()
+        };
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Wrong type. Expected a data word for __inc__, but found the function 'f'."
+        );
+        assert_eq!(before, runtime);
+
+        let mut runtime = Runtime {
+            value_stack: call("__dec_sat__"),
+            ..Runtime::new()
+        };
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Stack underflow. __dec_sat__ needs 1 word(s), but the stack only has 0."
+        );
+    }
+
+    #[test]
+    fn builtin_bitwise() {
+        for (word_size, f, operands, expected) in [
+            (WordSize::Bits32, "__and__", vec![0b1100, 0b1010], 0b1000),
+            (WordSize::Bits32, "__or__", vec![0b1100, 0b1010], 0b1110),
+            (WordSize::Bits32, "__xor__", vec![0b1100, 0b1010], 0b0110),
+            (WordSize::Bits32, "__not__", vec![0b1010], 0xFFFFFFF5),
+            (
+                WordSize::Bits64,
+                "__not__",
+                vec![0b1010],
+                0xFFFFFFFF_FFFFFFF5,
+            ),
+        ] {
+            let operands = operands.into_iter().map(Word::Data).collect::<Vec<_>>();
+            let mut runtime = Runtime {
+                value_stack: [vec![Word::Data(7)], operands, call(f)].concat(),
+                ..Runtime::new().with_word_size(word_size)
+            };
+            assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+            assert_eq!(
+                vec![Word::Data(7), Word::Data(expected)],
+                runtime.value_stack,
+                "{f}"
+            );
+        }
+
+        let mut runtime = Runtime {
+            value_stack: [
+                vec![Word::Function("foo".to_owned()), Word::Data(1)],
+                call("__xor__"),
+            ]
+            .concat(),
+            ..Runtime::new()
+        };
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Wrong type. Expected a data word for __xor__, but found the function 'foo'."
+        );
+        assert_eq!(before, runtime);
+
+        let mut runtime = Runtime {
+            value_stack: [vec![Word::Data(1)], call("__and__")].concat(),
+            ..Runtime::new()
+        };
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Stack underflow. __and__ needs 2 word(s), but the stack only has 1."
+        );
+        assert_eq!(before, runtime);
+    }
+
+    #[test]
+    fn builtin_shift() {
+        for (word_size, f, n, count, expected) in [
+            (WordSize::Bits32, "__shl_n__", 0b1011, 2, 0b101100),
+            (WordSize::Bits32, "__shl_n__", 0xF000000F, 4, 0xF0),
+            (WordSize::Bits64, "__shl_n__", 0xF000000F, 4, 0xF_000000F0),
+            (WordSize::Bits32, "__shl_n__", 1, 31, 0x80000000),
+            (WordSize::Bits32, "__shr_n__", 0b1011, 2, 0b10),
+            (WordSize::Bits32, "__shr_n__", 0xFFFFFFFF, 31, 1),
+            (WordSize::Bits64, "__shr_n__", u64::MAX, 63, 1),
+            (WordSize::Bits32, "__shr_n__", 5, 0, 5),
+        ] {
+            let mut runtime = Runtime {
+                value_stack: [vec![Word::Data(n), Word::Data(count)], call(f)].concat(),
+                ..Runtime::new().with_word_size(word_size)
+            };
+            assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+            assert_eq!(
+                vec![Word::Data(expected)],
+                runtime.value_stack,
+                "{f} {n} {count}"
+            );
+        }
+
+        for (word_size, count) in [(WordSize::Bits32, 32), (WordSize::Bits64, 64)] {
+            let mut runtime = Runtime {
+                value_stack: [vec![Word::Data(1), Word::Data(count)], call("__shl_n__")].concat(),
+                ..Runtime::new().with_word_size(word_size)
+            };
+            let before = runtime.clone();
+            assert_err_with_msg!(
+                runtime.run(Instruction::CallIf),
+                format!(
+                    "Runtime error: Cannot shift by {count} bits. __shl_n__ needs a count less than {count}."
+                )
+            );
+            assert_eq!(before, runtime);
+        }
+    }
+
+    #[test]
+    fn builtin_carry() {
+        let max32 = u32::MAX as u64;
+        for (word_size, f, b, a, expected, carry) in [
+            (WordSize::Bits32, "__add_carry__", 2, 3, 5, 0),
+            (WordSize::Bits32, "__add_carry__", max32, 1, 0, 1),
+            (
+                WordSize::Bits32,
+                "__add_carry__",
+                max32,
+                max32,
+                max32 - 1,
+                1,
+            ),
+            (WordSize::Bits64, "__add_carry__", max32, 1, max32 + 1, 0),
+            (WordSize::Bits64, "__add_carry__", u64::MAX, 2, 1, 1),
+            (WordSize::Bits32, "__sub_borrow__", 5, 3, 2, 0),
+            (WordSize::Bits32, "__sub_borrow__", 3, 3, 0, 0),
+            (WordSize::Bits32, "__sub_borrow__", 3, 5, max32 - 1, 1),
+            (WordSize::Bits64, "__sub_borrow__", 0, 1, u64::MAX, 1),
+        ] {
+            let mut runtime = Runtime {
+                value_stack: [vec![Word::Data(b), Word::Data(a)], call(f)].concat(),
+                ..Runtime::new().with_word_size(word_size)
+            };
+            assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+            assert_eq!(
+                vec![Word::Data(expected), Word::Data(carry)],
+                runtime.value_stack,
+                "{f} {b} {a}"
+            );
+        }
+
+        let mut runtime = Runtime {
+            value_stack: [vec![Word::Data(1)], call("__add_carry__")].concat(),
+            ..Runtime::new()
+        };
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Stack underflow. __add_carry__ needs 2 word(s), but the stack only has 1."
+        );
+        assert_eq!(before, runtime);
+    }
+
+    #[test]
+    fn builtin_pick_and_roll() {
+        let data = |words: &[u64]| words.iter().copied().map(Word::Data).collect::<Vec<_>>();
+        for (f, n, expected) in [
+            ("__pick__", 0, data(&[1, 2, 3, 3])),
+            ("__pick__", 2, data(&[1, 2, 3, 1])),
+            ("__roll__", 0, data(&[1, 2, 3])),
+            ("__roll__", 1, data(&[1, 3, 2])),
+            ("__roll__", 2, data(&[2, 3, 1])),
+        ] {
+            let mut runtime = Runtime {
+                value_stack: [data(&[1, 2, 3, n]), call(f)].concat(),
+                ..Runtime::new()
+            };
+            assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+            assert_eq!(expected, runtime.value_stack, "{f} {n}");
+        }
+
+        for f in ["__pick__", "__roll__"] {
+            let mut runtime = Runtime {
+                value_stack: [data(&[1, 2, 3, 3]), call(f)].concat(),
+                ..Runtime::new()
+            };
+            let before = runtime.clone();
+            assert_err_with_msg!(
+                runtime.run(Instruction::CallIf),
+                format!("Runtime error: Stack underflow. {f} needs 5 word(s), but the stack only has 4.")
+            );
+            assert_eq!(before, runtime);
+        }
+
+        let mut runtime = Runtime {
+            value_stack: [vec![Word::Function("f".to_owned())], call("__pick__")].concat(),
+            ..Runtime::new()
+        };
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Wrong type. Expected a data word for __pick__, but found the function 'f'."
+        );
+    }
+
+    #[test]
+    fn builtin_markers() {
+        let mut runtime = Runtime::new();
+        runtime.value_stack = [vec![Word::Data(1)], call("__mark__")].concat();
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        runtime.value_stack.extend([
+            Word::Data(2),
+            Word::Data(3),
+            Word::Data(1),
+            Word::Function("__count_to_mark__".to_owned()),
+        ]);
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        assert_eq!(
+            vec![
+                Word::Data(1),
+                Word::Marker,
+                Word::Data(2),
+                Word::Data(3),
+                Word::Data(2)
+            ],
+            runtime.value_stack
+        );
+
+        runtime.value_stack.pop();
+        runtime.value_stack.extend(call("__collect__"));
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        assert_eq!(
+            vec![Word::Data(1), Word::Data(2), Word::Data(3), Word::Data(2)],
+            runtime.value_stack
+        );
+
+        for f in ["__count_to_mark__", "__collect__"] {
+            runtime.value_stack = [vec![Word::Data(1)], call(f)].concat();
+            let before = runtime.clone();
+            assert_err_with_msg!(
+                runtime.run(Instruction::CallIf),
+                format!("Runtime error: {f} needs a marker on the stack, but there is none. Use __mark__ to push one.")
+            );
+            assert_eq!(before, runtime);
+        }
+
+        runtime.value_stack = [vec![Word::Marker], call("__print__")].concat();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Wrong type. Expected a data word for __print__, but found a marker."
+        );
+        runtime.value_stack = vec![Word::Marker, Word::Function("f".to_owned())];
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Wrong type. Expected a data word for the condition of CALLIF, but found a marker."
+        );
+    }
+
+    #[cfg(feature = "bigint")]
+    #[test]
+    fn builtin_big_arithmetic() {
+        let big = |s: &str| Word::Big(s.parse().unwrap());
+        let mut runtime = Runtime::new();
+        for instruction in [
+            Instruction::PushBig("123456789012345678901234567890".to_owned()),
+            Instruction::PushData(u32::MAX.into()),
+            Instruction::PushData(1),
+            Instruction::PushFunction("__bmul__".to_owned()),
+            Instruction::CallIf,
+        ] {
+            assert_ok_and_eq!(runtime.run(instruction), false);
+        }
+        assert_eq!(
+            vec![big("530242871153740042115374004211007157550")],
+            runtime.value_stack
+        );
+
+        // Results that fit in a word are regular data words
+        runtime.value_stack = [vec![big("4294967296"), Word::Data(10)], call("__bmod__")].concat();
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        assert_eq!(vec![Word::Data(6)], runtime.value_stack);
+
+        runtime.value_stack = [
+            vec![Word::Data(u32::MAX.into()), Word::Data(1)],
+            call("__badd__"),
+        ]
+        .concat();
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        assert_eq!(vec![big("4294967296")], runtime.value_stack);
+    }
+
+    #[cfg(feature = "bigint")]
+    #[test]
+    fn builtin_big_errors() {
+        let mut runtime = Runtime {
+            value_stack: [vec![Word::Data(5), Word::Data(0)], call("__bmod__")].concat(),
+            ..Runtime::new()
+        };
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_DIVISION);
+
+        let mut runtime = Runtime {
+            value_stack: [
+                vec![Word::Big(u64::MAX.into()), Word::Data(0)],
+                call("__birl__"),
+            ]
+            .concat(),
+            ..Runtime::new()
+        };
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_OVERFLOW);
+    }
+
+    #[cfg(not(feature = "bigint"))]
+    #[test]
+    fn big_without_feature() {
+        let mut runtime = Runtime::new();
+        assert_err_with_msg!(
+            runtime.run(Instruction::PushBig("4294967296".to_owned())),
+            ERR_NO_BIGINT
+        );
+
+        let mut runtime = Runtime {
+            value_stack: [vec![Word::Data(1), Word::Data(2)], call("__badd__")].concat(),
+            ..Runtime::new()
+        };
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_NO_BIGINT);
+    }
+
+    #[test]
+    fn builtin_birl_empty_stack() {
+        let mut runtime = Runtime {
+            value_stack: vec![Word::Data(1), Word::Function("__birl__".to_owned())],
+            ..Runtime::new()
+        };
+
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Stack underflow. __birl__ needs 2 word(s), but the stack only has 0."
+        );
+        assert_eq!(before, runtime);
+    }
+
+    #[test]
+    fn builtin_birl_almost_empty_stack() {
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Function("foo".to_owned()),
+                Word::Data(1),
+                Word::Function("__birl__".to_owned()),
+            ],
+            ..Runtime::new()
+        };
+
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Stack underflow. __birl__ needs 2 word(s), but the stack only has 1."
+        );
+        assert_eq!(before, runtime);
+    }
+
+    #[test]
+    fn builtin_birl_function_first() {
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Data(42),
+                Word::Function("foo".to_owned()),
+                Word::Data(1),
+                Word::Function("__birl__".to_owned()),
+            ],
+            ..Runtime::new()
+        };
+
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Wrong type. Expected a data word for __birl__, but found the function 'foo'."
+        );
+        assert_eq!(before, runtime);
+    }
+
+    #[test]
+    fn builtin_birl_function_second() {
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Function("foo".to_owned()),
+                Word::Data(42),
+                Word::Data(1),
+                Word::Function("__birl__".to_owned()),
+            ],
+            ..Runtime::new()
+        };
+
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Wrong type. Expected a data word for __birl__, but found the function 'foo'."
+        );
+        assert_eq!(before, runtime);
+    }
+
+    #[test]
+    fn builtin_defined() {
+        let function_table = HashMap::from([("foo".to_owned(), (0, vec![]))]);
+        for (f, expected) in [("foo", 1), ("__print__", 1), ("bar", 0), ("__bar__", 0)] {
+            let mut runtime = Runtime {
+                value_stack: vec![
+                    Word::Function(f.to_owned()),
+                    Word::Data(1),
+                    Word::Function("__defined__".to_owned()),
+                ],
+                function_table: function_table.clone(),
+                ..Runtime::new()
+            };
+            let after = Runtime {
+                value_stack: vec![Word::Data(expected)],
+                function_table: function_table.clone(),
+                ..Runtime::new()
+            };
+
+            assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+            assert_eq!(after, runtime);
+        }
+    }
+
+    #[test]
+    fn builtin_defined_empty_stack() {
+        let mut runtime = Runtime {
+            value_stack: vec![Word::Data(1), Word::Function("__defined__".to_owned())],
+            ..Runtime::new()
+        };
 
-    macro_rules! assert_ok_and_eq {
-        ( $actual:expr, $expected:expr ) => {
-            let actual_val = $actual;
-            assert!(actual_val.is_ok());
-            let actual_val = actual_val.unwrap();
-            assert_eq!($expected, actual_val);
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Stack underflow. __defined__ needs 1 word(s), but the stack only has 0."
+        );
+        assert_eq!(before, runtime);
+    }
+
+    #[test]
+    fn builtin_defined_data() {
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Data(42),
+                Word::Data(1),
+                Word::Function("__defined__".to_owned()),
+            ],
+            ..Runtime::new()
         };
+
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Wrong type. Expected a function for __defined__, but found the data word 42."
+        );
+        assert_eq!(before, runtime);
     }
 
-    macro_rules! assert_err_with_msg {
-        ( $value:expr, $msg:expr ) => {
-            match $value {
-                Ok(x) => panic!("Expected an error but received 'Ok({x:?})'."),
-                Err(e) => assert_eq!($msg, format!("{e}")),
-            };
+    #[test]
+    fn builtin_call_n_times() {
+        let function_table = HashMap::from([(
+            "twice".to_owned(),
+            (1, vec![Instruction::PushArg(0), Instruction::PushArg(0)]),
+        )]);
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Data(3),
+                Word::Function("twice".to_owned()),
+                Word::Data(1),
+                Word::Function("__call_n_times__".to_owned()),
+            ],
+            function_table: function_table.clone(),
+            ..Runtime::new()
+        };
+        let after = Runtime {
+            value_stack: vec![
+                Word::Data(0),
+                Word::Data(0),
+                Word::Data(1),
+                Word::Data(1),
+                Word::Data(2),
+                Word::Data(2),
+            ],
+            function_table,
+            ..Runtime::new()
         };
+
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        runtime.args_array = vec![];
+        assert_eq!(after, runtime);
     }
 
     #[test]
-    fn new_runtime() {
-        let expected = Runtime {
-            value_stack: vec![],
-            function_table: HashMap::new(),
-            rng: rand::thread_rng(),
-            instruction_stack: vec![],
-            args_array: vec![],
+    fn builtin_call_n_times_without_args() {
+        let function_table =
+            HashMap::from([("seven".to_owned(), (0, vec![Instruction::PushData(7)]))]);
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Data(2),
+                Word::Function("seven".to_owned()),
+                Word::Data(1),
+                Word::Function("__call_n_times__".to_owned()),
+            ],
+            function_table: function_table.clone(),
+            ..Runtime::new()
         };
-        assert_eq!(expected, Runtime::new());
+        let after = Runtime {
+            value_stack: vec![Word::Data(0), Word::Data(7), Word::Data(1), Word::Data(7)],
+            function_table,
+            ..Runtime::new()
+        };
+
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        assert_eq!(after, runtime);
     }
 
     #[test]
-    fn push_data() {
-        let mut actual = Runtime::new();
-        let mut expected = Runtime::new();
+    fn builtin_call_n_times_zero() {
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Data(0),
+                Word::Function("undefined".to_owned()),
+                Word::Data(1),
+                Word::Function("__call_n_times__".to_owned()),
+            ],
+            ..Runtime::new()
+        };
 
-        // Empty stack
-        assert_ok_and_eq!(actual.run(Instruction::PushData(123)), false);
-        expected.value_stack.push(Word::Data(123));
-        assert_eq!(expected, actual);
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        assert_eq!(Runtime::new(), runtime);
+    }
 
-        // Non-empty stack
-        assert_ok_and_eq!(actual.run(Instruction::PushData(456)), false);
-        expected.value_stack.push(Word::Data(456));
-        assert_eq!(expected, actual);
+    #[test]
+    fn builtin_call_n_times_exit() {
+        let function_table = HashMap::from([("exit".to_owned(), (1, vec![Instruction::Exit]))]);
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Data(5),
+                Word::Function("exit".to_owned()),
+                Word::Data(1),
+                Word::Function("__call_n_times__".to_owned()),
+            ],
+            function_table,
+            ..Runtime::new()
+        };
+
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), true);
     }
 
     #[test]
-    fn push_undefined_function() {
+    fn builtin_call_n_times_undefined() {
         let mut runtime = Runtime {
-            value_stack: vec![Word::Function("foo".to_owned())],
+            value_stack: vec![
+                Word::Data(1),
+                Word::Function("undefined".to_owned()),
+                Word::Data(1),
+                Word::Function("__call_n_times__".to_owned()),
+            ],
             ..Runtime::new()
         };
-        let instruction = Instruction::PushFunction("bar".to_owned());
-        let after = Runtime {
+
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
+        // The index was pushed before the call failed
+        assert_eq!(vec![Word::Data(0)], runtime.value_stack);
+        assert!(runtime.instruction_stack.is_empty());
+    }
+
+    #[test]
+    fn builtin_call_n_times_empty_stack() {
+        let mut runtime = Runtime {
             value_stack: vec![
                 Word::Function("foo".to_owned()),
-                Word::Function("bar".to_owned()),
+                Word::Data(1),
+                Word::Function("__call_n_times__".to_owned()),
             ],
             ..Runtime::new()
         };
 
-        assert_ok_and_eq!(runtime.run(instruction), false);
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Stack underflow. __call_n_times__ needs 2 word(s), but the stack only has 1."
+        );
+        assert_eq!(before, runtime);
+    }
+
+    #[test]
+    fn builtin_call_n_times_wrong_order() {
+        let mut runtime = Runtime {
+            value_stack: vec![
+                Word::Function("foo".to_owned()),
+                Word::Data(2),
+                Word::Data(1),
+                Word::Function("__call_n_times__".to_owned()),
+            ],
+            ..Runtime::new()
+        };
+
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Wrong type. Expected a function for __call_n_times__, but found the data word 2."
+        );
+        assert_eq!(before, runtime);
+    }
+
+    fn string(s: &str) -> Vec<Word> {
+        let mut words = vec![Word::Data(0)];
+        words.extend(s.chars().rev().map(|c| Word::Data(c as u64)));
+        words
+    }
+
+    fn call(f: &str) -> Vec<Word> {
+        vec![Word::Data(1), Word::Function(f.to_owned())]
+    }
+
+    #[test]
+    fn builtin_strlen() {
+        let mut runtime = Runtime {
+            value_stack: [string("abc"), string("héllo"), call("__strlen__")].concat(),
+            ..Runtime::new()
+        };
+        let after = Runtime {
+            value_stack: [string("abc"), string("héllo"), vec![Word::Data(5)]].concat(),
+            ..Runtime::new()
+        };
+
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
         assert_eq!(after, runtime);
     }
 
     #[test]
-    fn push_defined_function() {
+    fn builtin_strlen_empty_string() {
         let mut runtime = Runtime {
-            function_table: HashMap::from([("foo".to_owned(), (0, vec![]))]),
+            value_stack: [string(""), call("__strlen__")].concat(),
             ..Runtime::new()
         };
-        let instruction = Instruction::PushFunction("foo".to_owned());
         let after = Runtime {
-            value_stack: vec![Word::Function("foo".to_owned())],
-            function_table: HashMap::from([("foo".to_owned(), (0, vec![]))]),
+            value_stack: vec![Word::Data(0), Word::Data(0)],
             ..Runtime::new()
         };
 
-        assert_ok_and_eq!(runtime.run(instruction), false);
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
         assert_eq!(after, runtime);
     }
 
     #[test]
-    fn push_random() {
+    fn builtin_strlen_empty_stack() {
         let mut runtime = Runtime {
-            value_stack: vec![Word::Function("foo".to_owned()), Word::Data(123)],
+            value_stack: call("__strlen__"),
             ..Runtime::new()
         };
-        let instruction = Instruction::PushRandom;
-        let after = Runtime::new();
 
-        assert_ok_and_eq!(runtime.run(instruction), false);
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Stack underflow. __strlen__ needs 1 word(s), but the stack only has 0."
+        );
+        assert_eq!(before, runtime);
+    }
 
-        // Compare runtime without value stack
-        let updated_value_stack = runtime.value_stack;
-        runtime.value_stack = after.value_stack.clone();
-        assert_eq!(runtime, after);
+    #[test]
+    fn builtin_strlen_unterminated() {
+        let mut runtime = Runtime {
+            value_stack: [vec![Word::Data(97), Word::Data(98)], call("__strlen__")].concat(),
+            ..Runtime::new()
+        };
 
-        // Check value stack: right length, first few values untouched, new value is any data word
-        assert_eq!(updated_value_stack.len(), 3);
-        assert_eq!(
-            vec![Word::Function("foo".to_owned()), Word::Data(123)],
-            updated_value_stack[..2]
+        let before = runtime.clone();
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNTERMINATED);
+        assert_eq!(before, runtime);
+    }
+
+    #[test]
+    fn builtin_strlen_function() {
+        let mut runtime = Runtime {
+            value_stack: [
+                string("a"),
+                vec![Word::Function("foo".to_owned()), Word::Data(98)],
+                call("__strlen__"),
+            ]
+            .concat(),
+            ..Runtime::new()
+        };
+
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Wrong type. Expected a string for __strlen__, but found the function 'foo'."
         );
-        assert!(matches!(updated_value_stack[2], Word::Data(_)));
     }
 
     #[test]
-    fn push_valid_args() {
+    fn builtin_streq() {
+        for (a, b, expected) in [
+            ("abc", "abc", 1),
+            ("abc", "abd", 0),
+            ("abc", "ab", 0),
+            ("", "a", 0),
+            ("", "", 1),
+        ] {
+            let mut runtime = Runtime {
+                value_stack: [
+                    vec![Word::Data(42)],
+                    string(a),
+                    string(b),
+                    call("__streq__"),
+                ]
+                .concat(),
+                ..Runtime::new()
+            };
+            let after = Runtime {
+                value_stack: vec![Word::Data(42), Word::Data(expected)],
+                ..Runtime::new()
+            };
+
+            assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+            assert_eq!(after, runtime);
+        }
+    }
+
+    #[test]
+    fn builtin_streq_one_string() {
         let mut runtime = Runtime {
-            value_stack: vec![Word::Data(123), Word::Function("foo".to_owned())],
-            function_table: HashMap::from([(
-                "swap".to_owned(),
-                (1, vec![Instruction::PushArg(0), Instruction::PushArg(1)]),
-            )]),
-            args_array: vec![Word::Data(0), Word::Data(1)],
+            value_stack: [string("abc"), call("__streq__")].concat(),
             ..Runtime::new()
         };
-        let mut after = runtime.clone();
 
-        assert_ok_and_eq!(runtime.run(Instruction::PushArg(0)), false);
-        after.value_stack.push(Word::Data(0));
-        assert_eq!(after, runtime);
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Stack underflow. __streq__ needs 5 word(s), but the stack only has 4."
+        );
+        assert_eq!(before, runtime);
+    }
 
-        assert_ok_and_eq!(runtime.run(Instruction::PushArg(1)), false);
-        after.value_stack.push(Word::Data(1));
-        assert_eq!(after, runtime);
+    #[test]
+    fn builtin_streq_unterminated() {
+        let mut runtime = Runtime {
+            value_stack: [vec![Word::Data(97)], string("abc"), call("__streq__")].concat(),
+            ..Runtime::new()
+        };
+
+        let before = runtime.clone();
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNTERMINATED);
+        assert_eq!(before, runtime);
     }
 
     #[test]
-    fn push_invalid_args() {
+    fn builtin_strrev() {
         let mut runtime = Runtime {
-            value_stack: vec![Word::Data(123), Word::Function("foo".to_owned())],
-            function_table: HashMap::from([(
-                "swap".to_owned(),
-                (1, vec![Instruction::PushArg(0), Instruction::PushArg(1)]),
-            )]),
-            args_array: vec![Word::Data(0), Word::Data(1)],
+            value_stack: [string("xyz"), string("abcd"), call("__strrev__")].concat(),
+            ..Runtime::new()
+        };
+        let after = Runtime {
+            value_stack: [string("xyz"), string("dcba")].concat(),
             ..Runtime::new()
         };
-        let after = runtime.clone();
 
-        assert_err_with_msg!(runtime.run(Instruction::PushArg(2)), ERR_UNDEFINED);
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
         assert_eq!(after, runtime);
     }
 
     #[test]
-    fn define() {
-        let mut runtime = Runtime::new();
-        let instruction = Instruction::Define(
-            "foo".to_owned(),
-            2,
-            vec![Instruction::PushData(123), Instruction::PushData(456)],
-        );
-        let after = Runtime {
-            function_table: HashMap::from([(
-                "foo".to_owned(),
-                (
-                    2,
-                    vec![Instruction::PushData(123), Instruction::PushData(456)],
-                ),
-            )]),
-            ..Runtime::new()
-        };
+    fn builtin_strrev_unterminated() {
+        let mut runtime = Runtime {
+            value_stack: [vec![Word::Data(97), Word::Data(98)], call("__strrev__")].concat(),
+            ..Runtime::new()
+        };
+
+        let before = runtime.clone();
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNTERMINATED);
+        assert_eq!(before, runtime);
+    }
+
+    #[test]
+    fn builtin_num_to_str() {
+        for (n, expected) in [(0, "0"), (42, "42"), (u64::from(u32::MAX), "4294967295")] {
+            let mut runtime = Runtime {
+                value_stack: [vec![Word::Data(n)], call("__num_to_str__")].concat(),
+                ..Runtime::new()
+            };
+            let after = Runtime {
+                value_stack: string(expected),
+                ..Runtime::new()
+            };
+
+            assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+            assert_eq!(after, runtime);
+        }
+    }
+
+    #[test]
+    fn builtin_num_to_str_function() {
+        let mut runtime = Runtime {
+            value_stack: [
+                vec![Word::Function("foo".to_owned())],
+                call("__num_to_str__"),
+            ]
+            .concat(),
+            ..Runtime::new()
+        };
+
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Wrong type. Expected a data word for __num_to_str__, but found the function 'foo'."
+        );
+        assert_eq!(before, runtime);
+    }
+
+    #[test]
+    fn builtin_str_to_num() {
+        for (s, expected) in [("0", 0), ("0042", 42), ("4294967295", u64::from(u32::MAX))] {
+            let mut runtime = Runtime {
+                value_stack: [vec![Word::Data(7)], string(s), call("__str_to_num__")].concat(),
+                ..Runtime::new()
+            };
+            let after = Runtime {
+                value_stack: vec![Word::Data(7), Word::Data(expected)],
+                ..Runtime::new()
+            };
+
+            assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+            assert_eq!(after, runtime);
+        }
+    }
+
+    #[test]
+    fn builtin_str_to_num_64_bits() {
+        let mut runtime = Runtime {
+            value_stack: [string("18446744073709551615"), call("__str_to_num__")].concat(),
+            ..Runtime::new().with_word_size(WordSize::Bits64)
+        };
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        assert_eq!(vec![Word::Data(u64::MAX)], runtime.value_stack);
+
+        let mut runtime = Runtime {
+            value_stack: [string("4294967296"), call("__str_to_num__")].concat(),
+            ..Runtime::new()
+        };
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_OVERFLOW);
+    }
+
+    #[test]
+    fn builtin_str_to_num_invalid() {
+        for (s, msg) in [
+            ("", ERR_NUMBER),
+            ("12a", ERR_NUMBER),
+            ("-1", ERR_NUMBER),
+            (" 1", ERR_NUMBER),
+            ("4294967296", ERR_OVERFLOW),
+            ("99999999999999999999", ERR_OVERFLOW),
+        ] {
+            let mut runtime = Runtime {
+                value_stack: [string(s), call("__str_to_num__")].concat(),
+                ..Runtime::new()
+            };
+            let before = runtime.clone();
 
-        assert_ok_and_eq!(runtime.run(instruction), false);
-        assert_eq!(after, runtime);
+            assert_err_with_msg!(runtime.run(Instruction::CallIf), msg);
+            assert_eq!(before, runtime);
+        }
     }
 
     #[test]
-    fn callif_true() {
+    fn builtin_str_to_num_unterminated() {
         let mut runtime = Runtime {
-            value_stack: vec![
-                Word::Function("bar".to_owned()),
-                Word::Data(789),
-                Word::Data(1),
-                Word::Function("foo".to_owned()),
-            ],
-            function_table: HashMap::from([(
-                "foo".to_owned(),
-                (
-                    0,
-                    vec![Instruction::PushData(123), Instruction::PushData(456)],
-                ),
-            )]),
+            value_stack: [vec![Word::Data(49)], call("__str_to_num__")].concat(),
             ..Runtime::new()
         };
-        let after = Runtime {
-            value_stack: vec![
-                Word::Function("bar".to_owned()),
-                Word::Data(789),
-                Word::Data(123),
-                Word::Data(456),
-            ],
-            ..runtime.clone()
-        };
 
-        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
-        assert_eq!(after, runtime);
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNTERMINATED);
     }
 
     #[test]
-    fn callif_false() {
+    fn builtin_assert_eq() {
         let mut runtime = Runtime {
-            value_stack: vec![Word::Data(0), Word::Function("foo".to_owned())],
-            function_table: HashMap::from([(
-                "foo".to_owned(),
-                (
-                    1,
-                    vec![Instruction::PushData(123), Instruction::PushData(456)],
-                ),
-            )]),
+            value_stack: [
+                vec![Word::Data(1), Word::Function("foo".to_owned())],
+                call("__assert_eq__"),
+                vec![Word::Data(4), Word::Data(4)],
+                call("__assert_eq__"),
+                vec![Word::Data(3), Word::Data(4)],
+                call("__assert_eq__"),
+            ]
+            .concat(),
             ..Runtime::new()
         };
-        let after = Runtime {
-            value_stack: vec![],
-            ..runtime.clone()
-        };
 
-        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
-        assert_eq!(after, runtime);
+        for _ in 0..3 {
+            assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        }
+        assert!(runtime.value_stack.is_empty());
+        assert_eq!(
+            vec![
+                "Assertion failed: 3 != 4.",
+                "Assertion failed: 1 != function foo."
+            ],
+            runtime.take_assertion_failures()
+        );
+        assert!(runtime.take_assertion_failures().is_empty());
     }
 
     #[test]
-    fn callif_undefined() {
+    fn builtin_assert_eq_almost_empty_stack() {
         let mut runtime = Runtime {
-            value_stack: vec![
-                Word::Data(1),
-                Word::Function("undefined".to_owned()),
-                Word::Data(0),
-                Word::Function("undefined".to_owned()),
-            ],
+            value_stack: [vec![Word::Data(3)], call("__assert_eq__")].concat(),
             ..Runtime::new()
         };
-        let mut expected = runtime.clone();
-
-        // No problem if the function isn't called
-        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
-        expected.value_stack.remove(3);
-        expected.value_stack.remove(2);
-        assert_eq!(expected, runtime);
 
-        // Error if the function is called
-        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
-        expected.value_stack.remove(1);
-        expected.value_stack.remove(0);
-        assert_eq!(expected, runtime);
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Stack underflow. __assert_eq__ needs 2 word(s), but the stack only has 1."
+        );
+        assert_eq!(before, runtime);
     }
 
     #[test]
-    fn nested_functions_success() {
-        let a: u32 = 0b10101010_11001100_11110000_11111111;
-        let b: u32 = 0;
+    fn builtin_assert() {
         let mut runtime = Runtime {
-            value_stack: vec![
-                Word::Data(b),
-                Word::Data(a),
-                Word::Data(1),
-                Word::Function("foo".to_owned()),
-            ],
-            function_table: HashMap::from([
-                (
-                    "foo".to_owned(),
-                    (
-                        2,
-                        vec![
-                            Instruction::PushArg(1),
-                            Instruction::PushArg(0),
-                            Instruction::PushData(1),
-                            Instruction::PushFunction("bar".to_owned()),
-                            Instruction::CallIf,
-                            Instruction::PushData(123),
-                        ],
-                    ),
-                ),
-                (
-                    "bar".to_owned(),
-                    (
-                        0,
-                        vec![
-                            Instruction::PushData(1),
-                            Instruction::PushFunction("__birl__".to_owned()),
-                            Instruction::CallIf,
-                        ],
-                    ),
-                ),
-            ]),
+            value_stack: [
+                vec![Word::Data(42)],
+                string("oops"),
+                vec![Word::Data(3)],
+                call("__assert__"),
+            ]
+            .concat(),
             ..Runtime::new()
         };
         let after = Runtime {
-            value_stack: vec![Word::Data((!a | b).rotate_left(1)), Word::Data(123)],
-            ..runtime.clone()
+            value_stack: vec![Word::Data(42)],
+            ..Runtime::new()
         };
 
         assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
@@ -559,314 +4308,508 @@ mod tests {
     }
 
     #[test]
-    fn undefined_arg_after_nested_custom_function() {
+    fn builtin_assert_fail() {
         let mut runtime = Runtime {
-            value_stack: vec![
-                Word::Data(3),
-                Word::Data(1),
-                Word::Function("bad".to_owned()),
-            ],
-            function_table: HashMap::from([
-                (
-                    "bad".to_owned(),
-                    (
-                        1,
-                        vec![
-                            Instruction::PushArg(0),
-                            Instruction::PushData(1),
-                            Instruction::PushFunction("empty".to_owned()),
-                            Instruction::CallIf,
-                            Instruction::PushArg(0),
-                        ],
-                    ),
-                ),
-                ("empty".to_owned(), (0, vec![])),
-            ]),
+            value_stack: [string("x is 😀"), vec![Word::Data(0)], call("__assert__")].concat(),
             ..Runtime::new()
         };
-        let after = Runtime {
-            value_stack: vec![Word::Data(3)],
-            ..runtime.clone()
-        };
 
-        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
-        assert_eq!(after, runtime);
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Assertion failed: x is 😀"
+        );
+        assert_eq!(before, runtime);
     }
 
     #[test]
-    fn undefined_arg_after_nested_builtin_function() {
-        let a: u32 = 0b11111111_11111111_11111111_11111111;
-        let b: u32 = 0b10101010_11001100_11110000_11111111;
+    fn builtin_assert_without_message() {
         let mut runtime = Runtime {
-            value_stack: vec![
-                Word::Data(b),
-                Word::Data(a),
-                Word::Data(1),
-                Word::Function("bad".to_owned()),
-            ],
-            function_table: HashMap::from([(
-                "bad".to_owned(),
-                (
-                    2,
-                    vec![
-                        Instruction::PushArg(1),
-                        Instruction::PushArg(0),
-                        Instruction::PushData(1),
-                        Instruction::PushFunction("__birl__".to_owned()),
-                        Instruction::CallIf,
-                        Instruction::PushArg(0),
-                    ],
-                ),
-            )]),
+            value_stack: [vec![Word::Data(1)], call("__assert__")].concat(),
             ..Runtime::new()
         };
-        let after = Runtime {
-            value_stack: vec![Word::Data((!a | b).rotate_left(1))],
-            ..runtime.clone()
-        };
 
-        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
-        assert_eq!(after, runtime);
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Stack underflow. __assert__ needs 2 word(s), but the stack only has 1."
+        );
+        assert_eq!(before, runtime);
     }
 
     #[test]
-    fn undefined_arg_in_nested_function() {
+    fn builtin_panic() {
         let mut runtime = Runtime {
-            value_stack: vec![
-                Word::Data(4),
-                Word::Data(1),
-                Word::Function("foo".to_owned()),
-            ],
-            function_table: HashMap::from([
-                (
-                    "foo".to_owned(),
-                    (
-                        1,
-                        vec![
-                            Instruction::PushData(1),
-                            Instruction::PushFunction("bar".to_owned()),
-                            Instruction::CallIf,
-                        ],
-                    ),
-                ),
-                ("bar".to_owned(), (0, vec![Instruction::PushArg(0)])),
-            ]),
+            value_stack: [vec![Word::Data(42)], string("bad"), call("__panic__")].concat(),
             ..Runtime::new()
         };
-        let after = Runtime {
-            value_stack: vec![],
-            ..runtime.clone()
-        };
 
-        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
-        // Ignore the args array: it doesn't matter whether or not it's cleared right away, as long as args don't leak
-        // to the next function call (there should be a test for that, like `undefined_arg_after_error`).
-        runtime.args_array = vec![];
-        assert_eq!(after, runtime);
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Panicked: bad"
+        );
+        assert_eq!(before, runtime);
     }
 
     #[test]
-    fn undefined_arg_in_successive_functions() {
+    fn builtin_panic_invalid_message() {
         let mut runtime = Runtime {
-            value_stack: vec![
-                Word::Data(123),
-                Word::Data(1),
-                Word::Function("bar".to_owned()),
-                Word::Data(456),
-                Word::Data(1),
-                Word::Function("foo".to_owned()),
-            ],
-            function_table: HashMap::from([
-                ("foo".to_owned(), (1, vec![])),
-                ("bar".to_owned(), (0, vec![Instruction::PushArg(0)])),
-            ]),
+            value_stack: [vec![Word::Data(0), Word::Data(0xD800)], call("__panic__")].concat(),
             ..Runtime::new()
         };
-        let after_foo = Runtime {
-            value_stack: vec![
-                Word::Data(123),
-                Word::Data(1),
-                Word::Function("bar".to_owned()),
-            ],
-            ..runtime.clone()
-        };
-        let after_bar = Runtime {
-            value_stack: vec![Word::Data(123)],
-            ..runtime.clone()
-        };
 
-        // First call (to foo): no problem
-        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
-        // Ignore the args array: it doesn't matter whether or not it's cleared right away, as long as args don't leak
-        // to the next function call (there should be a test for that, like `undefined_arg_after_error`).
-        runtime.args_array = vec![];
-        assert_eq!(after_foo, runtime);
+        let before = runtime.clone();
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UTF32);
+        assert_eq!(before, runtime);
+    }
 
-        // Second call (to bar): args from foo should be cleared, so $1 is invalid
-        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
-        // Ignore the args array: it doesn't matter whether or not it's cleared right away, as long as args don't leak
-        // to the next function call (there should be a test for that, like `undefined_arg_after_error`).
-        runtime.args_array = vec![];
-        assert_eq!(after_bar, runtime);
+    #[test]
+    fn builtin_tcp() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0; 6];
+            stream.read_exact(&mut buffer).unwrap();
+            stream.write_all(&buffer).unwrap();
+        });
+
+        let mut runtime = Runtime::new();
+        let mut run = |stack: Vec<Word>| {
+            runtime.value_stack = stack;
+            runtime.run(Instruction::CallIf)?;
+            Ok::<_, Error>(runtime.value_stack.clone())
+        };
+        assert_eq!(
+            vec![Word::Data(1)],
+            run([string(&address), call("__tcp_connect__")].concat()).unwrap()
+        );
+        assert_eq!(
+            Vec::<Word>::new(),
+            run([string("hello\n"), vec![Word::Data(1)], call("__tcp_send__")].concat()).unwrap()
+        );
+        assert_eq!(
+            string("hello\n"),
+            run([vec![Word::Data(1)], call("__tcp_recv__")].concat()).unwrap()
+        );
+        // The server has closed the connection
+        assert_eq!(
+            string(""),
+            run([vec![Word::Data(1)], call("__tcp_recv__")].concat()).unwrap()
+        );
+        assert_eq!(
+            Vec::<Word>::new(),
+            run([vec![Word::Data(1)], call("__tcp_close__")].concat()).unwrap()
+        );
+        assert_err_with_msg!(
+            run([vec![Word::Data(1)], call("__tcp_recv__")].concat()),
+            "Runtime error: Invalid connection handle 1."
+        );
+        server.join().unwrap();
     }
 
-    /// Checks that arguments don't leak from one function call to the next after an error.
     #[test]
-    fn undefined_arg_after_error() {
+    fn builtin_tcp_sandbox() {
         let mut runtime = Runtime {
-            value_stack: vec![
-                Word::Data(42),
-                Word::Data(1),
-                Word::Function("bar".to_owned()),
-                Word::Data(123),
-                Word::Data(1),
-                Word::Function("foo".to_owned()),
-            ],
-            function_table: HashMap::from([
-                ("foo".to_owned(), (1, vec![Instruction::PushArg(999)])),
-                ("bar".to_owned(), (0, vec![Instruction::PushArg(0)])),
-            ]),
-            ..Runtime::new()
+            value_stack: [string("127.0.0.1:1"), call("__tcp_connect__")].concat(),
+            ..Runtime::new().with_sandbox(true)
         };
-        let after_foo = Runtime {
-            value_stack: vec![
-                Word::Data(42),
-                Word::Data(1),
-                Word::Function("bar".to_owned()),
-            ],
-            ..runtime.clone()
+
+        let before = runtime.clone();
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_SANDBOX);
+        assert_eq!(before, runtime);
+    }
+
+    #[test]
+    fn builtin_terminal() {
+        let terminal = Rc::new(FakeTerminal::default());
+        terminal.keys.borrow_mut().push_back('q' as u64);
+        let mut runtime = Runtime::new().with_terminal(terminal.clone());
+        let mut run = |stack: Vec<Word>| {
+            runtime.value_stack = stack;
+            runtime.run(Instruction::CallIf).unwrap();
+            runtime.value_stack.clone()
         };
-        let after_bar = Runtime {
-            value_stack: vec![Word::Data(42)],
-            ..runtime.clone()
+
+        assert!(run([vec![Word::Data(1)], call("__raw_mode__")].concat()).is_empty());
+        assert!(run(call("__clear__")).is_empty());
+        assert!(
+            run([vec![Word::Data(5), Word::Data(2)], call("__cursor_to__")].concat()).is_empty()
+        );
+        assert_eq!(
+            vec![Word::Data(24), Word::Data(80)],
+            run(call("__term_size__"))
+        );
+        assert_eq!(vec![Word::Data('q' as u64)], run(call("__read_key__")));
+        assert_eq!(
+            vec!["raw_mode true", "clear", "cursor_to 2 5"],
+            *terminal.log.borrow()
+        );
+    }
+
+    #[test]
+    fn builtin_poll_key() {
+        let terminal = Rc::new(FakeTerminal::default());
+        terminal.keys.borrow_mut().push_back(KEY_UP);
+        let mut runtime = Runtime {
+            value_stack: [call("__poll_key__"), call("__poll_key__")].concat(),
+            ..Runtime::new().with_terminal(terminal)
         };
 
-        // foo should fail because it's accessing an an argument that doesn't exist
-        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
-        // Ignore the args array: it doesn't matter whether or not it's cleared right away, as long as args don't leak
-        // to the next function call.
-        runtime.args_array = vec![];
-        assert_eq!(after_foo, runtime);
+        runtime.run(Instruction::CallIf).unwrap();
+        assert_eq!(
+            [
+                call("__poll_key__"),
+                vec![Word::Data(KEY_UP), Word::Data(1)]
+            ]
+            .concat(),
+            runtime.value_stack
+        );
 
-        // bar should fail because there's no argument 0 (even though there was an argument 0 in foo)
-        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
-        // Ignore the args array: it doesn't matter whether or not it's cleared right away, as long as args don't leak
-        // to the next function call.
-        runtime.args_array = vec![];
-        assert_eq!(after_bar, runtime);
+        runtime.value_stack.truncate(2);
+        runtime.run(Instruction::CallIf).unwrap();
+        assert_eq!(vec![Word::Data(0)], runtime.value_stack);
     }
 
     #[test]
-    fn callif_exit() {
+    fn builtin_cursor_to_invalid() {
         let mut runtime = Runtime {
-            value_stack: vec![Word::Data(1), Word::Function("exit".to_owned())],
-            function_table: HashMap::from([(
-                "exit".to_owned(),
-                (
-                    0,
-                    vec![
-                        Instruction::PushData(123),
-                        Instruction::Exit,
-                        Instruction::PushData(456),
-                    ],
-                ),
-            )]),
-            ..Runtime::new()
-        };
-        let after = Runtime {
-            value_stack: vec![Word::Data(123)],
-            instruction_stack: vec![Instruction::PushData(456)],
-            ..runtime.clone()
+            value_stack: [
+                vec![Word::Data(1), Word::Data(1 << 16)],
+                call("__cursor_to__"),
+            ]
+            .concat(),
+            ..Runtime::new().with_terminal(Rc::new(FakeTerminal::default()))
         };
 
-        assert_ok_and_eq!(runtime.run(Instruction::CallIf), true);
-        assert_eq!(after, runtime);
+        let before = runtime.clone();
+        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_OVERFLOW);
+        assert_eq!(before, runtime);
     }
 
     #[test]
-    fn callif_error() {
+    fn builtin_cursor_to_function() {
         let mut runtime = Runtime {
-            value_stack: vec![Word::Data(1), Word::Function("evil".to_owned())],
-            function_table: HashMap::from([(
-                "evil".to_owned(),
-                (
-                    0,
-                    vec![
-                        Instruction::PushData(123),
-                        Instruction::PushArg(1),
-                        Instruction::PushData(456),
-                    ],
-                ),
-            )]),
-            ..Runtime::new()
-        };
-        let after = Runtime {
-            value_stack: vec![Word::Data(123)],
-            instruction_stack: vec![],
-            ..runtime.clone()
+            value_stack: [
+                vec![Word::Function("row".to_owned()), Word::Data(1)],
+                call("__cursor_to__"),
+            ]
+            .concat(),
+            ..Runtime::new().with_terminal(Rc::new(FakeTerminal::default()))
         };
 
-        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDEFINED);
-        assert_eq!(after, runtime);
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Wrong type. Expected a data word for __cursor_to__, but found the function 'row'."
+        );
+        assert_eq!(before, runtime);
     }
 
     #[test]
-    fn callif_empty_stack() {
+    fn run_budgeted() {
         let mut runtime = Runtime::new();
+        runtime
+            .run(Instruction::Define(
+                "f".to_owned(),
+                0,
+                vec![Instruction::PushData(1), Instruction::PushData(2)],
+                Attributes::default(),
+            ))
+            .unwrap();
 
-        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDERFLOW);
-        assert_eq!(Runtime::new(), runtime);
+        runtime.push_instruction(Instruction::CallIf);
+        runtime.value_stack = call("f");
+        assert_eq!(Status::Pending, runtime.run_budgeted(2).unwrap());
+        assert_eq!(vec![Word::Data(1)], runtime.value_stack);
+        assert_eq!(Status::Done, runtime.run_budgeted(1).unwrap());
+        assert_eq!(vec![Word::Data(1), Word::Data(2)], runtime.value_stack);
+        assert_eq!(Status::Done, runtime.run_budgeted(1).unwrap());
+
+        runtime.push_instruction(Instruction::Exit);
+        assert_eq!(Status::Exit, runtime.run_budgeted(1).unwrap());
+        assert_eq!(Status::Done, runtime.run_budgeted(0).unwrap());
+
+        // The rest of a function that exited never runs
+        runtime
+            .run(Instruction::Define(
+                "g".to_owned(),
+                0,
+                vec![Instruction::Exit, Instruction::PushData(5)],
+                Attributes::default(),
+            ))
+            .unwrap();
+        runtime.value_stack = call("g");
+        runtime.push_instruction(Instruction::CallIf);
+        assert_eq!(Status::Exit, runtime.run_budgeted(10).unwrap());
+        assert_eq!(Status::Done, runtime.run_budgeted(10).unwrap());
+        assert_eq!(Vec::<Word>::new(), runtime.value_stack);
     }
 
     #[test]
-    fn callif_almost_empty_stack() {
+    fn builtin_spawn() {
+        let mut runtime = Runtime::new();
+        let define = |f: &str, body: &str| {
+            Instruction::Define(
+                f.to_owned(),
+                0,
+                vec![
+                    Instruction::PushData(1),
+                    Instruction::PushFunction(body.to_owned()),
+                    Instruction::CallIf,
+                    Instruction::PushData(1),
+                    Instruction::PushFunction("__send__".to_owned()),
+                    Instruction::CallIf,
+                ],
+                Attributes::default(),
+            )
+        };
+        // Sends the first word it receives back to the sender
+        runtime.run(define("echo", "__recv__")).unwrap();
+        // Only has its parent's ID on the stack, so __birl__ fails
+        runtime.run(define("fail", "__birl__")).unwrap();
+        let mut run = |stack: Vec<Word>| {
+            runtime.value_stack = stack;
+            runtime.run(Instruction::CallIf)?;
+            Ok::<_, Error>(runtime.value_stack.clone())
+        };
+
+        let spawn = |f: &str| [vec![Word::Function(f.to_owned())], call("__spawn__")].concat();
+        assert_eq!(vec![Word::Data(1)], run(spawn("echo")).unwrap());
+        assert_eq!(vec![Word::Data(2)], run(spawn("fail")).unwrap());
+        assert_err_with_msg!(run(spawn("__print__")), ERR_UNDEFINED);
+        assert!(
+            run([vec![Word::Data(42), Word::Data(1)], call("__send__")].concat())
+                .unwrap()
+                .is_empty()
+        );
+        assert_err_with_msg!(
+            run([vec![Word::Data(42), Word::Data(9)], call("__send__")].concat()),
+            "Runtime error: Process 9 does not exist."
+        );
+        assert_eq!(
+            vec![Word::Data(42), Word::Data(1)],
+            run(call("__recv__")).unwrap()
+        );
+
+        let errors = runtime
+            .join_processes()
+            .iter()
+            .map(|e| format!("{e:#}"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![format!(
+                "Process 2 stopped with an error.: {ERR_UNDERFLOW} __birl__ needs 2 word(s), but the stack only has 1."
+            )],
+            errors
+        );
+
+        // A process would print to stdout instead of the captured output
         let mut runtime = Runtime {
-            value_stack: vec![Word::Function("foo".to_owned())],
-            ..Runtime::new()
+            value_stack: spawn("echo"),
+            ..runtime.clone().with_io(Output::default(), std::io::empty())
         };
+        let before = runtime.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: __spawn__ cannot be used when the output is captured."
+        );
+        assert_eq!(before, runtime);
+    }
 
-        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDERFLOW);
-        assert_eq!(Runtime::new(), runtime);
+    /// Output that can be inspected after it has been given to a runtime.
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
     }
 
     #[test]
-    fn callif_data_first() {
-        let mut runtime = Runtime {
-            value_stack: vec![Word::Function("empty".to_owned()), Word::Data(1)],
-            ..Runtime::new()
+    fn builtin_print_policy() {
+        let output = Output::default();
+        let mut runtime = Runtime::new().with_io(output.clone(), std::io::empty());
+        let mut print = |policy: u64| {
+            runtime.value_stack = [
+                vec![Word::Data(0), Word::Data('b' as u64), Word::Data(0xD800)],
+                vec![Word::Data('a' as u64)],
+                call("__print__"),
+                vec![Word::Data(policy)],
+                call("__set_print_policy__"),
+            ]
+            .concat();
+            runtime.run(Instruction::CallIf)?;
+            runtime.run(Instruction::CallIf)?;
+            Ok::<_, Error>(String::from_utf8(output.0.take()).unwrap())
         };
 
-        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_TYPE);
-        assert_eq!(Runtime::new(), runtime);
+        assert_eq!("ab", print(1).unwrap());
+        assert_eq!("a\u{FFFD}b", print(2).unwrap());
+        assert_err_with_msg!(print(0), ERR_UTF32);
+        assert_err_with_msg!(
+            print(3),
+            "Runtime error: Invalid print policy 3. Expected 0 (error), 1 (skip), or 2 (replace)."
+        );
     }
 
     #[test]
-    fn callif_function_second() {
-        let mut runtime = Runtime {
-            value_stack: vec![
-                Word::Function("empty".to_owned()),
-                Word::Function("empty".to_owned()),
-            ],
-            ..Runtime::new()
+    fn builtin_bytes() {
+        let output = Output::default();
+        let mut runtime = Runtime::new().with_io(output.clone(), std::io::Cursor::new(vec![0xC3]));
+        let mut run = |stack: Vec<Word>| {
+            runtime.value_stack = stack;
+            runtime.run(Instruction::CallIf).unwrap();
+            runtime.value_stack.clone()
         };
 
-        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_TYPE);
-        assert_eq!(Runtime::new(), runtime);
+        assert!(run([vec![Word::Data(0x1FF)], call("__write_byte__")].concat()).is_empty());
+        assert_eq!(vec![0xFF], *output.0.borrow());
+        assert_eq!(vec![Word::Data(0xC3)], run(call("__read_byte__")));
+        assert_eq!(vec![Word::Data(256)], run(call("__read_byte__")));
     }
 
     #[test]
-    fn builtin_birl() {
-        let mut runtime = Runtime {
-            value_stack: vec![
-                Word::Data(0b100010),
-                Word::Data(0b101000),
-                Word::Data(1),
-                Word::Function("__birl__".to_owned()),
+    fn record_and_replay() {
+        let program = |runtime: &mut Runtime| {
+            runtime.run(Instruction::PushRandom).unwrap();
+            runtime.value_stack.extend(call("__input__"));
+            runtime.run(Instruction::CallIf).unwrap();
+            runtime.value_stack.extend(call("__read_byte__"));
+            runtime.run(Instruction::CallIf).unwrap();
+        };
+        let input = || std::io::Cursor::new(b"ab\n".to_vec());
+        let mut recorder = Runtime::new()
+            .with_io(Output::default(), input())
+            .with_replay(Replay::record());
+        program(&mut recorder);
+        let recording = recorder.take_recording();
+        assert_eq!(
+            vec![
+                Event::Random(match recorder.value_stack[0] {
+                    Word::Data(n) => n,
+                    _ => unreachable!(),
+                }),
+                Event::Line("ab\n".to_owned()),
+                Event::Byte(None),
             ],
+            recording
+        );
+        assert!(recorder.take_recording().is_empty());
+
+        // The replay does not read the input at all
+        let mut replayer = Runtime::new()
+            .with_io(Output::default(), std::io::empty())
+            .with_replay(Replay::Play(recording.into()));
+        program(&mut replayer);
+        assert_eq!(recorder.value_stack, replayer.value_stack);
+
+        let mut replayer = Runtime::new().with_replay(Replay::Play(vec![Event::Key(1)].into()));
+        assert_err_with_msg!(
+            replayer.run(Instruction::PushRandom),
+            "Runtime error: The program asked for a random word, but the replay has 'key 1' next."
+        );
+        assert_err_with_msg!(
+            replayer.run(Instruction::PushRandom),
+            "Runtime error: The program asked for a random word, but the replay has ended."
+        );
+    }
+
+    #[test]
+    fn host_function() {
+        let mut runtime = Runtime::new();
+        runtime
+            .register_builtin("__host_sub__", 2, |stack| {
+                match (stack.peek(0), stack.peek(1)) {
+                    (Some(&Word::Data(a)), Some(&Word::Data(b))) => {
+                        stack.pop();
+                        stack.pop();
+                        stack.push(Word::Data(b.wrapping_sub(a)))
+                    }
+                    _ => Err(anyhow!("Expected two numbers.")),
+                }
+            })
+            .unwrap();
+        assert!(runtime.is_defined("__host_sub__"));
+        // Host functions can see the whole stack, not only their arguments
+        runtime
+            .register_builtin("__host_depth__", 0, |stack| {
+                stack.push(Word::Data(stack.len() as u64))
+            })
+            .unwrap();
+        runtime.value_stack = [vec![Word::Data(7), Word::Data(8)], call("__host_depth__")].concat();
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        assert_eq!(
+            vec![Word::Data(7), Word::Data(8), Word::Data(2)],
+            runtime.value_stack
+        );
+
+        runtime.value_stack = [
+            vec![Word::Data(7), Word::Data(10), Word::Data(3)],
+            call("__host_sub__"),
+        ]
+        .concat();
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        assert_eq!(vec![Word::Data(7), Word::Data(7)], runtime.value_stack);
+
+        runtime.value_stack = [
+            vec![Word::Data(1), Word::Function("f".to_owned())],
+            call("__host_sub__"),
+        ]
+        .concat();
+        let before = runtime.value_stack.clone();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Host function '__host_sub__' failed: Expected two numbers."
+        );
+        assert_eq!(before, runtime.value_stack);
+
+        runtime.value_stack = [vec![Word::Data(1)], call("__host_sub__")].concat();
+        assert_err_with_msg!(
+            runtime.run(Instruction::CallIf),
+            "Runtime error: Stack underflow. __host_sub__ needs 2 word(s), but the stack only has 1."
+        );
+    }
+
+    #[test]
+    fn register_invalid_host_function() {
+        let mut runtime = Runtime::new();
+        for name in [
+            "__print__",
+            "__host__",
+            "__host_a b__",
+            "host_f",
+            "__host_f",
+        ] {
+            assert_err_with_msg!(
+                runtime.register_builtin(name, 0, |_| Ok(())),
+                format!("Invalid host function name '{name}'. Host functions must be named '__host_<name>__'.")
+            );
+        }
+
+        runtime
+            .register_builtin("__host_f__", 0, |_| Ok(()))
+            .unwrap();
+        assert_err_with_msg!(
+            runtime.register_builtin("__host_f__", 1, |_| Ok(())),
+            "Host function '__host_f__' is already registered."
+        );
+    }
+
+    #[test]
+    fn builtin_stack_dump() {
+        let mut runtime = Runtime {
+            value_stack: [vec![Word::Data(42)], call("__stack_dump__")].concat(),
             ..Runtime::new()
         };
         let after = Runtime {
-            value_stack: vec![Word::Data(0b11111111_11111111_11111111_11101111)],
+            value_stack: vec![Word::Data(42)],
             ..Runtime::new()
         };
 
@@ -875,61 +4818,239 @@ mod tests {
     }
 
     #[test]
-    fn builtin_birl_empty_stack() {
+    fn builtin_gc_stats() {
         let mut runtime = Runtime {
-            value_stack: vec![Word::Data(1), Word::Function("__birl__".to_owned())],
+            value_stack: [vec![Word::Data(42)], call("__gc_stats__")].concat(),
+            function_table: HashMap::from([("f".to_owned(), (0, vec![]))]),
             ..Runtime::new()
         };
 
-        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDERFLOW);
-        assert_eq!(Runtime::new(), runtime);
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), false);
+        let bytes = match runtime.value_stack.pop() {
+            Some(Word::Data(n)) => n,
+            w => panic!("Expected the number of bytes but got {w:?}."),
+        };
+        assert!(bytes > 0);
+        assert_eq!(
+            vec![Word::Data(42), Word::Data(1), Word::Data(0), Word::Data(1)],
+            runtime.value_stack
+        );
     }
 
     #[test]
-    fn builtin_birl_almost_empty_stack() {
-        let mut runtime = Runtime {
+    fn memory_stats() {
+        let mut runtime = Runtime::new();
+        let empty = runtime.memory_stats();
+        assert_eq!(
+            MemoryStats {
+                value_stack: 0,
+                instruction_stack: 0,
+                functions: 0,
+                bytes: empty.bytes,
+            },
+            empty
+        );
+
+        runtime.value_stack = vec![Word::Function("a long function name".to_owned()); 100];
+        let full = runtime.memory_stats();
+        assert_eq!(100, full.value_stack);
+        assert!(full.bytes >= empty.bytes + 100 * "a long function name".len());
+    }
+
+    #[test]
+    fn stack_to_debug_string() {
+        let runtime = Runtime {
             value_stack: vec![
-                Word::Function("foo".to_owned()),
-                Word::Data(1),
-                Word::Function("__birl__".to_owned()),
+                Word::Data(0),
+                Word::Data(10),
+                Word::Data(42),
+                Word::Function("f".to_owned()),
+                Word::Data(0xFFFFFFFF),
             ],
             ..Runtime::new()
         };
 
-        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_UNDERFLOW);
-        assert_eq!(Runtime::new(), runtime);
+        assert_eq!(
+            concat!(
+                "0 | 0xFFFFFFFF 4294967295  <-- top\n",
+                "1 | function f\n",
+                "2 | 0x0000002A         42 '*'\n",
+                "3 | 0x0000000A         10\n",
+                "4 | 0x00000000          0",
+            ),
+            runtime.stack_to_debug_string(10, false)
+        );
+        assert_eq!(
+            concat!(
+                "0 | 0xFFFFFFFF 4294967295  <-- top\n",
+                "1 | function f\n",
+                "... and 3 more",
+            ),
+            runtime.stack_to_debug_string(2, false)
+        );
+        assert_eq!(
+            "(5 word(s) not shown)",
+            runtime.stack_to_debug_string(0, false)
+        );
+        assert_eq!(
+            "(empty stack)",
+            Runtime::new().stack_to_debug_string(10, false)
+        );
     }
 
     #[test]
-    fn builtin_birl_function_first() {
-        let mut runtime = Runtime {
-            value_stack: vec![
-                Word::Data(42),
-                Word::Function("foo".to_owned()),
-                Word::Data(1),
-                Word::Function("__birl__".to_owned()),
+    fn pending_to_debug_string() {
+        let runtime = Runtime {
+            instruction_stack: vec![
+                Frame::Loop {
+                    f: "g".to_owned(),
+                    index: 2,
+                    count: 5,
+                },
+                Frame::Instruction(Instruction::Define(
+                    "f".to_owned(),
+                    1,
+                    vec![Instruction::PushArg(0)],
+                    Attributes::default(),
+                )),
+                Frame::Instruction(Instruction::CallIf),
+                Frame::Instruction(Instruction::PushFunction("f".to_owned())),
             ],
+            args_array: vec![Word::Data(3), Word::Function("f".to_owned())],
             ..Runtime::new()
         };
 
-        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_TYPE);
-        assert_eq!(Runtime::new(), runtime);
+        assert_eq!(
+            concat!(
+                "Pending instructions:\n",
+                "    PUSH f\n",
+                "    CALLIF\n",
+                "    DEFN f (1) { ... }\n",
+                "    __call_n_times__ with g (iterations 2 to 4 left)\n",
+                "Arguments: $0 = 3, $1 = function f",
+            ),
+            runtime.pending_to_debug_string(10)
+        );
+        assert_eq!(
+            concat!(
+                "Pending instructions:\n",
+                "    PUSH f\n",
+                "    ... and 3 more\n",
+                "Arguments: $0 = 3, $1 = function f",
+            ),
+            runtime.pending_to_debug_string(1)
+        );
+        assert_eq!(
+            "Pending instructions:\n    (none)\nArguments: (none)",
+            Runtime::new().pending_to_debug_string(10)
+        );
     }
 
     #[test]
-    fn builtin_birl_function_second() {
+    fn step() {
         let mut runtime = Runtime {
-            value_stack: vec![
-                Word::Function("foo".to_owned()),
-                Word::Data(42),
-                Word::Data(1),
-                Word::Function("__birl__".to_owned()),
-            ],
+            function_table: HashMap::from([("f".to_owned(), (0, vec![Instruction::Exit]))]),
             ..Runtime::new()
         };
 
-        assert_err_with_msg!(runtime.run(Instruction::CallIf), ERR_TYPE);
-        assert_eq!(Runtime::new(), runtime);
+        runtime.push_instruction(Instruction::CallIf);
+        runtime.push_instruction(Instruction::PushFunction("f".to_owned()));
+        runtime.push_instruction(Instruction::PushData(1));
+        runtime.push_instruction(Instruction::CallIf);
+        runtime.push_instruction(Instruction::PushFunction("f".to_owned()));
+        runtime.push_instruction(Instruction::PushData(0));
+        assert_ok_and_eq!(runtime.step(), Step::Ran { called: false });
+        assert_ok_and_eq!(runtime.step(), Step::Ran { called: false });
+        assert_ok_and_eq!(runtime.step(), Step::Ran { called: false });
+        assert_ok_and_eq!(runtime.step(), Step::Ran { called: false });
+        assert_ok_and_eq!(runtime.step(), Step::Ran { called: false });
+        assert_ok_and_eq!(runtime.step(), Step::Ran { called: true });
+        assert_ok_and_eq!(runtime.step(), Step::Exit);
+        assert_ok_and_eq!(runtime.step(), Step::Done);
+    }
+
+    #[test]
+    fn run_after_exit_in_function() {
+        let mut runtime = Runtime::new().with_rollback(true);
+        runtime
+            .run(Instruction::Define(
+                "f".to_owned(),
+                0,
+                vec![Instruction::Exit, Instruction::PushData(5)],
+                Attributes::default(),
+            ))
+            .unwrap();
+        runtime.value_stack = call("f");
+        assert_ok_and_eq!(runtime.run(Instruction::CallIf), true);
+
+        assert_ok_and_eq!(runtime.run(Instruction::PushData(9)), false);
+        assert_eq!(vec![Word::Data(9)], runtime.value_stack);
+        assert!(runtime.call_stack.is_empty());
+    }
+
+    #[test]
+    fn next_call_and_definition() {
+        let mut runtime = Runtime::new();
+
+        runtime.push_instruction(Instruction::CallIf);
+        runtime.push_instruction(Instruction::PushFunction("f".to_owned()));
+        runtime.push_instruction(Instruction::PushData(1));
+        runtime.push_instruction(Instruction::Define(
+            "f".to_owned(),
+            0,
+            vec![],
+            Attributes::default(),
+        ));
+        assert_eq!(Some("f"), runtime.next_definition());
+        assert_eq!(None, runtime.next_call());
+        for _ in 0..3 {
+            runtime.step().unwrap();
+        }
+        assert_eq!(None, runtime.next_definition());
+        assert_eq!(Some("f"), runtime.next_call());
+        runtime.value_stack[0] = Word::Data(0);
+        assert_eq!(None, runtime.next_call());
+    }
+
+    #[test]
+    fn step_back() {
+        let source = "
+            DEFN f (1) { PUSH $0 PUSH $0 PUSH 2 PUSH __birl__ CALLIF PUSH 7 }
+            PUSH 3 PUSH 1 PUSH f CALLIF
+            DEFN f (0) { PUSH 1 PUSH 1 PUSH __set_print_policy__ CALLIF }
+            PUSH 2 PUSH f PUSH 1 PUSH __call_n_times__ CALLIF
+            PUSH 0 PUSH f CALLIF
+        ";
+        let mut parser = crate::parser::Parser::new(crate::lexer::Lexer::new(
+            crate::reader::FileReader::from_source(source),
+        ));
+        let mut runtime = Runtime::new().with_journal(1000);
+        let state = |runtime: &Runtime| {
+            let calls = runtime.call_stack().map(str::to_owned).collect::<Vec<_>>();
+            (runtime.clone(), calls, runtime.print_policy)
+        };
+        let mut states = vec![state(&runtime)];
+        while let Some(instruction) = parser.next_instruction().unwrap() {
+            runtime.push_instruction(instruction);
+            states.push(state(&runtime));
+            while runtime.step().unwrap() != Step::Done {
+                states.push(state(&runtime));
+            }
+        }
+        assert_eq!(Some(PrintPolicy::Skip), states.last().map(|s| s.2));
+
+        states.pop();
+        while let Some(undone) = runtime.step_back() {
+            let (expected, calls, print_policy) = states.pop().unwrap();
+            assert_eq!(expected, runtime);
+            assert_eq!(calls, runtime.call_stack().collect::<Vec<_>>());
+            assert_eq!(print_policy, runtime.print_policy);
+            assert_eq!(
+                matches!(undone, Undone::Push(_)),
+                runtime.instruction_stack.is_empty()
+            );
+        }
+        assert!(states.is_empty());
     }
 
     #[test]
@@ -0,0 +1,131 @@
+//! Serving the REPL to remote clients (`plates serve`).
+//!
+//! Every connection gets its own REPL process, which reads from the connection and writes its output (including
+//! errors) back to it. Sessions cannot see each other's functions or stack, and a session that hangs or exits does not
+//! affect the others.
+
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    process::{Command, Stdio},
+    thread,
+};
+
+use anyhow::{anyhow, Context, Error};
+
+use crate::{print_error, print_info, print_verbose};
+
+/// A connection to a client.
+pub trait Connection: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+
+    /// Closes both directions of the connection.
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        std::os::unix::net::UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        std::os::unix::net::UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+/// Listens on a TCP address and serves each connection with the command returned by `session`.
+pub fn serve_tcp(address: impl ToSocketAddrs, session: impl Fn() -> Command) -> Result<(), Error> {
+    let listener = TcpListener::bind(address).context("Environment error: Failed to listen.")?;
+    let address = listener
+        .local_addr()
+        .context("Environment error: Failed to listen.")?;
+    print_info(&format!("Listening on {address}."));
+    serve(listener.incoming(), session);
+    Ok(())
+}
+
+/// Listens on a Unix socket and serves each connection with the command returned by `session`. The socket must not
+/// exist yet.
+#[cfg(unix)]
+pub fn serve_unix(path: &std::path::Path, session: impl Fn() -> Command) -> Result<(), Error> {
+    let listener = std::os::unix::net::UnixListener::bind(path)
+        .context("Environment error: Failed to listen.")?;
+    print_info(&format!("Listening on {}.", path.display()));
+    serve(listener.incoming(), session);
+    Ok(())
+}
+
+/// Unix sockets are only available on Unix.
+#[cfg(not(unix))]
+pub fn serve_unix(_path: &std::path::Path, _session: impl Fn() -> Command) -> Result<(), Error> {
+    Err(anyhow!(
+        "Environment error: Unix sockets are not supported on this platform."
+    ))
+}
+
+/// Serves every connection in its own thread. Connections that fail to open are reported and skipped.
+fn serve<C: Connection>(
+    connections: impl Iterator<Item = io::Result<C>>,
+    session: impl Fn() -> Command,
+) {
+    for (id, connection) in (1..).zip(connections) {
+        let connection = match connection {
+            Err(e) => {
+                print_error(
+                    &anyhow!(e).context("Environment error: Failed to accept a connection."),
+                );
+                continue;
+            }
+            Ok(connection) => connection,
+        };
+        let command = session();
+        thread::spawn(move || {
+            print_verbose(1, &format!("Session {id} started."));
+            match run_session(connection, command) {
+                Err(e) => print_error(&e.context(format!("Session {id} failed."))),
+                Ok(()) => print_verbose(1, &format!("Session {id} ended.")),
+            }
+        });
+    }
+}
+
+/// Runs `command` with the connection as its stdin and both its stdout and stderr until either side stops, then closes
+/// the connection.
+fn run_session<C: Connection>(mut connection: C, mut command: Command) -> Result<(), Error> {
+    // stdout and stderr share a pipe so that errors reach the client in the right place
+    let (mut output, output_writer) = io::pipe()?;
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(output_writer.try_clone()?)
+        .stderr(output_writer)
+        .spawn()
+        .context("Environment error: Failed to start the REPL.")?;
+    // The command holds on to the write end of the pipe, which would keep the output from ever ending
+    drop(command);
+
+    let mut stdin = child.stdin.take().expect("stdin should be piped");
+    let mut input = connection.try_clone()?;
+    let forward = thread::spawn(move || io::copy(&mut input, &mut stdin));
+    let copied = io::copy(&mut output, &mut connection);
+    if copied.is_err() {
+        // The client is gone, so nobody would see what the REPL does next
+        let _ = child.kill();
+    }
+    child.wait()?;
+    // Stops forwarding input to the REPL, which has already exited
+    let _ = connection.shutdown();
+    let _ = forward.join();
+    copied?;
+    Ok(())
+}
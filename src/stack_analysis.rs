@@ -0,0 +1,562 @@
+//! Finds calls that are certain to underflow the stack, functions that are certain to be left on the stack when the
+//! program ends (which were probably meant to be called), and functions that do not match their stack-effect
+//! annotations.
+//!
+//! The top level and every test start with an empty stack, so they are run on an abstract stack that keeps track of
+//! the type of each word (and its value, when it is a literal). The analysis stops at the first thing whose outcome
+//! cannot be known ahead of time, such as a call whose condition is not a literal or a built-in function that pushes a
+//! variable number of words. Anything reported before that point will definitely happen when the program runs.
+
+use std::collections::HashMap;
+
+use crate::{
+    lint::{Diagnostics, Lint},
+    optimizer,
+    parser::Instruction,
+};
+
+/// The maximum number of instructions to simulate from each starting point, in case of infinite loops.
+const STEP_LIMIT: usize = 10_000;
+
+/// The maximum depth of nested calls to simulate, so that deep recursion does not overflow the real stack.
+const DEPTH_LIMIT: usize = 100;
+
+/// The number of unknown words below the arguments when checking a stack-effect annotation, so that a body that takes
+/// words from below its arguments can still be followed.
+const EFFECT_PADDING: usize = 64;
+
+#[derive(Clone, Debug)]
+enum Value {
+    /// A data word, if its value is known.
+    Data(Option<u64>),
+    Function(String),
+}
+
+/// Stops the analysis.
+struct Stop;
+
+struct Simulation<'a> {
+    functions: HashMap<&'a str, (u32, &'a [Instruction])>,
+    stack: Vec<Value>,
+    args: Vec<Value>,
+    steps: usize,
+    depth: usize,
+    warnings: Vec<String>,
+    /// Whether the simulation stopped because of an EXIT.
+    exited: bool,
+}
+
+pub fn check(program: &[Instruction], diagnostics: &mut Diagnostics) {
+    let mut warnings = Vec::<String>::new();
+
+    let mut simulation = Simulation::new(HashMap::new());
+    let finished = simulation.run_block(program, "the top level").is_ok() || simulation.exited;
+    warnings.extend(simulation.warnings);
+    // Only the top level is checked for leftover functions, since tests often leave their stack in a mess
+    let mut uncalled = Vec::new();
+    if finished {
+        for word in simulation.stack.iter().rev() {
+            if let Value::Function(f) = word {
+                if !uncalled.contains(f) {
+                    uncalled.push(f.clone());
+                }
+            }
+        }
+    }
+
+    // Tests run in a fresh runtime in which every function has been defined
+    let mut functions = HashMap::new();
+    for instruction in program {
+        if let Instruction::Define(f, arg_count, body, _) = instruction {
+            functions.insert(f.as_str(), (*arg_count, body.as_slice()));
+        }
+    }
+    let mut tests = functions
+        .keys()
+        .filter(|f| crate::test_runner::is_test(f))
+        .copied()
+        .collect::<Vec<_>>();
+    tests.sort();
+    for test in tests {
+        let mut simulation = Simulation::new(functions.clone());
+        let _ = simulation.call(test, "the test runner");
+        warnings.extend(simulation.warnings);
+    }
+
+    let mut reported = Vec::new();
+    for warning in warnings {
+        if !reported.contains(&warning) {
+            diagnostics.warn(Lint::StackUnderflow, warning.clone());
+            reported.push(warning);
+        }
+    }
+    for f in uncalled {
+        diagnostics.warn(
+            Lint::UncalledFunction,
+            crate::lint::uncalled_function(&f).message,
+        );
+    }
+
+    // Annotated functions are run on their own with unknown arguments
+    for instruction in program {
+        let Instruction::Define(f, arg_count, body, attributes) = instruction else {
+            continue;
+        };
+        let Some(results) = attributes.results else {
+            continue;
+        };
+        let mut simulation = Simulation::new(functions.clone());
+        simulation.stack = vec![Value::Data(None); EFFECT_PADDING];
+        simulation.args = vec![Value::Data(None); *arg_count as usize];
+        if simulation
+            .run_block(body, &format!("function '{f}'"))
+            .is_err()
+        {
+            continue;
+        }
+        let actual = simulation.stack.len() as i64 - EFFECT_PADDING as i64;
+        if actual == i64::from(results) {
+            continue;
+        }
+        let found = if actual < 0 {
+            format!("it removes {} word(s) from below its arguments", -actual)
+        } else {
+            format!("it leaves {actual}")
+        };
+        diagnostics.warn(
+            Lint::StackEffect,
+            format!("Function '{f}' is declared to leave {results} word(s), but {found}."),
+        );
+    }
+}
+
+impl<'a> Simulation<'a> {
+    fn new(functions: HashMap<&'a str, (u32, &'a [Instruction])>) -> Self {
+        Simulation {
+            functions,
+            stack: Vec::new(),
+            args: Vec::new(),
+            steps: 0,
+            depth: 0,
+            warnings: Vec::new(),
+            exited: false,
+        }
+    }
+
+    fn run_block(&mut self, block: &'a [Instruction], location: &str) -> Result<(), Stop> {
+        for instruction in block {
+            self.steps += 1;
+            if self.steps > STEP_LIMIT {
+                return Err(Stop);
+            }
+
+            match instruction {
+                Instruction::PushData(n) => self.stack.push(Value::Data(Some(*n))),
+                Instruction::PushFunction(f) => self.stack.push(Value::Function(f.clone())),
+                Instruction::PushRandom | Instruction::PushBig(_) => {
+                    self.stack.push(Value::Data(None))
+                }
+                Instruction::PushArg(i) => {
+                    let arg = self.args.get(*i).cloned().ok_or(Stop)?;
+                    self.stack.push(arg);
+                }
+                Instruction::Define(f, arg_count, body, _) => {
+                    self.functions.insert(f, (*arg_count, body));
+                }
+                Instruction::CallIf => {
+                    let [condition, f] = self.pop("CALLIF", location)?;
+                    match (condition, f) {
+                        (Value::Data(Some(0)), Value::Function(_)) => {}
+                        (Value::Data(Some(_)), Value::Function(f)) => self.call(&f, location)?,
+                        _ => return Err(Stop),
+                    }
+                }
+                Instruction::Exit => {
+                    self.exited = true;
+                    return Err(Stop);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, f: &str, location: &str) -> Result<(), Stop> {
+        // As in the runtime, every call clears the arguments
+        self.args.clear();
+
+        match f {
+            "__birl__" | "__and__" | "__or__" | "__xor__" | "__shl_n__" | "__shr_n__" => {
+                match self.pop(f, location)? {
+                    [Value::Data(b), Value::Data(a)] => self.push_results(f, &[b, a], 1)?,
+                    _ => return Err(Stop),
+                }
+            }
+            "__add_carry__" | "__sub_borrow__" => match self.pop(f, location)? {
+                [Value::Data(b), Value::Data(a)] => self.push_results(f, &[b, a], 2)?,
+                _ => return Err(Stop),
+            },
+            "__inc__" | "__dec__" | "__inc_sat__" | "__dec_sat__" | "__not__" => {
+                match self.pop(f, location)? {
+                    [Value::Data(n)] => self.push_results(f, &[n], 1)?,
+                    _ => return Err(Stop),
+                }
+            }
+            "__pick__" | "__roll__" => {
+                let [index] = self.pop(f, location)?;
+                let Value::Data(Some(n)) = index else {
+                    return Err(Stop);
+                };
+                let n = usize::try_from(n).unwrap_or(usize::MAX);
+                if n >= self.stack.len() {
+                    self.stack.push(index);
+                    self.underflow(&format!("calling '{f}'"), n.saturating_add(2), location);
+                    return Err(Stop);
+                }
+                let i = self.stack.len() - 1 - n;
+                let word = match f {
+                    "__pick__" => self.stack[i].clone(),
+                    _ => self.stack.remove(i),
+                };
+                self.stack.push(word);
+            }
+            "__defined__" => match self.pop(f, location)? {
+                [Value::Function(_)] => self.stack.push(Value::Data(None)),
+                _ => return Err(Stop),
+            },
+            "__assert_eq__" => {
+                self.pop::<2>(f, location)?;
+            }
+            "__stack_dump__" => {}
+            _ if f.starts_with("__") => return Err(Stop),
+            _ => {
+                let (arg_count, body) = *self.functions.get(f).ok_or(Stop)?;
+                let needed = arg_count as usize;
+                if self.stack.len() < needed {
+                    self.underflow(&format!("calling '{f}'"), needed, location);
+                    return Err(Stop);
+                }
+                // $0 is the word that was on top
+                self.args = self.stack.split_off(self.stack.len() - needed);
+                self.args.reverse();
+                if self.depth == DEPTH_LIMIT {
+                    return Err(Stop);
+                }
+                self.depth += 1;
+                self.run_block(body, &format!("function '{f}'"))?;
+                self.depth -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Pushes the results of a built-in function that always returns `count` data words, given its operands (from the
+    /// bottom to the top). The results are only known if all the operands are. Stops if the function would fail.
+    fn push_results(
+        &mut self,
+        f: &str,
+        operands: &[Option<u64>],
+        count: usize,
+    ) -> Result<(), Stop> {
+        // `check` only accepts 32-bit literals
+        let operands = operands
+            .iter()
+            .map(|n| n.map(|n| n as u32))
+            .collect::<Option<Vec<_>>>();
+        match operands {
+            None => self.stack.extend(vec![Value::Data(None); count]),
+            Some(operands) => {
+                let results = optimizer::evaluate(f, &operands).ok_or(Stop)?;
+                self.stack
+                    .extend(results.into_iter().map(|n| Value::Data(Some(n.into()))));
+            }
+        }
+        Ok(())
+    }
+
+    /// Pops `N` words, returning them from the bottom to the top.
+    fn pop<const N: usize>(&mut self, what: &str, location: &str) -> Result<[Value; N], Stop> {
+        if self.stack.len() < N {
+            let what = match what {
+                "CALLIF" => what.to_owned(),
+                f => format!("calling '{f}'"),
+            };
+            self.underflow(&what, N, location);
+            return Err(Stop);
+        }
+        let words = self.stack.split_off(self.stack.len() - N);
+        Ok(words.try_into().unwrap_or_else(|_| unreachable!()))
+    }
+
+    fn underflow(&mut self, what: &str, needed: usize, location: &str) {
+        self.warnings.push(format!(
+            "Stack underflow in {location}: {what} needs {needed} word(s), but the stack will only have {}.",
+            self.stack.len()
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Attributes;
+
+    fn call(f: &str) -> Vec<Instruction> {
+        vec![
+            Instruction::PushData(1),
+            Instruction::PushFunction(f.to_owned()),
+            Instruction::CallIf,
+        ]
+    }
+
+    fn warnings(program: &[Instruction]) -> Vec<String> {
+        let mut diagnostics = Diagnostics::default();
+        check(program, &mut diagnostics);
+        diagnostics
+            .warnings()
+            .iter()
+            .map(|w| w.message.clone())
+            .collect()
+    }
+
+    #[test]
+    fn top_level() {
+        let program = [
+            vec![
+                Instruction::Define(
+                    "swap".to_owned(),
+                    2,
+                    vec![Instruction::PushArg(0)],
+                    Attributes::default(),
+                ),
+                Instruction::PushData(5),
+            ],
+            call("swap"),
+        ]
+        .concat();
+
+        assert_eq!(
+            vec![
+                "Stack underflow in the top level: calling 'swap' needs 2 word(s), but the stack will only have 1."
+            ],
+            warnings(&program)
+        );
+    }
+
+    #[test]
+    fn stack_effects() {
+        let define = |f: &str, arg_count, body, results| {
+            Instruction::Define(
+                f.to_owned(),
+                arg_count,
+                body,
+                Attributes {
+                    results: Some(results),
+                    ..Attributes::default()
+                },
+            )
+        };
+        let program = [
+            define(
+                "swap",
+                2,
+                vec![Instruction::PushArg(0), Instruction::PushArg(1)],
+                2,
+            ),
+            define("one", 0, vec![Instruction::PushData(1)], 2),
+            define("and_below", 1, call("__and__"), 0),
+            define(
+                "guess",
+                0,
+                vec![Instruction::PushRandom, Instruction::CallIf],
+                0,
+            ),
+        ];
+
+        assert_eq!(
+            vec![
+                "Function 'one' is declared to leave 2 word(s), but it leaves 1.",
+                "Function 'and_below' is declared to leave 0 word(s), but it removes 1 word(s) from below its arguments.",
+            ],
+            warnings(&program)
+        );
+    }
+
+    #[test]
+    fn pick_and_roll() {
+        let program = [
+            vec![
+                Instruction::PushData(7),
+                Instruction::PushFunction("f".to_owned()),
+                Instruction::PushData(1),
+            ],
+            call("__roll__"),
+            vec![Instruction::PushData(0)],
+            call("__pick__"),
+            vec![Instruction::PushData(3)],
+            call("__pick__"),
+        ]
+        .concat();
+
+        assert_eq!(
+            vec![
+                "Stack underflow in the top level: calling '__pick__' needs 5 word(s), but the stack will only have 4."
+            ],
+            warnings(&program)
+        );
+    }
+
+    #[test]
+    fn nested_calls() {
+        let definitions = vec![
+            Instruction::Define(
+                "g".to_owned(),
+                1,
+                [vec![Instruction::PushArg(0)], call("__birl__")].concat(),
+                Attributes::default(),
+            ),
+            Instruction::Define(
+                "f".to_owned(),
+                0,
+                [vec![Instruction::PushData(1)], call("g")].concat(),
+                Attributes::default(),
+            ),
+        ];
+
+        // The CALLIF with condition 0 does not pop anything else
+        let program = [
+            definitions.clone(),
+            vec![
+                Instruction::PushData(7),
+                Instruction::PushData(0),
+                Instruction::PushFunction("f".to_owned()),
+                Instruction::CallIf,
+            ],
+            call("f"),
+        ]
+        .concat();
+        assert!(warnings(&program).is_empty());
+
+        let program = [definitions, call("f")].concat();
+        assert_eq!(
+            vec![
+                "Stack underflow in function 'g': calling '__birl__' needs 2 word(s), but the stack will only have 1."
+            ],
+            warnings(&program)
+        );
+    }
+
+    #[test]
+    fn unknown_condition() {
+        let program = [
+            vec![
+                Instruction::Define("f".to_owned(), 3, vec![], Attributes::default()),
+                Instruction::PushRandom,
+                Instruction::PushFunction("f".to_owned()),
+                Instruction::CallIf,
+            ],
+            call("f"),
+        ]
+        .concat();
+
+        // The analysis stops at the first call, since it might not happen
+        assert!(warnings(&program).is_empty());
+    }
+
+    #[test]
+    fn callif() {
+        let program = vec![Instruction::PushData(1), Instruction::CallIf];
+
+        assert_eq!(
+            vec!["Stack underflow in the top level: CALLIF needs 2 word(s), but the stack will only have 1."],
+            warnings(&program)
+        );
+    }
+
+    #[test]
+    fn tests_start_with_empty_stack() {
+        let program = vec![
+            Instruction::Define(
+                "test_a".to_owned(),
+                0,
+                call("__assert_eq__"),
+                Attributes::default(),
+            ),
+            Instruction::PushData(1),
+            Instruction::PushData(1),
+        ];
+
+        assert_eq!(
+            vec![
+                "Stack underflow in function 'test_a': calling '__assert_eq__' needs 2 word(s), but the stack will only have 0."
+            ],
+            warnings(&program)
+        );
+    }
+
+    #[test]
+    fn uncalled_functions() {
+        let program = vec![
+            Instruction::Define("f".to_owned(), 0, vec![], Attributes::default()),
+            Instruction::PushFunction("f".to_owned()),
+            Instruction::PushData(1),
+            Instruction::PushFunction("__print__".to_owned()),
+            Instruction::PushFunction("f".to_owned()),
+        ];
+
+        assert_eq!(
+            vec![
+                "Function 'f' is still on the stack when the program ends. Did you forget to call it?",
+                "Function '__print__' is still on the stack when the program ends. Did you forget to call it?",
+            ],
+            warnings(&program)
+        );
+    }
+
+    #[test]
+    fn uncalled_function_at_exit() {
+        let program = [
+            vec![
+                Instruction::Define(
+                    "f".to_owned(),
+                    0,
+                    vec![Instruction::Exit],
+                    Attributes::default(),
+                ),
+                Instruction::PushFunction("g".to_owned()),
+            ],
+            call("f"),
+            vec![Instruction::PushFunction("h".to_owned())],
+        ]
+        .concat();
+
+        assert_eq!(
+            vec!["Function 'g' is still on the stack when the program ends. Did you forget to call it?"],
+            warnings(&program)
+        );
+
+        // The analysis cannot tell what the stack will be after a call that might not happen
+        let program = vec![
+            Instruction::PushFunction("g".to_owned()),
+            Instruction::PushRandom,
+            Instruction::PushFunction("g".to_owned()),
+            Instruction::CallIf,
+        ];
+        assert!(warnings(&program).is_empty());
+    }
+
+    #[test]
+    fn infinite_loop() {
+        let program = [
+            vec![Instruction::Define(
+                "f".to_owned(),
+                0,
+                call("f"),
+                Attributes::default(),
+            )],
+            call("f"),
+        ]
+        .concat();
+
+        assert!(warnings(&program).is_empty());
+    }
+}
@@ -0,0 +1,356 @@
+//! Size and complexity metrics of programs (`plates stats`).
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json::{json, Value};
+
+use crate::{graph::TOP_LEVEL, parser::Instruction, runtime::BUILTIN_FUNCTIONS};
+
+/// The metrics of a function, or of the top level.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FunctionStats {
+    pub name: String,
+    /// `None` for the top level.
+    pub arg_count: Option<u32>,
+    pub instructions: usize,
+    pub calls: usize,
+    /// The length of the longest chain of functions that push each other, starting with this one (which counts as 1,
+    /// except for the top level), or `None` if the chain can go on forever because of recursion.
+    pub nesting: Option<usize>,
+    /// How many words the function leaves on the stack after its arguments are popped (negative if it takes words from
+    /// below its arguments).
+    pub effect: i64,
+    /// The most words that the function has on the stack at once, after its arguments are popped.
+    pub peak: i64,
+    /// Whether `effect` and `peak` take every call into account. Calls to arguments, to recursive functions, and to
+    /// most built-in functions cannot be followed, so they are counted as only popping their condition and function.
+    pub exact: bool,
+}
+
+/// The metrics of a whole program.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// The top level first, then the functions in the order they were defined.
+    pub functions: Vec<FunctionStats>,
+    /// How many times each built-in function is pushed.
+    pub builtins: BTreeMap<String, usize>,
+}
+
+/// The number of words that some built-in functions pop and push. The others pop or push a number of words that
+/// depends on the stack.
+fn builtin_effect(f: &str) -> Option<(i64, i64)> {
+    match f {
+        "__birl__" | "__and__" | "__or__" | "__xor__" | "__shl_n__" | "__shr_n__" => Some((2, 1)),
+        "__add_carry__" | "__sub_borrow__" => Some((2, 2)),
+        "__inc__" | "__dec__" | "__inc_sat__" | "__dec_sat__" | "__not__" | "__defined__" => {
+            Some((1, 1))
+        }
+        "__assert_eq__" => Some((2, 0)),
+        "__set_print_policy__" => Some((1, 0)),
+        "__mark__" | "__strlen__" => Some((0, 1)),
+        "__gc_stats__" => Some((0, 4)),
+        "__stack_dump__" | "__clear__" => Some((0, 0)),
+        _ => None,
+    }
+}
+
+/// The effect, peak, and exactness of a block.
+type Effect = (i64, i64, bool);
+
+struct Analysis<'a> {
+    definitions: HashMap<&'a str, (u32, &'a [Instruction])>,
+    effects: HashMap<&'a str, Option<Effect>>,
+    nestings: HashMap<&'a str, Option<Option<usize>>>,
+}
+
+impl<'a> Analysis<'a> {
+    /// Estimates the effect of a function, assuming that every call happens. `None` while the function is being
+    /// estimated, so that recursive calls can be recognized.
+    fn function_effect(&mut self, f: &'a str) -> Option<Effect> {
+        if let Some(effect) = self.effects.get(f) {
+            return *effect;
+        }
+        let (_, body) = *self.definitions.get(f)?;
+        self.effects.insert(f, None);
+        let effect = self.block_effect(body);
+        self.effects.insert(f, Some(effect));
+        Some(effect)
+    }
+
+    fn block_effect(&mut self, block: &'a [Instruction]) -> Effect {
+        let (mut height, mut peak, mut exact) = (0, 0, true);
+        for (i, instruction) in block.iter().enumerate() {
+            match instruction {
+                Instruction::PushData(_)
+                | Instruction::PushBig(_)
+                | Instruction::PushFunction(_)
+                | Instruction::PushRandom
+                | Instruction::PushArg(_) => height += 1,
+                Instruction::CallIf => {
+                    height -= 2;
+                    let callee = match i.checked_sub(1).map(|i| &block[i]) {
+                        Some(Instruction::PushFunction(f)) => Some(f.as_str()),
+                        _ => None,
+                    };
+                    let known = callee.and_then(|f| match builtin_effect(f) {
+                        Some((pops, pushes)) => Some((pops, pushes, pushes, true)),
+                        None => {
+                            let arg_count = self.definitions.get(f)?.0;
+                            let (effect, peak, exact) = self.function_effect(f)?;
+                            Some((i64::from(arg_count), effect, peak, exact))
+                        }
+                    });
+                    match known {
+                        None => exact = false,
+                        Some((pops, pushes, callee_peak, callee_exact)) => {
+                            height -= pops;
+                            peak = peak.max(height + callee_peak);
+                            height += pushes;
+                            exact &= callee_exact;
+                        }
+                    }
+                }
+                Instruction::Exit => break,
+                Instruction::Define(..) => {}
+            }
+            peak = peak.max(height);
+        }
+        (height, peak, exact)
+    }
+
+    /// The nesting of a function (see [`FunctionStats::nesting`]), or `Some(None)` if it is recursive. `None` while
+    /// the function is being looked at.
+    fn nesting(&mut self, f: &'a str) -> Option<Option<usize>> {
+        if let Some(nesting) = self.nestings.get(f) {
+            return Some(nesting.unwrap_or(None));
+        }
+        let (_, body) = *self.definitions.get(f)?;
+        self.nestings.insert(f, None);
+        let nesting = self.block_nesting(body).map(|n| n + 1);
+        self.nestings.insert(f, Some(nesting));
+        Some(nesting)
+    }
+
+    fn block_nesting(&mut self, block: &'a [Instruction]) -> Option<usize> {
+        let mut deepest = 0;
+        for instruction in block {
+            if let Instruction::PushFunction(f) = instruction {
+                match self.nesting(f) {
+                    // Not a defined function
+                    None if !self.definitions.contains_key(f.as_str()) => {}
+                    Some(Some(n)) => deepest = deepest.max(n),
+                    _ => return None,
+                }
+            }
+        }
+        Some(deepest)
+    }
+}
+
+fn count(block: &[Instruction], f: impl Fn(&Instruction) -> bool) -> usize {
+    block.iter().filter(|i| f(i)).count()
+}
+
+impl Stats {
+    pub fn new(program: &[Instruction]) -> Self {
+        let mut analysis = Analysis {
+            definitions: HashMap::new(),
+            effects: HashMap::new(),
+            nestings: HashMap::new(),
+        };
+        let mut order = Vec::new();
+        for instruction in program {
+            if let Instruction::Define(f, arg_count, body, _) = instruction {
+                if !order.contains(&f.as_str()) {
+                    order.push(f);
+                }
+                analysis
+                    .definitions
+                    .insert(f, (*arg_count, body.as_slice()));
+            }
+        }
+
+        let is_instruction = |i: &Instruction| !matches!(i, Instruction::Define(..));
+        let (effect, peak, exact) = analysis.block_effect(program);
+        let mut stats = Stats {
+            functions: vec![FunctionStats {
+                name: TOP_LEVEL.to_owned(),
+                arg_count: None,
+                instructions: count(program, is_instruction),
+                calls: count(program, |i| *i == Instruction::CallIf),
+                nesting: analysis.block_nesting(program),
+                effect,
+                peak,
+                exact,
+            }],
+            builtins: BTreeMap::new(),
+        };
+        for f in order {
+            let (arg_count, body) = analysis.definitions[f];
+            let (effect, peak, exact) = analysis.function_effect(f).unwrap_or((0, 0, false));
+            // The top level is not a function, so recursion through it does not count
+            let nesting = analysis.nesting(f).flatten();
+            stats.functions.push(FunctionStats {
+                name: f.to_owned(),
+                arg_count: Some(arg_count),
+                instructions: count(body, is_instruction),
+                calls: count(body, |i| *i == Instruction::CallIf),
+                nesting,
+                effect,
+                peak,
+                exact,
+            });
+        }
+        stats.count_builtins(program);
+        stats
+    }
+
+    fn count_builtins(&mut self, block: &[Instruction]) {
+        for instruction in block {
+            match instruction {
+                Instruction::PushFunction(f) if BUILTIN_FUNCTIONS.contains(&f.as_str()) => {
+                    *self.builtins.entry(f.clone()).or_default() += 1;
+                }
+                Instruction::Define(_, _, body, _) => self.count_builtins(body),
+                _ => {}
+            }
+        }
+    }
+
+    /// Renders the metrics as a table with a row per function, followed by the built-in functions that are used. An
+    /// estimated effect or peak is prefixed with `~`.
+    pub fn to_table(&self) -> String {
+        let estimate = |f: &FunctionStats, n: i64| match f.exact {
+            true => n.to_string(),
+            false => format!("~{n}"),
+        };
+        let mut rows = vec![[
+            "function".to_owned(),
+            "args".to_owned(),
+            "instructions".to_owned(),
+            "calls".to_owned(),
+            "nesting".to_owned(),
+            "effect".to_owned(),
+            "peak".to_owned(),
+        ]];
+        for f in &self.functions {
+            rows.push([
+                f.name.clone(),
+                f.arg_count.map_or("-".to_owned(), |n| n.to_string()),
+                f.instructions.to_string(),
+                f.calls.to_string(),
+                f.nesting.map_or("recursive".to_owned(), |n| n.to_string()),
+                estimate(f, f.effect),
+                estimate(f, f.peak),
+            ]);
+        }
+        let widths = (0..7)
+            .map(|i| {
+                rows.iter()
+                    .map(|row| row[i].chars().count())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect::<Vec<_>>();
+
+        let mut table = String::new();
+        for row in rows {
+            // The names are aligned to the left and the numbers to the right
+            let mut line = format!("{:<width$}", row[0], width = widths[0]);
+            for (cell, width) in row.iter().zip(&widths).skip(1) {
+                line += &format!("  {cell:>width$}");
+            }
+            table += &line;
+            table += "\n";
+        }
+        if !self.builtins.is_empty() {
+            table += "\nbuilt-in functions:\n";
+            let width = self.builtins.keys().map(String::len).max().unwrap_or(0);
+            for (f, n) in &self.builtins {
+                table += &format!("    {f:<width$}  {n}\n");
+            }
+        }
+        table
+    }
+
+    pub fn to_json(&self) -> Value {
+        let functions = self
+            .functions
+            .iter()
+            .map(|f| {
+                json!({
+                    "name": f.name,
+                    "args": f.arg_count,
+                    "instructions": f.instructions,
+                    "calls": f.calls,
+                    "nesting": f.nesting,
+                    "effect": f.effect,
+                    "peak": f.peak,
+                    "exact": f.exact,
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({ "functions": functions, "builtins": self.builtins })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::Lexer, parser::Parser, reader::FileReader};
+
+    fn stats(source: &str) -> Stats {
+        let mut parser = Parser::new(Lexer::new(FileReader::from_source(source)));
+        let mut instructions = vec![];
+        while let Some(instruction) = parser.next_instruction().unwrap() {
+            instructions.push(instruction);
+        }
+        Stats::new(&instructions)
+    }
+
+    const PROGRAM: &str = "
+        DEFN double (1) { PUSH $0 PUSH $0 PUSH 2 PUSH __birl__ CALLIF }
+        DEFN quadruple (1) { PUSH $0 PUSH 1 PUSH double CALLIF PUSH 1 PUSH double CALLIF }
+        DEFN loop (0) { PUSH 1 PUSH loop CALLIF }
+        DEFN apply (1) { PUSH 1 PUSH $0 CALLIF }
+        PUSH 3 PUSH 1 PUSH quadruple CALLIF PUSH 0 PUSH 10 PUSH 1 PUSH __print__ CALLIF
+    ";
+
+    #[test]
+    fn table() {
+        assert_eq!(
+            "function     args  instructions  calls    nesting  effect  peak
+(top level)     -             9      2          2      ~3    ~5
+double          1             5      1          1       1     4
+quadruple       1             7      2          2       1     4
+loop            0             3      1  recursive      ~0    ~2
+apply           1             3      1          1      ~0    ~2
+
+built-in functions:
+    __birl__   1
+    __print__  1
+",
+            stats(PROGRAM).to_table()
+        );
+    }
+
+    #[test]
+    fn json() {
+        let json = stats(PROGRAM).to_json();
+        assert_eq!(
+            json!({
+                "name": "quadruple",
+                "args": 1,
+                "instructions": 7,
+                "calls": 2,
+                "nesting": 2,
+                "effect": 1,
+                "peak": 4,
+                "exact": true,
+            }),
+            json["functions"][2]
+        );
+        assert_eq!(Value::Null, json["functions"][3]["nesting"]);
+        assert_eq!(json!({ "__birl__": 1, "__print__": 1 }), json["builtins"]);
+    }
+}
@@ -0,0 +1,224 @@
+//! Access to the terminal for the built-in functions that draw on the screen and read keys.
+//!
+//! The runtime only talks to the terminal through the `Terminal` trait, so tests (and embedders) can replace it.
+
+use std::{cell::Cell, fmt::Debug, io::Write, time::Duration};
+
+use crossterm::{
+    cursor::MoveTo,
+    event::{Event, KeyCode, KeyEvent, KeyEventKind},
+    execute,
+    terminal::{self, Clear, ClearType},
+};
+
+/// The key code of the up arrow. The other arrows follow. Key codes below this are Unicode code points.
+pub const KEY_UP: u64 = 0x110000;
+pub const KEY_DOWN: u64 = KEY_UP + 1;
+pub const KEY_LEFT: u64 = KEY_UP + 2;
+pub const KEY_RIGHT: u64 = KEY_UP + 3;
+
+pub trait Terminal: Debug {
+    /// Clears the screen and moves the cursor to the top-left corner.
+    fn clear(&self) -> std::io::Result<()>;
+
+    /// Moves the cursor. Both coordinates start at 0.
+    fn cursor_to(&self, column: u16, row: u16) -> std::io::Result<()>;
+
+    /// Returns the number of columns and rows.
+    fn size(&self) -> std::io::Result<(u16, u16)>;
+
+    fn set_raw_mode(&self, enabled: bool) -> std::io::Result<()>;
+
+    /// Waits for a key to be pressed and returns its key code.
+    fn read_key(&self) -> std::io::Result<u64>;
+
+    /// Returns the key code of a key that has been pressed, if there is one, without waiting.
+    fn poll_key(&self) -> std::io::Result<Option<u64>>;
+}
+
+/// The real terminal. Raw mode is turned off again when it is dropped.
+#[derive(Debug, Default)]
+pub struct Crossterm {
+    raw_mode: Cell<bool>,
+}
+
+impl Terminal for Crossterm {
+    fn clear(&self) -> std::io::Result<()> {
+        execute!(std::io::stdout(), Clear(ClearType::All), MoveTo(0, 0))
+    }
+
+    fn cursor_to(&self, column: u16, row: u16) -> std::io::Result<()> {
+        execute!(std::io::stdout(), MoveTo(column, row))
+    }
+
+    fn size(&self) -> std::io::Result<(u16, u16)> {
+        terminal::size()
+    }
+
+    fn set_raw_mode(&self, enabled: bool) -> std::io::Result<()> {
+        // Whatever was printed so far should show up before the mode changes
+        std::io::stdout().flush()?;
+        if enabled {
+            terminal::enable_raw_mode()?;
+        } else {
+            terminal::disable_raw_mode()?;
+        }
+        self.raw_mode.set(enabled);
+        Ok(())
+    }
+
+    fn read_key(&self) -> std::io::Result<u64> {
+        std::io::stdout().flush()?;
+        loop {
+            if let Some(key) = key_code(crossterm::event::read()?) {
+                return Ok(key);
+            }
+        }
+    }
+
+    fn poll_key(&self) -> std::io::Result<Option<u64>> {
+        std::io::stdout().flush()?;
+        while crossterm::event::poll(Duration::ZERO)? {
+            if let Some(key) = key_code(crossterm::event::read()?) {
+                return Ok(Some(key));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for Crossterm {
+    fn drop(&mut self) {
+        if self.raw_mode.get() {
+            let _ = terminal::disable_raw_mode();
+        }
+    }
+}
+
+/// A terminal that cannot be used, for when stdout is not the screen (for example, in the debug adapter, which talks to
+/// the editor over stdout). Everything fails.
+#[derive(Debug, Default)]
+pub struct NoTerminal;
+
+impl NoTerminal {
+    fn error() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::Unsupported, "There is no terminal.")
+    }
+}
+
+impl Terminal for NoTerminal {
+    fn clear(&self) -> std::io::Result<()> {
+        Err(NoTerminal::error())
+    }
+
+    fn cursor_to(&self, _: u16, _: u16) -> std::io::Result<()> {
+        Err(NoTerminal::error())
+    }
+
+    fn size(&self) -> std::io::Result<(u16, u16)> {
+        Err(NoTerminal::error())
+    }
+
+    fn set_raw_mode(&self, _: bool) -> std::io::Result<()> {
+        Err(NoTerminal::error())
+    }
+
+    fn read_key(&self) -> std::io::Result<u64> {
+        Err(NoTerminal::error())
+    }
+
+    fn poll_key(&self) -> std::io::Result<Option<u64>> {
+        Err(NoTerminal::error())
+    }
+}
+
+/// Converts a key press to a key code, ignoring other events and keys that do not have one.
+fn key_code(event: Event) -> Option<u64> {
+    let Event::Key(KeyEvent {
+        code,
+        kind: KeyEventKind::Press,
+        ..
+    }) = event
+    else {
+        return None;
+    };
+    match code {
+        KeyCode::Char(c) => Some(c as u64),
+        KeyCode::Enter => Some('\n' as u64),
+        KeyCode::Tab => Some('\t' as u64),
+        KeyCode::Backspace => Some(0x08),
+        KeyCode::Esc => Some(0x1B),
+        KeyCode::Up => Some(KEY_UP),
+        KeyCode::Down => Some(KEY_DOWN),
+        KeyCode::Left => Some(KEY_LEFT),
+        KeyCode::Right => Some(KEY_RIGHT),
+        _ => None,
+    }
+}
+
+/// A terminal that records what is done to it and returns keys from a list.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct FakeTerminal {
+    pub log: std::cell::RefCell<Vec<String>>,
+    pub keys: std::cell::RefCell<std::collections::VecDeque<u64>>,
+}
+
+#[cfg(test)]
+impl Terminal for FakeTerminal {
+    fn clear(&self) -> std::io::Result<()> {
+        self.log.borrow_mut().push("clear".to_owned());
+        Ok(())
+    }
+
+    fn cursor_to(&self, column: u16, row: u16) -> std::io::Result<()> {
+        self.log
+            .borrow_mut()
+            .push(format!("cursor_to {column} {row}"));
+        Ok(())
+    }
+
+    fn size(&self) -> std::io::Result<(u16, u16)> {
+        Ok((80, 24))
+    }
+
+    fn set_raw_mode(&self, enabled: bool) -> std::io::Result<()> {
+        self.log.borrow_mut().push(format!("raw_mode {enabled}"));
+        Ok(())
+    }
+
+    fn read_key(&self) -> std::io::Result<u64> {
+        self.keys
+            .borrow_mut()
+            .pop_front()
+            .ok_or_else(|| std::io::ErrorKind::UnexpectedEof.into())
+    }
+
+    fn poll_key(&self) -> std::io::Result<Option<u64>> {
+        Ok(self.keys.borrow_mut().pop_front())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::KeyModifiers;
+
+    use super::*;
+
+    #[test]
+    fn key_codes() {
+        let press = |code| Event::Key(KeyEvent::new(code, KeyModifiers::NONE));
+        assert_eq!(Some('a' as u64), key_code(press(KeyCode::Char('a'))));
+        assert_eq!(Some(10), key_code(press(KeyCode::Enter)));
+        assert_eq!(Some(KEY_LEFT), key_code(press(KeyCode::Left)));
+        assert_eq!(None, key_code(press(KeyCode::F(1))));
+        assert_eq!(None, key_code(Event::FocusGained));
+
+        let release = Event::Key(KeyEvent::new_with_kind(
+            KeyCode::Char('a'),
+            KeyModifiers::NONE,
+            KeyEventKind::Release,
+        ));
+        assert_eq!(None, key_code(release));
+    }
+}
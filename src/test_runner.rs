@@ -0,0 +1,225 @@
+use crate::{parser::Instruction, runtime::Runtime};
+
+/// Functions whose names start with this are tests.
+pub const TEST_PREFIX: &str = "test_";
+
+/// Returns true iff the function is a test. Only the name within its module counts, so `m::test_a` is a test too.
+pub fn is_test(f: &str) -> bool {
+    let name = f.rsplit_once("::").map_or(f, |(_, name)| name);
+    name.starts_with(TEST_PREFIX)
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct TestResult {
+    pub name: String,
+    /// Failed assertions, followed by the error that stopped the test (if any).
+    pub failures: Vec<String>,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Runs every test in the program, in order of first definition. Each test runs in a fresh runtime in which all the
+/// functions in the program have been defined. The rest of the top-level code is not run.
+pub fn run_tests(program: &[Instruction]) -> Vec<TestResult> {
+    let definitions = program
+        .iter()
+        .filter(|i| matches!(i, Instruction::Define(..)))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let mut names = Vec::<&str>::new();
+    for instruction in &definitions {
+        if let Instruction::Define(f, ..) = instruction {
+            if is_test(f) && !names.contains(&f.as_str()) {
+                names.push(f);
+            }
+        }
+    }
+
+    names
+        .into_iter()
+        .map(|name| run_test(&definitions, name))
+        .collect()
+}
+
+fn run_test(definitions: &[Instruction], name: &str) -> TestResult {
+    let mut runtime = Runtime::new();
+    let call = [
+        Instruction::PushData(1),
+        Instruction::PushFunction(name.to_owned()),
+        Instruction::CallIf,
+    ];
+
+    let mut error = None;
+    for instruction in definitions.iter().cloned().chain(call) {
+        match runtime.run(instruction) {
+            Err(e) => {
+                error = Some(format!("{e}"));
+                break;
+            }
+            Ok(true) => break,
+            Ok(false) => {}
+        }
+    }
+
+    let mut failures = runtime.take_assertion_failures();
+    failures.extend(error);
+    TestResult {
+        name: name.to_owned(),
+        failures,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Attributes;
+
+    fn assert_eq_call(left: u64, right: u64) -> Vec<Instruction> {
+        vec![
+            Instruction::PushData(left),
+            Instruction::PushData(right),
+            Instruction::PushData(1),
+            Instruction::PushFunction("__assert_eq__".to_owned()),
+            Instruction::CallIf,
+        ]
+    }
+
+    #[test]
+    fn run() {
+        let program = vec![
+            Instruction::Define(
+                "test_pass".to_owned(),
+                0,
+                assert_eq_call(1, 1),
+                Attributes::default(),
+            ),
+            Instruction::Define(
+                "test_fail".to_owned(),
+                0,
+                [
+                    assert_eq_call(1, 2),
+                    assert_eq_call(3, 3),
+                    assert_eq_call(4, 5),
+                ]
+                .concat(),
+                Attributes::default(),
+            ),
+            Instruction::Define(
+                "helper".to_owned(),
+                0,
+                assert_eq_call(1, 2),
+                Attributes::default(),
+            ),
+            // The top-level code is not run
+            Instruction::PushData(1),
+            Instruction::PushFunction("undefined".to_owned()),
+            Instruction::CallIf,
+            Instruction::Define(
+                "test_error".to_owned(),
+                0,
+                [
+                    assert_eq_call(6, 7),
+                    vec![
+                        Instruction::PushData(1),
+                        Instruction::PushFunction("undefined".to_owned()),
+                        Instruction::CallIf,
+                    ],
+                    assert_eq_call(8, 9),
+                ]
+                .concat(),
+                Attributes::default(),
+            ),
+            Instruction::Define(
+                "test_exit".to_owned(),
+                0,
+                [vec![Instruction::Exit], assert_eq_call(1, 2)].concat(),
+                Attributes::default(),
+            ),
+            // Tests in modules count too, but not functions in modules whose names start with `test_`
+            Instruction::Define(
+                "m::test_module".to_owned(),
+                0,
+                assert_eq_call(1, 2),
+                Attributes::default(),
+            ),
+            Instruction::Define(
+                "test_m::helper".to_owned(),
+                0,
+                assert_eq_call(1, 2),
+                Attributes::default(),
+            ),
+            // Only the last definition counts, but the order is based on the first
+            Instruction::Define(
+                "test_pass".to_owned(),
+                0,
+                assert_eq_call(2, 3),
+                Attributes::default(),
+            ),
+        ];
+
+        let expected = vec![
+            TestResult {
+                name: "test_pass".to_owned(),
+                failures: vec!["Assertion failed: 2 != 3.".to_owned()],
+            },
+            TestResult {
+                name: "test_fail".to_owned(),
+                failures: vec![
+                    "Assertion failed: 1 != 2.".to_owned(),
+                    "Assertion failed: 4 != 5.".to_owned(),
+                ],
+            },
+            TestResult {
+                name: "test_error".to_owned(),
+                failures: vec![
+                    "Assertion failed: 6 != 7.".to_owned(),
+                    "Runtime error: Undefined argument or function.".to_owned(),
+                ],
+            },
+            TestResult {
+                name: "test_exit".to_owned(),
+                failures: vec![],
+            },
+            TestResult {
+                name: "m::test_module".to_owned(),
+                failures: vec!["Assertion failed: 1 != 2.".to_owned()],
+            },
+        ];
+        assert_eq!(expected, run_tests(&program));
+    }
+
+    #[test]
+    fn fresh_runtime() {
+        let program = vec![
+            Instruction::Define(
+                "test_a".to_owned(),
+                0,
+                vec![Instruction::PushData(5)],
+                Attributes::default(),
+            ),
+            Instruction::Define(
+                "test_b".to_owned(),
+                0,
+                vec![
+                    Instruction::PushData(5),
+                    Instruction::PushData(1),
+                    Instruction::PushFunction("__assert_eq__".to_owned()),
+                    Instruction::CallIf,
+                ],
+                Attributes::default(),
+            ),
+        ];
+
+        let results = run_tests(&program);
+        assert!(results[0].passed());
+        assert_eq!(
+            vec!["Runtime error: Stack underflow. __assert_eq__ needs 2 word(s), but the stack only has 1.".to_owned()],
+            results[1].failures
+        );
+    }
+}
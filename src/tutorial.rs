@@ -0,0 +1,196 @@
+use std::{cell::Cell, rc::Rc};
+
+use anyhow::Error;
+
+use crate::{
+    lexer::Lexer,
+    parser::{Instruction, Parser},
+    prompt::Prompt,
+    reader::{FileReader, InteractiveReader},
+    runtime::Runtime,
+};
+
+pub struct Lesson {
+    pub title: &'static str,
+    /// The explanation and the exercise.
+    pub text: &'static str,
+    /// Code to run before each attempt, e.g. to define functions for the exercise.
+    pub setup: &'static str,
+    /// Returns true iff the exercise has been solved, given the state of the runtime after an attempt.
+    pub check: fn(&Runtime) -> bool,
+}
+
+pub const LESSONS: &[Lesson] = &[
+    Lesson {
+        title: "Pushing data",
+        text: "plates programs work with a stack of words. `PUSH 42` pushes the number 42 onto the stack.\n\
+               Exercise: push the numbers 1, 2, and 3, with 3 on top.",
+        setup: "",
+        check: |runtime| runtime.stack_data() == Some(vec![1, 2, 3]),
+    },
+    Lesson {
+        title: "Calling functions",
+        text: "Functions can be pushed onto the stack too. `CALLIF` pops a function and then a condition, and calls \
+               the function if the condition is not zero. The function `seven` has been defined for you: it \
+               pushes 7.\n\
+               Exercise: call `seven` with the condition 0, then call it with the condition 1.",
+        setup: "DEFN seven (0) { PUSH 7 }",
+        check: |runtime| runtime.stack_data() == Some(vec![7]),
+    },
+    Lesson {
+        title: "Built-in functions",
+        text: "Functions that start and end with `__` are built in. For example, `__birl__` pops `a` and then `b`, \
+               and pushes `(!a | b)` rotated left by one bit.\n\
+               Exercise: push 0 and 4294967295, then use `__birl__` to turn them into 0.",
+        setup: "",
+        check: |runtime| runtime.stack_data() == Some(vec![0]),
+    },
+    Lesson {
+        title: "Defining functions",
+        text: "`DEFN name (n) { ... }` defines a function that takes `n` arguments. The arguments are popped \
+               from the stack when the function is called, and `PUSH $0` pushes the first one (the one that was \
+               on top).\n\
+               Exercise: define a function `double` that takes one argument and pushes it twice.",
+        setup: "",
+        check: |runtime| {
+            runtime.is_defined("double")
+                && after(runtime, "PUSH 5 PUSH 1 PUSH double CALLIF") == Some(vec![5, 5])
+        },
+    },
+    Lesson {
+        title: "Loops",
+        text: "`__call_n_times__` pops a function and then a number `n`, and calls the function `n` times. Before \
+               each call, it pushes the index of the iteration. The function `nothing` has been defined for you: \
+               it takes no arguments and does nothing.\n\
+               Exercise: use `__call_n_times__` to push the numbers from 0 to 4.",
+        setup: "DEFN nothing (0) { }",
+        check: |runtime| runtime.stack_data() == Some(vec![0, 1, 2, 3, 4]),
+    },
+];
+
+/// Runs the tutorial, starting from the given lesson (counting from zero). Every line the user enters is an attempt
+/// at the exercise. Each attempt starts from a fresh runtime.
+pub fn run(start: usize, prompt: Prompt) {
+    let stack_size = Rc::new(Cell::new(0));
+    let reader = InteractiveReader::new(prompt, Rc::clone(&stack_size));
+    let mut parser = Parser::new(Lexer::new(reader));
+
+    let mut lesson = start;
+    present(lesson);
+    let mut runtime = setup(&LESSONS[lesson]);
+    let mut failed = false;
+
+    loop {
+        match parser.next_instruction() {
+            Ok(None) => break,
+            Ok(Some(instruction)) => match runtime.run(instruction) {
+                Err(e) => {
+                    crate::print_error(&e);
+                    failed = true;
+                }
+                Ok(true) => break,
+                Ok(false) => {}
+            },
+            Err(e) => {
+                crate::print_error(&e);
+                parser.clear_line();
+                failed = true;
+            }
+        }
+        stack_size.set(runtime.stack_size());
+        if !parser.full_line_consumed() {
+            continue;
+        }
+
+        crate::print_debug(&runtime.stack_to_string());
+        if !failed && (LESSONS[lesson].check)(&runtime) {
+            crate::print_info("Correct!");
+            lesson += 1;
+            if lesson == LESSONS.len() {
+                crate::print_info("You finished the tutorial!");
+                return;
+            }
+            present(lesson);
+        } else {
+            println!("Not quite. Try again! The stack has been reset.");
+        }
+        runtime = setup(&LESSONS[lesson]);
+        stack_size.set(runtime.stack_size());
+        failed = false;
+    }
+}
+
+fn present(lesson: usize) {
+    println!();
+    crate::print_info(&format!(
+        "Lesson {} of {}: {}",
+        lesson + 1,
+        LESSONS.len(),
+        LESSONS[lesson].title
+    ));
+    println!("{}", LESSONS[lesson].text);
+    println!("Type EXIT to quit.");
+}
+
+fn setup(lesson: &Lesson) -> Runtime {
+    let mut runtime = Runtime::new();
+    for instruction in parse(lesson.setup).expect("Invalid setup code") {
+        runtime.run(instruction).expect("Invalid setup code");
+    }
+    runtime
+}
+
+/// Returns the data on the stack after running `source` in a copy of the runtime.
+fn after(runtime: &Runtime, source: &str) -> Option<Vec<u64>> {
+    let mut runtime = runtime.clone();
+    for instruction in parse(source).ok()? {
+        runtime.run(instruction).ok()?;
+    }
+    runtime.stack_data()
+}
+
+fn parse(source: &str) -> Result<Vec<Instruction>, Error> {
+    let mut parser = Parser::new(Lexer::new(FileReader::from_source(source)));
+    let mut instructions = vec![];
+    while let Some(instruction) = parser.next_instruction()? {
+        instructions.push(instruction);
+    }
+    Ok(instructions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A solution to each lesson.
+    const SOLUTIONS: &[&str] = &[
+        "PUSH 1 PUSH 2 PUSH 3",
+        "PUSH 0 PUSH seven CALLIF PUSH 1 PUSH seven CALLIF",
+        "PUSH 0 PUSH 4294967295 PUSH 1 PUSH __birl__ CALLIF",
+        "DEFN double (1) { PUSH $0 PUSH $0 }",
+        "PUSH 5 PUSH nothing PUSH 1 PUSH __call_n_times__ CALLIF",
+    ];
+
+    #[test]
+    fn lessons() {
+        assert_eq!(LESSONS.len(), SOLUTIONS.len());
+        for (lesson, solution) in LESSONS.iter().zip(SOLUTIONS) {
+            let mut runtime = setup(lesson);
+            assert!(!(lesson.check)(&runtime), "{}", lesson.title);
+
+            for instruction in parse(solution).unwrap() {
+                runtime.run(instruction).unwrap();
+            }
+            assert!((lesson.check)(&runtime), "{}", lesson.title);
+        }
+    }
+
+    #[test]
+    fn wrong_function() {
+        let mut runtime = setup(&LESSONS[3]);
+        for instruction in parse("DEFN double (1) { PUSH $0 }").unwrap() {
+            runtime.run(instruction).unwrap();
+        }
+        assert!(!(LESSONS[3].check)(&runtime));
+    }
+}
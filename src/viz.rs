@@ -0,0 +1,212 @@
+//! Charts of how deep the stack and the call stack get while a program runs (`--viz`).
+
+use std::fmt::Write;
+
+/// The most samples that are kept. Once there are more, neighbouring samples are merged.
+const MAX_SAMPLES: usize = 10_000;
+
+const WIDTH: usize = 800;
+const HEIGHT: usize = 300;
+/// The space around the plot, for the labels.
+const MARGIN: usize = 40;
+
+/// The depths after one step, or the deepest ones over a few consecutive steps.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Sample {
+    /// The number of the first step that the sample covers, starting at 1.
+    pub step: u64,
+    pub stack_depth: usize,
+    pub call_depth: usize,
+}
+
+/// Collects a sample after every step. Long programs are sampled at regular intervals instead, keeping the deepest
+/// stack and call stack of each interval, so that the samples fit in memory.
+#[derive(Debug)]
+pub struct Recorder {
+    samples: Vec<Sample>,
+    /// The number of steps that each sample covers.
+    stride: u64,
+    /// The sample for the steps since the last full interval.
+    partial: Option<Sample>,
+    steps: u64,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Recorder {
+            samples: Vec::new(),
+            stride: 1,
+            partial: None,
+            steps: 0,
+        }
+    }
+}
+
+impl Recorder {
+    /// Records the depths after a step.
+    pub fn record(&mut self, stack_depth: usize, call_depth: usize) {
+        self.steps += 1;
+        let sample = self.partial.get_or_insert(Sample {
+            step: self.steps,
+            stack_depth,
+            call_depth,
+        });
+        sample.stack_depth = sample.stack_depth.max(stack_depth);
+        sample.call_depth = sample.call_depth.max(call_depth);
+        if !self.steps.is_multiple_of(self.stride) {
+            return;
+        }
+
+        self.samples.extend(self.partial.take());
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples = self
+                .samples
+                .chunks(2)
+                .map(|pair| Sample {
+                    step: pair[0].step,
+                    stack_depth: pair.iter().map(|s| s.stack_depth).max().unwrap_or_default(),
+                    call_depth: pair.iter().map(|s| s.call_depth).max().unwrap_or_default(),
+                })
+                .collect();
+            self.stride *= 2;
+        }
+    }
+
+    /// Returns the samples so far, oldest first.
+    pub fn samples(&self) -> Vec<Sample> {
+        self.samples.iter().chain(&self.partial).copied().collect()
+    }
+
+    /// Renders the samples as CSV, with a header row.
+    pub fn to_csv(&self) -> String {
+        let mut csv = "step,stack_depth,call_depth\n".to_owned();
+        for sample in self.samples() {
+            let _ = writeln!(
+                csv,
+                "{},{},{}",
+                sample.step, sample.stack_depth, sample.call_depth
+            );
+        }
+        csv
+    }
+
+    /// Renders the samples as an SVG line chart, with the stack depth in blue and the call depth in orange.
+    pub fn to_svg(&self) -> String {
+        let samples = self.samples();
+        let max_depth = samples
+            .iter()
+            .map(|s| s.stack_depth.max(s.call_depth))
+            .max()
+            .unwrap_or_default()
+            .max(1);
+        let plot_width = (WIDTH - 2 * MARGIN) as f64;
+        let plot_height = (HEIGHT - 2 * MARGIN) as f64;
+        let x = |step: u64| {
+            MARGIN as f64
+                + plot_width * (step - 1) as f64 / self.steps.max(2).saturating_sub(1) as f64
+        };
+        let y =
+            |depth: usize| (HEIGHT - MARGIN) as f64 - plot_height * depth as f64 / max_depth as f64;
+        let line = |depth: fn(&Sample) -> usize, color: &str| {
+            let points = samples
+                .iter()
+                .map(|s| format!("{:.1},{:.1}", x(s.step), y(depth(s))))
+                .collect::<Vec<_>>();
+            format!(
+                r#"<polyline fill="none" stroke="{color}" stroke-width="1.5" points="{}"/>"#,
+                points.join(" ")
+            )
+        };
+
+        let mut svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{HEIGHT}" font-family="sans-serif" font-size="12">"#
+        );
+        svg += "\n";
+        let _ = writeln!(
+            svg,
+            r#"<rect width="{WIDTH}" height="{HEIGHT}" fill="white"/>"#
+        );
+        let (left, right, top, bottom) = (MARGIN, WIDTH - MARGIN, MARGIN, HEIGHT - MARGIN);
+        let _ = writeln!(
+            svg,
+            r#"<polyline fill="none" stroke="black" points="{left},{top} {left},{bottom} {right},{bottom}"/>"#
+        );
+        let _ = writeln!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="end">{max_depth}</text>"#,
+            left - 4,
+            top + 4
+        );
+        let _ = writeln!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="end">0</text>"#,
+            left - 4,
+            bottom + 4
+        );
+        let _ = writeln!(
+            svg,
+            r#"<text x="{right}" y="{}" text-anchor="end">{} steps</text>"#,
+            bottom + 16,
+            self.steps
+        );
+        let _ = writeln!(
+            svg,
+            r##"<text x="{left}" y="{}" fill="#1f77b4">stack depth</text>"##,
+            top - 10
+        );
+        let _ = writeln!(
+            svg,
+            r##"<text x="{}" y="{}" fill="#ff7f0e">call depth</text>"##,
+            left + 100,
+            top - 10
+        );
+        let _ = writeln!(svg, "{}", line(|s| s.stack_depth, "#1f77b4"));
+        let _ = writeln!(svg, "{}", line(|s| s.call_depth, "#ff7f0e"));
+        svg += "</svg>\n";
+        svg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv() {
+        let mut recorder = Recorder::default();
+        recorder.record(1, 0);
+        recorder.record(2, 1);
+        recorder.record(1, 0);
+        assert_eq!(
+            "step,stack_depth,call_depth\n1,1,0\n2,2,1\n3,1,0\n",
+            recorder.to_csv()
+        );
+    }
+
+    #[test]
+    fn long_programs_are_sampled() {
+        let mut recorder = Recorder::default();
+        let steps = 3 * MAX_SAMPLES as u64 + 1;
+        for i in 0..steps {
+            recorder.record(i as usize % 7, 0);
+        }
+        let samples = recorder.samples();
+        assert!(samples.len() <= MAX_SAMPLES);
+        // Every sample covers the same number of steps and keeps the deepest stack among them
+        assert_eq!(1, samples[0].step);
+        assert_eq!(5, samples[1].step);
+        assert_eq!(3, samples[0].stack_depth);
+        assert_eq!(steps, samples.last().unwrap().step);
+    }
+
+    #[test]
+    fn svg() {
+        let mut recorder = Recorder::default();
+        recorder.record(1, 0);
+        recorder.record(2, 1);
+        let svg = recorder.to_svg();
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.contains(r##"<polyline fill="none" stroke="#1f77b4" stroke-width="1.5" points="40.0,150.0 760.0,40.0"/>"##));
+        assert!(svg.contains(">2 steps<"));
+    }
+}
@@ -0,0 +1,83 @@
+//! Cross-reference listings (`plates xref`).
+
+use crate::parser::{Reference, ReferenceKind};
+
+/// Lists every defined function in the order they were defined, each followed by the places where it is defined,
+/// imported, or pushed. Functions that are never pushed are marked, since they are probably dead code.
+pub fn listing(references: &[Reference]) -> String {
+    let mut functions = Vec::<&str>::new();
+    for reference in references {
+        if reference.kind == ReferenceKind::Definition
+            && !functions.contains(&reference.name.as_str())
+        {
+            functions.push(&reference.name);
+        }
+    }
+
+    let mut listing = String::new();
+    for f in functions {
+        let places = references
+            .iter()
+            .filter(|r| r.name == f)
+            .collect::<Vec<_>>();
+        listing += f;
+        if !places.iter().any(|r| r.kind == ReferenceKind::Push) {
+            listing += " (never pushed)";
+        }
+        listing += "\n";
+        for r in places {
+            let kind = match r.kind {
+                ReferenceKind::Definition => "defined",
+                ReferenceKind::Import => "imported",
+                ReferenceKind::Push => "pushed",
+            };
+            listing += &format!("    {}:{}:{}: {kind}\n", r.file, r.line, r.column);
+        }
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::Lexer, parser::Parser, reader::FileReader};
+
+    #[test]
+    fn modules_and_imports() {
+        let path = |name: &str| {
+            std::env::temp_dir().join(format!("plates-test-{}-xref-{name}", std::process::id()))
+        };
+        let (lib, main) = (path("lib.plates"), path("main.plates"));
+        std::fs::write(
+            &lib,
+            "MODULE math\nDEFN square (1) { PUSH $0 PUSH $0 PUSH 2 PUSH __birl__ CALLIF }\nDEFN cube (1) { PUSH $0 PUSH 1 PUSH square CALLIF }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &main,
+            "IMPORT math::square AS sq\nPUSH 3 PUSH 1 PUSH sq CALLIF\n",
+        )
+        .unwrap();
+
+        let reader = FileReader::new(vec![lib.clone(), main.clone()]).unwrap();
+        let mut parser = Parser::new(Lexer::new(reader)).with_references();
+        while parser.next_instruction().unwrap().is_some() {}
+        let references = parser.take_references();
+        std::fs::remove_file(&lib).unwrap();
+        std::fs::remove_file(&main).unwrap();
+        let (lib, main) = (lib.display(), main.display());
+        assert_eq!(
+            format!(
+                "math::square
+    {lib}:2:6: defined
+    {lib}:3:37: pushed
+    {main}:1:8: imported
+    {main}:2:20: pushed
+math::cube (never pushed)
+    {lib}:3:6: defined
+"
+            ),
+            listing(&references)
+        );
+    }
+}
@@ -0,0 +1,67 @@
+//! Helpers shared by the integration tests.
+
+use std::{
+    fs,
+    path::Path,
+    process::{Command, ExitStatus},
+};
+
+/// Runs plates with the given arguments and stdin (or no stdin), and returns stdout and stderr together. Both streams
+/// go to the same file so that errors show up where they happened.
+pub fn run_plates(args: &[&str], stdin: Option<&Path>) -> String {
+    run_plates_with_status(args, stdin).0
+}
+
+/// Like [`run_plates`], but also returns the exit status.
+pub fn run_plates_with_status(args: &[&str], stdin: Option<&Path>) -> (String, ExitStatus) {
+    let output_file = std::env::temp_dir().join(format!(
+        "plates-test-{}-{}.txt",
+        std::process::id(),
+        args.join("-").replace(['/', '\\', '.', ':'], "_")
+    ));
+    let output = fs::File::create(&output_file).unwrap();
+
+    let mut command = Command::new(env!("CARGO_BIN_EXE_plates"));
+    command
+        .args(args)
+        .env("NO_COLOR", "1")
+        .env_remove("PLATES_INIT")
+        .stdout(output.try_clone().unwrap())
+        .stderr(output);
+    match stdin {
+        None => command.stdin(std::process::Stdio::null()),
+        Some(path) => command.stdin(fs::File::open(path).unwrap()),
+    };
+    let status = command.status().unwrap();
+
+    let result = fs::read_to_string(&output_file).unwrap();
+    fs::remove_file(output_file).unwrap();
+    (result, status)
+}
+
+/// Returns the files in `tests/<dir>` with the given extension, sorted by name. The paths are relative to the root of
+/// the package (which is where the tests run), so that they look the same in every environment.
+pub fn test_files(dir: &str, extension: &str) -> Vec<std::path::PathBuf> {
+    let dir = Path::new("tests").join(dir);
+    let mut files = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == extension))
+        .collect::<Vec<_>>();
+    files.sort();
+    assert!(!files.is_empty());
+    files
+}
+
+/// Compares the output for `file` with the `.expected` file next to it. If `UPDATE_EXPECTED` is set, the expected
+/// output is overwritten instead.
+pub fn check_expected(file: &Path, actual: &str) {
+    let expected_file = file.with_extension("expected");
+    if std::env::var_os("UPDATE_EXPECTED").is_some() {
+        fs::write(&expected_file, actual).unwrap();
+        return;
+    }
+
+    let expected = fs::read_to_string(&expected_file).unwrap_or_default();
+    assert_eq!(expected, actual, "{}", file.display());
+}
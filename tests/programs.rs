@@ -0,0 +1,358 @@
+//! Runs each program in `tests/programs` and compares its output (stdout and stderr together) with the `.expected`
+//! file next to it. If there is a `.input` file with the same name, it is used as stdin. Run with
+//! `UPDATE_EXPECTED=1` to overwrite the expected output instead.
+
+mod common;
+
+#[test]
+fn programs() {
+    for program in common::test_files("programs", "plates") {
+        let input = program.with_extension("input");
+        let (output, status) = common::run_plates_with_status(
+            &["run", program.to_str().unwrap()],
+            input.exists().then_some(input.as_path()),
+        );
+        common::check_expected(&program, &output);
+        // syntax_error.plates and runtime_error.plates fail
+        let failed = program.to_str().unwrap().ends_with("_error.plates");
+        assert_eq!(
+            Some(i32::from(failed)),
+            status.code(),
+            "{}",
+            program.display()
+        );
+    }
+}
+
+#[test]
+fn optimized_programs() {
+    // Optimization must not change what a program does
+    for program in common::test_files("programs", "plates") {
+        let input = program.with_extension("input");
+        let output = common::run_plates(
+            &["run", "--opt", program.to_str().unwrap()],
+            input.exists().then_some(input.as_path()),
+        );
+        common::check_expected(&program, &output);
+    }
+}
+
+#[test]
+fn json_programs() {
+    // Going through JSON must not change what a program does either
+    for program in common::test_files("programs", "plates") {
+        let input = program.with_extension("input");
+        let Some(output) = run_through_json(
+            &[program.to_str().unwrap()],
+            input.exists().then_some(input.as_path()),
+        ) else {
+            continue;
+        };
+        common::check_expected(&program, &output);
+    }
+    // Private functions must stay private
+    let output = run_through_json(
+        &["tests/private/lib.plates", "tests/private/main.plates"],
+        None,
+    )
+    .unwrap();
+    common::check_expected(std::path::Path::new("tests/private/main.plates"), &output);
+}
+
+/// Emits the files as JSON and runs the JSON, or returns `None` if the files have syntax errors (in which case there is
+/// nothing to emit).
+fn run_through_json(files: &[&str], input: Option<&std::path::Path>) -> Option<String> {
+    let json = std::env::temp_dir().join(format!(
+        "plates-test-{}-{}.json",
+        std::process::id(),
+        files.join("-").replace(['/', '\\', '.'], "_")
+    ));
+    let emitted = common::run_plates(&[&["run", "--emit", "json"], files].concat(), None);
+    if !emitted.starts_with('{') {
+        assert!(emitted.contains("Syntax error"), "{emitted}");
+        return None;
+    }
+    std::fs::write(&json, emitted).unwrap();
+    let output = common::run_plates(&["run", "--run-json", json.to_str().unwrap()], input);
+    std::fs::remove_file(json).unwrap();
+    Some(output)
+}
+
+#[test]
+fn print_stack() {
+    // The output in both formats goes in the same expected file
+    for program in common::test_files("stack", "plates") {
+        let path = program.to_str().unwrap();
+        let human = common::run_plates(&["run", "--print-stack", path], None);
+        let json = common::run_plates(
+            &["run", "--print-stack=3", "--stack-format", "json", path],
+            None,
+        );
+        common::check_expected(&program, &(human + &json));
+    }
+}
+
+#[test]
+fn exit_status_from_stack() {
+    for (program, status) in [
+        ("tests/stack/results.plates", 3),
+        ("tests/programs/hello.plates", 1),
+    ] {
+        let status_code = std::process::Command::new(env!("CARGO_BIN_EXE_plates"))
+            .args(["run", "--exit-status-from-stack", program])
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .unwrap()
+            .code();
+        assert_eq!(Some(status), status_code, "{program}");
+    }
+}
+
+#[test]
+fn quiet() {
+    let output = common::run_plates(&["run", "-q", "tests/programs/hello.plates"], None);
+    assert_eq!("Hello, world!\n", output);
+}
+
+#[test]
+fn color() {
+    // The tests run with NO_COLOR set, which --color=always overrides
+    let output = common::run_plates(
+        &["run", "--color=always", "tests/programs/hello.plates"],
+        None,
+    );
+    assert!(output.contains("\x1b["), "{output}");
+    let output = common::run_plates(
+        &["run", "--color=never", "tests/programs/hello.plates"],
+        None,
+    );
+    assert!(!output.contains("\x1b["), "{output}");
+}
+
+#[test]
+fn doc() {
+    for program in common::test_files("doc", "plates") {
+        let output = common::run_plates(&["doc", program.to_str().unwrap()], None);
+        common::check_expected(&program, &output);
+    }
+}
+
+#[test]
+fn modules() {
+    // Each file has its own module and imports
+    let output = common::run_plates(
+        &[
+            "run",
+            "tests/modules/shout.plates",
+            "tests/modules/main.plates",
+        ],
+        None,
+    );
+    common::check_expected(std::path::Path::new("tests/modules/main.plates"), &output);
+}
+
+#[test]
+fn private_functions() {
+    let output = common::run_plates(
+        &[
+            "run",
+            "tests/private/lib.plates",
+            "tests/private/main.plates",
+        ],
+        None,
+    );
+    common::check_expected(std::path::Path::new("tests/private/main.plates"), &output);
+}
+
+#[test]
+fn exit_across_files() {
+    // The EXIT at the end of the first file keeps the second one from running
+    let output = common::run_plates(
+        &["check", "tests/exit/lib.plates", "tests/exit/main.plates"],
+        None,
+    );
+    common::check_expected(std::path::Path::new("tests/exit/main.plates"), &output);
+}
+
+#[test]
+fn warn_uncalled() {
+    let warning = "warning[W007]: Function '__print__' is still on the stack when the program ends. Did you forget to call it? (uncalled-function)";
+    let output = common::run_plates(
+        &["run", "--warn-uncalled", "tests/stack/results.plates"],
+        None,
+    );
+    assert!(output.contains(warning), "{output}");
+    let output = common::run_plates(&["run", "tests/stack/results.plates"], None);
+    assert!(!output.contains(warning), "{output}");
+}
+
+#[test]
+fn json_logs() {
+    let output = common::run_plates(
+        &[
+            "run",
+            "--log-level",
+            "debug",
+            "--log-json",
+            "tests/programs/hello.plates",
+        ],
+        None,
+    );
+    assert!(output.contains("Hello, world!"), "{output}");
+    let call = output
+        .lines()
+        .find(|line| line.contains(r#""message":"call""#))
+        .unwrap_or_else(|| panic!("{output}"));
+    assert!(call.starts_with('{') && call.ends_with('}'), "{call}");
+    assert!(call.contains(r#""level":"DEBUG""#), "{call}");
+}
+
+#[test]
+fn viz() {
+    let csv = std::env::temp_dir().join(format!("plates-test-{}-viz.csv", std::process::id()));
+    let svg = csv.with_extension("svg");
+    for chart in [&csv, &svg] {
+        common::run_plates(
+            &[
+                "run",
+                "--viz",
+                chart.to_str().unwrap(),
+                "tests/programs/loop.plates",
+            ],
+            None,
+        );
+    }
+    let csv_chart = std::fs::read_to_string(&csv).unwrap();
+    let svg_chart = std::fs::read_to_string(&svg).unwrap();
+    std::fs::remove_file(csv).unwrap();
+    std::fs::remove_file(svg).unwrap();
+
+    let mut lines = csv_chart.lines();
+    assert_eq!(Some("step,stack_depth,call_depth"), lines.next());
+    assert_eq!(Some("1,0,0"), lines.next());
+    // loop.plates calls print_line five times
+    assert!(lines.any(|line| !line.ends_with(",0")), "{csv_chart}");
+    assert!(svg_chart.starts_with("<svg "), "{svg_chart}");
+}
+
+#[test]
+fn unexpected_end_of_file() {
+    let file = std::env::temp_dir().join(format!(
+        "plates-test-{}-unclosed.plates",
+        std::process::id()
+    ));
+    std::fs::write(&file, "PUSH 1\nDEFN f (0) {\n    PUSH 2\n").unwrap();
+    let output = common::run_plates(
+        &["run", "tests/programs/hello.plates", file.to_str().unwrap()],
+        None,
+    );
+    std::fs::remove_file(&file).unwrap();
+    assert_eq!(
+        format!(
+            "Hello, world!\n\
+             Error in {} on line 3.\n\n\
+             Caused by:\n    \
+             Syntax error: Unexpected end of file in body of function 'f'.\n",
+            file.display()
+        ),
+        output
+    );
+}
+
+#[test]
+fn minify() {
+    let output = common::run_plates(
+        &["fmt", "-q", "--minify", "tests/programs/loop.plates"],
+        None,
+    );
+    assert!(output.ends_with("CALLIF\na = print_line\n"), "{output}");
+
+    let map = std::env::temp_dir().join(format!("plates-test-{}-renames.txt", std::process::id()));
+    let output = common::run_plates(
+        &[
+            "fmt",
+            "--minify",
+            "--rename-map",
+            map.to_str().unwrap(),
+            "tests/programs/loop.plates",
+        ],
+        None,
+    );
+    let renames = std::fs::read_to_string(&map).unwrap();
+    std::fs::remove_file(map).unwrap();
+    assert!(output.ends_with("CALLIF\n"), "{output}");
+    assert_eq!("a = print_line\n", renames);
+}
+
+#[test]
+fn failing_subcommands() {
+    for subcommand in [
+        "run", "fmt", "compile", "build", "doc", "graph", "stats", "xref",
+    ] {
+        let (output, status) =
+            common::run_plates_with_status(&[subcommand, "tests/does_not_exist.plates"], None);
+        assert!(
+            output.contains("Failed to read file"),
+            "{subcommand}: {output}"
+        );
+        assert_eq!(Some(1), status.code(), "{subcommand}");
+    }
+}
+
+#[test]
+fn pending() {
+    let output = common::run_plates(
+        &[
+            "run",
+            "--debug=call",
+            "--pending",
+            "2",
+            "tests/programs/loop.plates",
+        ],
+        None,
+    );
+    assert!(
+        output.contains("Pending instructions:\n    PUSH 0\n    PUSH 10\n    ... and "),
+        "{output}"
+    );
+    // Nothing is pending between top-level instructions
+    let output = common::run_plates(
+        &[
+            "run",
+            "--debug",
+            "--pending",
+            "2",
+            "tests/programs/loop.plates",
+        ],
+        None,
+    );
+    assert!(
+        output.starts_with("error: --pending needs --debug=instruction or --debug=call."),
+        "{output}"
+    );
+}
+
+#[test]
+fn debug_diff() {
+    let output = common::run_plates(
+        &[
+            "run",
+            "--debug",
+            "--debug-diff",
+            "tests/programs/loop.plates",
+        ],
+        None,
+    );
+    let diffs = output.lines().take(4).collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            "(no change)",
+            "+1 [5]",
+            "+1 [function print_line]",
+            "+1 [1]",
+        ],
+        diffs,
+        "{output}"
+    );
+}
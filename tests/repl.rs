@@ -0,0 +1,170 @@
+//! Runs each scripted REPL session in `tests/repl` and compares the transcript (stdout and stderr together) with the
+//! `.expected` file next to it. Run with `UPDATE_EXPECTED=1` to overwrite the expected transcripts instead.
+
+mod common;
+
+#[test]
+fn transcripts() {
+    for input in common::test_files("repl", "input") {
+        let transcript = common::run_plates(&["repl", "--transcript", "--debug"], Some(&input));
+        common::check_expected(&input, &transcript);
+    }
+}
+
+#[test]
+fn no_terminal_features() {
+    assert_eq!(
+        "Welcome to the plates REPL!\n> \nEnd of input. Goodbye!\n",
+        common::run_plates(&["repl", "--transcript"], None)
+    );
+}
+
+#[test]
+fn exit_status() {
+    for (input, status) in [("tests/repl/eof.input", 0), ("tests/repl/exit.input", 3)] {
+        let (_, exit_status) = common::run_plates_with_status(
+            &["repl", "--transcript"],
+            Some(std::path::Path::new(input)),
+        );
+        assert_eq!(Some(status), exit_status.code(), "{input}");
+    }
+}
+
+#[test]
+fn startup_file() {
+    let dir = std::env::temp_dir();
+    let init = dir.join(format!("plates-test-{}-platesrc", std::process::id()));
+    let input = dir.join(format!("plates-test-{}-startup", std::process::id()));
+    std::fs::write(
+        &init,
+        "DEFN one (0) { PUSH 1 }\nPUSH 7 PUSH 1 PUSH nope CALLIF\nPUSH 8\n",
+    )
+    .unwrap();
+    std::fs::write(&input, ":functions\nPUSH 1\n").unwrap();
+
+    let transcript = common::run_plates(
+        &[
+            "repl",
+            "--transcript",
+            "--debug",
+            "--init",
+            init.to_str().unwrap(),
+        ],
+        Some(&input),
+    );
+    std::fs::remove_file(&init).unwrap();
+    std::fs::remove_file(input).unwrap();
+    // The startup file stops at the first error, but the REPL keeps what it did until then (including the words that
+    // the failed CALLIF put back)
+    assert_eq!(
+        format!(
+            "Welcome to the plates REPL!\n\
+             Failed to run startup file '{}'.\n\n\
+             Caused by:\n    \
+             Runtime error: Undefined argument or function.\n\
+             > :functions\n\
+             one (0)\n\
+             > PUSH 1\n\
+             0 | 0x00000001          1  <-- top\n\
+             1 | function nope\n\
+             2 | 0x00000001          1\n\
+             3 | 0x00000007          7\n\n\
+             > \n\
+             End of input. Goodbye!\n",
+            init.display()
+        ),
+        transcript
+    );
+}
+
+#[test]
+fn program_reads_input() {
+    // The line after CALLIF is read by __input__ rather than by the REPL
+    let input = std::env::temp_dir().join(format!("plates-test-{}-repl-input", std::process::id()));
+    std::fs::write(
+        &input,
+        "PUSH 0 PUSH 1\nPUSH __input__\nCALLIF\nhi\nPUSH 1\n",
+    )
+    .unwrap();
+    let transcript = common::run_plates(&["repl", "--transcript", "--debug"], Some(&input));
+    std::fs::remove_file(input).unwrap();
+    assert!(
+        transcript.contains(
+            "> CALLIF\n\
+             0 | 0x00000068        104 'h'  <-- top\n\
+             1 | 0x00000069        105 'i'\n\
+             2 | 0x0000000A         10\n\
+             3 | 0x00000000          0\n\n\
+             > PUSH 1\n"
+        ),
+        "{transcript}"
+    );
+}
+
+#[test]
+fn windows_line_endings() {
+    // Sessions typed in a Windows console end their lines with \r\n, which must not change the transcript
+    for input in common::test_files("repl", "input") {
+        let crlf = std::env::temp_dir().join(format!(
+            "plates-test-{}-{}",
+            std::process::id(),
+            input.file_name().unwrap().to_str().unwrap()
+        ));
+        let contents = std::fs::read_to_string(&input).unwrap();
+        std::fs::write(&crlf, contents.replace("\r\n", "\n").replace('\n', "\r\n")).unwrap();
+
+        let transcript = common::run_plates(&["repl", "--transcript", "--debug"], Some(&crlf));
+        std::fs::remove_file(crlf).unwrap();
+        common::check_expected(&input, &transcript);
+    }
+}
+
+#[test]
+fn serve() {
+    use std::io::{BufRead, Read, Write};
+
+    let mut server = std::process::Command::new(env!("CARGO_BIN_EXE_plates"))
+        .args(["serve", "--port", "0"])
+        .env("NO_COLOR", "1")
+        .env_remove("PLATES_INIT")
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut listening = String::new();
+    std::io::BufReader::new(server.stdout.take().unwrap())
+        .read_line(&mut listening)
+        .unwrap();
+    let address = listening
+        .trim_end()
+        .strip_prefix("Listening on ")
+        .and_then(|address| address.strip_suffix('.'))
+        .unwrap()
+        .to_owned();
+
+    // Each connection has its own session, so the second one cannot see the function defined in the first
+    let session = |input: &str| {
+        let mut connection = std::net::TcpStream::connect(&address).unwrap();
+        connection.write_all(input.as_bytes()).unwrap();
+        connection.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut transcript = String::new();
+        connection.read_to_string(&mut transcript).unwrap();
+        transcript
+    };
+    let first = session("DEFN f (0) { PUSH 3 }\n:functions\nPUSH 1 PUSH nope CALLIF\n");
+    let second = session(":functions\n");
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    assert_eq!(
+        "Welcome to the plates REPL!\n\
+         > > f (0)\n\
+         > Runtime error: Undefined argument or function.\n\
+         > \n\
+         End of input. Goodbye!\n",
+        first
+    );
+    assert_eq!(
+        "Welcome to the plates REPL!\n> > \nEnd of input. Goodbye!\n",
+        second
+    );
+}